@@ -0,0 +1,486 @@
+use crate::blocks::get_address;
+use crate::blocks::log;
+use crate::blocks::rlp::encode_bytes;
+use crate::blocks::rlp::encode_list;
+use crate::blocks::rlp::encode_number;
+use chainblocks::block::Block;
+use chainblocks::cblog;
+use chainblocks::cstr;
+use chainblocks::types::common_type;
+use chainblocks::types::ClonedVar;
+use chainblocks::types::Context;
+use chainblocks::types::ParamVar;
+use chainblocks::types::Parameters;
+use chainblocks::types::Type;
+use chainblocks::types::Var;
+use secp256k1::PublicKey;
+use secp256k1::SecretKey;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::env;
+use std::ffi::CString;
+use web3::signing::keccak256;
+use web3::signing::Key;
+use web3::signing::SecretKeyRef;
+use web3::types::Address;
+use web3::types::H256;
+use zeroize::Zeroize;
+
+pub const BAD_CHECKSUM: &str = "Address doesn't match its EIP-55 checksum";
+
+/// The EIP-55 mixed case form, with its 0x prefix.
+pub fn to_checksum(address: &Address) -> String {
+  let lower = hex::encode(address.as_bytes());
+  let hash = keccak256(lower.as_bytes());
+  let digits: String = lower
+    .chars()
+    .enumerate()
+    .map(|(i, c)| {
+      let nibble = if i % 2 == 0 {
+        hash[i / 2] >> 4
+      } else {
+        hash[i / 2] & 0x0f
+      };
+      if nibble >= 8 {
+        c.to_ascii_uppercase()
+      } else {
+        c
+      }
+    })
+    .collect();
+  format!("0x{}", digits)
+}
+
+// all lower or all upper case addresses carry no checksum
+fn checked_address<'a>(text: &str, strict: bool, invalid: &'a str) -> Result<Address, &'a str> {
+  let digits = text.strip_prefix("0x").unwrap_or(text);
+  let address: Address = digits.parse().or_else(|_| Err(invalid))?;
+  let mixed = digits.chars().any(|c| c.is_ascii_lowercase())
+    && digits.chars().any(|c| c.is_ascii_uppercase());
+  if mixed && to_checksum(&address)[2..] != *digits {
+    if strict {
+      return Err(BAD_CHECKSUM);
+    }
+    cblog!(
+      "Address {} doesn't match its checksum, expected {}",
+      text,
+      to_checksum(&address)
+    );
+  }
+  Ok(address)
+}
+
+/// Parses a hex address, mismatching checksums are only logged unless WEB3_STRICT_CHECKSUM is set.
+pub fn parse_address<'a>(text: &str, invalid: &'a str) -> Result<Address, &'a str> {
+  let strict = match env::var("WEB3_STRICT_CHECKSUM") {
+    Ok(val) => val != "0" && val != "false",
+    Err(_) => false,
+  };
+  checked_address(text, strict, invalid)
+}
+
+/// The address of a 32 bytes secret key or of a 33 or 65 bytes SEC1 public key, 64 bytes without its 0x04 prefix.
+pub fn key_address<'a>(key: &[u8]) -> Result<Address, &'a str> {
+  let public = match key.len() {
+    32 => {
+      let secret = SecretKey::from_slice(key).or_else(|_| Err("Invalid secret key"))?;
+      return Ok(SecretKeyRef::new(&secret).address());
+    }
+    33 | 65 => PublicKey::from_slice(key).or_else(|_| Err("Invalid public key"))?,
+    64 => {
+      PublicKey::from_slice(&[&[4u8][..], key].concat()).or_else(|_| Err("Invalid public key"))?
+    }
+    _ => return Err("Keys are 32 bytes secret or 33, 64 or 65 bytes public keys"),
+  };
+  let point = public.serialize_uncompressed();
+  Ok(Address::from_slice(&keccak256(&point[1..])[12..]))
+}
+
+/// Where CREATE deploys a contract from this sender and nonce.
+pub fn create_address(sender: &Address, nonce: u64) -> Address {
+  let encoded = encode_list(&[encode_bytes(sender.as_bytes()), encode_number(nonce.into())]);
+  Address::from_slice(&keccak256(&encoded)[12..])
+}
+
+/// Where CREATE2 deploys this init code, see EIP-1014.
+pub fn create2_address(sender: &Address, salt: &H256, init_code: &[u8]) -> Address {
+  let preimage = [
+    &[0xffu8][..],
+    sender.as_bytes(),
+    salt.as_bytes(),
+    &keccak256(init_code)[..],
+  ]
+  .concat();
+  Address::from_slice(&keccak256(&preimage)[12..])
+}
+
+// hex strings with or without 0x, or raw bytes
+fn bytes_from_var<'a>(value: &Var, invalid: &'a str) -> Result<Vec<u8>, &'a str> {
+  if let Ok(text) = <&str>::try_from(value) {
+    hex::decode(text.strip_prefix("0x").unwrap_or(text)).or_else(|_| Err(invalid))
+  } else {
+    let bytes: &[u8] = value.try_into().or_else(|_| Err(invalid))?;
+    Ok(bytes.to_vec())
+  }
+}
+
+fn address_output<'a>(address: &Address, output: &mut ClonedVar) -> Result<Var, &'a str> {
+  let checksum = CString::new(to_checksum(address)).unwrap();
+  *output = checksum.as_ref().into();
+  Ok(output.0)
+}
+
+lazy_static! {
+  static ref ADDRESS_TYPES: Vec<Type> = vec![common_type::string, common_type::bytes];
+  static ref KEY_TYPES: Vec<Type> = vec![common_type::bytes, common_type::string];
+  static ref NONCE_TYPES: Vec<Type> = vec![common_type::int];
+  static ref INIT_CODE_TYPES: Vec<Type> = vec![common_type::bytes, common_type::string];
+  static ref STRING_TYPES: Vec<Type> = vec![common_type::string];
+  static ref CREATE_PARAMETERS: Parameters = vec![(
+    cstr!("Sender"),
+    cstr!("The address deploying the contract."),
+    vec![
+      common_type::string,
+      common_type::string_var,
+      common_type::bytes,
+      common_type::bytes_var
+    ],
+  )
+    .into(),];
+  static ref CREATE2_PARAMETERS: Parameters = vec![
+    (
+      cstr!("Sender"),
+      cstr!("The address deploying the contract, usually a factory contract."),
+      vec![
+        common_type::string,
+        common_type::string_var,
+        common_type::bytes,
+        common_type::bytes_var
+      ],
+    )
+      .into(),
+    (
+      cstr!("Salt"),
+      cstr!("The 32 bytes salt given to CREATE2, as bytes or hex."),
+      vec![
+        common_type::bytes,
+        common_type::bytes_var,
+        common_type::string,
+        common_type::string_var
+      ],
+    )
+      .into(),
+  ];
+}
+
+pub struct ToChecksum {
+  output: ClonedVar,
+}
+
+impl Default for ToChecksum {
+  fn default() -> Self {
+    ToChecksum {
+      output: ClonedVar(Var::default()),
+    }
+  }
+}
+
+impl Block for ToChecksum {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.Address.ToChecksum-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.Address.ToChecksum")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.Address.ToChecksum"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &ADDRESS_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &STRING_TYPES
+  }
+
+  fn activate(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
+    let address = get_address(*input)?;
+    address_output(&address, &mut self.output)
+  }
+}
+
+pub struct FromKey {
+  output: ClonedVar,
+}
+
+impl Default for FromKey {
+  fn default() -> Self {
+    FromKey {
+      output: ClonedVar(Var::default()),
+    }
+  }
+}
+
+impl Block for FromKey {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.Address.FromKey-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.Address.FromKey")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.Address.FromKey"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &KEY_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &STRING_TYPES
+  }
+
+  fn activate(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
+    let mut key = bytes_from_var(input, "Failed to decode key")?;
+    let address = key_address(&key);
+    key.zeroize();
+    address_output(&address?, &mut self.output)
+  }
+}
+
+pub struct Create {
+  sender: ParamVar,
+  output: ClonedVar,
+}
+
+impl Default for Create {
+  fn default() -> Self {
+    Create {
+      sender: ParamVar::new(().into()),
+      output: ClonedVar(Var::default()),
+    }
+  }
+}
+
+impl Block for Create {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.Address.Create-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.Address.Create")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.Address.Create"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &NONCE_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &STRING_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&CREATE_PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.sender.set_param(value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.sender.get_param(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.sender.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.sender.cleanup();
+  }
+
+  fn activate(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
+    let sender = get_address(self.sender.get())?;
+    let nonce: i64 = input.try_into()?;
+    let nonce = u64::try_from(nonce).or_else(|_| Err("Nonce can't be negative"))?;
+    address_output(&create_address(&sender, nonce), &mut self.output)
+  }
+}
+
+pub struct Create2 {
+  sender: ParamVar,
+  salt: ParamVar,
+  output: ClonedVar,
+}
+
+impl Default for Create2 {
+  fn default() -> Self {
+    Create2 {
+      sender: ParamVar::new(().into()),
+      salt: ParamVar::new(().into()),
+      output: ClonedVar(Var::default()),
+    }
+  }
+}
+
+impl Block for Create2 {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.Address.Create2-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.Address.Create2")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.Address.Create2"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &INIT_CODE_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &STRING_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&CREATE2_PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.sender.set_param(value),
+      1 => self.salt.set_param(value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.sender.get_param(),
+      1 => self.salt.get_param(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.sender.warmup(context);
+    self.salt.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.salt.cleanup();
+    self.sender.cleanup();
+  }
+
+  fn activate(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
+    let sender = get_address(self.sender.get())?;
+    let salt = bytes_from_var(&self.salt.get(), "Failed to decode Salt")?;
+    if salt.len() != 32 {
+      return Err("Salt must be 32 bytes");
+    }
+    let init_code = bytes_from_var(input, "Failed to decode init code")?;
+    let address = create2_address(&sender, &H256::from_slice(&salt), &init_code);
+    address_output(&address, &mut self.output)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn address(text: &str) -> Address {
+    text[2..].to_lowercase().parse().unwrap()
+  }
+
+  #[test]
+  fn checksums_like_eip55() {
+    for expected in &[
+      "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+      "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+      "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+      "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+    ] {
+      assert_eq!(to_checksum(&address(expected)), *expected);
+      assert_eq!(
+        checked_address(expected, true, "bad"),
+        Ok(address(expected))
+      );
+    }
+  }
+
+  #[test]
+  fn rejects_bad_checksums_when_strict() {
+    let good = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+    let typo = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD";
+    assert_eq!(checked_address(typo, true, "bad"), Err(BAD_CHECKSUM));
+    assert_eq!(checked_address(typo, false, "bad"), Ok(address(good)));
+    // single case carries no checksum
+    let lower = good.to_lowercase();
+    assert_eq!(checked_address(&lower, true, "bad"), Ok(address(good)));
+    let upper = good[2..].to_uppercase();
+    assert_eq!(checked_address(&upper, true, "bad"), Ok(address(good)));
+    assert_eq!(checked_address("0x5aAeb6", true, "bad"), Err("bad"));
+  }
+
+  #[test]
+  fn derives_addresses_from_keys() {
+    let secret =
+      hex::decode("4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318").unwrap();
+    let expected = address("0x2c7536E3605D9C16a7a3D7b1898e529396a65c23");
+    assert_eq!(key_address(&secret), Ok(expected));
+    let public = PublicKey::from_secret_key(
+      &secp256k1::Secp256k1::signing_only(),
+      &SecretKey::from_slice(&secret).unwrap(),
+    );
+    assert_eq!(key_address(&public.serialize()), Ok(expected));
+    let point = public.serialize_uncompressed();
+    assert_eq!(key_address(&point), Ok(expected));
+    assert_eq!(key_address(&point[1..]), Ok(expected));
+    assert!(key_address(&[1u8; 20]).is_err());
+  }
+
+  #[test]
+  fn predicts_deployments() {
+    let sender = address("0x6ac7ea33f8831ea9dcc53393aaa88b25a785dbf0");
+    assert_eq!(
+      create_address(&sender, 0),
+      address("0xcd234a471b72ba2f1ccf0a70fcaba648a5eecd8d")
+    );
+    assert_eq!(
+      create_address(&sender, 1),
+      address("0x343c43a37d37dff08ae8c4a11544c718abb4fcf8")
+    );
+    // the examples of EIP-1014
+    assert_eq!(
+      create2_address(&Address::zero(), &H256::zero(), &[0]),
+      address("0x4D1A2e2bB4F88F0250f26Ffff098B0b30B26BF38")
+    );
+    assert_eq!(
+      create2_address(
+        &address("0xdeadbeef00000000000000000000000000000000"),
+        &H256::zero(),
+        &[0]
+      ),
+      address("0xB928f69Bb1D91Cd65274e3c79d8986362984fDA3")
+    );
+  }
+}
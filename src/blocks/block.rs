@@ -1,12 +1,18 @@
-use crate::blocks::get_timeout;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::selector::BlockSelector;
+use crate::blocks::shared_from_var;
 use crate::blocks::NodeData;
+use crate::blocks::BLOCK_HASH_TYPE;
 use crate::blocks::NODE_TYPE;
 use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
 use chainblocks::block::Block;
 use chainblocks::cblog;
 use chainblocks::cbstr;
 use chainblocks::core::activate_blocking;
-use chainblocks::core::log;
 use chainblocks::core::BlockingBlock;
 use chainblocks::cstr;
 use chainblocks::types::common_type;
@@ -20,12 +26,60 @@ use chainblocks::types::Seq;
 use chainblocks::types::Table;
 use chainblocks::types::Type;
 use chainblocks::types::Var;
+use jsonrpc_core::serde_json;
+use jsonrpc_core::types::Value;
 use std::convert::TryInto;
-use std::rc::Rc;
 use std::str;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::timeout;
-use web3::types::BlockId;
+use web3::types::Block;
+use web3::types::Transaction;
+use web3::types::H256;
+use web3::Transport as _;
+
+async fn request_block<'a>(
+  node: &NodeData,
+  block: &BlockSelector,
+  full: bool,
+  timeout: Duration,
+) -> Result<Value, &'a str> {
+  let (method, params) = block.block_request(full);
+  let transport = node.web3.transport();
+  let value = node
+    .retry
+    .timeout(timeout, || transport.execute(method, params.clone()))
+    .await
+    .or_else(|_| Err("Block request timedout"))?
+    .or_else(|e| {
+      cblog!("Block error: {}", e);
+      Err("Block request failed")
+    })?;
+  if value.is_null() {
+    Err("Block not found")
+  } else {
+    Ok(value)
+  }
+}
+
+/// The block with the hashes of its transactions.
+pub async fn get_block<'a>(
+  node: &NodeData,
+  block: &BlockSelector,
+  timeout: Duration,
+) -> Result<Block<H256>, &'a str> {
+  let value = request_block(node, block, false, timeout).await?;
+  serde_json::from_value(value).or_else(|_| Err("Invalid block"))
+}
+
+/// The block with its transactions in full.
+pub async fn get_full_block<'a>(
+  node: &NodeData,
+  block: &BlockSelector,
+  timeout: Duration,
+) -> Result<Block<Transaction>, &'a str> {
+  let value = request_block(node, block, true, timeout).await?;
+  serde_json::from_value(value).or_else(|_| Err("Invalid block"))
+}
 
 static TABLE_TYPES: &'static [Type] = &[
   common_type::bytes,
@@ -72,9 +126,9 @@ static TABLE_TYPE2: Type = Type::table(TABLE_KEYS, TABLE_TYPES2);
 pub struct EthBlock {
   full: bool,
   node_param: ParamVar,
-  node: Option<Rc<Option<NodeData>>>,
+  node: Option<Arc<NodeData>>,
   output: Table,
-  timeout: Duration,
+  timeout: ParamVar,
   requiring: ExposedTypes,
 }
 
@@ -85,14 +139,20 @@ impl Default for EthBlock {
       node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
       node: None,
       output: Table::new(),
-      timeout: get_timeout(),
+      timeout: ParamVar::new(().into()),
       requiring: Vec::new(),
     }
   }
 }
 
 lazy_static! {
-  static ref INPUT_TYPES: Vec<Type> = vec![common_type::int];
+  static ref INPUT_TYPES: Vec<Type> = vec![
+    common_type::none,
+    common_type::int,
+    common_type::string,
+    common_type::bytes,
+    BLOCK_HASH_TYPE
+  ];
   static ref OUTPUT_TYPES: Vec<Type> = vec![TABLE_TYPE];
   static ref OUTPUT_TYPES2: Vec<Type> = vec![TABLE_TYPE2];
   static ref PARAMETERS: Parameters = vec![
@@ -108,6 +168,19 @@ lazy_static! {
       vec![NODE_VAR],
     )
       .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
   ];
 }
 
@@ -144,6 +217,7 @@ impl Block for EthBlock {
     match index {
       0 => self.full = value.try_into().unwrap(),
       1 => self.node_param.set_param(value),
+      2 => self.timeout.set_param(value),
       _ => unreachable!(),
     }
   }
@@ -152,6 +226,7 @@ impl Block for EthBlock {
     match index {
       0 => self.full.into(),
       1 => self.node_param.get_param(),
+      2 => self.timeout.get_param(),
       _ => unreachable!(),
     }
   }
@@ -170,10 +245,12 @@ impl Block for EthBlock {
 
   fn warmup(&mut self, context: &Context) -> Result<(), &str> {
     self.node_param.warmup(context);
+    self.timeout.warmup(context);
     Ok(())
   }
 
   fn cleanup(&mut self) {
+    self.timeout.cleanup();
     self.node_param.cleanup();
     self.node = None;
   }
@@ -186,125 +263,125 @@ impl Block for EthBlock {
 impl BlockingBlock for EthBlock {
   fn activate_blocking(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
     if self.node.is_none() {
-      self.node = Some(Var::from_object_as_clone::<Option<NodeData>>(
-        self.node_param.get(),
-        &NODE_TYPE,
-      )?);
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
     }
-    let block_number: u64 = input.try_into()?;
-    let node = Var::get_mut_from_clone(&self.node)?;
-    let (scheduler, eth) = (&mut node.scheduler, &mut node.web3.eth());
+    // a number, tag or hash, the latest block if none
+    let block = BlockSelector::from_var(input)?.unwrap_or(BlockSelector::Tag("latest"));
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
     if self.full {
-      let fut = eth.block_with_txs(BlockId::Number(block_number.into()));
-      scheduler.block_on(async {
-        let timed_fut = timeout(self.timeout, fut);
-        let fut_res = timed_fut.await;
-        if let Ok(res) = fut_res {
-          match res {
-            Ok(value) => {
-              if let Some(value) = value {
-                bytes_to_var_no_opt!(self.output, value.parent_hash, 32, "parent_hash");
-                bytes_to_var_no_opt!(self.output, value.uncles_hash, 32, "uncles_hash");
-                bytes_to_var_no_opt!(self.output, value.author, 20, "author");
-                bytes_to_var_no_opt!(self.output, value.state_root, 32, "state_root");
-                bytes_to_var_no_opt!(
-                  self.output,
-                  value.transactions_root,
-                  32,
-                  "transactions_root"
-                );
-                bytes_to_var_no_opt!(self.output, value.receipts_root, 32, "receipts_root");
-                bytes_to_var_no_opt!(self.output, value.gas_used, 32, "gas_used");
-                bytes_to_var_no_opt!(self.output, value.gas_limit, 32, "gas_limit");
-                bytes_to_var_no_opt!(self.output, value.timestamp, 32, "timestamp");
-                bytes_to_var_no_opt!(self.output, value.difficulty, 32, "difficulty");
-                let tvar = self.output.get_mut_fast_static(cstr!("transactions"));
-                let mut transactions: Seq = tvar.try_into()?;
-                for value in value.transactions {
-                  let mut tab = Table::new();
-                  // mandatory keys
-                  tab.insert_fast_static(cstr!("input"), value.input.0.as_slice().into());
-                  let bytes: [u8; 32] = value.gas.into();
-                  let bytes = &bytes[..];
-                  tab.insert_fast_static(cstr!("gas"), bytes.into());
-                  let bytes: [u8; 32] = value.gas_price.into();
-                  let bytes = &bytes[..];
-                  tab.insert_fast_static(cstr!("gas_price"), bytes.into());
-                  let bytes: [u8; 32] = value.value.into();
-                  let bytes = &bytes[..];
-                  tab.insert_fast_static(cstr!("value"), bytes.into());
-                  let bytes: [u8; 32] = value.nonce.into();
-                  let bytes = &bytes[..];
-                  tab.insert_fast_static(cstr!("nonce"), bytes.into());
-                  // optional keys
-                  bytes_to_var!(tab, value.from, 20, "from");
-                  bytes_to_var!(tab, value.to, 20, "to");
-                  bytes_to_var!(tab, value.v, 8, "v");
-                  bytes_to_var!(tab, value.r, 32, "r");
-                  bytes_to_var!(tab, value.s, 32, "s");
-                  transactions.push(tab.as_ref().into());
-                }
-                *tvar = transactions.as_ref().into();
-                Ok(self.output.as_ref().into())
-              } else {
-                Err("Block not found")
-              }
-            }
-            Err(e) => {
-              cblog!("Block error: {}", e);
-              Err("Block request failed")
-            }
-          }
-        } else {
-          Err("Block request timedout")
-        }
-      })
+      let value = RUNTIME.block_on(get_full_block(node, &block, timeout))?;
+      header_to_table(&value, &mut self.output);
+      let tvar = self.output.get_mut_fast_static(cstr!("transactions"));
+      let mut transactions: Seq = tvar.try_into()?;
+      for value in value.transactions {
+        let mut tab = Table::new();
+        // mandatory keys
+        tab.insert_fast_static(cstr!("input"), value.input.0.as_slice().into());
+        let bytes: [u8; 32] = value.gas.into();
+        let bytes = &bytes[..];
+        tab.insert_fast_static(cstr!("gas"), bytes.into());
+        let bytes: [u8; 32] = value.gas_price.into();
+        let bytes = &bytes[..];
+        tab.insert_fast_static(cstr!("gas_price"), bytes.into());
+        let bytes: [u8; 32] = value.value.into();
+        let bytes = &bytes[..];
+        tab.insert_fast_static(cstr!("value"), bytes.into());
+        let bytes: [u8; 32] = value.nonce.into();
+        let bytes = &bytes[..];
+        tab.insert_fast_static(cstr!("nonce"), bytes.into());
+        // optional keys
+        bytes_to_var!(tab, value.from, 20, "from");
+        bytes_to_var!(tab, value.to, 20, "to");
+        bytes_to_var!(tab, value.v, 8, "v");
+        bytes_to_var!(tab, value.r, 32, "r");
+        bytes_to_var!(tab, value.s, 32, "s");
+        transactions.push(tab.as_ref().into());
+      }
+      *tvar = transactions.as_ref().into();
     } else {
-      let fut = eth.block(BlockId::Number(block_number.into()));
-      scheduler.block_on(async {
-        let timed_fut = timeout(self.timeout, fut);
-        let fut_res = timed_fut.await;
-        if let Ok(res) = fut_res {
-          match res {
-            Ok(value) => {
-              if let Some(value) = value {
-                bytes_to_var_no_opt!(self.output, value.parent_hash, 32, "parent_hash");
-                bytes_to_var_no_opt!(self.output, value.uncles_hash, 32, "uncles_hash");
-                bytes_to_var_no_opt!(self.output, value.author, 20, "author");
-                bytes_to_var_no_opt!(self.output, value.state_root, 32, "state_root");
-                bytes_to_var_no_opt!(
-                  self.output,
-                  value.transactions_root,
-                  32,
-                  "transactions_root"
-                );
-                bytes_to_var_no_opt!(self.output, value.receipts_root, 32, "receipts_root");
-                bytes_to_var_no_opt!(self.output, value.gas_used, 32, "gas_used");
-                bytes_to_var_no_opt!(self.output, value.gas_limit, 32, "gas_limit");
-                bytes_to_var_no_opt!(self.output, value.timestamp, 32, "timestamp");
-                bytes_to_var_no_opt!(self.output, value.difficulty, 32, "difficulty");
-                let tvar = self.output.get_mut_fast_static(cstr!("transactions"));
-                let mut transactions: Seq = tvar.try_into()?;
-                for transaction in value.transactions {
-                  let bytes: [u8; 32] = transaction.into();
-                  let bytes = &bytes[..];
-                  transactions.push(bytes.into());
-                }
-                *tvar = transactions.as_ref().into();
-                Ok(self.output.as_ref().into())
-              } else {
-                Err("Block not found")
-              }
-            }
-            Err(e) => {
-              cblog!("Block error: {}", e);
-              Err("Block request failed")
-            }
-          }
-        } else {
-          Err("Block request timedout")
-        }
-      })
+      let value = RUNTIME.block_on(get_block(node, &block, timeout))?;
+      header_to_table(&value, &mut self.output);
+      let tvar = self.output.get_mut_fast_static(cstr!("transactions"));
+      let mut transactions: Seq = tvar.try_into()?;
+      for transaction in value.transactions {
+        let bytes: [u8; 32] = transaction.into();
+        let bytes = &bytes[..];
+        transactions.push(bytes.into());
+      }
+      *tvar = transactions.as_ref().into();
     }
+    Ok(self.output.as_ref().into())
+  }
+}
+
+fn header_to_table<T>(value: &Block<T>, output: &mut Table) {
+  bytes_to_var_no_opt!(output, value.parent_hash, 32, "parent_hash");
+  bytes_to_var_no_opt!(output, value.uncles_hash, 32, "uncles_hash");
+  bytes_to_var_no_opt!(output, value.author, 20, "author");
+  bytes_to_var_no_opt!(output, value.state_root, 32, "state_root");
+  bytes_to_var_no_opt!(output, value.transactions_root, 32, "transactions_root");
+  bytes_to_var_no_opt!(output, value.receipts_root, 32, "receipts_root");
+  bytes_to_var_no_opt!(output, value.gas_used, 32, "gas_used");
+  bytes_to_var_no_opt!(output, value.gas_limit, 32, "gas_limit");
+  bytes_to_var_no_opt!(output, value.timestamp, 32, "timestamp");
+  bytes_to_var_no_opt!(output, value.difficulty, 32, "difficulty");
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::block;
+  use crate::blocks::mock::hash;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::ACCOUNT;
+  use jsonrpc_core::serde_json::json;
+
+  fn timeout() -> Duration {
+    Duration::from_secs(5)
+  }
+
+  #[test]
+  fn fetches_blocks_by_number() {
+    let node = MockNode::start();
+    node.expect_params(
+      "eth_getBlockByNumber",
+      json!(["0x10", false]),
+      block(0x10, false),
+    );
+    let eth = node.node();
+    let value = RUNTIME
+      .block_on(get_block(&eth, &BlockSelector::Number(0x10), timeout()))
+      .unwrap();
+    assert_eq!(value.number, Some(0x10.into()));
+    assert_eq!(value.transactions, vec![hash(0xaa).parse().unwrap()]);
+  }
+
+  #[test]
+  fn fetches_full_blocks_by_hash() {
+    let node = MockNode::start();
+    node.expect_params(
+      "eth_getBlockByHash",
+      json!([hash(0x10), true]),
+      block(0x10, true),
+    );
+    let eth = node.node();
+    let selector = BlockSelector::Hash {
+      hash: hash(0x10).parse().unwrap(),
+      require_canonical: false,
+    };
+    let value = RUNTIME
+      .block_on(get_full_block(&eth, &selector, timeout()))
+      .unwrap();
+    assert_eq!(value.transactions[0].from, Some(ACCOUNT.parse().unwrap()));
+  }
+
+  #[test]
+  fn unknown_blocks_are_not_found() {
+    let node = MockNode::start();
+    node.expect("eth_getBlockByNumber", json!(null));
+    let eth = node.node();
+    let value = RUNTIME.block_on(get_block(&eth, &BlockSelector::Tag("latest"), timeout()));
+    assert_eq!(value, Err("Block not found"));
   }
 }
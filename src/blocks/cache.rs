@@ -0,0 +1,312 @@
+use crate::blocks::log;
+use crate::blocks::multi::parse_quantity;
+use chainblocks::cblog;
+use jsonrpc_core::serde_json;
+use jsonrpc_core::types::Call;
+use jsonrpc_core::types::Params;
+use jsonrpc_core::types::Value;
+use lru::LruCache;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+/// Responses of queries that can never change, like a call at a given block
+/// or a mined transaction, optionally kept on disk between runs.
+pub struct Cache {
+  chain_id: u64,
+  entries: Mutex<LruCache<String, Value>>,
+  file: Option<Mutex<File>>,
+  hits: AtomicU64,
+  misses: AtomicU64,
+}
+
+impl Cache {
+  pub fn new<'a>(capacity: usize, path: Option<&str>, chain_id: u64) -> Result<Cache, &'a str> {
+    let mut entries = LruCache::new(capacity.max(1));
+    let file = match path {
+      Some(path) => {
+        let (loaded, foreign) = load(path, chain_id, &mut entries)?;
+        if foreign > 0 {
+          cblog!(
+            "CacheFile {} holds {} entries of other chains, dropping them",
+            path,
+            foreign
+          );
+        }
+        // the file is append only, rewrite it once it holds too many stale lines
+        if foreign > 0 || loaded > capacity * 2 {
+          compact(path, chain_id, &entries)?;
+        }
+        let file = OpenOptions::new()
+          .create(true)
+          .append(true)
+          .open(path)
+          .or_else(|_| Err("Failed to open CacheFile"))?;
+        Some(Mutex::new(file))
+      }
+      None => None,
+    };
+    Ok(Cache {
+      chain_id,
+      entries: Mutex::new(entries),
+      file,
+      hits: AtomicU64::new(0),
+      misses: AtomicU64::new(0),
+    })
+  }
+
+  /// The cached answer if the request is deterministic and was seen before.
+  pub fn get(&self, key: &str) -> Option<Value> {
+    let value = self.entries.lock().unwrap().get(&key.to_owned()).cloned();
+    if value.is_some() {
+      self.hits.fetch_add(1, Ordering::Relaxed);
+    } else {
+      self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+    value
+  }
+
+  pub fn insert(&self, key: String, value: &Value) {
+    if !is_final(&key, value) {
+      return;
+    }
+    if let Some(file) = &self.file {
+      let line = entry(self.chain_id, &key, value);
+      if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+        cblog!("Failed to write to CacheFile: {}", e);
+      }
+    }
+    self.entries.lock().unwrap().put(key, value.clone());
+  }
+
+  pub fn hits(&self) -> u64 {
+    self.hits.load(Ordering::Relaxed)
+  }
+
+  pub fn misses(&self) -> u64 {
+    self.misses.load(Ordering::Relaxed)
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.lock().unwrap().len()
+  }
+}
+
+/// The cache key of a request, None if its answer might still change.
+pub fn cache_key(request: &Call) -> Option<String> {
+  let call = match request {
+    Call::MethodCall(call) => call,
+    _ => return None,
+  };
+  let params = match &call.params {
+    Params::Array(params) => params,
+    _ => return None,
+  };
+  let fixed = match call.method.as_str() {
+    "eth_call"
+    | "eth_getBalance"
+    | "eth_getCode"
+    | "eth_getStorageAt"
+    | "eth_getTransactionCount" => params.last().map_or(false, is_fixed_block),
+    "eth_getBlockByNumber" => params.first().map_or(false, is_fixed_block),
+    // only kept once mined, see is_final
+    "eth_getBlockByHash" | "eth_getTransactionByHash" | "eth_getTransactionReceipt" => true,
+    _ => false,
+  };
+  if fixed {
+    Some(format!("{}:{}", call.method, Value::Array(params.clone())))
+  } else {
+    None
+  }
+}
+
+// a block number or hash, tags like latest or pending move
+fn is_fixed_block(block: &Value) -> bool {
+  match block {
+    Value::String(s) => parse_quantity(block).is_some() || (s.starts_with("0x") && s.len() == 66),
+    Value::Object(object) => object.contains_key("blockHash") || object.contains_key("blockNumber"),
+    _ => false,
+  }
+}
+
+// unknown blocks and pending transactions come back as null or without a block
+fn is_final(key: &str, value: &Value) -> bool {
+  match value {
+    Value::Null => false,
+    Value::Object(object) if key.starts_with("eth_getTransaction") => object
+      .get("blockNumber")
+      .map_or(false, |number| !number.is_null()),
+    _ => true,
+  }
+}
+
+fn entry(chain_id: u64, key: &str, value: &Value) -> Value {
+  serde_json::json!({ "chain": chain_id, "key": key, "value": value })
+}
+
+// returns the amount of lines loaded and of lines from other chains
+fn load<'a>(
+  path: &str,
+  chain_id: u64,
+  entries: &mut LruCache<String, Value>,
+) -> Result<(usize, usize), &'a str> {
+  let file = match File::open(path) {
+    Ok(file) => file,
+    Err(_) => return Ok((0, 0)),
+  };
+  let (mut loaded, mut foreign) = (0, 0);
+  for line in BufReader::new(file).lines() {
+    let line = line.or_else(|_| Err("Failed to read CacheFile"))?;
+    // a line cut by a crash is just skipped
+    if let Ok(Value::Object(mut entry)) = serde_json::from_str(&line) {
+      if entry.get("chain").and_then(Value::as_u64) != Some(chain_id) {
+        foreign += 1;
+        continue;
+      }
+      if let (Some(Value::String(key)), Some(value)) = (entry.remove("key"), entry.remove("value"))
+      {
+        entries.put(key, value);
+        loaded += 1;
+      }
+    }
+  }
+  Ok((loaded, foreign))
+}
+
+fn compact<'a>(
+  path: &str,
+  chain_id: u64,
+  entries: &LruCache<String, Value>,
+) -> Result<(), &'a str> {
+  let tmp = format!("{}.tmp", path);
+  {
+    let mut file = File::create(&tmp).or_else(|_| Err("Failed to write CacheFile"))?;
+    // oldest first, so the most recent entries win when loading again
+    for (key, value) in entries.iter().rev() {
+      let line = entry(chain_id, key, value);
+      writeln!(file, "{}", line).or_else(|_| Err("Failed to write CacheFile"))?;
+    }
+  }
+  fs::rename(&tmp, path).or_else(|_| Err("Failed to write CacheFile"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::env;
+  use web3::helpers::build_request;
+
+  fn call(method: &str, params: Value) -> Call {
+    match params {
+      Value::Array(params) => build_request(1, method, params),
+      _ => unreachable!(),
+    }
+  }
+
+  fn temp_file(name: &str) -> String {
+    let path = env::temp_dir().join(format!("cb-web3-{}-{}.jsonl", name, std::process::id()));
+    let _ = fs::remove_file(&path);
+    path.to_str().unwrap().to_owned()
+  }
+
+  #[test]
+  fn only_fixed_queries_have_a_key() {
+    let at = |block: Value| call("eth_getBalance", serde_json::json!(["0x00", block]));
+    assert!(cache_key(&at(Value::String("latest".into()))).is_none());
+    assert!(cache_key(&at(Value::String("pending".into()))).is_none());
+    assert!(cache_key(&at(Value::String("0x10".into()))).is_some());
+    assert!(cache_key(&at(serde_json::json!({ "blockHash": "0x01" }))).is_some());
+    assert!(cache_key(&call(
+      "eth_getBlockByNumber",
+      serde_json::json!(["0x10", false])
+    ))
+    .is_some());
+    assert!(cache_key(&call(
+      "eth_getBlockByNumber",
+      serde_json::json!(["latest", false])
+    ))
+    .is_none());
+    assert!(cache_key(&call(
+      "eth_getTransactionReceipt",
+      serde_json::json!(["0x01"])
+    ))
+    .is_some());
+    // a node can be switched to another chain under the same url
+    assert!(cache_key(&call("eth_chainId", serde_json::json!([]))).is_none());
+    assert!(cache_key(&call("eth_blockNumber", serde_json::json!([]))).is_none());
+  }
+
+  #[test]
+  fn pending_answers_are_not_kept() {
+    let cache = Cache::new(4, None, 1).unwrap();
+    let key = cache_key(&call(
+      "eth_getTransactionByHash",
+      serde_json::json!(["0x01"]),
+    ))
+    .unwrap();
+    cache.insert(
+      key.clone(),
+      &serde_json::json!({ "hash": "0x01", "blockNumber": null }),
+    );
+    cache.insert(key.clone(), &Value::Null);
+    assert_eq!(cache.get(&key), None);
+    cache.insert(
+      key.clone(),
+      &serde_json::json!({ "hash": "0x01", "blockNumber": "0x2" }),
+    );
+    assert!(cache.get(&key).is_some());
+    assert_eq!((cache.hits(), cache.misses()), (1, 1));
+  }
+
+  #[test]
+  fn evicts_least_recently_used() {
+    let cache = Cache::new(2, None, 1).unwrap();
+    for key in &["a", "b", "c"] {
+      cache.insert(key.to_string(), &Value::Bool(true));
+    }
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get("a"), None);
+    assert!(cache.get("c").is_some());
+  }
+
+  #[test]
+  fn file_survives_restarts_of_the_same_chain() {
+    let path = temp_file("restart");
+    {
+      let cache = Cache::new(8, Some(&path), 1).unwrap();
+      cache.insert("key".into(), &Value::String("0x2a".into()));
+    }
+    let cache = Cache::new(8, Some(&path), 1).unwrap();
+    assert_eq!(cache.get("key"), Some(Value::String("0x2a".into())));
+
+    // another chain starts empty and drops the foreign lines from the file
+    let other = Cache::new(8, Some(&path), 5).unwrap();
+    assert_eq!(other.len(), 0);
+    let again = Cache::new(8, Some(&path), 1).unwrap();
+    assert_eq!(again.len(), 0);
+    let _ = fs::remove_file(&path);
+  }
+
+  #[test]
+  fn file_is_compacted() {
+    let path = temp_file("compact");
+    {
+      let cache = Cache::new(2, Some(&path), 1).unwrap();
+      for i in 0..10 {
+        cache.insert(format!("key{}", i), &Value::Bool(true));
+      }
+    }
+    let cache = Cache::new(2, Some(&path), 1).unwrap();
+    assert!(cache.get("key9").is_some());
+    assert!(cache.get("key8").is_some());
+    let lines = fs::read_to_string(&path).unwrap().lines().count();
+    assert_eq!(lines, 2);
+    let _ = fs::remove_file(&path);
+  }
+}
@@ -0,0 +1,127 @@
+use crate::blocks::get_shared;
+use crate::blocks::shared_from_var;
+use crate::blocks::NodeData;
+use crate::blocks::NODE_TYPE;
+use crate::blocks::NODE_VAR;
+use chainblocks::block::Block;
+use chainblocks::cstr;
+use chainblocks::types::common_type;
+use chainblocks::types::Context;
+use chainblocks::types::ExposedInfo;
+use chainblocks::types::ExposedTypes;
+use chainblocks::types::ParamVar;
+use chainblocks::types::Parameters;
+use chainblocks::types::Type;
+use chainblocks::types::Var;
+use std::convert::TryInto;
+use std::sync::Arc;
+
+pub struct ChainId {
+  node_param: ParamVar,
+  node: Option<Arc<NodeData>>,
+  requiring: ExposedTypes,
+}
+
+lazy_static! {
+  static ref IN_TYPES: Vec<Type> = vec![common_type::any];
+  static ref OUT_TYPES: Vec<Type> = vec![common_type::int];
+  static ref PARAMETERS: Parameters = vec![(
+    cstr!("Node"),
+    cstr!("The ethereum node block variable to use."),
+    vec![NODE_VAR],
+  )
+    .into(),];
+}
+
+impl Default for ChainId {
+  fn default() -> Self {
+    ChainId {
+      node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
+      node: None,
+      requiring: Vec::new(),
+    }
+  }
+}
+
+impl Block for ChainId {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.ChainId-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.ChainId")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.ChainId"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &IN_TYPES
+  }
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &OUT_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.node_param.set_param(value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.node_param.get_param(),
+      _ => unreachable!(),
+    }
+  }
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.requiring.clear();
+    let exp_info = ExposedInfo {
+      exposedType: NODE_TYPE,
+      name: self.node_param.get_name(),
+      help: cstr!("The required ethereum node to use as gateway.").into(),
+      ..ExposedInfo::default()
+    };
+    self.requiring.push(exp_info);
+    Some(&self.requiring)
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.node_param.warmup(context);
+    Ok(())
+  }
+  fn cleanup(&mut self) {
+    self.node_param.cleanup();
+    self.node = None;
+  }
+  fn activate(&mut self, _: &Context, _input: &Var) -> Result<Var, &str> {
+    if self.node.is_none() {
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
+    }
+    // queried once when the node was opened, no need to ask again
+    let node = get_shared(&self.node)?;
+    node.chain_id.try_into()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::RUNTIME;
+
+  #[test]
+  fn chain_id_is_asked_once_on_connect() {
+    let node = MockNode::start();
+    node.expect("eth_chainId", quantity(5));
+    let eth = node.node();
+    assert_eq!(eth.chain_id, 5);
+    let _ = RUNTIME.block_on(eth.web3.eth().gas_price());
+    assert_eq!(node.count("eth_chainId"), 1);
+  }
+}
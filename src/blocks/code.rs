@@ -0,0 +1,468 @@
+use crate::blocks::ens::resolve_address;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::selector::block_param;
+use crate::blocks::selector::eth_call;
+use crate::blocks::selector::BlockSelector;
+use crate::blocks::shared_from_var;
+use crate::blocks::storage_var::fetch_slots;
+use crate::blocks::NodeData;
+use crate::blocks::BLOCK_HASH_TYPE;
+use crate::blocks::BLOCK_VAR;
+use crate::blocks::NODE_TYPE;
+use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
+use chainblocks::block::Block;
+use chainblocks::cblog;
+use chainblocks::cbstr;
+use chainblocks::core::activate_blocking;
+use chainblocks::core::BlockingBlock;
+use chainblocks::cstr;
+use chainblocks::types::common_type;
+use chainblocks::types::Context;
+use chainblocks::types::ExposedInfo;
+use chainblocks::types::ExposedTypes;
+use chainblocks::types::ParamVar;
+use chainblocks::types::Parameters;
+use chainblocks::types::RawString;
+use chainblocks::types::Table;
+use chainblocks::types::Type;
+use chainblocks::types::Var;
+use std::sync::Arc;
+use std::time::Duration;
+use web3::helpers;
+use web3::helpers::CallFuture;
+use web3::signing::keccak256;
+use web3::types::Address;
+use web3::types::Bytes;
+use web3::types::CallRequest;
+use web3::types::H256;
+use web3::Transport as _;
+
+// EIP-1967, keccak256 of the names minus one
+const IMPLEMENTATION_SLOT: &str =
+  "360894a13ba1a3210667c828492db98dca3e2076cc3735a920a3ca505d382bbc";
+const BEACON_SLOT: &str = "a3f0ad74e5423aebfd80d3ef4346578335a9a72aeaee59ff6cb3582b35133d50";
+// EIP-1822, keccak256("PROXIABLE")
+const PROXIABLE_SLOT: &str = "c5f16f0fcc639fa48a6947836d9850f504798523bf8c9a3a87d5876cf622bcf7";
+// implementation() of beacons
+const IMPLEMENTATION_SELECTOR: [u8; 4] = [0x5c, 0x60, 0xda, 0x1b];
+// EIP-1167 runtime code, around the 20 bytes of the target
+const MINIMAL_PREFIX: &[u8] = &[0x36, 0x3d, 0x3d, 0x37, 0x3d, 0x3d, 0x3d, 0x36, 0x3d, 0x73];
+const MINIMAL_SUFFIX: &[u8] = &[
+  0x5a, 0xf4, 0x3d, 0x82, 0x80, 0x3e, 0x90, 0x3d, 0x91, 0x60, 0x2b, 0x57, 0xfd, 0x5b, 0xf3,
+];
+
+static TABLE_TYPES: &'static [Type] = &[common_type::bytes, common_type::bytes];
+const TABLE_KEYS: &[RawString] = &[cbstr!("code"), cbstr!("code_hash")];
+static TABLE_TYPE: Type = Type::table(TABLE_KEYS, TABLE_TYPES);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyKind {
+  Eip1967,
+  Eip1822,
+  Beacon,
+  Minimal,
+}
+
+/// A proxy and the contract it delegates its calls to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Proxy {
+  pub kind: ProxyKind,
+  pub implementation: Address,
+  // only for beacon proxies
+  pub beacon: Option<Address>,
+}
+
+/// The target of an EIP-1167 minimal proxy, None for any other code.
+pub fn minimal_proxy_target(code: &[u8]) -> Option<Address> {
+  let prefix = MINIMAL_PREFIX.len();
+  if code.len() == prefix + 20 + MINIMAL_SUFFIX.len()
+    && code.starts_with(MINIMAL_PREFIX)
+    && code.ends_with(MINIMAL_SUFFIX)
+  {
+    Some(Address::from_slice(&code[prefix..prefix + 20]))
+  } else {
+    None
+  }
+}
+
+// slots hold addresses right aligned, an empty slot is no address
+fn slot_address(word: H256) -> Option<Address> {
+  if word.is_zero() {
+    None
+  } else {
+    Some(Address::from_slice(&word.as_bytes()[12..]))
+  }
+}
+
+/// The code deployed at the address, empty for accounts without any.
+pub async fn get_code<'a>(
+  node: &NodeData,
+  address: Address,
+  block: Option<BlockSelector>,
+  timeout: Duration,
+) -> Result<Vec<u8>, &'a str> {
+  let transport = node.web3.transport();
+  let params = vec![helpers::serialize(&address), block_param(block)];
+  let code = node
+    .retry
+    .timeout(timeout, || {
+      CallFuture::<Bytes, _>::new(transport.execute("eth_getCode", params.clone()))
+    })
+    .await
+    .or_else(|_| Err("Code request timed out"))?
+    .or_else(|e| {
+      cblog!("Code error: {}", e);
+      Err("Code request failed")
+    })?;
+  Ok(code.0)
+}
+
+/// Finds the implementation behind a proxy from its code and standard slots,
+/// None if the address is no proxy of a kind known here.
+pub async fn detect_proxy<'a>(
+  node: &NodeData,
+  address: Address,
+  block: Option<BlockSelector>,
+  timeout: Duration,
+) -> Result<Option<Proxy>, &'a str> {
+  let code = get_code(node, address, block, timeout).await?;
+  if code.is_empty() {
+    return Ok(None);
+  }
+  if let Some(implementation) = minimal_proxy_target(&code) {
+    return Ok(Some(Proxy {
+      kind: ProxyKind::Minimal,
+      implementation,
+      beacon: None,
+    }));
+  }
+  let (transport, retry) = (node.web3.transport(), &node.retry);
+  let slots: Vec<H256> = [IMPLEMENTATION_SLOT, BEACON_SLOT, PROXIABLE_SLOT]
+    .iter()
+    .map(|slot| slot.parse().unwrap())
+    .collect();
+  let words = retry
+    .timeout(timeout, || fetch_slots(transport, address, &slots, block))
+    .await
+    .or_else(|_| Err("Storage request timed out"))?
+    .or_else(|e| {
+      cblog!("Storage error: {}", e);
+      Err("Storage request failed")
+    })?;
+  if let Some(implementation) = slot_address(words[0]) {
+    return Ok(Some(Proxy {
+      kind: ProxyKind::Eip1967,
+      implementation,
+      beacon: None,
+    }));
+  }
+  if let Some(beacon) = slot_address(words[1]) {
+    let request = CallRequest {
+      to: Some(beacon),
+      data: Some(Bytes(IMPLEMENTATION_SELECTOR.to_vec())),
+      ..CallRequest::default()
+    };
+    let output = retry
+      .timeout(timeout, || eth_call(transport, &request, block))
+      .await
+      .or_else(|_| Err("Beacon request timed out"))?
+      .or_else(|e| {
+        cblog!("Beacon error: {}", e);
+        Err("Beacon request failed")
+      })?;
+    if output.0.len() != 32 {
+      return Err("Beacon didn't answer an implementation");
+    }
+    return Ok(Some(Proxy {
+      kind: ProxyKind::Beacon,
+      implementation: Address::from_slice(&output.0[12..]),
+      beacon: Some(beacon),
+    }));
+  }
+  if let Some(implementation) = slot_address(words[2]) {
+    return Ok(Some(Proxy {
+      kind: ProxyKind::Eip1822,
+      implementation,
+      beacon: None,
+    }));
+  }
+  Ok(None)
+}
+
+pub struct Code {
+  address: ParamVar,
+  block: ParamVar,
+  node_param: ParamVar,
+  node: Option<Arc<NodeData>>,
+  output: Table,
+  timeout: ParamVar,
+  requiring: ExposedTypes,
+}
+
+impl Default for Code {
+  fn default() -> Self {
+    Code {
+      address: ParamVar::new(cstr!("").into()),
+      block: ParamVar::new(().into()),
+      node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
+      node: None,
+      output: Table::new(),
+      timeout: ParamVar::new(().into()),
+      requiring: Vec::new(),
+    }
+  }
+}
+
+lazy_static! {
+  static ref INPUT_TYPES: Vec<Type> = vec![common_type::none];
+  static ref OUTPUT_TYPES: Vec<Type> = vec![TABLE_TYPE];
+  static ref PARAMETERS: Parameters = vec![
+    (
+      cstr!("Address"),
+      cstr!("The address of the contract, or an ENS name."),
+      vec![
+        common_type::string,
+        common_type::string_var,
+        common_type::bytes,
+        common_type::bytes_var
+      ],
+    )
+      .into(),
+    (
+      cstr!("Block"),
+      cstr!(
+        "The optional block to read from history: a number, a tag like safe or finalized, or a block hash."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::string,
+        common_type::bytes,
+        BLOCK_HASH_TYPE,
+        BLOCK_VAR
+      ],
+    )
+      .into(),
+    (
+      cstr!("Node"),
+      cstr!("The ethereum node block variable to use."),
+      vec![NODE_VAR],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
+  ];
+}
+
+impl Block for Code {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.Code-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.Code")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.Code"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &INPUT_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &OUTPUT_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.address.set_param(value),
+      1 => self.block.set_param(value),
+      2 => self.node_param.set_param(value),
+      3 => self.timeout.set_param(value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.address.get_param(),
+      1 => self.block.get_param(),
+      2 => self.node_param.get_param(),
+      3 => self.timeout.get_param(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.requiring.clear();
+    let exp_info = ExposedInfo {
+      exposedType: NODE_TYPE,
+      name: self.node_param.get_name(),
+      help: cstr!("The required ethereum node to use as gateway.").into(),
+      ..ExposedInfo::default()
+    };
+    self.requiring.push(exp_info);
+    Some(&self.requiring)
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.node_param.warmup(context);
+    self.address.warmup(context);
+    self.block.warmup(context);
+    self.timeout.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.timeout.cleanup();
+    self.block.cleanup();
+    self.address.cleanup();
+    self.node_param.cleanup();
+    self.node = None;
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(activate_blocking(self, context, input))
+  }
+}
+
+impl BlockingBlock for Code {
+  fn activate_blocking(&mut self, _: &Context, _: &Var) -> Result<Var, &str> {
+    if self.node.is_none() {
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
+    }
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
+    let address = resolve_address(node, self.address.get(), timeout)?;
+    let block = BlockSelector::from_var(&self.block.get())?;
+    let code = RUNTIME.block_on(get_code(node, address, block, timeout))?;
+    // the same hash the account holds, even for no code
+    let hash = keccak256(&code);
+    self
+      .output
+      .insert_fast_static(cstr!("code"), code.as_slice().into());
+    self
+      .output
+      .insert_fast_static(cstr!("code_hash"), (&hash[..]).into());
+    Ok(self.output.as_ref().into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::MockNode;
+  use jsonrpc_core::serde_json::json;
+  use web3::types::U256;
+
+  const PROXY: &str = "0x5fbdb2315678afecb367f032d93f642f64180aa3";
+
+  fn slot_of(name: &[u8]) -> H256 {
+    let hash = U256::from(&keccak256(name)[..]);
+    let slot: [u8; 32] = (hash - 1).into();
+    slot.into()
+  }
+
+  fn storage(node: &MockNode, slot: &str, word: H256) {
+    node.expect_params(
+      "eth_getStorageAt",
+      json!([PROXY, format!("0x{}", slot), "latest"]),
+      json!(format!("{:?}", word)),
+    );
+  }
+
+  fn detect(node: &MockNode) -> Result<Option<Proxy>, &'static str> {
+    let eth = node.node();
+    let address = PROXY.parse().unwrap();
+    RUNTIME.block_on(detect_proxy(&eth, address, None, Duration::from_secs(5)))
+  }
+
+  #[test]
+  fn slots_are_the_standard_ones() {
+    let implementation: H256 = IMPLEMENTATION_SLOT.parse().unwrap();
+    assert_eq!(implementation, slot_of(b"eip1967.proxy.implementation"));
+    let beacon: H256 = BEACON_SLOT.parse().unwrap();
+    assert_eq!(beacon, slot_of(b"eip1967.proxy.beacon"));
+    let proxiable: H256 = PROXIABLE_SLOT.parse().unwrap();
+    assert_eq!(proxiable, H256::from(keccak256(b"PROXIABLE")));
+    assert_eq!(keccak256(b"implementation()")[..4], IMPLEMENTATION_SELECTOR);
+  }
+
+  #[test]
+  fn reads_minimal_proxies() {
+    let target = Address::repeat_byte(0xbe);
+    let code = [MINIMAL_PREFIX, target.as_bytes(), MINIMAL_SUFFIX].concat();
+    assert_eq!(minimal_proxy_target(&code), Some(target));
+    assert_eq!(minimal_proxy_target(&code[1..]), None);
+
+    let node = MockNode::start();
+    node.expect("eth_getCode", json!(format!("0x{}", hex::encode(&code))));
+    let proxy = detect(&node).unwrap().unwrap();
+    assert_eq!(proxy.kind, ProxyKind::Minimal);
+    assert_eq!(proxy.implementation, target);
+    assert!(node.requests("eth_getStorageAt").is_empty());
+  }
+
+  #[test]
+  fn reads_standard_slots() {
+    let node = MockNode::start();
+    node.expect("eth_getCode", json!("0x6080"));
+    node.expect("eth_getStorageAt", json!(format!("{:?}", H256::zero())));
+    assert_eq!(detect(&node), Ok(None));
+
+    let implementation = Address::repeat_byte(0x11);
+    storage(&node, PROXIABLE_SLOT, implementation.into());
+    let proxy = detect(&node).unwrap().unwrap();
+    assert_eq!(proxy.kind, ProxyKind::Eip1822);
+    assert_eq!(proxy.implementation, implementation);
+
+    let beacon = Address::repeat_byte(0x22);
+    storage(&node, BEACON_SLOT, beacon.into());
+    node.expect(
+      "eth_call",
+      json!(format!("{:?}", H256::from(Address::repeat_byte(0x33)))),
+    );
+    let proxy = detect(&node).unwrap().unwrap();
+    assert_eq!(proxy.kind, ProxyKind::Beacon);
+    assert_eq!(proxy.implementation, Address::repeat_byte(0x33));
+    assert_eq!(proxy.beacon, Some(beacon));
+    let call = &node.requests("eth_call")[0].params[0];
+    assert_eq!(call["to"], json!(format!("{:?}", beacon)));
+    assert_eq!(call["data"], json!("0x5c60da1b"));
+
+    // the implementation slot wins over the others
+    storage(&node, IMPLEMENTATION_SLOT, implementation.into());
+    let proxy = detect(&node).unwrap().unwrap();
+    assert_eq!(proxy.kind, ProxyKind::Eip1967);
+    assert_eq!(proxy.implementation, implementation);
+  }
+
+  #[test]
+  fn accounts_without_code_are_no_proxies() {
+    let node = MockNode::start();
+    node.expect("eth_getCode", json!("0x"));
+    assert_eq!(detect(&node), Ok(None));
+    assert!(node.requests("eth_getStorageAt").is_empty());
+  }
+}
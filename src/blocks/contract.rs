@@ -1,9 +1,16 @@
-use crate::blocks::get_address;
-use crate::blocks::{ContractData, NodeData};
-use crate::blocks::{CONTRACT_TYPE, NODE_TYPE, NODE_VAR};
+use crate::blocks::code::detect_proxy;
+use crate::blocks::code::Proxy;
+use crate::blocks::code::ProxyKind;
+use crate::blocks::ens::resolve_address;
+use crate::blocks::get_block_timeout;
+use crate::blocks::log;
+use crate::blocks::shared_from_var;
+use crate::blocks::{ContractData, NodeData, Shared};
+use crate::blocks::{CONTRACT_TYPE, NODE_TYPE, NODE_VAR, RUNTIME, TIMEOUT_VAR};
 use chainblocks::block::Block;
 use chainblocks::cblog;
-use chainblocks::core::log;
+use chainblocks::cbstr;
+use chainblocks::core::do_blocking;
 use chainblocks::cstr;
 use chainblocks::types::common_type;
 use chainblocks::types::Context;
@@ -11,13 +18,23 @@ use chainblocks::types::ExposedInfo;
 use chainblocks::types::ExposedTypes;
 use chainblocks::types::ParamVar;
 use chainblocks::types::Parameters;
+use chainblocks::types::RawString;
+use chainblocks::types::Table;
+use chainblocks::types::Type;
 use chainblocks::types::Types;
 use chainblocks::types::Var;
 use std::convert::TryInto;
 use std::ffi::CString;
-use std::rc::Rc;
 use std::str;
+use std::sync::Arc;
+use std::time::Duration;
 use web3::contract::Contract;
+use web3::types::Address;
+
+static PROXY_TYPES: &'static [Type] =
+  &[common_type::string, common_type::bytes, common_type::bytes];
+const PROXY_KEYS: &[RawString] = &[cbstr!("kind"), cbstr!("implementation"), cbstr!("beacon")];
+static PROXY_TYPE: Type = Type::table(PROXY_KEYS, PROXY_TYPES);
 
 pub struct SharedContract {
   instance: ParamVar,
@@ -26,7 +43,12 @@ pub struct SharedContract {
   contract_address: ParamVar,
   contract_current: Var,
   abi_json: CString,
-  contract: Rc<Option<ContractData>>,
+  contract: Shared<ContractData>,
+  proxy: bool,
+  proxy_name: CString,
+  proxy_var: ParamVar,
+  proxy_output: Table,
+  timeout: ParamVar,
   init_done: bool,
   exposing: ExposedTypes,
   requiring: ExposedTypes,
@@ -37,7 +59,7 @@ lazy_static! {
   static ref PARAMETERS: Parameters = vec![
     (
       cstr!("Contract"),
-      cstr!("The contract address."),
+      cstr!("The contract address, or an ENS name."),
       vec![
         common_type::string,
         common_type::string_var,
@@ -64,6 +86,27 @@ lazy_static! {
       vec![NODE_VAR],
     )
       .into(),
+    (
+      cstr!("Proxy"),
+      cstr!(
+        "If EIP-1967, EIP-1822, beacon and minimal proxies should be detected, exposing their kind and implementation as <Name>.Proxy."
+      ),
+      vec![common_type::bool],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
   ];
 }
 
@@ -77,7 +120,12 @@ impl Default for SharedContract {
       contract_address: ParamVar::new(cstr!("").into()),
       contract_current: Var::default(),
       abi_json: CString::new("").unwrap(),
-      contract: Rc::new(None),
+      contract: Shared::default(),
+      proxy: false,
+      proxy_name: CString::new("default.Eth.Contract.Proxy").unwrap(),
+      proxy_var: ParamVar::new(().into()),
+      proxy_output: Table::new(),
+      timeout: ParamVar::new(().into()),
       init_done: false,
       exposing: Vec::new(),
       requiring: Vec::new(),
@@ -114,6 +162,8 @@ impl Block for SharedContract {
       1 => self.abi_json = value.try_into().unwrap_or(CString::new("").unwrap()),
       2 => self.instance_name = value.try_into().unwrap_or(CString::new("").unwrap()),
       3 => self.node_param.set_param(value),
+      4 => self.proxy = value.try_into().unwrap_or(false),
+      5 => self.timeout.set_param(value),
       _ => unreachable!(),
     }
   }
@@ -124,6 +174,8 @@ impl Block for SharedContract {
       1 => self.abi_json.as_ref().into(),
       2 => self.instance_name.as_ref().into(),
       3 => self.node_param.get_param(),
+      4 => self.proxy.into(),
+      5 => self.timeout.get_param(),
       _ => Var::default(),
     }
   }
@@ -137,6 +189,17 @@ impl Block for SharedContract {
       ..ExposedInfo::default()
     };
     self.exposing.push(exp_info);
+    if self.proxy {
+      self.proxy_name = proxy_name(&self.instance_name);
+      let exp_info = ExposedInfo {
+        exposedType: PROXY_TYPE,
+        name: self.proxy_name.as_ptr(),
+        help: cstr!("The kind and implementation of the proxy, none and empty if it's not one.")
+          .into(),
+        ..ExposedInfo::default()
+      };
+      self.exposing.push(exp_info);
+    }
     Some(&self.exposing)
   }
 
@@ -165,57 +228,174 @@ impl Block for SharedContract {
   fn warmup(&mut self, context: &Context) -> Result<(), &str> {
     self.instance.set_name(self.instance_name.to_str().unwrap());
     self.instance.warmup(context);
+    if self.proxy {
+      self.proxy_name = proxy_name(&self.instance_name);
+      self.proxy_var.set_name(self.proxy_name.to_str().unwrap());
+      self.proxy_var.warmup(context);
+    }
     self.node_param.warmup(context);
     self.contract_address.warmup(context);
+    self.timeout.warmup(context);
     Ok(())
   }
 
   fn cleanup(&mut self) {
     self.instance.cleanup();
+    if self.proxy {
+      self.proxy_var.cleanup();
+    }
     self.node_param.cleanup();
     self.contract_address.cleanup();
+    self.timeout.cleanup();
     self.init_done = false;
-    self.contract = Rc::new(None);
+    self.contract.set(None);
   }
 
-  fn activate(&mut self, _context: &Context, input: &Var) -> Result<Var, &str> {
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
     // TODO check if contract address has changed.. it so need to re-init here!
     let vaddress = self.contract_address.get();
     if !self.init_done || self.contract_current != vaddress {
-      self.contract_current = vaddress;
-      let mut node_ref = Some(Var::from_object_as_clone::<Option<NodeData>>(
-        self.node_param.get(),
-        &NODE_TYPE,
-      )?);
-      let node_ptr = node_ref
-        .as_mut()
-        .ok_or_else(|| "Failed to unwrap node data shared pointer")?;
-      let node_o = &**node_ptr;
-      let node = node_o
-        .as_ref()
-        .ok_or_else(|| "Failed to unwrap node data, was empty")?;
-      let address = get_address(vaddress)?;
-      let contract = Contract::from_json(node.web3.eth(), address, self.abi_json.as_bytes())
-        .or_else(|e| {
-          cblog!("Contract::from_json error: {}", e);
-          Err("Failed to initialize contract")
-        })?;
-      self.contract = Rc::new(Some(ContractData {
-        contract: contract,
-        json_abi: json::parse(
+      Ok(do_blocking(context, || -> Result<Var, &str> {
+        self.contract_current = vaddress;
+        let node: Arc<NodeData> = shared_from_var(self.node_param.get(), &NODE_TYPE)?;
+        let timeout = get_block_timeout(&self.timeout, &node)?;
+        let address = resolve_address(&node, vaddress, timeout)?;
+        let abi = self
+          .abi_json
+          .to_str()
+          .or_else(|_| Err("Invalid abi string"))?;
+        let bind = bind_contract(node, address, abi, self.proxy, timeout);
+        let (contract, proxy) = RUNTIME.block_on(bind)?;
+        if self.proxy {
+          let (kind, implementation, beacon) = match proxy {
+            Some(proxy) => (
+              match proxy.kind {
+                ProxyKind::Eip1967 => cstr!("eip1967"),
+                ProxyKind::Eip1822 => cstr!("eip1822"),
+                ProxyKind::Beacon => cstr!("beacon"),
+                ProxyKind::Minimal => cstr!("minimal"),
+              },
+              proxy.implementation.as_bytes().to_vec(),
+              proxy
+                .beacon
+                .map_or(Vec::new(), |beacon| beacon.as_bytes().to_vec()),
+            ),
+            None => (cstr!("none"), Vec::new(), Vec::new()),
+          };
+          self
+            .proxy_output
+            .insert_fast_static(cstr!("kind"), kind.into());
+          self
+            .proxy_output
+            .insert_fast_static(cstr!("implementation"), implementation.as_slice().into());
           self
-            .abi_json
-            .to_str()
-            .or_else(|_| Err("Invalid abi string"))?,
-        )
-        .or_else(|_| Err("Failed to parse contract's json abi"))?,
-        node: self.node_param.get(),
-      }));
-      self
-        .instance
-        .set(Var::new_object(&self.contract, &CONTRACT_TYPE));
-      self.init_done = true;
+            .proxy_output
+            .insert_fast_static(cstr!("beacon"), beacon.as_slice().into());
+          self.proxy_var.set(self.proxy_output.as_ref().into());
+        }
+        self.contract.set(Some(contract));
+        self.instance.set(self.contract.var(&CONTRACT_TYPE));
+        self.init_done = true;
+        Ok(*input)
+      }))
+    } else {
+      Ok(*input)
     }
-    Ok(*input)
+  }
+}
+
+fn proxy_name(instance_name: &CString) -> CString {
+  CString::new(format!("{}.Proxy", instance_name.to_str().unwrap())).unwrap()
+}
+
+/// Binds the abi to a deployed address on the node.
+pub fn contract_data<'a>(
+  node: Arc<NodeData>,
+  address: Address,
+  abi: &str,
+) -> Result<ContractData, &'a str> {
+  let contract = Contract::from_json(node.web3.eth(), address, abi.as_bytes()).or_else(|e| {
+    cblog!("Contract::from_json error: {}", e);
+    Err("Failed to initialize contract")
+  })?;
+  let json_abi = json::parse(abi).or_else(|_| Err("Failed to parse contract's json abi"))?;
+  Ok(ContractData {
+    contract,
+    json_abi,
+    node,
+  })
+}
+
+/// Binds the abi to a deployed address, also reading the proxy in front of it when asked.
+pub async fn bind_contract<'a>(
+  node: Arc<NodeData>,
+  address: Address,
+  abi: &str,
+  proxy: bool,
+  timeout: Duration,
+) -> Result<(ContractData, Option<Proxy>), &'a str> {
+  let detected = if proxy {
+    // calls still go to the proxy, the abi is the one of the implementation
+    detect_proxy(&node, address, None, timeout).await?
+  } else {
+    None
+  };
+  Ok((contract_data(node, address, abi)?, detected))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::ABI;
+  use crate::blocks::mock::CONTRACT;
+  use jsonrpc_core::serde_json::json;
+  use web3::types::H256;
+
+  #[test]
+  fn binds_the_abi_to_an_address() {
+    let node = MockNode::start().node();
+    let data = contract_data(node, CONTRACT.parse().unwrap(), ABI).unwrap();
+    assert_eq!(data.contract.address(), CONTRACT.parse().unwrap());
+    assert!(data.contract.abi().function("poke").is_ok());
+    assert!(data.json_abi.is_array());
+  }
+
+  #[test]
+  fn refuses_invalid_abis() {
+    let node = MockNode::start().node();
+    let address = CONTRACT.parse().unwrap();
+    assert_eq!(
+      contract_data(node, address, "{").err(),
+      Some("Failed to initialize contract")
+    );
+  }
+
+  #[test]
+  fn proxies_are_detected_when_asked() {
+    let node = MockNode::start();
+    let implementation = Address::repeat_byte(0x11);
+    node.expect("eth_getCode", json!("0x6080"));
+    node.expect(
+      "eth_getStorageAt",
+      json!(format!("{:?}", H256::from(implementation))),
+    );
+    let address = CONTRACT.parse().unwrap();
+    let timeout = Duration::from_secs(5);
+
+    let (_, proxy) = RUNTIME
+      .block_on(bind_contract(node.node(), address, ABI, false, timeout))
+      .unwrap();
+    assert_eq!(proxy, None);
+    assert_eq!(node.count("eth_getCode"), 0);
+
+    let (data, proxy) = RUNTIME
+      .block_on(bind_contract(node.node(), address, ABI, true, timeout))
+      .unwrap();
+    let proxy = proxy.unwrap();
+    assert_eq!(proxy.kind, ProxyKind::Eip1967);
+    assert_eq!(proxy.implementation, implementation);
+    // calls still go to the proxy
+    assert_eq!(data.contract.address(), address);
   }
 }
@@ -1,11 +1,15 @@
-use crate::blocks::get_timeout;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::shared_from_var;
 use crate::blocks::NodeData;
 use crate::blocks::NODE_TYPE;
 use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
 use chainblocks::block::Block;
 use chainblocks::cblog;
 use chainblocks::core::activate_blocking;
-use chainblocks::core::log;
 use chainblocks::core::BlockingBlock;
 use chainblocks::cstr;
 use chainblocks::types::common_type;
@@ -17,27 +21,56 @@ use chainblocks::types::Parameters;
 use chainblocks::types::Type;
 use chainblocks::types::Var;
 use std::convert::TryInto;
-use std::rc::Rc;
 use std::str;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::timeout;
+
+/// The number of the latest block the node knows of.
+pub async fn block_number<'a>(node: &NodeData, timeout: Duration) -> Result<u64, &'a str> {
+  let eth = node.web3.eth();
+  let number = node
+    .retry
+    .timeout(timeout, || eth.block_number())
+    .await
+    .or_else(|_| Err("Timed out"))?
+    .or_else(|e| {
+      cblog!("CurrentBlock error: {}", e);
+      Err("Failed to fetch current block number")
+    })?;
+  Ok(number.as_u64())
+}
 
 pub struct CurrentBlock {
   node_param: ParamVar,
-  node: Option<Rc<Option<NodeData>>>,
-  timeout: Duration,
+  node: Option<Arc<NodeData>>,
+  timeout: ParamVar,
   requiring: ExposedTypes,
 }
 
 lazy_static! {
   static ref IN_TYPES: Vec<Type> = vec![common_type::none];
   static ref OUT_TYPES: Vec<Type> = vec![common_type::int];
-  static ref PARAMETERS: Parameters = vec![(
-    cstr!("Node"),
-    cstr!("The ethereum node block variable to use."),
-    vec![NODE_VAR],
-  )
-    .into(),];
+  static ref PARAMETERS: Parameters = vec![
+    (
+      cstr!("Node"),
+      cstr!("The ethereum node block variable to use."),
+      vec![NODE_VAR],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
+  ];
 }
 
 impl Default for CurrentBlock {
@@ -45,7 +78,7 @@ impl Default for CurrentBlock {
     CurrentBlock {
       node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
       node: None,
-      timeout: get_timeout(),
+      timeout: ParamVar::new(().into()),
       requiring: Vec::new(),
     }
   }
@@ -77,6 +110,7 @@ impl Block for CurrentBlock {
   fn setParam(&mut self, index: i32, value: &Var) {
     match index {
       0 => self.node_param.set_param(value),
+      1 => self.timeout.set_param(value),
       _ => unreachable!(),
     }
   }
@@ -84,6 +118,7 @@ impl Block for CurrentBlock {
   fn getParam(&mut self, index: i32) -> Var {
     match index {
       0 => self.node_param.get_param(),
+      1 => self.timeout.get_param(),
       _ => unreachable!(),
     }
   }
@@ -101,9 +136,11 @@ impl Block for CurrentBlock {
 
   fn warmup(&mut self, context: &Context) -> Result<(), &str> {
     self.node_param.warmup(context);
+    self.timeout.warmup(context);
     Ok(())
   }
   fn cleanup(&mut self) {
+    self.timeout.cleanup();
     self.node_param.cleanup();
     self.node = None;
   }
@@ -115,29 +152,36 @@ impl Block for CurrentBlock {
 impl BlockingBlock for CurrentBlock {
   fn activate_blocking(&mut self, _: &Context, _input: &Var) -> Result<Var, &str> {
     if self.node.is_none() {
-      self.node = Some(Var::from_object_as_clone::<Option<NodeData>>(
-        self.node_param.get(),
-        &NODE_TYPE,
-      )?);
-    }
-    let node = Var::get_mut_from_clone(&self.node)?;
-    let (scheduler, eth) = (&mut node.scheduler, &mut node.web3.eth());
-    let res = scheduler
-      .block_on(async {
-        let fut = eth.block_number();
-        let timed_fut = timeout(self.timeout, fut);
-        timed_fut.await
-      })
-      .or_else(|_| Err("Timed out"))?;
-    match res {
-      Ok(value) => {
-        let u: u64 = value.as_u64();
-        Ok(u.try_into()?)
-      }
-      Err(e) => {
-        cblog!("CurrentBlock error: {}", e);
-        Err("Failed to fetch current gas price")
-      }
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
     }
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
+    let number = RUNTIME.block_on(block_number(node, timeout))?;
+    Ok(number.try_into()?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::MockNode;
+
+  #[test]
+  fn fetches_the_latest_block_number() {
+    let node = MockNode::start();
+    node.expect("eth_blockNumber", quantity(0x1234));
+    let eth = node.node();
+    let number = RUNTIME.block_on(block_number(&eth, Duration::from_secs(5)));
+    assert_eq!(number, Ok(0x1234));
+  }
+
+  #[test]
+  fn node_errors_are_reported() {
+    let node = MockNode::start();
+    node.expect_error("eth_blockNumber", -32000, "unavailable");
+    let eth = node.node();
+    let number = RUNTIME.block_on(block_number(&eth, Duration::from_secs(5)));
+    assert_eq!(number, Err("Failed to fetch current block number"));
   }
 }
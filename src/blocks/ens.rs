@@ -0,0 +1,788 @@
+use crate::blocks::get_address;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::selector::eth_call;
+use crate::blocks::shared_from_var;
+use crate::blocks::NodeData;
+use crate::blocks::NODE_TYPE;
+use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
+use chainblocks::block::Block;
+use chainblocks::cblog;
+use chainblocks::core::activate_blocking;
+use chainblocks::core::BlockingBlock;
+use chainblocks::cstr;
+use chainblocks::types::common_type;
+use chainblocks::types::ClonedVar;
+use chainblocks::types::Context;
+use chainblocks::types::ExposedInfo;
+use chainblocks::types::ExposedTypes;
+use chainblocks::types::ParamVar;
+use chainblocks::types::Parameters;
+use chainblocks::types::Seq;
+use chainblocks::types::Type;
+use chainblocks::types::Var;
+use ethabi::token::Token;
+use ethabi::ParamType;
+use lru::LruCache;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use web3::signing::keccak256;
+use web3::types::Address;
+use web3::types::Bytes;
+use web3::types::CallRequest;
+use web3::types::H256;
+
+// deployed at the same address on mainnet and its testnets only
+const REGISTRY: &str = "00000000000c2e074ec69a0dfb2997ba6c7d2e1e";
+// mainnet, ropsten, rinkeby, goerli, holesky and sepolia
+const REGISTRY_CHAINS: &[u64] = &[1, 3, 4, 5, 17000, 11155111];
+// records rarely change, names are resolved again after this long
+const TTL: Duration = Duration::from_secs(300);
+const CAPACITY: usize = 1024;
+
+/// Addresses of the names a node resolved, kept for a while.
+pub struct EnsCache(Mutex<LruCache<String, (Address, Instant)>>);
+
+impl Default for EnsCache {
+  fn default() -> Self {
+    EnsCache(Mutex::new(LruCache::new(CAPACITY)))
+  }
+}
+
+impl EnsCache {
+  fn get(&self, name: &str) -> Option<Address> {
+    let mut entries = self.0.lock().unwrap();
+    match entries.get(&name.to_string()) {
+      Some((address, resolved)) if resolved.elapsed() < TTL => Some(*address),
+      _ => None,
+    }
+  }
+
+  fn put(&self, name: &str, address: Address) {
+    let mut entries = self.0.lock().unwrap();
+    entries.put(name.to_string(), (address, Instant::now()));
+  }
+}
+
+/// The ENS registry of the chain when Eth has no EnsRegistry, None if ENS is not deployed there.
+pub fn default_registry(chain_id: u64) -> Option<Address> {
+  if REGISTRY_CHAINS.contains(&chain_id) {
+    Some(REGISTRY.parse().unwrap())
+  } else {
+    None
+  }
+}
+
+/// If an address parameter holds an ENS name rather than hex.
+pub fn is_name(text: &str) -> bool {
+  text.contains('.') && !text.starts_with("0x")
+}
+
+/// EIP-137 namehash, labels are lowercased but not fully UTS-46 normalized.
+pub fn namehash(name: &str) -> H256 {
+  let mut node = [0u8; 32];
+  if !name.is_empty() {
+    for label in name.to_lowercase().rsplit('.') {
+      node = keccak256(&[&node[..], &keccak256(label.as_bytes())[..]].concat());
+    }
+  }
+  node.into()
+}
+
+fn calldata(signature: &str, tokens: &[Token]) -> Vec<u8> {
+  [
+    &keccak256(signature.as_bytes())[..4],
+    &ethabi::encode(tokens)[..],
+  ]
+  .concat()
+}
+
+async fn call<'a>(
+  node: &NodeData,
+  to: Address,
+  data: Vec<u8>,
+  timeout: Duration,
+) -> Result<Vec<u8>, &'a str> {
+  let request = CallRequest {
+    to: Some(to),
+    data: Some(Bytes(data)),
+    ..CallRequest::default()
+  };
+  let transport = node.web3.transport();
+  let output = node
+    .retry
+    .timeout(timeout, || eth_call(transport, &request, None))
+    .await
+    .or_else(|_| Err("ENS request timed out"))?
+    .or_else(|e| {
+      cblog!("ENS error: {}", e);
+      Err("ENS request failed")
+    })?;
+  Ok(output.0)
+}
+
+fn decode<'a>(kind: ParamType, output: &[u8]) -> Result<Token, &'a str> {
+  ethabi::decode(&[kind], output)
+    .ok()
+    .and_then(|mut tokens| tokens.pop())
+    .ok_or("Malformed ENS answer")
+}
+
+// asks the resolver of the name, None if it has none
+async fn record<'a>(
+  node: &NodeData,
+  name: &str,
+  signature: &str,
+  args: Vec<Token>,
+  kind: ParamType,
+  timeout: Duration,
+) -> Result<Option<Token>, &'a str> {
+  let hash = Token::FixedBytes(namehash(name).as_bytes().to_vec());
+  let data = calldata("resolver(bytes32)", std::slice::from_ref(&hash));
+  let registry = node.ens_registry.ok_or_else(|| {
+    cblog!("ENS is not deployed on chain {}", node.chain_id);
+    "No ENS registry on this chain, set EnsRegistry on Eth"
+  })?;
+  let output = call(node, registry, data, timeout).await?;
+  let resolver = match decode(ParamType::Address, &output)? {
+    Token::Address(resolver) if !resolver.is_zero() => resolver,
+    _ => return Ok(None),
+  };
+  let mut tokens = vec![hash];
+  tokens.extend(args);
+  let output = call(node, resolver, calldata(signature, &tokens), timeout).await?;
+  Ok(Some(decode(kind, &output)?))
+}
+
+/// The address an ENS name points to, cached by the node.
+pub async fn resolve_name<'a>(
+  node: &NodeData,
+  name: &str,
+  timeout: Duration,
+) -> Result<Address, &'a str> {
+  let name = name.to_lowercase();
+  if let Some(address) = node.ens.get(&name) {
+    return Ok(address);
+  }
+  let answer = record(
+    node,
+    &name,
+    "addr(bytes32)",
+    Vec::new(),
+    ParamType::Address,
+    timeout,
+  );
+  match answer.await? {
+    Some(Token::Address(address)) if !address.is_zero() => {
+      node.ens.put(&name, address);
+      Ok(address)
+    }
+    _ => {
+      cblog!("ENS name {} has no address", name);
+      Err("ENS name doesn't resolve to an address")
+    }
+  }
+}
+
+/// The primary ENS name of an address, None unless the name resolves back to it.
+pub async fn lookup_address<'a>(
+  node: &NodeData,
+  address: Address,
+  timeout: Duration,
+) -> Result<Option<String>, &'a str> {
+  let reverse = format!("{}.addr.reverse", hex::encode(address.as_bytes()));
+  let answer = record(
+    node,
+    &reverse,
+    "name(bytes32)",
+    Vec::new(),
+    ParamType::String,
+    timeout,
+  );
+  let name = match answer.await? {
+    Some(Token::String(name)) if !name.is_empty() => name,
+    _ => return Ok(None),
+  };
+  // anyone can put any name in their reverse record
+  let forward = record(
+    node,
+    &name,
+    "addr(bytes32)",
+    Vec::new(),
+    ParamType::Address,
+    timeout,
+  );
+  if forward.await? == Some(Token::Address(address)) {
+    Ok(Some(name))
+  } else {
+    Ok(None)
+  }
+}
+
+/// A text record of the name, like url or avatar, empty if unset.
+pub async fn text_record<'a>(
+  node: &NodeData,
+  name: &str,
+  key: &str,
+  timeout: Duration,
+) -> Result<String, &'a str> {
+  let args = vec![Token::String(key.to_string())];
+  let answer = record(
+    node,
+    name,
+    "text(bytes32,string)",
+    args,
+    ParamType::String,
+    timeout,
+  );
+  match answer.await? {
+    Some(Token::String(text)) => Ok(text),
+    Some(_) => Err("Malformed ENS answer"),
+    None => Err("ENS name has no resolver"),
+  }
+}
+
+/// The EIP-1577 contenthash of the name, empty if unset.
+pub async fn contenthash<'a>(
+  node: &NodeData,
+  name: &str,
+  timeout: Duration,
+) -> Result<Vec<u8>, &'a str> {
+  let answer = record(
+    node,
+    name,
+    "contenthash(bytes32)",
+    Vec::new(),
+    ParamType::Bytes,
+    timeout,
+  );
+  match answer.await? {
+    Some(Token::Bytes(hash)) => Ok(hash),
+    Some(_) => Err("Malformed ENS answer"),
+    None => Err("ENS name has no resolver"),
+  }
+}
+
+/// Reads an address parameter like get_address, resolving ENS names through the node.
+pub fn resolve_address<'a>(
+  node: &NodeData,
+  value: Var,
+  timeout: Duration,
+) -> Result<Address, &'a str> {
+  if let Ok(text) = <&str>::try_from(&value) {
+    if is_name(text) {
+      return RUNTIME.block_on(resolve_name(node, text, timeout));
+    }
+  }
+  get_address(value)
+}
+
+/// The ENS names given to address args, to resolve before var_to_tokens.
+pub fn input_names(input: &Var, input_types: &[String]) -> Vec<String> {
+  let args: &[Var] = input.try_into().unwrap_or(&[]);
+  let mut names = Vec::new();
+  for (arg, input_type) in args.iter().zip(input_types) {
+    if !input_type.starts_with("address") {
+      continue;
+    }
+    let values: Vec<Var> = match Seq::try_from(arg) {
+      Ok(seq) => seq.iter().collect(),
+      Err(_) => vec![*arg],
+    };
+    for value in values {
+      if let Ok(text) = String::try_from(&value) {
+        if is_name(&text) {
+          names.push(text);
+        }
+      }
+    }
+  }
+  names
+}
+
+pub async fn resolve_names<'a>(
+  node: &NodeData,
+  names: Vec<String>,
+  timeout: Duration,
+) -> Result<HashMap<String, Address>, &'a str> {
+  let mut resolved = HashMap::new();
+  for name in names {
+    let address = resolve_name(node, &name, timeout).await?;
+    resolved.insert(name, address);
+  }
+  Ok(resolved)
+}
+
+pub struct LookupAddress {
+  node_param: ParamVar,
+  node: Option<Arc<NodeData>>,
+  output: ClonedVar,
+  timeout: ParamVar,
+  requiring: ExposedTypes,
+}
+
+impl Default for LookupAddress {
+  fn default() -> Self {
+    LookupAddress {
+      node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
+      node: None,
+      output: ClonedVar(Var::default()),
+      timeout: ParamVar::new(().into()),
+      requiring: Vec::new(),
+    }
+  }
+}
+
+pub struct EnsRecord {
+  key: CString,
+  node_param: ParamVar,
+  node: Option<Arc<NodeData>>,
+  output: ClonedVar,
+  timeout: ParamVar,
+  requiring: ExposedTypes,
+}
+
+impl Default for EnsRecord {
+  fn default() -> Self {
+    EnsRecord {
+      key: CString::new("").unwrap(),
+      node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
+      node: None,
+      output: ClonedVar(Var::default()),
+      timeout: ParamVar::new(().into()),
+      requiring: Vec::new(),
+    }
+  }
+}
+
+lazy_static! {
+  static ref ADDRESS_TYPES: Vec<Type> = vec![common_type::string, common_type::bytes];
+  static ref NAME_TYPES: Vec<Type> = vec![common_type::string, common_type::none];
+  static ref STRING_TYPES: Vec<Type> = vec![common_type::string];
+  static ref BYTES_TYPES: Vec<Type> = vec![common_type::bytes];
+  static ref LOOKUP_PARAMETERS: Parameters = vec![
+    (
+      cstr!("Node"),
+      cstr!("The ethereum node block variable to use."),
+      vec![NODE_VAR],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
+  ];
+  static ref RECORD_PARAMETERS: Parameters = vec![
+    (
+      cstr!("Key"),
+      cstr!("The text record to read, like url, avatar or com.twitter, or contenthash for the EIP-1577 content hash as bytes."),
+      vec![common_type::string],
+    )
+      .into(),
+    (
+      cstr!("Node"),
+      cstr!("The ethereum node block variable to use."),
+      vec![NODE_VAR],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
+  ];
+}
+
+impl Block for LookupAddress {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.LookupAddress-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.LookupAddress")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.LookupAddress"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &ADDRESS_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &NAME_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&LOOKUP_PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.node_param.set_param(value),
+      1 => self.timeout.set_param(value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.node_param.get_param(),
+      1 => self.timeout.get_param(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.requiring.clear();
+    let exp_info = ExposedInfo {
+      exposedType: NODE_TYPE,
+      name: self.node_param.get_name(),
+      help: cstr!("The required ethereum node to use as gateway.").into(),
+      ..ExposedInfo::default()
+    };
+    self.requiring.push(exp_info);
+    Some(&self.requiring)
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.node_param.warmup(context);
+    self.timeout.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.timeout.cleanup();
+    self.node_param.cleanup();
+    self.node = None;
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(activate_blocking(self, context, input))
+  }
+}
+
+impl BlockingBlock for LookupAddress {
+  fn activate_blocking(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
+    if self.node.is_none() {
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
+    }
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
+    let address = get_address(*input)?;
+    self.output = match RUNTIME.block_on(lookup_address(node, address, timeout))? {
+      Some(name) => {
+        let name = CString::new(name).or_else(|_| Err("Invalid ENS name"))?;
+        name.as_ref().into()
+      }
+      None => ClonedVar(Var::default()),
+    };
+    Ok(self.output.0)
+  }
+}
+
+impl Block for EnsRecord {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.EnsRecord-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.EnsRecord")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.EnsRecord"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &STRING_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    if self.key.as_bytes() == b"contenthash" {
+      &BYTES_TYPES
+    } else {
+      &STRING_TYPES
+    }
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&RECORD_PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.key = value.try_into().unwrap_or(CString::new("").unwrap()),
+      1 => self.node_param.set_param(value),
+      2 => self.timeout.set_param(value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.key.as_ref().into(),
+      1 => self.node_param.get_param(),
+      2 => self.timeout.get_param(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.requiring.clear();
+    let exp_info = ExposedInfo {
+      exposedType: NODE_TYPE,
+      name: self.node_param.get_name(),
+      help: cstr!("The required ethereum node to use as gateway.").into(),
+      ..ExposedInfo::default()
+    };
+    self.requiring.push(exp_info);
+    Some(&self.requiring)
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    if self.key.as_bytes().is_empty() {
+      return Err("EnsRecord requires a Key");
+    }
+    self.node_param.warmup(context);
+    self.timeout.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.timeout.cleanup();
+    self.node_param.cleanup();
+    self.node = None;
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(activate_blocking(self, context, input))
+  }
+}
+
+impl BlockingBlock for EnsRecord {
+  fn activate_blocking(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
+    if self.node.is_none() {
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
+    }
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
+    let name: &str = input.try_into()?;
+    let key = self.key.to_str().or_else(|_| Err("Invalid Key string"))?;
+    self.output = if key == "contenthash" {
+      let hash = RUNTIME.block_on(contenthash(node, name, timeout))?;
+      hash.as_slice().into()
+    } else {
+      let text = RUNTIME.block_on(text_record(node, name, key, timeout))?;
+      let text = CString::new(text).or_else(|_| Err("Invalid ENS text record"))?;
+      text.as_ref().into()
+    };
+    Ok(self.output.0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::eth::connect;
+  use crate::blocks::eth::NodeConfig;
+  use crate::blocks::mock::MockNode;
+  use jsonrpc_core::serde_json::json;
+  use jsonrpc_core::types::Value;
+
+  // the mock node is not on a chain with ENS, so it comes with its own
+  const LOCAL_REGISTRY: &str = "0xe7f1725e7734ce288f8367e1bb143e90bb3f0512";
+  const RESOLVER: &str = "0x4976fb03c32e5b8cfe2b6ccb31c09ba78ebaba41";
+
+  fn ens_node(node: &MockNode) -> NodeData {
+    let mut config = NodeConfig::new(vec![node.http_url()]);
+    config.ens_registry = Some(LOCAL_REGISTRY.parse().unwrap());
+    RUNTIME.block_on(connect(config)).unwrap()
+  }
+
+  fn hex_call(to: &str, data: Vec<u8>) -> Value {
+    json!([{ "to": to, "data": format!("0x{}", hex::encode(data)) }, "latest"])
+  }
+
+  fn answer(tokens: &[Token]) -> Value {
+    json!(format!("0x{}", hex::encode(ethabi::encode(tokens))))
+  }
+
+  // points the name to the resolver in the registry
+  fn register(node: &MockNode, name: &str) {
+    let hash = Token::FixedBytes(namehash(name).as_bytes().to_vec());
+    node.expect_params(
+      "eth_call",
+      hex_call(LOCAL_REGISTRY, calldata("resolver(bytes32)", &[hash])),
+      answer(&[Token::Address(RESOLVER.parse().unwrap())]),
+    );
+  }
+
+  fn set_record(node: &MockNode, name: &str, signature: &str, args: Vec<Token>, value: Token) {
+    let mut tokens = vec![Token::FixedBytes(namehash(name).as_bytes().to_vec())];
+    tokens.extend(args);
+    node.expect_params(
+      "eth_call",
+      hex_call(RESOLVER, calldata(signature, &tokens)),
+      answer(&[value]),
+    );
+  }
+
+  fn unknown_names(node: &MockNode) {
+    node.expect("eth_call", answer(&[Token::Address(Address::zero())]));
+  }
+
+  #[test]
+  fn hashes_names_like_eip137() {
+    assert_eq!(namehash(""), H256::zero());
+    assert_eq!(
+      namehash("eth"),
+      "93cdeb708b7545dc668eb9280176169d1c33cfd8ed6f04690a0bcc88a93fc4ae"
+        .parse()
+        .unwrap()
+    );
+    assert_eq!(
+      namehash("foo.eth"),
+      "de9b09fd7c5f901e23a3f19fecc54828e9c848539801e86591bd9801b019f84f"
+        .parse()
+        .unwrap()
+    );
+    assert_eq!(namehash("Foo.ETH"), namehash("foo.eth"));
+    assert!(is_name("vitalik.eth"));
+    assert!(!is_name("0x5fbdb2315678afecb367f032d93f642f64180aa3"));
+    assert!(!is_name("5fbdb2315678afecb367f032d93f642f64180aa3"));
+  }
+
+  #[test]
+  fn resolves_and_caches_names() {
+    let node = MockNode::start();
+    let owner = Address::repeat_byte(0x42);
+    unknown_names(&node);
+    register(&node, "vitalik.eth");
+    set_record(
+      &node,
+      "vitalik.eth",
+      "addr(bytes32)",
+      Vec::new(),
+      Token::Address(owner),
+    );
+    let eth = ens_node(&node);
+    let timeout = Duration::from_secs(5);
+    let resolved = RUNTIME.block_on(resolve_name(&eth, "Vitalik.eth", timeout));
+    assert_eq!(resolved, Ok(owner));
+    let calls = node.requests("eth_call").len();
+    let again = RUNTIME.block_on(resolve_name(&eth, "vitalik.eth", timeout));
+    assert_eq!(again, Ok(owner));
+    assert_eq!(node.requests("eth_call").len(), calls);
+
+    let missing = RUNTIME.block_on(resolve_name(&eth, "nobody.eth", timeout));
+    assert_eq!(missing, Err("ENS name doesn't resolve to an address"));
+  }
+
+  #[test]
+  fn reverse_names_must_resolve_back() {
+    let node = MockNode::start();
+    let owner = Address::repeat_byte(0x42);
+    let reverse = format!("{}.addr.reverse", hex::encode(owner.as_bytes()));
+    unknown_names(&node);
+    register(&node, &reverse);
+    set_record(
+      &node,
+      &reverse,
+      "name(bytes32)",
+      Vec::new(),
+      Token::String("vitalik.eth".into()),
+    );
+    let eth = ens_node(&node);
+    let timeout = Duration::from_secs(5);
+    // claimed but not pointing back
+    let lookup = RUNTIME.block_on(lookup_address(&eth, owner, timeout));
+    assert_eq!(lookup, Ok(None));
+
+    register(&node, "vitalik.eth");
+    set_record(
+      &node,
+      "vitalik.eth",
+      "addr(bytes32)",
+      Vec::new(),
+      Token::Address(owner),
+    );
+    let lookup = RUNTIME.block_on(lookup_address(&eth, owner, timeout));
+    assert_eq!(lookup, Ok(Some("vitalik.eth".to_string())));
+    let stranger = RUNTIME.block_on(lookup_address(&eth, Address::repeat_byte(1), timeout));
+    assert_eq!(stranger, Ok(None));
+  }
+
+  #[test]
+  fn reads_text_records_and_contenthashes() {
+    let node = MockNode::start();
+    unknown_names(&node);
+    register(&node, "vitalik.eth");
+    set_record(
+      &node,
+      "vitalik.eth",
+      "text(bytes32,string)",
+      vec![Token::String("url".into())],
+      Token::String("https://vitalik.ca".into()),
+    );
+    let ipfs = vec![0xe3, 0x01, 0x01, 0x70];
+    set_record(
+      &node,
+      "vitalik.eth",
+      "contenthash(bytes32)",
+      Vec::new(),
+      Token::Bytes(ipfs.clone()),
+    );
+    let eth = ens_node(&node);
+    let timeout = Duration::from_secs(5);
+    let url = RUNTIME.block_on(text_record(&eth, "vitalik.eth", "url", timeout));
+    assert_eq!(url, Ok("https://vitalik.ca".to_string()));
+    let hash = RUNTIME.block_on(contenthash(&eth, "vitalik.eth", timeout));
+    assert_eq!(hash, Ok(ipfs));
+    let missing = RUNTIME.block_on(text_record(&eth, "nobody.eth", "url", timeout));
+    assert_eq!(missing, Err("ENS name has no resolver"));
+  }
+
+  #[test]
+  fn the_registry_depends_on_the_chain() {
+    assert_eq!(default_registry(1), Some(REGISTRY.parse().unwrap()));
+    assert_eq!(default_registry(11155111), default_registry(1));
+    assert_eq!(default_registry(137), None);
+
+    let node = MockNode::start();
+    let eth = node.node();
+    let resolved = RUNTIME.block_on(resolve_name(&eth, "vitalik.eth", Duration::from_secs(5)));
+    assert_eq!(
+      resolved,
+      Err("No ENS registry on this chain, set EnsRegistry on Eth")
+    );
+    assert!(node.requests("eth_call").is_empty());
+  }
+}
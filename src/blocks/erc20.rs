@@ -0,0 +1,1515 @@
+use crate::blocks::contract::contract_data;
+use crate::blocks::ens::resolve_address;
+use crate::blocks::get_block_timeout;
+use crate::blocks::log;
+use crate::blocks::selector::eth_call;
+use crate::blocks::shared_from_var;
+use crate::blocks::units::format_units;
+use crate::blocks::units::parse_units;
+use crate::blocks::write::caller_from_var;
+use crate::blocks::write::options_from_table;
+use crate::blocks::write::receipt_to_table;
+use crate::blocks::write::send_call;
+use crate::blocks::write::Caller;
+use crate::blocks::write::TRANSACTION_TABLE_TYPE;
+use crate::blocks::NodeData;
+use crate::blocks::NODE_TYPE;
+use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
+use chainblocks::block::Block;
+use chainblocks::cblog;
+use chainblocks::cbstr;
+use chainblocks::core::activate_blocking;
+use chainblocks::core::do_blocking;
+use chainblocks::core::BlockingBlock;
+use chainblocks::cstr;
+use chainblocks::types::common_type;
+use chainblocks::types::ClonedVar;
+use chainblocks::types::Context;
+use chainblocks::types::ExposedInfo;
+use chainblocks::types::ExposedTypes;
+use chainblocks::types::ParamVar;
+use chainblocks::types::Parameters;
+use chainblocks::types::RawString;
+use chainblocks::types::Table;
+use chainblocks::types::Type;
+use chainblocks::types::Var;
+use ethabi::token::Token;
+use ethabi::ParamType;
+use secp256k1::SecretKey;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use web3::contract::Options;
+use web3::signing::keccak256;
+use web3::signing::Key;
+use web3::signing::SecretKeyRef;
+use web3::types::Address;
+use web3::types::Bytes;
+use web3::types::CallRequest;
+use web3::types::TransactionReceipt;
+use web3::types::H256;
+use web3::types::U256;
+
+// the common part of the standard, plus EIP-2612 permit
+pub const ERC20_ABI: &str = r#"[
+  {"type":"function","name":"balanceOf","stateMutability":"view","inputs":[{"name":"owner","type":"address"}],"outputs":[{"name":"","type":"uint256"}]},
+  {"type":"function","name":"allowance","stateMutability":"view","inputs":[{"name":"owner","type":"address"},{"name":"spender","type":"address"}],"outputs":[{"name":"","type":"uint256"}]},
+  {"type":"function","name":"decimals","stateMutability":"view","inputs":[],"outputs":[{"name":"","type":"uint8"}]},
+  {"type":"function","name":"symbol","stateMutability":"view","inputs":[],"outputs":[{"name":"","type":"string"}]},
+  {"type":"function","name":"totalSupply","stateMutability":"view","inputs":[],"outputs":[{"name":"","type":"uint256"}]},
+  {"type":"function","name":"transfer","stateMutability":"nonpayable","inputs":[{"name":"to","type":"address"},{"name":"value","type":"uint256"}],"outputs":[{"name":"","type":"bool"}]},
+  {"type":"function","name":"approve","stateMutability":"nonpayable","inputs":[{"name":"spender","type":"address"},{"name":"value","type":"uint256"}],"outputs":[{"name":"","type":"bool"}]},
+  {"type":"function","name":"transferFrom","stateMutability":"nonpayable","inputs":[{"name":"from","type":"address"},{"name":"to","type":"address"},{"name":"value","type":"uint256"}],"outputs":[{"name":"","type":"bool"}]},
+  {"type":"function","name":"permit","stateMutability":"nonpayable","inputs":[{"name":"owner","type":"address"},{"name":"spender","type":"address"},{"name":"value","type":"uint256"},{"name":"deadline","type":"uint256"},{"name":"v","type":"uint8"},{"name":"r","type":"bytes32"},{"name":"s","type":"bytes32"}],"outputs":[]},
+  {"type":"function","name":"nonces","stateMutability":"view","inputs":[{"name":"owner","type":"address"}],"outputs":[{"name":"","type":"uint256"}]},
+  {"type":"function","name":"DOMAIN_SEPARATOR","stateMutability":"view","inputs":[],"outputs":[{"name":"","type":"bytes32"}]}
+]"#;
+
+const PERMIT_TYPE: &str =
+  "Permit(address owner,address spender,uint256 value,uint256 nonce,uint256 deadline)";
+
+lazy_static! {
+  static ref ABI: ethabi::Contract = ethabi::Contract::load(ERC20_ABI.as_bytes()).unwrap();
+}
+
+/// Decimals of the tokens a node used, they never change.
+#[derive(Default)]
+pub struct DecimalsCache(Mutex<HashMap<Address, u32>>);
+
+async fn call_token<'a>(
+  node: &NodeData,
+  token: Address,
+  from: Option<Address>,
+  method: &str,
+  args: &[Token],
+  timeout: Duration,
+) -> Result<Vec<u8>, &'a str> {
+  let data = ABI
+    .function(method)
+    .and_then(|function| function.encode_input(args))
+    .or_else(|_| Err("Failed to encode token call"))?;
+  let request = CallRequest {
+    from,
+    to: Some(token),
+    data: Some(Bytes(data)),
+    ..CallRequest::default()
+  };
+  let transport = node.web3.transport();
+  let output = node
+    .retry
+    .timeout(timeout, || eth_call(transport, &request, None))
+    .await
+    .or_else(|_| Err("Token request timed out"))?
+    .or_else(|e| {
+      cblog!("ERC20 {} error: {}", method, e);
+      Err("Token call failed")
+    })?;
+  Ok(output.0)
+}
+
+fn decode<'a>(kind: ParamType, output: &[u8]) -> Result<Token, &'a str> {
+  ethabi::decode(&[kind], output)
+    .ok()
+    .and_then(|mut tokens| tokens.pop())
+    .ok_or("Malformed token answer")
+}
+
+async fn call_uint<'a>(
+  node: &NodeData,
+  token: Address,
+  method: &str,
+  args: &[Token],
+  timeout: Duration,
+) -> Result<U256, &'a str> {
+  let output = call_token(node, token, None, method, args, timeout).await?;
+  match decode(ParamType::Uint(256), &output)? {
+    Token::Uint(value) => Ok(value),
+    _ => Err("Malformed token answer"),
+  }
+}
+
+/// The decimals of the token, asked once per node.
+pub async fn decimals<'a>(
+  node: &NodeData,
+  token: Address,
+  timeout: Duration,
+) -> Result<u32, &'a str> {
+  if let Some(decimals) = node.decimals.0.lock().unwrap().get(&token) {
+    return Ok(*decimals);
+  }
+  let value = call_uint(node, token, "decimals", &[], timeout).await?;
+  // parse_units can't scale past 10^77
+  if value > 77.into() {
+    return Err("Token decimals are out of range");
+  }
+  let decimals = value.as_u32();
+  node.decimals.0.lock().unwrap().insert(token, decimals);
+  Ok(decimals)
+}
+
+/// The symbol of the token, some old ones like MKR return a bytes32.
+pub async fn symbol<'a>(
+  node: &NodeData,
+  token: Address,
+  timeout: Duration,
+) -> Result<String, &'a str> {
+  let output = call_token(node, token, None, "symbol", &[], timeout).await?;
+  if let Ok(Token::String(symbol)) = decode(ParamType::String, &output) {
+    return Ok(symbol);
+  }
+  if output.len() != 32 {
+    return Err("Malformed token answer");
+  }
+  let end = output.iter().position(|byte| *byte == 0).unwrap_or(32);
+  String::from_utf8(output[..end].to_vec()).or_else(|_| Err("Malformed token symbol"))
+}
+
+/// Simulates a write from its sender, tokens like USDT return nothing instead of true.
+pub async fn check_success<'a>(
+  node: &NodeData,
+  token: Address,
+  from: Address,
+  method: &str,
+  args: &[Token],
+  timeout: Duration,
+) -> Result<(), &'a str> {
+  let output = call_token(node, token, Some(from), method, args, timeout).await?;
+  if output.is_empty() {
+    return Ok(());
+  }
+  match decode(ParamType::Bool, &output)? {
+    Token::Bool(true) => Ok(()),
+    _ => Err("Token call returned false"),
+  }
+}
+
+/// The EIP-712 hash the owner signs to permit the spender.
+pub fn permit_digest(
+  domain: H256,
+  owner: Address,
+  spender: Address,
+  value: U256,
+  nonce: U256,
+  deadline: U256,
+) -> H256 {
+  let permit = ethabi::encode(&[
+    Token::FixedBytes(keccak256(PERMIT_TYPE.as_bytes()).to_vec()),
+    Token::Address(owner),
+    Token::Address(spender),
+    Token::Uint(value),
+    Token::Uint(nonce),
+    Token::Uint(deadline),
+  ]);
+  let message = [
+    &[0x19u8, 0x01][..],
+    domain.as_bytes(),
+    &keccak256(&permit)[..],
+  ]
+  .concat();
+  keccak256(&message).into()
+}
+
+/// Signs a permit with the owner key, the arguments of permit.
+pub async fn sign_permit<'a>(
+  node: &NodeData,
+  token: Address,
+  owner: &SecretKey,
+  spender: Address,
+  value: U256,
+  deadline: U256,
+  timeout: Duration,
+) -> Result<Vec<Token>, &'a str> {
+  let key = SecretKeyRef::new(owner);
+  let output = call_token(node, token, None, "DOMAIN_SEPARATOR", &[], timeout).await?;
+  let domain = match decode(ParamType::FixedBytes(32), &output)? {
+    Token::FixedBytes(domain) => H256::from_slice(&domain),
+    _ => return Err("Malformed token answer"),
+  };
+  let args = [Token::Address(key.address())];
+  let nonce = call_uint(node, token, "nonces", &args, timeout).await?;
+  let digest = permit_digest(domain, key.address(), spender, value, nonce, deadline);
+  // without a chain id v is 27 or 28, as ecrecover wants it
+  let signature = key
+    .sign(digest.as_bytes(), None)
+    .or_else(|_| Err("Failed to sign permit"))?;
+  Ok(vec![
+    Token::Address(key.address()),
+    Token::Address(spender),
+    Token::Uint(value),
+    Token::Uint(deadline),
+    Token::Uint(signature.v.into()),
+    Token::FixedBytes(signature.r.as_bytes().to_vec()),
+    Token::FixedBytes(signature.s.as_bytes().to_vec()),
+  ])
+}
+
+/// Checks the write from its sender first, then sends it to the token.
+pub async fn send_checked<'a>(
+  node: &Arc<NodeData>,
+  token: Address,
+  method: &str,
+  args: &[Token],
+  from: Caller,
+  confirmations: usize,
+  opts: Options,
+  timeout: Duration,
+) -> Result<TransactionReceipt, &'a str> {
+  check_success(node, token, from.address(), method, args, timeout).await?;
+  let contract = contract_data(node.clone(), token, ERC20_ABI)?;
+  let web3 = &node.web3;
+  send_call(
+    &contract,
+    web3,
+    node.chain_id,
+    method,
+    args,
+    from,
+    confirmations,
+    opts,
+    timeout,
+  )
+  .await
+}
+
+// raw bytes as they are, strings and ints in whole tokens
+async fn amount<'a>(
+  node: &NodeData,
+  token: Address,
+  value: &Var,
+  timeout: Duration,
+) -> Result<U256, &'a str> {
+  if let Ok(bytes) = <&[u8]>::try_from(value) {
+    if bytes.len() > 32 {
+      return Err("Amount overflows 256 bits");
+    }
+    return Ok(bytes.into());
+  }
+  let text = if let Ok(text) = <&str>::try_from(value) {
+    text.to_string()
+  } else {
+    let whole: i64 = value.try_into()?;
+    whole.to_string()
+  };
+  parse_units(&text, decimals(node, token, timeout).await?)
+}
+
+// the amount and the decimals of the token, for the output
+async fn read_amount<'a>(
+  node: &NodeData,
+  token: Address,
+  method: &str,
+  args: &[Token],
+  timeout: Duration,
+) -> Result<(U256, u32), &'a str> {
+  let value = call_uint(node, token, method, args, timeout).await?;
+  Ok((value, decimals(node, token, timeout).await?))
+}
+
+fn amount_to_table(value: U256, decimals: u32, output: &mut Table) -> Var {
+  let raw: [u8; 32] = value.into();
+  let amount = CString::new(format_units(value, decimals)).unwrap();
+  output.insert_fast_static(cstr!("raw"), (&raw[..]).into());
+  output.insert_fast_static(cstr!("amount"), amount.as_ref().into());
+  output.as_ref().into()
+}
+
+static AMOUNT_TABLE_TYPES: &'static [Type] = &[common_type::bytes, common_type::string];
+const AMOUNT_TABLE_KEYS: &[RawString] = &[cbstr!("raw"), cbstr!("amount")];
+static AMOUNT_TABLE_TYPE: Type = Type::table(AMOUNT_TABLE_KEYS, AMOUNT_TABLE_TYPES);
+
+fn address_types() -> Vec<Type> {
+  vec![
+    common_type::string,
+    common_type::string_var,
+    common_type::bytes,
+    common_type::bytes_var,
+  ]
+}
+
+// Token, Node and Timeout, then the ones of the block
+fn token_parameters(extra: Vec<(&'static str, &'static str, Vec<Type>)>) -> Parameters {
+  let mut parameters: Parameters = vec![
+    (
+      cstr!("Token"),
+      cstr!("The address of the token contract, or an ENS name."),
+      address_types(),
+    )
+      .into(),
+    (
+      cstr!("Node"),
+      cstr!("The ethereum node block variable to use."),
+      vec![NODE_VAR],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR,
+      ],
+    )
+      .into(),
+  ];
+  parameters.extend(extra.into_iter().map(|parameter| parameter.into()));
+  parameters
+}
+
+// plus From, Confirmations and Options for the ones sending transactions
+fn writer_parameters(mut extra: Vec<(&'static str, &'static str, Vec<Type>)>) -> Parameters {
+  let mut parameters = vec![
+    (
+      cstr!("From"),
+      cstr!("The sender secret key or an unlocked account's public key or ENS name. In the case of a secret key, using a file is safer as it won't be kept in memory."),
+      vec![
+        common_type::path,
+        common_type::path_var,
+        common_type::string,
+        common_type::string_var,
+      ],
+    ),
+    (
+      cstr!("Confirmations"),
+      cstr!("The amount of confirmations required."),
+      vec![common_type::int],
+    ),
+    (
+      cstr!("Options"),
+      cstr!("Various options to add to this call. (avail: gas, gas-price, value, nonce)"),
+      vec![
+        common_type::none,
+        common_type::bytes_table,
+        common_type::bytes_table_var,
+      ],
+    ),
+  ];
+  parameters.append(&mut extra);
+  token_parameters(parameters)
+}
+
+lazy_static! {
+  static ref NONE_TYPES: Vec<Type> = vec![common_type::none];
+  static ref OWNER_TYPES: Vec<Type> = vec![common_type::string, common_type::bytes];
+  static ref VALUE_TYPES: Vec<Type> = vec![
+    common_type::bytes,
+    common_type::string,
+    common_type::int,
+  ];
+  static ref INT_TYPES: Vec<Type> = vec![common_type::int];
+  static ref STRING_TYPES: Vec<Type> = vec![common_type::string];
+  static ref AMOUNT_TYPES: Vec<Type> = vec![AMOUNT_TABLE_TYPE];
+  static ref TRANSACTION_TYPES: Vec<Type> = vec![TRANSACTION_TABLE_TYPE];
+  static ref PARAMETERS: Parameters = token_parameters(Vec::new());
+  static ref ALLOWANCE_PARAMETERS: Parameters = token_parameters(vec![(
+    cstr!("Spender"),
+    cstr!("The address allowed to spend the tokens of the input owner, or an ENS name."),
+    address_types(),
+  )]);
+  static ref TRANSFER_PARAMETERS: Parameters = writer_parameters(vec![(
+    cstr!("To"),
+    cstr!("The address receiving the tokens, or an ENS name."),
+    address_types(),
+  )]);
+  static ref APPROVE_PARAMETERS: Parameters = writer_parameters(vec![(
+    cstr!("Spender"),
+    cstr!("The address allowed to spend the tokens of From, or an ENS name."),
+    address_types(),
+  )]);
+  static ref TRANSFER_FROM_PARAMETERS: Parameters = writer_parameters(vec![
+    (
+      cstr!("Owner"),
+      cstr!("The address the tokens are taken from, it must have approved From, or an ENS name."),
+      address_types(),
+    ),
+    (
+      cstr!("To"),
+      cstr!("The address receiving the tokens, or an ENS name."),
+      address_types(),
+    ),
+  ]);
+  static ref PERMIT_PARAMETERS: Parameters = writer_parameters(vec![
+    (
+      cstr!("Spender"),
+      cstr!("The address allowed to spend the tokens of the owner, or an ENS name."),
+      address_types(),
+    ),
+    (
+      cstr!("OwnerKey"),
+      cstr!("The secret key of the owner signing the permit, From only pays for the transaction. Using a file is safer as it won't be kept in memory."),
+      vec![
+        common_type::path,
+        common_type::path_var,
+        common_type::string,
+        common_type::string_var,
+      ],
+    ),
+    (
+      cstr!("Deadline"),
+      cstr!("The unix time in seconds the permit expires at, none for never."),
+      vec![common_type::none, common_type::int, common_type::int_var],
+    ),
+  ]);
+}
+
+struct TokenUser {
+  token: ParamVar,
+  node_param: ParamVar,
+  node: Option<Arc<NodeData>>,
+  timeout: ParamVar,
+  requiring: ExposedTypes,
+}
+
+impl Default for TokenUser {
+  fn default() -> Self {
+    TokenUser {
+      token: ParamVar::new(cstr!("").into()),
+      node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
+      node: None,
+      timeout: ParamVar::new(().into()),
+      requiring: Vec::new(),
+    }
+  }
+}
+
+impl TokenUser {
+  fn set_param(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.token.set_param(value),
+      1 => self.node_param.set_param(value),
+      2 => self.timeout.set_param(value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn get_param(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.token.get_param(),
+      1 => self.node_param.get_param(),
+      2 => self.timeout.get_param(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn required_variables(&mut self) -> Option<&ExposedTypes> {
+    self.requiring.clear();
+    let exp_info = ExposedInfo {
+      exposedType: NODE_TYPE,
+      name: self.node_param.get_name(),
+      help: cstr!("The required ethereum node to use as gateway.").into(),
+      ..ExposedInfo::default()
+    };
+    self.requiring.push(exp_info);
+    Some(&self.requiring)
+  }
+
+  fn warmup(&mut self, context: &Context) {
+    self.node_param.warmup(context);
+    self.token.warmup(context);
+    self.timeout.warmup(context);
+  }
+
+  fn cleanup(&mut self) {
+    self.timeout.cleanup();
+    self.token.cleanup();
+    self.node_param.cleanup();
+    self.node = None;
+  }
+
+  /// The node, the timeout of its requests and the token address.
+  fn context<'a>(&mut self) -> Result<(Arc<NodeData>, Duration, Address), &'a str> {
+    if self.node.is_none() {
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
+    }
+    let node = self
+      .node
+      .clone()
+      .ok_or("Failed to unwrap shared object, was empty")?;
+    let timeout = get_block_timeout(&self.timeout, &node)?;
+    let token = resolve_address(&node, self.token.get(), timeout)?;
+    Ok((node, timeout, token))
+  }
+}
+
+struct TokenWriter {
+  tu: TokenUser,
+  from: ParamVar,
+  // checked in warmup, setParam can't fail
+  confirmations: i64,
+  options: ParamVar,
+  output: Table,
+}
+
+impl Default for TokenWriter {
+  fn default() -> Self {
+    TokenWriter {
+      tu: TokenUser::default(),
+      from: ParamVar::new(Var::default()),
+      confirmations: 12,
+      options: ParamVar::new(().into()),
+      output: Table::new(),
+    }
+  }
+}
+
+impl TokenWriter {
+  fn set_param(&mut self, index: i32, value: &Var) {
+    match index {
+      3 => self.from.set_param(value),
+      4 => self.confirmations = value.try_into().unwrap_or(12),
+      5 => self.options.set_param(value),
+      _ => self.tu.set_param(index, value),
+    }
+  }
+
+  fn get_param(&mut self, index: i32) -> Var {
+    match index {
+      3 => self.from.get_param(),
+      4 => self.confirmations.into(),
+      5 => self.options.get_param(),
+      _ => self.tu.get_param(index),
+    }
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    if self.confirmations < 0 {
+      return Err("Confirmations can't be negative");
+    }
+    self.tu.warmup(context);
+    self.from.warmup(context);
+    self.options.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.options.cleanup();
+    self.from.cleanup();
+    self.tu.cleanup();
+  }
+
+  /// Sends the method with these arguments from From and outputs its receipt.
+  fn send<'a>(
+    &mut self,
+    node: &Arc<NodeData>,
+    token: Address,
+    method: &str,
+    args: &[Token],
+    timeout: Duration,
+  ) -> Result<Var, &'a str> {
+    let from = caller_from_var(node, &self.from.get(), timeout)?;
+    let options: Option<Table> = {
+      let optvar = self.options.get();
+      if optvar.is_none() {
+        None
+      } else {
+        Some(optvar.as_ref().try_into()?)
+      }
+    };
+    let opts = options_from_table(options)?;
+    let transaction = RUNTIME.block_on(send_checked(
+      node,
+      token,
+      method,
+      args,
+      from,
+      self.confirmations as usize,
+      opts,
+      timeout,
+    ))?;
+    receipt_to_table(&transaction, &mut self.output)?;
+    Ok((&self.output).into())
+  }
+}
+
+#[derive(Default)]
+pub struct BalanceOf {
+  tu: TokenUser,
+  output: Table,
+}
+
+impl Block for BalanceOf {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.ERC20.BalanceOf-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.ERC20.BalanceOf")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.ERC20.BalanceOf"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &OWNER_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &AMOUNT_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    self.tu.set_param(index, value)
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    self.tu.get_param(index)
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.tu.required_variables()
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.tu.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.tu.cleanup();
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(activate_blocking(self, context, input))
+  }
+}
+
+impl BlockingBlock for BalanceOf {
+  fn activate_blocking(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
+    let (node, timeout, token) = self.tu.context()?;
+    let owner = resolve_address(&node, *input, timeout)?;
+    let args = [Token::Address(owner)];
+    let (value, decimals) =
+      RUNTIME.block_on(read_amount(&node, token, "balanceOf", &args, timeout))?;
+    Ok(amount_to_table(value, decimals, &mut self.output))
+  }
+}
+
+#[derive(Default)]
+pub struct Allowance {
+  tu: TokenUser,
+  spender: ParamVar,
+  output: Table,
+}
+
+impl Block for Allowance {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.ERC20.Allowance-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.ERC20.Allowance")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.ERC20.Allowance"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &OWNER_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &AMOUNT_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&ALLOWANCE_PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      3 => self.spender.set_param(value),
+      _ => self.tu.set_param(index, value),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      3 => self.spender.get_param(),
+      _ => self.tu.get_param(index),
+    }
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.tu.required_variables()
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.tu.warmup(context);
+    self.spender.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.spender.cleanup();
+    self.tu.cleanup();
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(activate_blocking(self, context, input))
+  }
+}
+
+impl BlockingBlock for Allowance {
+  fn activate_blocking(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
+    let (node, timeout, token) = self.tu.context()?;
+    let owner = resolve_address(&node, *input, timeout)?;
+    let spender = resolve_address(&node, self.spender.get(), timeout)?;
+    let args = [Token::Address(owner), Token::Address(spender)];
+    let (value, decimals) =
+      RUNTIME.block_on(read_amount(&node, token, "allowance", &args, timeout))?;
+    Ok(amount_to_table(value, decimals, &mut self.output))
+  }
+}
+
+#[derive(Default)]
+pub struct TotalSupply {
+  tu: TokenUser,
+  output: Table,
+}
+
+impl Block for TotalSupply {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.ERC20.TotalSupply-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.ERC20.TotalSupply")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.ERC20.TotalSupply"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &NONE_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &AMOUNT_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    self.tu.set_param(index, value)
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    self.tu.get_param(index)
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.tu.required_variables()
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.tu.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.tu.cleanup();
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(activate_blocking(self, context, input))
+  }
+}
+
+impl BlockingBlock for TotalSupply {
+  fn activate_blocking(&mut self, _: &Context, _: &Var) -> Result<Var, &str> {
+    let (node, timeout, token) = self.tu.context()?;
+    let (value, decimals) =
+      RUNTIME.block_on(read_amount(&node, token, "totalSupply", &[], timeout))?;
+    Ok(amount_to_table(value, decimals, &mut self.output))
+  }
+}
+
+#[derive(Default)]
+pub struct Decimals {
+  tu: TokenUser,
+}
+
+impl Block for Decimals {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.ERC20.Decimals-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.ERC20.Decimals")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.ERC20.Decimals"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &NONE_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &INT_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    self.tu.set_param(index, value)
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    self.tu.get_param(index)
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.tu.required_variables()
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.tu.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.tu.cleanup();
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(activate_blocking(self, context, input))
+  }
+}
+
+impl BlockingBlock for Decimals {
+  fn activate_blocking(&mut self, _: &Context, _: &Var) -> Result<Var, &str> {
+    let (node, timeout, token) = self.tu.context()?;
+    let decimals = RUNTIME.block_on(decimals(&node, token, timeout))?;
+    Ok((decimals as i64).into())
+  }
+}
+
+pub struct Symbol {
+  tu: TokenUser,
+  output: ClonedVar,
+}
+
+impl Default for Symbol {
+  fn default() -> Self {
+    Symbol {
+      tu: TokenUser::default(),
+      output: ClonedVar(Var::default()),
+    }
+  }
+}
+
+impl Block for Symbol {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.ERC20.Symbol-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.ERC20.Symbol")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.ERC20.Symbol"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &NONE_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &STRING_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    self.tu.set_param(index, value)
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    self.tu.get_param(index)
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.tu.required_variables()
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.tu.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.tu.cleanup();
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(activate_blocking(self, context, input))
+  }
+}
+
+impl BlockingBlock for Symbol {
+  fn activate_blocking(&mut self, _: &Context, _: &Var) -> Result<Var, &str> {
+    let (node, timeout, token) = self.tu.context()?;
+    let symbol = RUNTIME.block_on(symbol(&node, token, timeout))?;
+    let symbol = CString::new(symbol).or_else(|_| Err("Malformed token symbol"))?;
+    self.output = symbol.as_ref().into();
+    Ok(self.output.0)
+  }
+}
+
+#[derive(Default)]
+pub struct Transfer {
+  w: TokenWriter,
+  to: ParamVar,
+}
+
+impl Block for Transfer {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.ERC20.Transfer-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.ERC20.Transfer")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.ERC20.Transfer"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &VALUE_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &TRANSACTION_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&TRANSFER_PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      6 => self.to.set_param(value),
+      _ => self.w.set_param(index, value),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      6 => self.to.get_param(),
+      _ => self.w.get_param(index),
+    }
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.w.tu.required_variables()
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.w.warmup(context)?;
+    self.to.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.to.cleanup();
+    self.w.cleanup();
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(do_blocking(context, || -> Result<Var, &str> {
+      let (node, timeout, token) = self.w.tu.context()?;
+      let to = resolve_address(&node, self.to.get(), timeout)?;
+      let value = RUNTIME.block_on(amount(&node, token, input, timeout))?;
+      let args = [Token::Address(to), Token::Uint(value)];
+      self.w.send(&node, token, "transfer", &args, timeout)
+    }))
+  }
+}
+
+#[derive(Default)]
+pub struct Approve {
+  w: TokenWriter,
+  spender: ParamVar,
+}
+
+impl Block for Approve {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.ERC20.Approve-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.ERC20.Approve")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.ERC20.Approve"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &VALUE_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &TRANSACTION_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&APPROVE_PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      6 => self.spender.set_param(value),
+      _ => self.w.set_param(index, value),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      6 => self.spender.get_param(),
+      _ => self.w.get_param(index),
+    }
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.w.tu.required_variables()
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.w.warmup(context)?;
+    self.spender.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.spender.cleanup();
+    self.w.cleanup();
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(do_blocking(context, || -> Result<Var, &str> {
+      let (node, timeout, token) = self.w.tu.context()?;
+      let spender = resolve_address(&node, self.spender.get(), timeout)?;
+      let value = RUNTIME.block_on(amount(&node, token, input, timeout))?;
+      let args = [Token::Address(spender), Token::Uint(value)];
+      self.w.send(&node, token, "approve", &args, timeout)
+    }))
+  }
+}
+
+#[derive(Default)]
+pub struct TransferFrom {
+  w: TokenWriter,
+  owner: ParamVar,
+  to: ParamVar,
+}
+
+impl Block for TransferFrom {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.ERC20.TransferFrom-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.ERC20.TransferFrom")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.ERC20.TransferFrom"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &VALUE_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &TRANSACTION_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&TRANSFER_FROM_PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      6 => self.owner.set_param(value),
+      7 => self.to.set_param(value),
+      _ => self.w.set_param(index, value),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      6 => self.owner.get_param(),
+      7 => self.to.get_param(),
+      _ => self.w.get_param(index),
+    }
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.w.tu.required_variables()
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.w.warmup(context)?;
+    self.owner.warmup(context);
+    self.to.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.to.cleanup();
+    self.owner.cleanup();
+    self.w.cleanup();
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(do_blocking(context, || -> Result<Var, &str> {
+      let (node, timeout, token) = self.w.tu.context()?;
+      let owner = resolve_address(&node, self.owner.get(), timeout)?;
+      let to = resolve_address(&node, self.to.get(), timeout)?;
+      let value = RUNTIME.block_on(amount(&node, token, input, timeout))?;
+      let args = [
+        Token::Address(owner),
+        Token::Address(to),
+        Token::Uint(value),
+      ];
+      self.w.send(&node, token, "transferFrom", &args, timeout)
+    }))
+  }
+}
+
+pub struct Permit {
+  w: TokenWriter,
+  spender: ParamVar,
+  owner_key: ParamVar,
+  deadline: ParamVar,
+}
+
+impl Default for Permit {
+  fn default() -> Self {
+    Permit {
+      w: TokenWriter::default(),
+      spender: ParamVar::new(cstr!("").into()),
+      owner_key: ParamVar::new(Var::default()),
+      deadline: ParamVar::new(().into()),
+    }
+  }
+}
+
+impl Block for Permit {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.ERC20.Permit-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.ERC20.Permit")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.ERC20.Permit"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &VALUE_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &TRANSACTION_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PERMIT_PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      6 => self.spender.set_param(value),
+      7 => self.owner_key.set_param(value),
+      8 => self.deadline.set_param(value),
+      _ => self.w.set_param(index, value),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      6 => self.spender.get_param(),
+      7 => self.owner_key.get_param(),
+      8 => self.deadline.get_param(),
+      _ => self.w.get_param(index),
+    }
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.w.tu.required_variables()
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.w.warmup(context)?;
+    self.spender.warmup(context);
+    self.owner_key.warmup(context);
+    self.deadline.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.deadline.cleanup();
+    self.owner_key.cleanup();
+    self.spender.cleanup();
+    self.w.cleanup();
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(do_blocking(context, || -> Result<Var, &str> {
+      let (node, timeout, token) = self.w.tu.context()?;
+      let spender = resolve_address(&node, self.spender.get(), timeout)?;
+      let owner = match caller_from_var(&node, &self.owner_key.get(), timeout)? {
+        Caller::PrivateKey(key) => key,
+        Caller::PublicKey(_) => return Err("OwnerKey must be a secret key"),
+      };
+      let deadline = self.deadline.get();
+      let deadline = if deadline.is_none() {
+        U256::max_value()
+      } else {
+        let secs: i64 = (&deadline).try_into()?;
+        u64::try_from(secs)
+          .or_else(|_| Err("Deadline can't be negative"))?
+          .into()
+      };
+      let args = RUNTIME.block_on(async {
+        let value = amount(&node, token, input, timeout).await?;
+        sign_permit(&node, token, &owner, spender, value, deadline, timeout).await
+      })?;
+      self.w.send(&node, token, "permit", &args, timeout)
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::hash;
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::receipt;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::ACCOUNT;
+  use crate::blocks::mock::CONTRACT;
+  use crate::blocks::mock::KEY;
+  use jsonrpc_core::serde_json::json;
+  use jsonrpc_core::types::Value;
+  use web3::signing::recover;
+
+  const TIMEOUT: Duration = Duration::from_secs(5);
+
+  fn answer(tokens: &[Token]) -> Value {
+    json!(format!("0x{}", hex::encode(ethabi::encode(tokens))))
+  }
+
+  fn token() -> Address {
+    CONTRACT.parse().unwrap()
+  }
+
+  fn token_call(method: &str, args: &[Token]) -> Value {
+    let data = ABI.function(method).unwrap().encode_input(args).unwrap();
+    let to = format!("0x{}", CONTRACT);
+    json!([{ "to": to, "data": format!("0x{}", hex::encode(data)) }, "latest"])
+  }
+
+  #[test]
+  fn asks_decimals_once_per_token() {
+    let node = MockNode::start();
+    node.expect("eth_call", answer(&[Token::Uint(6.into())]));
+    let node_data = node.node();
+    let first = RUNTIME.block_on(decimals(&node_data, token(), TIMEOUT));
+    let second = RUNTIME.block_on(decimals(&node_data, token(), TIMEOUT));
+    assert_eq!((first, second), (Ok(6), Ok(6)));
+    assert_eq!(node.count("eth_call"), 1);
+
+    node.expect("eth_call", answer(&[Token::Uint(78.into())]));
+    let other = ACCOUNT.parse().unwrap();
+    assert_eq!(
+      RUNTIME.block_on(decimals(&node_data, other, TIMEOUT)),
+      Err("Token decimals are out of range")
+    );
+  }
+
+  #[test]
+  fn reads_bytes32_symbols() {
+    let node = MockNode::start();
+    node.expect("eth_call", answer(&[Token::String("DAI".into())]));
+    let node_data = node.node();
+    assert_eq!(
+      RUNTIME.block_on(symbol(&node_data, token(), TIMEOUT)),
+      Ok("DAI".to_string())
+    );
+
+    // MKR and a few other old tokens
+    let mut mkr = b"MKR".to_vec();
+    mkr.resize(32, 0);
+    node.expect("eth_call", answer(&[Token::FixedBytes(mkr)]));
+    assert_eq!(
+      RUNTIME.block_on(symbol(&node_data, token(), TIMEOUT)),
+      Ok("MKR".to_string())
+    );
+  }
+
+  #[test]
+  fn accepts_tokens_returning_nothing() {
+    let node = MockNode::start();
+    let node_data = node.node();
+    let from = ACCOUNT.parse().unwrap();
+    let args = [Token::Address(from), Token::Uint(1.into())];
+    let check = || {
+      RUNTIME.block_on(check_success(
+        &node_data,
+        token(),
+        from,
+        "transfer",
+        &args,
+        TIMEOUT,
+      ))
+    };
+
+    node.expect("eth_call", json!("0x"));
+    assert_eq!(check(), Ok(()));
+    node.expect("eth_call", answer(&[Token::Bool(true)]));
+    assert_eq!(check(), Ok(()));
+    node.expect("eth_call", answer(&[Token::Bool(false)]));
+    assert_eq!(check(), Err("Token call returned false"));
+    // simulated from the sender, or allowances and balances would not apply
+    let call = &node.requests("eth_call")[0];
+    assert_eq!(call.params[0]["from"], format!("0x{}", ACCOUNT));
+  }
+
+  #[test]
+  fn signs_permits_for_the_owner() {
+    let node = MockNode::start();
+    let domain = H256::repeat_byte(0x42);
+    let owner: Address = ACCOUNT.parse().unwrap();
+    node.expect_params(
+      "eth_call",
+      token_call("DOMAIN_SEPARATOR", &[]),
+      answer(&[Token::FixedBytes(domain.as_bytes().to_vec())]),
+    );
+    node.expect_params(
+      "eth_call",
+      token_call("nonces", &[Token::Address(owner)]),
+      answer(&[Token::Uint(7.into())]),
+    );
+    let node_data = node.node();
+    let key: SecretKey = KEY.parse().unwrap();
+    let spender = CONTRACT.parse().unwrap();
+
+    let args = RUNTIME
+      .block_on(sign_permit(
+        &node_data,
+        token(),
+        &key,
+        spender,
+        100.into(),
+        U256::max_value(),
+        TIMEOUT,
+      ))
+      .unwrap();
+    assert_eq!(args[0], Token::Address(owner));
+    let (v, r, s) = match (&args[4], &args[5], &args[6]) {
+      (Token::Uint(v), Token::FixedBytes(r), Token::FixedBytes(s)) => (v.as_u64(), r, s),
+      _ => panic!("not a signature"),
+    };
+    assert!(v == 27 || v == 28);
+
+    // what the token will recover with the nonce it gave
+    let digest = permit_digest(
+      domain,
+      owner,
+      spender,
+      100.into(),
+      7.into(),
+      U256::max_value(),
+    );
+    let signature = [&r[..], &s[..]].concat();
+    let recovered = recover(digest.as_bytes(), &signature, (v - 27) as i32);
+    assert_eq!(recovered.unwrap(), owner);
+  }
+
+  #[test]
+  fn sends_transfers_to_tokens_returning_nothing() {
+    let node = MockNode::start();
+    // USDT answers the simulation with no data
+    node.expect("eth_call", json!("0x"));
+    node.expect("eth_getTransactionCount", quantity(3));
+    node.expect("eth_gasPrice", quantity(1_000_000_000));
+    node.expect("eth_sendRawTransaction", json!(hash(0xaa)));
+    node.expect("eth_getTransactionReceipt", receipt(&hash(0xaa), 2));
+    let node_data = node.node();
+    let key: SecretKey = KEY.parse().unwrap();
+    let args = [
+      Token::Address(ACCOUNT.parse().unwrap()),
+      Token::Uint(5.into()),
+    ];
+
+    let receipt = RUNTIME
+      .block_on(send_checked(
+        &node_data,
+        token(),
+        "transfer",
+        &args,
+        Caller::PrivateKey(key),
+        0,
+        Options::default(),
+        TIMEOUT,
+      ))
+      .unwrap();
+    assert_eq!(format!("{:?}", receipt.transaction_hash), hash(0xaa));
+    assert_eq!(node.count("eth_sendRawTransaction"), 1);
+  }
+
+  #[test]
+  fn does_not_send_failing_writes() {
+    let node = MockNode::start();
+    node.expect("eth_call", answer(&[Token::Bool(false)]));
+    let node_data = node.node();
+    let args = [
+      Token::Address(ACCOUNT.parse().unwrap()),
+      Token::Uint(5.into()),
+    ];
+
+    let sent = RUNTIME.block_on(send_checked(
+      &node_data,
+      token(),
+      "approve",
+      &args,
+      Caller::PublicKey(ACCOUNT.parse().unwrap()),
+      0,
+      Options::default(),
+      TIMEOUT,
+    ));
+    assert_eq!(sent.err(), Some("Token call returned false"));
+    assert_eq!(node.count("eth_sendTransaction"), 0);
+  }
+}
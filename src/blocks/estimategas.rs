@@ -1,13 +1,23 @@
-use crate::blocks::get_timeout;
+use crate::blocks::address::parse_address;
+use crate::blocks::ens::input_names;
+use crate::blocks::ens::is_name;
+use crate::blocks::ens::resolve_name;
+use crate::blocks::ens::resolve_names;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::retry::RetryPolicy;
+use crate::blocks::shared_from_var;
 use crate::blocks::tokens::gather_inputs;
 use crate::blocks::tokens::var_to_tokens;
 use crate::blocks::ContractUser;
-use crate::blocks::{ContractData, EthData, NodeData};
-use crate::blocks::{CONTRACT_TYPE, CONTRACT_VAR, NODE_TYPE};
+use crate::blocks::EthData;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
+use crate::blocks::{CONTRACT_TYPE, CONTRACT_VAR};
 use chainblocks::block::Block;
 use chainblocks::cblog;
 use chainblocks::core::do_blocking;
-use chainblocks::core::log;
 use chainblocks::cstr;
 use chainblocks::types::common_type;
 use chainblocks::types::Context;
@@ -22,14 +32,13 @@ use std::convert::TryInto;
 use std::ffi::CString;
 use std::str;
 use std::time::Duration;
-use tokio::time::timeout;
 use web3::contract::Error;
 use web3::contract::Options;
 use web3::types::{Address, U256};
 
 pub struct EstimateGas {
   cu: ContractUser,
-  timeout: Duration,
+  timeout: ParamVar,
   output: ClonedVar,
 }
 
@@ -51,10 +60,23 @@ lazy_static! {
       .into(),
     (
       cstr!("From"),
-      cstr!("The address we are calling from."),
+      cstr!("The address we are calling from, or an ENS name."),
       vec![common_type::string, common_type::string_var],
     )
-      .into()
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
   ];
 }
 
@@ -73,7 +95,7 @@ impl Default for EstimateGas {
         node: None,
         requiring: Vec::new(),
       },
-      timeout: get_timeout(),
+      timeout: ParamVar::new(().into()),
       output: ClonedVar(Var::default()),
     }
   }
@@ -84,29 +106,22 @@ impl EstimateGas {
     data: &EthData,
     input: &Var,
     timeout_: Duration,
+    retry: &RetryPolicy,
   ) -> Result<U256, &'a str> {
     let method = data.method.to_str().or_else(|_| Err("Invalid string"))?;
-    let tokens = var_to_tokens(input, &data.input_types)?;
-    let contract_a = data
-      .contract
-      .as_ref()
-      .ok_or_else(|| "Failed to unwrap contract data shared pointer")?;
-    let contract_o = &**contract_a;
-    let contract = contract_o
-      .as_ref()
-      .ok_or_else(|| "Failed to unwrap contract data, was empty")?;
+    let contract = get_shared(&data.contract)?;
+    let names = input_names(input, &data.input_types);
+    let names = resolve_names(&contract.node, names, timeout_).await?;
+    let tokens = var_to_tokens(input, &data.input_types, &names)?;
 
     let from: Address = {
       if let Some(from_str) = &data.from {
         let s = from_str.to_str().or_else(|_| Err("Invalid string"))?;
         if s.len() > 0 {
-          if s.starts_with("0x") {
-            let subs: &str = &s[2..];
-            subs
-              .parse()
-              .or_else(|_| Err("Failed to parse From address"))?
+          if is_name(s) {
+            resolve_name(&contract.node, s, timeout_).await?
           } else {
-            s.parse().or_else(|_| Err("Failed to parse From address"))?
+            parse_address(s, "Failed to parse From address")?
           }
         } else {
           return Err("EstimateGas requires a From address");
@@ -116,14 +131,15 @@ impl EstimateGas {
       }
     };
 
-    let fut = contract.contract.estimate_gas(
-      method,
-      // notice as_slice is necessary to make the "into" jigsaw fall into pieces
-      tokens.as_slice(),
-      from,
-      Options::default(),
-    );
-    let timed_fut = timeout(timeout_, fut);
+    let timed_fut = retry.timeout(timeout_, || {
+      contract.contract.estimate_gas(
+        method,
+        // notice as_slice is necessary to make the "into" jigsaw fall into pieces
+        tokens.as_slice(),
+        from,
+        Options::default(),
+      )
+    });
     let result: Result<U256, Error> = timed_fut.await.or_else(|_| Err("RPC request timed out"))?;
     result.or_else(|e| {
       cblog!("query error: {}", e);
@@ -160,6 +176,7 @@ impl Block for EstimateGas {
       0 => self.cu.instance.set_param(value),
       1 => self.cu.data.method = value.try_into().unwrap_or(CString::new("").unwrap()),
       2 => self.cu.from.set_param(value),
+      3 => self.timeout.set_param(value),
       _ => unreachable!(),
     }
   }
@@ -169,6 +186,7 @@ impl Block for EstimateGas {
       0 => self.cu.instance.get_param(),
       1 => self.cu.data.method.as_ref().into(),
       2 => self.cu.from.get_param(),
+      3 => self.timeout.get_param(),
       _ => Var::default(),
     }
   }
@@ -192,11 +210,13 @@ impl Block for EstimateGas {
 
     self.cu.instance.warmup(context);
     self.cu.from.warmup(context);
+    self.timeout.warmup(context);
 
     Ok(())
   }
 
   fn cleanup(&mut self) {
+    self.timeout.cleanup();
     self.cu.instance.cleanup();
     self.cu.from.cleanup();
     self.cu.node = None;
@@ -205,13 +225,10 @@ impl Block for EstimateGas {
 
   fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
     if self.cu.data.contract.is_none() {
-      self.cu.data.contract = Some(Var::from_object_as_clone::<Option<ContractData>>(
-        self.cu.instance.get(),
-        &CONTRACT_TYPE,
-      )?);
+      self.cu.data.contract = Some(shared_from_var(self.cu.instance.get(), &CONTRACT_TYPE)?);
 
       self.cu.data.from = (&self.cu.from.get()).try_into()?;
-      let contract = Var::get_mut_from_clone(&self.cu.data.contract)?;
+      let contract = get_shared(&self.cu.data.contract)?;
       // also init input_types from jsonF
       let method = self
         .cu
@@ -221,18 +238,17 @@ impl Block for EstimateGas {
         .or_else(|_| Err("Invalid string"))?;
       self.cu.data.input_types = gather_inputs(method, &contract.json_abi)?;
       // grab node from contract as well
-      self.cu.node = Some(Var::from_object_as_clone::<Option<NodeData>>(
-        contract.node,
-        &NODE_TYPE,
-      )?);
+      self.cu.node = Some(contract.node.clone());
     }
 
     Ok(do_blocking(context, || -> Result<Var, &str> {
-      let node = Var::get_mut_from_clone(&self.cu.node)?;
-      let res = node.scheduler.block_on(EstimateGas::activate_async(
+      let node = get_shared(&self.cu.node)?;
+      let timeout = get_block_timeout(&self.timeout, node)?;
+      let res = RUNTIME.block_on(EstimateGas::activate_async(
         &mut self.cu.data,
         input,
-        self.timeout,
+        timeout,
+        &node.retry,
       ))?;
       let ubits: [u8; 32] = res.into();
       let sbits = &ubits[..];
@@ -241,3 +257,44 @@ impl Block for EstimateGas {
     }))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::ACCOUNT;
+
+  fn estimate(data: &EthData) -> Result<U256, &'static str> {
+    let retry = RetryPolicy::default();
+    let input = Var::default();
+    RUNTIME.block_on(EstimateGas::activate_async(
+      data,
+      &input,
+      Duration::from_secs(5),
+      &retry,
+    ))
+  }
+
+  #[test]
+  fn estimates_from_the_caller() {
+    let node = MockNode::start();
+    node.expect("eth_estimateGas", quantity(26000));
+    let data = node.eth_data("poke");
+    assert_eq!(estimate(&data).unwrap(), 26000.into());
+    let request = &node.requests("eth_estimateGas")[0];
+    assert_eq!(request.params[0]["from"], format!("0x{}", ACCOUNT));
+  }
+
+  #[test]
+  fn requires_a_caller() {
+    let node = MockNode::start();
+    let mut data = node.eth_data("poke");
+    data.from = None;
+    assert_eq!(
+      estimate(&data).err(),
+      Some("EstimateGas requires a From address")
+    );
+    assert_eq!(node.count("eth_estimateGas"), 0);
+  }
+}
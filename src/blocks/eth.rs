@@ -1,40 +1,87 @@
+use crate::blocks::auth::redact_url;
+use crate::blocks::auth::Auth;
+use crate::blocks::cache::Cache;
+use crate::blocks::ens::default_registry;
+use crate::blocks::ens::EnsCache;
+use crate::blocks::erc20::DecimalsCache;
+use crate::blocks::get_address;
+use crate::blocks::get_timeout;
+use crate::blocks::limiter::Limiter;
+use crate::blocks::log;
+use crate::blocks::metrics;
+use crate::blocks::metrics::Metrics;
+use crate::blocks::multi::Connection;
+use crate::blocks::multi::MultiTransport;
+use crate::blocks::multi::Strategy;
+use crate::blocks::retry::RetryPolicy;
+use crate::blocks::timeout_from_var;
+use crate::blocks::write::Nonces;
 use crate::blocks::NodeData;
-use crate::blocks::Transport;
+use crate::blocks::Shared;
 use crate::blocks::NODE_TYPE;
+use crate::blocks::RUNTIME;
 use chainblocks::block::Block;
+use chainblocks::cblog;
 use chainblocks::core::do_blocking;
 use chainblocks::cstr;
 use chainblocks::types::common_type;
+use chainblocks::types::ClonedVar;
 use chainblocks::types::Context;
 use chainblocks::types::ExposedInfo;
 use chainblocks::types::ExposedTypes;
 use chainblocks::types::ParamVar;
 use chainblocks::types::Parameters;
+use chainblocks::types::Seq;
+use chainblocks::types::Table;
 use chainblocks::types::Type;
 use chainblocks::types::Types;
 use chainblocks::types::Var;
 use std::convert::TryInto;
+use std::ffi::CStr;
 use std::ffi::CString;
-use std::rc::Rc;
 use std::str;
-use tokio::runtime;
+use std::sync::Arc;
+use std::time::Duration;
+use web3::types::Address;
 
 pub struct Eth {
   exposing: ExposedTypes,
-  node_url: CString,
-  node: Rc<Option<NodeData>>,
+  node_urls: ClonedVar,
+  strategy: CString,
+  quorum: i64,
+  max_lag: i64,
+  max_attempts: i64,
+  backoff: f64,
+  jitter: f64,
+  retry_codes: ClonedVar,
+  retry_statuses: ClonedVar,
+  headers: ClonedVar,
+  jwt_secret: ClonedVar,
+  expected_chain_id: i64,
+  timeout: ClonedVar,
+  cache_size: i64,
+  cache_file: ClonedVar,
+  rate_limit: f64,
+  burst: i64,
+  max_concurrent: i64,
+  metrics_address: ClonedVar,
+  ens_registry: ClonedVar,
+  node: Shared<NodeData>,
   instance: ParamVar,
   instance_name: CString,
   init_done: bool,
 }
 
+static HEADERS_TYPES: &'static [Type] = &[common_type::string];
+static HEADERS_TYPE: Type = Type::table(&[], HEADERS_TYPES);
+
 lazy_static! {
   static ref INOUT_TYPES: Vec<Type> = vec![common_type::any];
   static ref PARAMETERS: Parameters = vec![
     (
       cstr!("Url"),
-      cstr!("The http/https/ws/wss address to the ethereum node."),
-      vec![common_type::string],
+      cstr!("The http/https/ws/wss address or IPC socket path (or ipc:// url) of the ethereum node, or a list of addresses to spread requests over. record://<fixture>#<url> saves the traffic with url to a fixture file, replay://<fixture> answers from it offline."),
+      vec![common_type::string, common_type::strings],
     )
       .into(),
     (
@@ -43,16 +90,150 @@ lazy_static! {
       vec![common_type::string],
     )
       .into(),
+    (
+      cstr!("Strategy"),
+      cstr!("How requests are spread when multiple Url are given. (avail: failover, round-robin, quorum)"),
+      vec![common_type::string],
+    )
+      .into(),
+    (
+      cstr!("Quorum"),
+      cstr!("The amount of matching answers required for reads when Strategy is quorum."),
+      vec![common_type::int],
+    )
+      .into(),
+    (
+      cstr!("MaxLag"),
+      cstr!("The amount of blocks an address can fall behind the others before being skipped, 0 disables the check."),
+      vec![common_type::int],
+    )
+      .into(),
+    (
+      cstr!("MaxAttempts"),
+      cstr!("The maximum amount of attempts for read requests failing for transient reasons."),
+      vec![common_type::int],
+    )
+      .into(),
+    (
+      cstr!("Backoff"),
+      cstr!("The delay in seconds before the first retry, doubling on every further attempt."),
+      vec![common_type::float],
+    )
+      .into(),
+    (
+      cstr!("Jitter"),
+      cstr!("The random fraction (0.0 to 1.0) of the retry delay added or removed."),
+      vec![common_type::float],
+    )
+      .into(),
+    (
+      cstr!("RetryCodes"),
+      cstr!("The JSON-RPC error codes worth a retry. (default: -32005, -32603)"),
+      vec![common_type::none, common_type::ints],
+    )
+      .into(),
+    (
+      cstr!("RetryStatuses"),
+      cstr!("The HTTP statuses worth a retry. (default: 429, 502, 503)"),
+      vec![common_type::none, common_type::ints],
+    )
+      .into(),
+    (
+      cstr!("Headers"),
+      cstr!("Extra headers to send with every request and WebSocket handshake, e.g. Authorization. Write only."),
+      vec![common_type::none, HEADERS_TYPE],
+    )
+      .into(),
+    (
+      cstr!("JwtSecret"),
+      cstr!("The path to a hex encoded secret used to sign a JWT for every request, as the engine API requires."),
+      vec![common_type::none, common_type::path, common_type::string],
+    )
+      .into(),
+    (
+      cstr!("ExpectedChainId"),
+      cstr!("The chain id the node must be on, activation fails otherwise. 0 disables the check."),
+      vec![common_type::int],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!("The default timeout in seconds of every request attempt, none to use WEB3_TIMEOUT (or 30)."),
+      vec![common_type::none, common_type::int, common_type::float],
+    )
+      .into(),
+    (
+      cstr!("CacheSize"),
+      cstr!("The amount of answers to queries at a fixed block or of mined transactions to keep in memory, 0 disables the cache."),
+      vec![common_type::int],
+    )
+      .into(),
+    (
+      cstr!("CacheFile"),
+      cstr!("The file where cached answers are persisted between runs, requires CacheSize."),
+      vec![common_type::none, common_type::path, common_type::string],
+    )
+      .into(),
+    (
+      cstr!("RateLimit"),
+      cstr!("The maximum amount of requests per second sent to the node by all the blocks using it, 0 disables the limit."),
+      vec![common_type::float],
+    )
+      .into(),
+    (
+      cstr!("Burst"),
+      cstr!("The amount of requests that can be sent at once before RateLimit kicks in, 0 to allow one second worth of requests."),
+      vec![common_type::int],
+    )
+      .into(),
+    (
+      cstr!("MaxConcurrent"),
+      cstr!("The maximum amount of requests in flight at the same time, 0 means no limit."),
+      vec![common_type::int],
+    )
+      .into(),
+    (
+      cstr!("MetricsAddress"),
+      cstr!("The local address (e.g. 127.0.0.1:9100) where the RPC metrics of this node are served in the Prometheus text format, none to disable."),
+      vec![common_type::none, common_type::string],
+    )
+      .into(),
+    (
+      cstr!("EnsRegistry"),
+      cstr!("The ENS registry used to resolve names, none for the one of mainnet, which its testnets share. Resolving names fails on other chains unless it's set."),
+      vec![common_type::none, common_type::string, common_type::bytes],
+    )
+      .into(),
   ];
 }
 
 impl Default for Eth {
   // add code here
   fn default() -> Self {
+    let url: Var = cstr!("https://cloudflare-eth.com").into();
     Eth {
       exposing: Vec::new(),
-      node_url: CString::new("https://cloudflare-eth.com").unwrap(),
-      node: Rc::new(None),
+      node_urls: (&url).into(),
+      strategy: CString::new("failover").unwrap(),
+      quorum: 2,
+      max_lag: 0,
+      max_attempts: 3,
+      backoff: 0.25,
+      jitter: 0.2,
+      retry_codes: ClonedVar(Var::default()),
+      retry_statuses: ClonedVar(Var::default()),
+      headers: ClonedVar(Var::default()),
+      jwt_secret: ClonedVar(Var::default()),
+      expected_chain_id: 0,
+      timeout: ClonedVar(Var::default()),
+      cache_size: 0,
+      cache_file: ClonedVar(Var::default()),
+      rate_limit: 0.0,
+      burst: 0,
+      max_concurrent: 0,
+      metrics_address: ClonedVar(Var::default()),
+      ens_registry: ClonedVar(Var::default()),
+      node: Shared::default(),
       instance: ParamVar::new(().into()),
       instance_name: CString::new("default.Eth").unwrap(),
       init_done: false,
@@ -85,16 +266,59 @@ impl Block for Eth {
 
   fn setParam(&mut self, index: i32, value: &Var) {
     match index {
-      0 => self.node_url = value.try_into().unwrap_or(CString::new("").unwrap()),
+      0 => self.node_urls = value.into(),
       1 => self.instance_name = value.try_into().unwrap_or(CString::new("").unwrap()),
+      2 => {
+        self.strategy = value
+          .try_into()
+          .unwrap_or(CString::new("failover").unwrap())
+      }
+      3 => self.quorum = value.try_into().unwrap_or(2),
+      4 => self.max_lag = value.try_into().unwrap_or(0),
+      5 => self.max_attempts = value.try_into().unwrap_or(3),
+      6 => self.backoff = value.try_into().unwrap_or(0.25),
+      7 => self.jitter = value.try_into().unwrap_or(0.2),
+      8 => self.retry_codes = value.into(),
+      9 => self.retry_statuses = value.into(),
+      10 => self.headers = value.into(),
+      11 => self.jwt_secret = value.into(),
+      12 => self.expected_chain_id = value.try_into().unwrap_or(0),
+      13 => self.timeout = value.into(),
+      14 => self.cache_size = value.try_into().unwrap_or(0),
+      15 => self.cache_file = value.into(),
+      16 => self.rate_limit = value.try_into().unwrap_or(0.0),
+      17 => self.burst = value.try_into().unwrap_or(0),
+      18 => self.max_concurrent = value.try_into().unwrap_or(0),
+      19 => self.metrics_address = value.into(),
+      20 => self.ens_registry = value.into(),
       _ => unreachable!(),
     }
   }
 
   fn getParam(&mut self, index: i32) -> Var {
     match index {
-      0 => self.node_url.as_ref().into(),
+      0 => self.node_urls.0,
       1 => self.instance_name.as_ref().into(),
+      2 => self.strategy.as_ref().into(),
+      3 => self.quorum.into(),
+      4 => self.max_lag.into(),
+      5 => self.max_attempts.into(),
+      6 => self.backoff.into(),
+      7 => self.jitter.into(),
+      8 => self.retry_codes.0,
+      9 => self.retry_statuses.0,
+      // headers usually carry keys, never hand them back
+      10 => Var::default(),
+      11 => self.jwt_secret.0,
+      12 => self.expected_chain_id.into(),
+      13 => self.timeout.0,
+      14 => self.cache_size.into(),
+      15 => self.cache_file.0,
+      16 => self.rate_limit.into(),
+      17 => self.burst.into(),
+      18 => self.max_concurrent.into(),
+      19 => self.metrics_address.0,
+      20 => self.ens_registry.0,
       _ => Var::default(),
     }
   }
@@ -120,56 +344,331 @@ impl Block for Eth {
   fn cleanup(&mut self) {
     self.instance.cleanup();
     self.init_done = false;
-    self.node = Rc::new(None);
+    self.node.set(None);
   }
 
   fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
     if !self.init_done {
       Ok(do_blocking(context, || -> Result<Var, &str> {
-        let uri = self.node_url.to_str().or_else(|_| Err("Invalid string"))?;
-        let scheduler = runtime::Builder::new_current_thread()
-          .enable_all()
-          .build()
-          .expect("Failed to create tokio runtime!");
-
-        if let Ok(transport) = scheduler
-          .block_on(web3::transports::WebSocket::new(uri))
-          .or_else(|_| Err("WebSocket creation failed"))
-        {
-          let et: Transport = web3::transports::Either::Right(transport);
-          let web3 = web3::Web3::new(et);
-
-          let node_data = NodeData {
-            web3: web3,
-            scheduler: scheduler,
-          };
-
-          // commit what we created into the shared data
-          self.node = Rc::new(Some(node_data));
-          self.instance.set(Var::new_object(&self.node, &NODE_TYPE));
-
-          return Ok(*input);
-        }
-        if let Ok(transport) = web3::transports::Http::new(uri) {
-          let et: Transport = web3::transports::Either::Left(transport);
-          let web3 = web3::Web3::new(et);
-
-          let node_data = NodeData {
-            web3: web3,
-            scheduler: scheduler,
-          };
-
-          // commit what we created into the shared data
-          self.node = Rc::new(Some(node_data));
-          self.instance.set(Var::new_object(&self.node, &NODE_TYPE));
-          self.init_done = true;
-          Ok(*input)
-        } else {
-          Err("Failed to open remote node")
-        }
+        let config = self.config()?;
+        let node_data = RUNTIME.block_on(connect(config))?;
+
+        // commit what we created into the shared data
+        self.node.set(Some(node_data));
+        self.instance.set(self.node.var(&NODE_TYPE));
+        self.init_done = true;
+        Ok(*input)
       }))
     } else {
       Ok(*input)
     }
   }
 }
+
+impl Eth {
+  fn retry_policy(&self) -> Result<RetryPolicy, &str> {
+    let mut policy = RetryPolicy::default();
+    policy.max_attempts = self.max_attempts.max(1) as u32;
+    policy.backoff = Duration::from_secs_f64(self.backoff.max(0.0));
+    policy.jitter = self.jitter;
+    if !self.retry_codes.0.is_none() {
+      let codes: Seq = self.retry_codes.0.as_ref().try_into()?;
+      policy.rpc_codes.clear();
+      for code in codes.iter() {
+        policy.rpc_codes.push(code.as_ref().try_into()?);
+      }
+    }
+    if !self.retry_statuses.0.is_none() {
+      let statuses: Seq = self.retry_statuses.0.as_ref().try_into()?;
+      policy.http_statuses.clear();
+      for status in statuses.iter() {
+        let status: i64 = status.as_ref().try_into()?;
+        policy.http_statuses.push(status as u16);
+      }
+    }
+    Ok(policy)
+  }
+
+  fn auth(&self) -> Result<Auth, &str> {
+    let mut headers = Vec::new();
+    if !self.headers.0.is_none() {
+      let table: Table = self.headers.0.as_ref().try_into()?;
+      for (name, value) in table.iter() {
+        let name = unsafe { CStr::from_ptr(name.0) }
+          .to_str()
+          .or_else(|_| Err("Invalid header name"))?;
+        let value: &str = value.as_ref().try_into()?;
+        headers.push((name.to_owned(), value.to_owned()));
+      }
+    }
+    let jwt_secret = if self.jwt_secret.0.is_none() {
+      None
+    } else {
+      let path: &str = self.jwt_secret.0.as_ref().try_into()?;
+      Some(Auth::load_jwt_secret(path)?)
+    };
+    Ok(Auth::new(headers, jwt_secret))
+  }
+
+  fn config(&self) -> Result<NodeConfig, &str> {
+    let mut config = NodeConfig::new(self.urls()?);
+    config.strategy = Strategy::parse(
+      self.strategy.to_str().or_else(|_| Err("Invalid string"))?,
+      self.quorum.max(1) as usize,
+    )?;
+    config.max_lag = self.max_lag.max(0) as u64;
+    config.retry = self.retry_policy()?;
+    config.auth = self.auth()?;
+    config.expected_chain_id = self.expected_chain_id.max(0) as u64;
+    config.timeout = timeout_from_var(&self.timeout.0)?.unwrap_or_else(get_timeout);
+    config.cache_size = self.cache_size.max(0) as usize;
+    if !self.cache_file.0.is_none() {
+      let path: &str = self.cache_file.0.as_ref().try_into()?;
+      config.cache_file = Some(path.to_owned());
+    }
+    config.limiter = Limiter::new(
+      self.rate_limit,
+      self.burst.max(0) as u32,
+      self.max_concurrent.max(0) as u32,
+    );
+    if !self.metrics_address.0.is_none() {
+      let address: &str = self.metrics_address.0.as_ref().try_into()?;
+      config.metrics_address = Some(address.to_owned());
+    }
+    if !self.ens_registry.0.is_none() {
+      config.ens_registry = Some(get_address(self.ens_registry.0)?);
+    }
+    config.name = self
+      .instance_name
+      .to_str()
+      .or_else(|_| Err("Invalid string"))?
+      .to_owned();
+    Ok(config)
+  }
+
+  fn urls(&self) -> Result<Vec<String>, &str> {
+    let value = self.node_urls.0;
+    if value.is_seq() {
+      let seq: Seq = value.as_ref().try_into()?;
+      let mut urls = Vec::new();
+      for url in seq.iter() {
+        let url: &str = url.as_ref().try_into()?;
+        urls.push(url.to_owned());
+      }
+      if urls.is_empty() {
+        Err("Url list is empty")
+      } else {
+        Ok(urls)
+      }
+    } else {
+      let url: &str = value.as_ref().try_into()?;
+      Ok(vec![url.to_owned()])
+    }
+  }
+}
+
+/// Everything needed to open a node, Eth fills it from its parameters.
+pub struct NodeConfig {
+  pub urls: Vec<String>,
+  pub strategy: Strategy,
+  pub max_lag: u64,
+  pub retry: RetryPolicy,
+  pub auth: Auth,
+  pub expected_chain_id: u64,
+  pub timeout: Duration,
+  pub cache_size: usize,
+  pub cache_file: Option<String>,
+  pub limiter: Limiter,
+  pub metrics_address: Option<String>,
+  // None for the one deployed on mainnet and its testnets
+  pub ens_registry: Option<Address>,
+  // the node label of exported metrics
+  pub name: String,
+}
+
+impl NodeConfig {
+  pub fn new(urls: Vec<String>) -> NodeConfig {
+    NodeConfig {
+      urls,
+      strategy: Strategy::Failover,
+      max_lag: 0,
+      retry: RetryPolicy::default(),
+      auth: Auth::default(),
+      expected_chain_id: 0,
+      timeout: get_timeout(),
+      cache_size: 0,
+      cache_file: None,
+      limiter: Limiter::new(0.0, 0, 0),
+      metrics_address: None,
+      ens_registry: None,
+      name: "default.Eth".to_owned(),
+    }
+  }
+}
+
+/// Opens every endpoint and makes sure we are talking to the network we think we are.
+pub async fn connect(config: NodeConfig) -> Result<NodeData, &'static str> {
+  let auth = Arc::new(config.auth);
+
+  // endpoints down at startup are skipped, as long as one is left
+  let mut endpoints = Vec::new();
+  for url in config.urls {
+    match Connection::open(&url, auth.clone()).await {
+      Ok(connection) => endpoints.push((url, connection)),
+      Err(e) => cblog!("Failed to open remote node {}: {}", redact_url(&url), e),
+    }
+  }
+  if endpoints.is_empty() {
+    return Err("Failed to open remote node");
+  }
+
+  let metrics = Arc::new(Metrics::default());
+  let transport = MultiTransport::new(
+    endpoints,
+    config.strategy,
+    config.max_lag,
+    config.timeout,
+    config.limiter,
+    metrics.clone(),
+  )?;
+  let web3 = web3::Web3::new(transport);
+
+  let chain_id = config
+    .retry
+    .timeout(config.timeout, || web3.eth().chain_id())
+    .await
+    .or_else(|_| Err("Timed out"))?
+    .or_else(|e| {
+      cblog!("Failed to query chain id: {}", e);
+      Err("Failed to query chain id")
+    })?
+    .as_u64();
+  if config.expected_chain_id > 0 && chain_id != config.expected_chain_id {
+    cblog!(
+      "Eth node is on chain {} but {} was expected",
+      chain_id,
+      config.expected_chain_id
+    );
+    return Err("Node is on an unexpected chain");
+  }
+
+  // attached only now, a persisted cache must match the chain
+  let cache = if config.cache_size > 0 {
+    let cache = Cache::new(config.cache_size, config.cache_file.as_deref(), chain_id)?;
+    Some(Arc::new(cache))
+  } else {
+    None
+  };
+  if let Some(cache) = &cache {
+    web3.transport().set_cache(cache.clone());
+  }
+
+  if let Some(address) = &config.metrics_address {
+    metrics::export(address, &config.name, &metrics, cache.as_ref())?;
+  }
+
+  Ok(NodeData {
+    web3,
+    retry: config.retry,
+    chain_id,
+    timeout: config.timeout,
+    cache,
+    metrics,
+    ens: EnsCache::default(),
+    ens_registry: config.ens_registry.or_else(|| default_registry(chain_id)),
+    decimals: DecimalsCache::default(),
+    nonces: Nonces::default(),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::CHAIN_ID;
+  use std::io::Read;
+  use std::io::Write;
+  use std::net::TcpListener;
+  use std::net::TcpStream;
+  use web3::types::BlockNumber;
+
+  fn open(config: NodeConfig) -> Result<NodeData, &'static str> {
+    RUNTIME.block_on(connect(config))
+  }
+
+  #[test]
+  fn connects_and_reads_the_chain_id() {
+    let node = MockNode::start();
+    let mut config = NodeConfig::new(vec![node.http_url()]);
+    config.expected_chain_id = CHAIN_ID;
+    assert_eq!(open(config).unwrap().chain_id, CHAIN_ID);
+    assert_eq!(node.count("eth_chainId"), 1);
+  }
+
+  #[test]
+  fn refuses_unexpected_chains() {
+    let node = MockNode::start();
+    let mut config = NodeConfig::new(vec![node.http_url()]);
+    config.expected_chain_id = 1;
+    assert_eq!(open(config).err(), Some("Node is on an unexpected chain"));
+  }
+
+  #[test]
+  fn skips_endpoints_down_at_startup() {
+    let node = MockNode::start();
+    let dead = "ws://127.0.0.1:1".to_owned();
+    assert!(open(NodeConfig::new(vec![dead.clone(), node.ws_url()])).is_ok());
+    assert_eq!(
+      open(NodeConfig::new(vec![dead])).err(),
+      Some("Failed to open remote node")
+    );
+  }
+
+  #[test]
+  fn sends_configured_headers() {
+    let node = MockNode::start();
+    let mut config = NodeConfig::new(vec![node.http_url()]);
+    config.auth = Auth::new(vec![("X-Api-Key".into(), "secret".into())], None);
+    open(config).unwrap();
+    let headers = &node.requests("eth_chainId")[0].headers;
+    assert!(headers.contains(&("x-api-key".to_owned(), "secret".to_owned())));
+  }
+
+  #[test]
+  fn caches_fixed_queries_when_asked() {
+    let node = MockNode::start();
+    node.expect("eth_getBalance", quantity(5));
+    let mut config = NodeConfig::new(vec![node.http_url()]);
+    config.cache_size = 16;
+    let data = open(config).unwrap();
+    assert!(data.cache.is_some());
+
+    let block = Some(BlockNumber::Number(0x10.into()));
+    for _ in 0..2 {
+      let balance = RUNTIME.block_on(data.web3.eth().balance(Default::default(), block));
+      assert_eq!(balance.unwrap(), 5.into());
+    }
+    assert_eq!(node.count("eth_getBalance"), 1);
+  }
+
+  #[test]
+  fn exports_metrics() {
+    let node = MockNode::start();
+    let address = {
+      let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+      listener.local_addr().unwrap().to_string()
+    };
+    let mut config = NodeConfig::new(vec![node.http_url()]);
+    config.metrics_address = Some(address.clone());
+    config.name = "test.Eth".to_owned();
+    let _data = open(config).unwrap();
+
+    let mut socket = TcpStream::connect(&address).unwrap();
+    socket.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    let mut page = String::new();
+    socket.read_to_string(&mut page).unwrap();
+    assert!(page.starts_with("HTTP/1.1 200 OK"));
+    assert!(page.contains(r#"eth_rpc_requests_total{node="test.Eth",method="eth_chainId"} 1"#));
+  }
+}
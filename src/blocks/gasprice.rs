@@ -1,11 +1,15 @@
-use crate::blocks::get_timeout;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::shared_from_var;
 use crate::blocks::NodeData;
 use crate::blocks::NODE_TYPE;
 use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
 use chainblocks::block::Block;
 use chainblocks::cblog;
 use chainblocks::core::activate_blocking;
-use chainblocks::core::log;
 use chainblocks::core::BlockingBlock;
 use chainblocks::cstr;
 use chainblocks::types::common_type;
@@ -17,29 +21,57 @@ use chainblocks::types::ParamVar;
 use chainblocks::types::Parameters;
 use chainblocks::types::Type;
 use chainblocks::types::Var;
-use std::rc::Rc;
 use std::str;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::timeout;
 use web3::types::U256;
 
+/// The gas price the node suggests, in wei.
+pub async fn gas_price<'a>(node: &NodeData, timeout: Duration) -> Result<U256, &'a str> {
+  let eth = node.web3.eth();
+  node
+    .retry
+    .timeout(timeout, || eth.gas_price())
+    .await
+    .or_else(|_| Err("Timed out"))?
+    .or_else(|e| {
+      cblog!("GasPrice error: {}", e);
+      Err("Failed to fetch current gas price")
+    })
+}
+
 pub struct GasPrice {
   node_param: ParamVar,
-  node: Option<Rc<Option<NodeData>>>,
+  node: Option<Arc<NodeData>>,
   output: ClonedVar,
-  timeout: Duration,
+  timeout: ParamVar,
   requiring: ExposedTypes,
 }
 
 lazy_static! {
   static ref IN_TYPES: Vec<Type> = vec![common_type::none];
   static ref OUT_TYPES: Vec<Type> = vec![common_type::bytes];
-  static ref PARAMETERS: Parameters = vec![(
-    cstr!("Node"),
-    cstr!("The ethereum node block variable to use."),
-    vec![NODE_VAR],
-  )
-    .into(),];
+  static ref PARAMETERS: Parameters = vec![
+    (
+      cstr!("Node"),
+      cstr!("The ethereum node block variable to use."),
+      vec![NODE_VAR],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
+  ];
 }
 
 impl Default for GasPrice {
@@ -48,7 +80,7 @@ impl Default for GasPrice {
       node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
       node: None,
       output: ClonedVar(Var::default()),
-      timeout: get_timeout(),
+      timeout: ParamVar::new(().into()),
       requiring: Vec::new(),
     }
   }
@@ -80,6 +112,7 @@ impl Block for GasPrice {
   fn setParam(&mut self, index: i32, value: &Var) {
     match index {
       0 => self.node_param.set_param(value),
+      1 => self.timeout.set_param(value),
       _ => unreachable!(),
     }
   }
@@ -87,6 +120,7 @@ impl Block for GasPrice {
   fn getParam(&mut self, index: i32) -> Var {
     match index {
       0 => self.node_param.get_param(),
+      1 => self.timeout.get_param(),
       _ => unreachable!(),
     }
   }
@@ -104,9 +138,11 @@ impl Block for GasPrice {
 
   fn warmup(&mut self, context: &Context) -> Result<(), &str> {
     self.node_param.warmup(context);
+    self.timeout.warmup(context);
     Ok(())
   }
   fn cleanup(&mut self) {
+    self.timeout.cleanup();
     self.node_param.cleanup();
     self.node = None;
   }
@@ -118,32 +154,39 @@ impl Block for GasPrice {
 impl BlockingBlock for GasPrice {
   fn activate_blocking(&mut self, _: &Context, _input: &Var) -> Result<Var, &str> {
     if self.node.is_none() {
-      self.node = Some(Var::from_object_as_clone::<Option<NodeData>>(
-        self.node_param.get(),
-        &NODE_TYPE,
-      )?);
-    }
-    let node = Var::get_mut_from_clone(&self.node)?;
-    let (scheduler, eth) = (&mut node.scheduler, &mut node.web3.eth());
-    let res = scheduler
-      .block_on(async {
-        let fut = eth.gas_price();
-        let timed_fut = timeout(self.timeout, fut);
-        timed_fut.await
-      })
-      .or_else(|_| Err("Timed out"))?;
-    match res {
-      Ok(value) => {
-        let u: U256 = value.into();
-        let ubits: [u8; 32] = u.into();
-        let sbits = &ubits[..];
-        self.output = sbits.into();
-        Ok(self.output.0)
-      }
-      Err(e) => {
-        cblog!("GasPrice error: {}", e);
-        Err("Failed to fetch current gas price")
-      }
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
     }
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
+    let value = RUNTIME.block_on(gas_price(node, timeout))?;
+    let ubits: [u8; 32] = value.into();
+    let sbits = &ubits[..];
+    self.output = sbits.into();
+    Ok(self.output.0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::MockNode;
+
+  #[test]
+  fn fetches_the_gas_price() {
+    let node = MockNode::start();
+    node.expect("eth_gasPrice", quantity(0x3b9aca00));
+    let eth = node.node();
+    let price = RUNTIME.block_on(gas_price(&eth, Duration::from_secs(5)));
+    assert_eq!(price.unwrap(), U256::from(1_000_000_000u64));
+  }
+
+  #[test]
+  fn node_errors_are_reported() {
+    let node = MockNode::start();
+    node.expect_error("eth_gasPrice", -32000, "unavailable");
+    let eth = node.node();
+    let price = RUNTIME.block_on(gas_price(&eth, Duration::from_secs(5)));
+    assert_eq!(price, Err("Failed to fetch current gas price"));
   }
 }
@@ -0,0 +1,276 @@
+use crate::blocks::auth::Auth;
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use jsonrpc_core::serde_json;
+use jsonrpc_core::types::Call;
+use jsonrpc_core::types::Id;
+use jsonrpc_core::types::Output;
+use jsonrpc_core::types::Request;
+use jsonrpc_core::types::Response;
+use jsonrpc_core::types::Value;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+use web3::error::Error;
+use web3::error::TransportError;
+use web3::helpers::build_request;
+use web3::BatchTransport;
+use web3::RequestId;
+use web3::Transport;
+
+// answers are timed by the caller, a connection has to be made quickly though
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// HTTP transport adding our own headers (and JWT) to every request.
+#[derive(Clone)]
+pub struct Http {
+  client: Client,
+  url: Url,
+  auth: Arc<Auth>,
+  id: Arc<AtomicUsize>,
+}
+
+impl fmt::Debug for Http {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    // the url might contain a key, keep it out of logs
+    f.debug_struct("Http").finish()
+  }
+}
+
+impl Http {
+  pub fn new(url: &str, auth: Arc<Auth>) -> web3::Result<Http> {
+    let url = Url::parse(url).or_else(|e| Err(message_error(format!("Invalid url: {}", e))))?;
+    let client = Client::builder()
+      .connect_timeout(CONNECT_TIMEOUT)
+      .build()
+      .or_else(|e| Err(message_error(format!("Failed to build client: {}", e))))?;
+    Ok(Http {
+      client,
+      url,
+      auth,
+      id: Arc::new(AtomicUsize::new(1)),
+    })
+  }
+
+  fn post(&self, request: Request) -> BoxFuture<'static, web3::Result<Response>> {
+    let mut builder = self
+      .client
+      .post(self.url.clone())
+      .header(CONTENT_TYPE, "application/json");
+    for (name, value) in self.auth.headers() {
+      builder = builder.header(name.as_str(), value.as_str());
+    }
+    // errors must not carry the url, it might contain a key
+    async move {
+      let body = serde_json::to_vec(&request)?;
+      let response = builder.body(body).send().await.or_else(|e| {
+        Err(message_error(format!(
+          "Failed to send request: {}",
+          e.without_url()
+        )))
+      })?;
+      let status = response.status();
+      let body = response.bytes().await.or_else(|e| {
+        Err(message_error(format!(
+          "Failed to read response: {}",
+          e.without_url()
+        )))
+      })?;
+      if !status.is_success() {
+        return Err(Error::Transport(TransportError::Code(status.as_u16())));
+      }
+      serde_json::from_slice(&body).or_else(|e| {
+        Err(Error::InvalidResponse(format!(
+          "Invalid JSON-RPC response: {}",
+          e
+        )))
+      })
+    }
+    .boxed()
+  }
+}
+
+impl Transport for Http {
+  type Out = BoxFuture<'static, web3::Result<Value>>;
+
+  fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+    let id = self.id.fetch_add(1, Ordering::AcqRel);
+    (id, build_request(id, method, params))
+  }
+
+  fn send(&self, _id: RequestId, request: Call) -> Self::Out {
+    let response = self.post(Request::Single(request));
+    async move {
+      match response.await? {
+        Response::Single(output) => output_result(output),
+        Response::Batch(_) => Err(Error::InvalidResponse("Expected a single response".into())),
+      }
+    }
+    .boxed()
+  }
+}
+
+impl BatchTransport for Http {
+  type Batch = BoxFuture<'static, web3::Result<Vec<web3::Result<Value>>>>;
+
+  fn send_batch<T>(&self, requests: T) -> Self::Batch
+  where
+    T: IntoIterator<Item = (RequestId, Call)>,
+  {
+    let (ids, calls): (Vec<RequestId>, Vec<Call>) = requests.into_iter().unzip();
+    let response = self.post(Request::Batch(calls));
+    async move {
+      match response.await? {
+        Response::Batch(outputs) => Ok(batch_results(&ids, outputs)),
+        // a single answer to a batch means the whole batch was refused
+        Response::Single(output) => Err(match output_result(output) {
+          Err(e) => e,
+          Ok(_) => Error::InvalidResponse("Expected a batch response".into()),
+        }),
+      }
+    }
+    .boxed()
+  }
+}
+
+pub fn output_result(output: Output) -> web3::Result<Value> {
+  match output {
+    Output::Success(success) => Ok(success.result),
+    Output::Failure(failure) => Err(Error::Rpc(failure.error)),
+  }
+}
+
+/// Matches batch answers to the requests, nodes are free to reorder them.
+pub fn batch_results(ids: &[RequestId], outputs: Vec<Output>) -> Vec<web3::Result<Value>> {
+  let mut by_id: BTreeMap<RequestId, Output> = outputs
+    .into_iter()
+    .filter_map(|output| match output.id() {
+      Id::Num(id) => Some((*id as RequestId, output)),
+      _ => None,
+    })
+    .collect();
+  ids
+    .iter()
+    .map(|id| match by_id.remove(id) {
+      Some(output) => output_result(output),
+      None => Err(Error::InvalidResponse(format!(
+        "Missing response for request {}",
+        id
+      ))),
+    })
+    .collect()
+}
+
+fn message_error(message: String) -> Error {
+  Error::Transport(TransportError::Message(message))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::RUNTIME;
+  use jsonrpc_core::types::Failure;
+  use jsonrpc_core::types::Success;
+  use jsonrpc_core::types::Version;
+
+  fn http(node: &MockNode, auth: Auth) -> Http {
+    Http::new(&node.http_url(), Arc::new(auth)).unwrap()
+  }
+
+  fn success(id: u64, result: Value) -> Output {
+    Output::Success(Success {
+      jsonrpc: Some(Version::V2),
+      result,
+      id: Id::Num(id),
+    })
+  }
+
+  #[test]
+  fn sends_auth_headers() {
+    let node = MockNode::start();
+    node.expect("eth_gasPrice", quantity(7));
+    let auth = Auth::new(
+      vec![("X-Api-Key".into(), "secret".into())],
+      Some(vec![7; 32]),
+    );
+    let http = http(&node, auth);
+    assert_eq!(
+      RUNTIME
+        .block_on(http.execute("eth_gasPrice", vec![]))
+        .unwrap(),
+      quantity(7)
+    );
+
+    let headers = &node.requests("eth_gasPrice")[0].headers;
+    let header = |name: &str| {
+      headers
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value.clone())
+    };
+    assert_eq!(header("x-api-key").as_deref(), Some("secret"));
+    assert!(header("authorization").unwrap().starts_with("Bearer ey"));
+  }
+
+  #[test]
+  fn http_errors_keep_their_status() {
+    let node = MockNode::start();
+    node.fail_http(Some(503));
+    let http = http(&node, Auth::default());
+    match RUNTIME.block_on(http.execute("eth_gasPrice", vec![])) {
+      Err(Error::Transport(TransportError::Code(503))) => {}
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+
+  #[test]
+  fn errors_do_not_leak_the_url() {
+    // nothing listens on port 1
+    let http = Http::new(
+      "http://127.0.0.1:1/v3/secret-key",
+      Arc::new(Auth::default()),
+    )
+    .unwrap();
+    let error = RUNTIME
+      .block_on(http.execute("eth_gasPrice", vec![]))
+      .unwrap_err();
+    assert!(!format!("{}", error).contains("secret-key"));
+    assert!(!format!("{:?}", error).contains("secret-key"));
+  }
+
+  #[test]
+  fn batches_are_sent_at_once() {
+    let node = MockNode::start();
+    node.expect("eth_gasPrice", quantity(7));
+    let http = http(&node, Auth::default());
+    let batch = vec![
+      http.prepare("eth_gasPrice", vec![]),
+      http.prepare("eth_chainId", vec![]),
+    ];
+    let results = RUNTIME.block_on(http.send_batch(batch)).unwrap();
+    assert_eq!(results[0].as_ref().unwrap(), &quantity(7));
+    assert_eq!(results[1].as_ref().unwrap(), &quantity(1337));
+  }
+
+  #[test]
+  fn batch_answers_are_matched_by_id() {
+    let failure = Output::Failure(Failure {
+      jsonrpc: Some(Version::V2),
+      error: jsonrpc_core::types::Error::method_not_found(),
+      id: Id::Num(3),
+    });
+    let outputs = vec![failure, success(1, quantity(1)), success(9, quantity(9))];
+    let results = batch_results(&[1, 2, 3], outputs);
+    assert_eq!(results[0].as_ref().unwrap(), &quantity(1));
+    assert!(matches!(results[1], Err(Error::InvalidResponse(_))));
+    assert!(matches!(results[2], Err(Error::Rpc(_))));
+  }
+}
@@ -0,0 +1,137 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tokio::time;
+
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+/// Requests per second and in flight allowed towards a node, shared by
+/// every block using it.
+pub struct Limiter {
+  rate: f64,
+  burst: f64,
+  bucket: Mutex<Bucket>,
+  concurrency: Option<Arc<Semaphore>>,
+}
+
+impl Limiter {
+  /// A rate of 0 or less and max_concurrent of 0 disable the matching limit,
+  /// burst defaults to one second worth of requests.
+  pub fn new(rate: f64, burst: u32, max_concurrent: u32) -> Limiter {
+    let burst = if burst > 0 {
+      burst as f64
+    } else {
+      rate.ceil().max(1.0)
+    };
+    Limiter {
+      rate,
+      burst,
+      bucket: Mutex::new(Bucket {
+        tokens: burst,
+        updated: Instant::now(),
+      }),
+      concurrency: if max_concurrent > 0 {
+        Some(Arc::new(Semaphore::new(max_concurrent as usize)))
+      } else {
+        None
+      },
+    }
+  }
+
+  /// Waits for our turn, cost is the amount of calls (the size of a batch).
+  /// The returned permit keeps a concurrency slot until dropped.
+  pub async fn acquire(&self, cost: usize) -> Option<OwnedSemaphorePermit> {
+    if self.rate > 0.0 {
+      let wait = self.reserve(cost as f64);
+      if wait > Duration::from_secs(0) {
+        time::sleep(wait).await;
+      }
+    }
+    match &self.concurrency {
+      // never closed, so acquire can't fail
+      Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+      None => None,
+    }
+  }
+
+  // takes the tokens right away, going in debt if needed, so callers are
+  // served in order and a batch bigger than the burst still goes through
+  fn reserve(&self, cost: f64) -> Duration {
+    let mut bucket = self.bucket.lock().unwrap();
+    let now = Instant::now();
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+    bucket.updated = now;
+    bucket.tokens -= cost;
+    if bucket.tokens >= 0.0 {
+      Duration::from_secs(0)
+    } else {
+      Duration::from_secs_f64(-bucket.tokens / self.rate)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::RUNTIME;
+
+  #[test]
+  fn spaces_requests_past_the_burst() {
+    let limiter = Limiter::new(20.0, 2, 0);
+    let started = Instant::now();
+    RUNTIME.block_on(async {
+      for _ in 0..4 {
+        limiter.acquire(1).await;
+      }
+    });
+    // two go out at once, the other two wait 50ms each
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(90), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+  }
+
+  #[test]
+  fn batches_cost_their_size() {
+    let limiter = Limiter::new(100.0, 10, 0);
+    let started = Instant::now();
+    RUNTIME.block_on(async {
+      // bigger than the burst, still goes through
+      limiter.acquire(20).await;
+      limiter.acquire(1).await;
+    });
+    assert!(started.elapsed() >= Duration::from_millis(90));
+  }
+
+  #[test]
+  fn caps_requests_in_flight() {
+    let limiter = Limiter::new(0.0, 0, 1);
+    RUNTIME.block_on(async {
+      let permit = limiter.acquire(1).await;
+      assert!(permit.is_some());
+      let second = time::timeout(Duration::from_millis(50), limiter.acquire(1)).await;
+      assert!(second.is_err());
+      drop(permit);
+      let third = time::timeout(Duration::from_millis(50), limiter.acquire(1)).await;
+      assert!(third.is_ok());
+    });
+  }
+
+  #[test]
+  fn unlimited_by_default() {
+    let limiter = Limiter::new(0.0, 0, 0);
+    let started = Instant::now();
+    RUNTIME.block_on(async {
+      for _ in 0..100 {
+        assert!(limiter.acquire(1).await.is_none());
+      }
+    });
+    assert!(started.elapsed() < Duration::from_millis(50));
+  }
+}
@@ -0,0 +1,463 @@
+use crate::blocks::cache::Cache;
+use crate::blocks::log;
+use crate::blocks::RUNTIME;
+use chainblocks::cblog;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::TcpListener;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use std::time::Instant;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use web3::error::Error;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+pub const BUCKETS: [f64; 11] = [
+  0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The kinds errors are counted by, cancelled is mostly timeouts.
+pub const ERROR_KINDS: [&str; 6] = [
+  "rpc",
+  "transport",
+  "unreachable",
+  "invalid_response",
+  "cancelled",
+  "other",
+];
+
+#[derive(Clone, Default)]
+pub struct MethodStats {
+  pub calls: u64,
+  pub errors: u64,
+  pub seconds: f64,
+  // one more than BUCKETS, the last one is +Inf
+  pub buckets: [u64; 12],
+}
+
+impl MethodStats {
+  /// The upper bound of the bucket holding the given quantile (0.0 to 1.0).
+  pub fn quantile(&self, q: f64) -> f64 {
+    let target = (self.calls as f64 * q).ceil() as u64;
+    let mut seen = 0;
+    for (i, count) in self.buckets.iter().enumerate() {
+      seen += count;
+      if seen >= target && seen > 0 {
+        return BUCKETS.get(i).cloned().unwrap_or(f64::INFINITY);
+      }
+    }
+    0.0
+  }
+}
+
+#[derive(Default)]
+struct Counters {
+  methods: BTreeMap<String, MethodStats>,
+  errors: [u64; 6],
+}
+
+/// Calls, latencies and errors of the requests sent to a node.
+#[derive(Default)]
+pub struct Metrics {
+  counters: Mutex<Counters>,
+  in_flight: AtomicI64,
+}
+
+impl Metrics {
+  /// Counts the calls as in flight until the returned guard is dropped.
+  pub fn start(&self, methods: Vec<String>) -> InFlight<'_> {
+    self.in_flight.fetch_add(1, Ordering::Relaxed);
+    InFlight {
+      metrics: self,
+      methods,
+      started: Instant::now(),
+      done: false,
+    }
+  }
+
+  pub fn in_flight(&self) -> i64 {
+    self.in_flight.load(Ordering::Relaxed)
+  }
+
+  pub fn snapshot(&self) -> (BTreeMap<String, MethodStats>, [u64; 6]) {
+    let counters = self.counters.lock().unwrap();
+    (counters.methods.clone(), counters.errors)
+  }
+
+  fn record(&self, method: &str, seconds: f64, error: Option<&'static str>) {
+    let mut counters = self.counters.lock().unwrap();
+    if let Some(kind) = error {
+      let idx = ERROR_KINDS.iter().position(|k| *k == kind).unwrap_or(5);
+      counters.errors[idx] += 1;
+    }
+    let stats = counters.methods.entry(method.to_owned()).or_default();
+    stats.calls += 1;
+    stats.seconds += seconds;
+    if error.is_some() {
+      stats.errors += 1;
+    }
+    let bucket = BUCKETS
+      .iter()
+      .position(|bound| seconds <= *bound)
+      .unwrap_or(BUCKETS.len());
+    stats.buckets[bucket] += 1;
+  }
+}
+
+pub struct InFlight<'a> {
+  metrics: &'a Metrics,
+  methods: Vec<String>,
+  started: Instant,
+  done: bool,
+}
+
+impl<'a> InFlight<'a> {
+  /// Records the outcome of every call, in the order given to start.
+  pub fn finish<'e, I>(mut self, errors: I)
+  where
+    I: IntoIterator<Item = Option<&'e Error>>,
+  {
+    let seconds = self.started.elapsed().as_secs_f64();
+    let mut errors = errors.into_iter();
+    for method in &self.methods {
+      let error = errors.next().flatten().map(error_kind);
+      self.metrics.record(method, seconds, error);
+    }
+    self.done = true;
+  }
+}
+
+impl<'a> Drop for InFlight<'a> {
+  fn drop(&mut self) {
+    self.metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+    // dropped before an answer, usually by a timeout
+    if !self.done {
+      let seconds = self.started.elapsed().as_secs_f64();
+      for method in &self.methods {
+        self.metrics.record(method, seconds, Some("cancelled"));
+      }
+    }
+  }
+}
+
+pub fn error_kind(error: &Error) -> &'static str {
+  match error {
+    Error::Rpc(_) => "rpc",
+    Error::Transport(_) | Error::Io(_) => "transport",
+    Error::Unreachable => "unreachable",
+    Error::InvalidResponse(_) | Error::Decoder(_) => "invalid_response",
+    _ => "other",
+  }
+}
+
+struct Exported {
+  node: String,
+  metrics: Weak<Metrics>,
+  cache: Option<Weak<Cache>>,
+}
+
+lazy_static! {
+  // nodes exported on every listening address
+  static ref EXPORTED: Mutex<BTreeMap<String, Vec<Exported>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Serves the metrics of the node in the Prometheus text format on the given
+/// address, nodes sharing an address are told apart by the node label.
+pub fn export<'a>(
+  address: &str,
+  node: &str,
+  metrics: &Arc<Metrics>,
+  cache: Option<&Arc<Cache>>,
+) -> Result<(), &'a str> {
+  let mut exported = EXPORTED.lock().unwrap();
+  if !exported.contains_key(address) {
+    let listener = TcpListener::bind(address).or_else(|e| {
+      cblog!("Failed to listen for metrics on {}: {}", address, e);
+      Err("Failed to listen on MetricsAddress")
+    })?;
+    listener
+      .set_nonblocking(true)
+      .or_else(|_| Err("Failed to listen on MetricsAddress"))?;
+    RUNTIME.spawn(serve(listener, address.to_owned()));
+  }
+  let nodes = exported.entry(address.to_owned()).or_default();
+  // drop what was left by nodes since cleaned up, or by a previous run of this one
+  nodes.retain(|n| n.node != node && n.metrics.strong_count() > 0);
+  nodes.push(Exported {
+    node: node.to_owned(),
+    metrics: Arc::downgrade(metrics),
+    cache: cache.map(Arc::downgrade),
+  });
+  Ok(())
+}
+
+async fn serve(listener: TcpListener, address: String) {
+  let listener = match tokio::net::TcpListener::from_std(listener) {
+    Ok(listener) => listener,
+    Err(e) => {
+      cblog!("Failed to listen for metrics on {}: {}", address, e);
+      return;
+    }
+  };
+  loop {
+    let mut socket = match listener.accept().await {
+      Ok((socket, _)) => socket,
+      Err(_) => continue,
+    };
+    let body = render(&address);
+    tokio::spawn(async move {
+      // whatever was asked for, there is only one page
+      let mut request = [0u8; 1024];
+      let _ = socket.read(&mut request).await;
+      let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+      );
+      let _ = socket.write_all(response.as_bytes()).await;
+      let _ = socket.shutdown().await;
+    });
+  }
+}
+
+fn render(address: &str) -> String {
+  let nodes: Vec<(String, Arc<Metrics>, Option<Arc<Cache>>)> = {
+    let exported = EXPORTED.lock().unwrap();
+    exported
+      .get(address)
+      .map(|nodes| {
+        nodes
+          .iter()
+          .filter_map(|n| {
+            let metrics = n.metrics.upgrade()?;
+            let cache = n.cache.as_ref().and_then(Weak::upgrade);
+            Some((escape(&n.node), metrics, cache))
+          })
+          .collect()
+      })
+      .unwrap_or_default()
+  };
+
+  let mut out = String::new();
+  let snapshots: Vec<_> = nodes
+    .iter()
+    .map(|(node, metrics, _)| {
+      let (methods, errors) = metrics.snapshot();
+      let methods: BTreeMap<String, MethodStats> = methods
+        .into_iter()
+        .map(|(method, stats)| (escape(&method), stats))
+        .collect();
+      (node, (methods, errors))
+    })
+    .collect();
+
+  header(
+    &mut out,
+    "eth_rpc_requests_total",
+    "counter",
+    "JSON-RPC calls sent to the node.",
+  );
+  for (node, (methods, _)) in &snapshots {
+    for (method, stats) in methods {
+      let _ = writeln!(
+        out,
+        "eth_rpc_requests_total{{node=\"{}\",method=\"{}\"}} {}",
+        node, method, stats.calls
+      );
+    }
+  }
+
+  header(
+    &mut out,
+    "eth_rpc_method_errors_total",
+    "counter",
+    "Failed JSON-RPC calls by method.",
+  );
+  for (node, (methods, _)) in &snapshots {
+    for (method, stats) in methods {
+      let _ = writeln!(
+        out,
+        "eth_rpc_method_errors_total{{node=\"{}\",method=\"{}\"}} {}",
+        node, method, stats.errors
+      );
+    }
+  }
+
+  header(
+    &mut out,
+    "eth_rpc_errors_total",
+    "counter",
+    "Failed JSON-RPC calls by kind of error.",
+  );
+  for (node, (_, errors)) in &snapshots {
+    for (kind, count) in ERROR_KINDS.iter().zip(errors.iter()) {
+      let _ = writeln!(
+        out,
+        "eth_rpc_errors_total{{node=\"{}\",kind=\"{}\"}} {}",
+        node, kind, count
+      );
+    }
+  }
+
+  header(
+    &mut out,
+    "eth_rpc_duration_seconds",
+    "histogram",
+    "Time taken by JSON-RPC calls, retries and failovers included.",
+  );
+  for (node, (methods, _)) in &snapshots {
+    for (method, stats) in methods {
+      let mut cumulative = 0;
+      for (i, count) in stats.buckets.iter().enumerate() {
+        cumulative += count;
+        let bound = BUCKETS
+          .get(i)
+          .map(|b| b.to_string())
+          .unwrap_or_else(|| "+Inf".into());
+        let _ = writeln!(
+          out,
+          "eth_rpc_duration_seconds_bucket{{node=\"{}\",method=\"{}\",le=\"{}\"}} {}",
+          node, method, bound, cumulative
+        );
+      }
+      let _ = writeln!(
+        out,
+        "eth_rpc_duration_seconds_sum{{node=\"{}\",method=\"{}\"}} {}",
+        node, method, stats.seconds
+      );
+      let _ = writeln!(
+        out,
+        "eth_rpc_duration_seconds_count{{node=\"{}\",method=\"{}\"}} {}",
+        node, method, stats.calls
+      );
+    }
+  }
+
+  header(
+    &mut out,
+    "eth_rpc_in_flight",
+    "gauge",
+    "JSON-RPC requests waiting for an answer.",
+  );
+  for (node, metrics, _) in &nodes {
+    let _ = writeln!(
+      out,
+      "eth_rpc_in_flight{{node=\"{}\"}} {}",
+      node,
+      metrics.in_flight()
+    );
+  }
+
+  header(
+    &mut out,
+    "eth_cache_hits_total",
+    "counter",
+    "Calls answered from the cache.",
+  );
+  for (node, _, cache) in &nodes {
+    if let Some(cache) = cache {
+      let _ = writeln!(
+        out,
+        "eth_cache_hits_total{{node=\"{}\"}} {}",
+        node,
+        cache.hits()
+      );
+    }
+  }
+  header(
+    &mut out,
+    "eth_cache_misses_total",
+    "counter",
+    "Cacheable calls sent to the node.",
+  );
+  for (node, _, cache) in &nodes {
+    if let Some(cache) = cache {
+      let _ = writeln!(
+        out,
+        "eth_cache_misses_total{{node=\"{}\"}} {}",
+        node,
+        cache.misses()
+      );
+    }
+  }
+  out
+}
+
+// label values are quoted, node names and methods can be anything
+fn escape(value: &str) -> String {
+  value
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+  let _ = writeln!(out, "# HELP {} {}", name, help);
+  let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use web3::error::TransportError;
+
+  #[test]
+  fn counts_calls_and_errors_by_kind() {
+    let metrics = Metrics::default();
+    let rpc = Error::Rpc(jsonrpc_core::types::Error::internal_error());
+    let transport = Error::Transport(TransportError::Code(503));
+    metrics
+      .start(vec![
+        "eth_call".into(),
+        "eth_call".into(),
+        "eth_getBalance".into(),
+      ])
+      .finish(vec![None, Some(&rpc), Some(&transport)]);
+    assert_eq!(metrics.in_flight(), 0);
+
+    let (methods, errors) = metrics.snapshot();
+    assert_eq!(methods["eth_call"].calls, 2);
+    assert_eq!(methods["eth_call"].errors, 1);
+    assert_eq!(methods["eth_getBalance"].errors, 1);
+    assert_eq!(errors, [1, 1, 0, 0, 0, 0]);
+  }
+
+  #[test]
+  fn dropped_requests_are_cancelled() {
+    let metrics = Metrics::default();
+    let guard = metrics.start(vec!["eth_call".into()]);
+    assert_eq!(metrics.in_flight(), 1);
+    drop(guard);
+    assert_eq!(metrics.in_flight(), 0);
+    let (methods, errors) = metrics.snapshot();
+    assert_eq!(methods["eth_call"].errors, 1);
+    assert_eq!(errors[4], 1);
+  }
+
+  #[test]
+  fn label_values_are_escaped() {
+    let metrics = Arc::new(Metrics::default());
+    metrics
+      .start(vec!["eth_\"call\"".into()])
+      .finish(vec![None]);
+    export("127.0.0.1:0", "main\\node\n", &metrics, None).unwrap();
+    let page = render("127.0.0.1:0");
+    assert!(page
+      .contains("eth_rpc_requests_total{node=\"main\\\\node\\n\",method=\"eth_\\\"call\\\"\"} 1"));
+  }
+
+  #[test]
+  fn quantiles_come_from_buckets() {
+    let mut stats = MethodStats::default();
+    stats.calls = 10;
+    stats.buckets[0] = 9;
+    stats.buckets[11] = 1;
+    assert_eq!(stats.quantile(0.5), 0.005);
+    assert_eq!(stats.quantile(0.95), f64::INFINITY);
+    assert_eq!(MethodStats::default().quantile(0.5), 0.0);
+  }
+}
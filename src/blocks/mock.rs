@@ -0,0 +1,566 @@
+use crate::blocks::contract::contract_data;
+use crate::blocks::eth::connect;
+use crate::blocks::eth::NodeConfig;
+use crate::blocks::EthData;
+use crate::blocks::NodeData;
+use crate::blocks::RUNTIME;
+use futures::channel::mpsc;
+use futures::stream::StreamExt;
+use jsonrpc_core::serde_json;
+use jsonrpc_core::types::Call;
+use jsonrpc_core::types::Error as RpcError;
+use jsonrpc_core::types::ErrorCode;
+use jsonrpc_core::types::Id;
+use jsonrpc_core::types::Output;
+use jsonrpc_core::types::Params;
+use jsonrpc_core::types::Request;
+use jsonrpc_core::types::Response;
+use jsonrpc_core::types::Value;
+use jsonrpc_core::types::Version;
+use soketto::handshake::server::Response as Handshake;
+use soketto::handshake::Server;
+use std::ffi::CString;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+pub const CHAIN_ID: u64 = 1337;
+
+struct Expectation {
+  method: String,
+  // None matches any params
+  params: Option<Value>,
+  answer: Result<Value, RpcError>,
+  // None answers forever
+  times: Option<usize>,
+}
+
+/// A request as the mock received it.
+#[derive(Clone, Debug)]
+pub struct Received {
+  pub method: String,
+  pub params: Value,
+  pub headers: Vec<(String, String)>,
+}
+
+struct Subscriber {
+  id: String,
+  kind: String,
+  socket: mpsc::UnboundedSender<String>,
+}
+
+struct Socket {
+  reader: JoinHandle<()>,
+  writer: mpsc::UnboundedSender<String>,
+}
+
+#[derive(Default)]
+struct State {
+  expectations: Vec<Expectation>,
+  received: Vec<Received>,
+  subscribers: Vec<Subscriber>,
+  sockets: Vec<Socket>,
+  // answered instead of the JSON-RPC response while set
+  http_status: Option<u16>,
+  http_delay: Duration,
+  counter: u64,
+}
+
+/// A local JSON-RPC node answering over HTTP and WebSocket from programmed
+/// expectations, so blocks can be pointed at something scriptable.
+/// eth_chainId, eth_subscribe and eth_unsubscribe have built in answers,
+/// anything not expected gets a method not found error.
+#[derive(Clone)]
+pub struct MockNode {
+  state: Arc<Mutex<State>>,
+  http: SocketAddr,
+  ws: SocketAddr,
+}
+
+impl MockNode {
+  /// Listens on two random local ports, served by the shared runtime.
+  pub fn start() -> MockNode {
+    let state = Arc::new(Mutex::new(State::default()));
+    let (http, ws) = RUNTIME.block_on(async {
+      let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let ws = TcpListener::bind("127.0.0.1:0").await.unwrap();
+      (http, ws)
+    });
+    let node = MockNode {
+      state,
+      http: http.local_addr().unwrap(),
+      ws: ws.local_addr().unwrap(),
+    };
+    RUNTIME.spawn(node.clone().accept(http, false));
+    RUNTIME.spawn(node.clone().accept(ws, true));
+    node
+  }
+
+  pub fn http_url(&self) -> String {
+    format!("http://{}", self.http)
+  }
+
+  pub fn ws_url(&self) -> String {
+    format!("ws://{}", self.ws)
+  }
+
+  /// Answers every call of the method with result, whatever the params.
+  pub fn expect(&self, method: &str, result: Value) -> &Self {
+    self.push(method, None, Ok(result), None)
+  }
+
+  /// Answers calls of the method with exactly these params.
+  pub fn expect_params(&self, method: &str, params: Value, result: Value) -> &Self {
+    self.push(method, Some(params), Ok(result), None)
+  }
+
+  /// Answers the next calls of the method with result, then falls through
+  /// to the expectations set before.
+  pub fn expect_times(&self, method: &str, times: usize, result: Value) -> &Self {
+    self.push(method, None, Ok(result), Some(times))
+  }
+
+  pub fn expect_error(&self, method: &str, code: i64, message: &str) -> &Self {
+    self.push(method, None, Err(rpc_error(code, message)), None)
+  }
+
+  /// Fails the next calls of the method, then falls through.
+  pub fn expect_error_times(&self, method: &str, times: usize, code: i64, message: &str) -> &Self {
+    self.push(method, None, Err(rpc_error(code, message)), Some(times))
+  }
+
+  /// Makes the HTTP endpoint answer with this status, None to recover.
+  /// Requests are still recorded.
+  pub fn fail_http(&self, status: Option<u16>) {
+    self.state.lock().unwrap().http_status = status;
+  }
+
+  /// Holds every HTTP answer back for this long, to trigger timeouts.
+  pub fn delay_http(&self, delay: Duration) {
+    self.state.lock().unwrap().http_delay = delay;
+  }
+
+  /// Every request received for the method, in order.
+  pub fn requests(&self, method: &str) -> Vec<Received> {
+    let state = self.state.lock().unwrap();
+    state
+      .received
+      .iter()
+      .filter(|r| r.method == method)
+      .cloned()
+      .collect()
+  }
+
+  pub fn count(&self, method: &str) -> usize {
+    self.requests(method).len()
+  }
+
+  /// Sends result to every live subscription of the kind (newHeads, logs...).
+  pub fn notify(&self, kind: &str, result: Value) {
+    let state = self.state.lock().unwrap();
+    for subscriber in state.subscribers.iter().filter(|s| s.kind == kind) {
+      let notification = serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "eth_subscription",
+        "params": { "subscription": subscriber.id, "result": result },
+      });
+      let _ = subscriber.socket.unbounded_send(notification.to_string());
+    }
+  }
+
+  pub fn subscriptions(&self) -> usize {
+    self.state.lock().unwrap().subscribers.len()
+  }
+
+  /// Closes every WebSocket connection, as a node restart would.
+  pub fn drop_connections(&self) {
+    let mut state = self.state.lock().unwrap();
+    for socket in state.sockets.drain(..) {
+      socket.reader.abort();
+      // ends the writer task, which closes the connection
+      socket.writer.close_channel();
+    }
+    state.subscribers.clear();
+  }
+
+  fn push(
+    &self,
+    method: &str,
+    params: Option<Value>,
+    answer: Result<Value, RpcError>,
+    times: Option<usize>,
+  ) -> &Self {
+    self.state.lock().unwrap().expectations.push(Expectation {
+      method: method.to_owned(),
+      params,
+      answer,
+      times,
+    });
+    self
+  }
+
+  async fn accept(self, listener: TcpListener, ws: bool) {
+    while let Ok((socket, _)) = listener.accept().await {
+      if ws {
+        tokio::spawn(self.clone().serve_ws(socket));
+      } else {
+        tokio::spawn(self.clone().serve_http(socket));
+      }
+    }
+  }
+
+  // just enough HTTP/1.1 for a JSON-RPC client, with keep alive
+  async fn serve_http(self, socket: TcpStream) {
+    let mut reader = BufReader::new(socket);
+    loop {
+      let mut line = String::new();
+      match reader.read_line(&mut line).await {
+        Ok(0) | Err(_) => return,
+        Ok(_) => {}
+      }
+      let mut headers = Vec::new();
+      let mut length = 0;
+      loop {
+        line.clear();
+        if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+          return;
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+          break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+          let (name, value) = (name.trim().to_lowercase(), value.trim().to_owned());
+          if name == "content-length" {
+            length = value.parse().unwrap_or(0);
+          }
+          headers.push((name, value));
+        }
+      }
+      let mut body = vec![0u8; length];
+      if reader.read_exact(&mut body).await.is_err() {
+        return;
+      }
+
+      let answer = self.handle(&body, &headers, None);
+      let (status, delay) = {
+        let state = self.state.lock().unwrap();
+        (state.http_status, state.http_delay)
+      };
+      if delay > Duration::from_secs(0) {
+        sleep(delay).await;
+      }
+      let (status, body) = match status {
+        Some(status) => (status, String::new()),
+        None => (200, answer),
+      };
+      let response = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+      );
+      if reader
+        .get_mut()
+        .write_all(response.as_bytes())
+        .await
+        .is_err()
+      {
+        return;
+      }
+    }
+  }
+
+  async fn serve_ws(self, socket: TcpStream) {
+    let mut server = Server::new(socket.compat());
+    // soketto keeps the handshake headers to itself, only HTTP records them
+    let key = match server.receive_request().await {
+      Ok(request) => request.key(),
+      Err(_) => return,
+    };
+    let accept = Handshake::Accept {
+      key,
+      protocol: None,
+    };
+    if server.send_response(&accept).await.is_err() {
+      return;
+    }
+    let (mut sender, mut receiver) = server.into_builder().finish();
+
+    // everything goes out through one writer, answers and notifications alike
+    let (writer, mut outgoing) = mpsc::unbounded::<String>();
+    tokio::spawn(async move {
+      while let Some(text) = outgoing.next().await {
+        if sender.send_text(text).await.is_err() || sender.flush().await.is_err() {
+          break;
+        }
+      }
+      let _ = sender.close().await;
+    });
+
+    let node = self.clone();
+    let socket = writer.clone();
+    let reader = tokio::spawn(async move {
+      loop {
+        let mut data = Vec::new();
+        if receiver.receive_data(&mut data).await.is_err() {
+          return;
+        }
+        let answer = node.handle(&data, &[], Some(&socket));
+        if socket.unbounded_send(answer).is_err() {
+          return;
+        }
+      }
+    });
+    self
+      .state
+      .lock()
+      .unwrap()
+      .sockets
+      .push(Socket { reader, writer });
+  }
+
+  fn handle(
+    &self,
+    body: &[u8],
+    headers: &[(String, String)],
+    socket: Option<&mpsc::UnboundedSender<String>>,
+  ) -> String {
+    let response = match serde_json::from_slice(body) {
+      Ok(Request::Single(call)) => Response::Single(self.answer(call, headers, socket)),
+      Ok(Request::Batch(calls)) => Response::Batch(
+        calls
+          .into_iter()
+          .map(|call| self.answer(call, headers, socket))
+          .collect(),
+      ),
+      Err(_) => Response::from(RpcError::parse_error(), Some(Version::V2)),
+    };
+    serde_json::to_string(&response).unwrap()
+  }
+
+  fn answer(
+    &self,
+    call: Call,
+    headers: &[(String, String)],
+    socket: Option<&mpsc::UnboundedSender<String>>,
+  ) -> Output {
+    let call = match call {
+      Call::MethodCall(call) => call,
+      _ => return Output::from(Err(RpcError::invalid_request()), Id::Null, None),
+    };
+    let (method, id) = (call.method, call.id);
+    let params = match call.params {
+      Params::Array(params) => Value::Array(params),
+      Params::Map(params) => Value::Object(params),
+      Params::None => Value::Array(Vec::new()),
+    };
+    let mut state = self.state.lock().unwrap();
+    state.received.push(Received {
+      method: method.clone(),
+      params: params.clone(),
+      headers: headers.to_vec(),
+    });
+
+    // the latest matching expectation wins
+    let expected = state
+      .expectations
+      .iter_mut()
+      .rev()
+      .find(|e| {
+        e.method == method && e.params.as_ref().map_or(true, |p| *p == params) && e.times != Some(0)
+      })
+      .map(|e| {
+        if let Some(times) = &mut e.times {
+          *times -= 1;
+        }
+        e.answer.clone()
+      });
+
+    let answer = match (expected, method.as_str(), socket) {
+      (Some(answer), _, _) => answer,
+      (None, "eth_chainId", _) => Ok(Value::String(format!("0x{:x}", CHAIN_ID))),
+      (None, "eth_subscribe", Some(socket)) => {
+        state.counter += 1;
+        let id = format!("0x{:x}", state.counter);
+        state.subscribers.push(Subscriber {
+          id: id.clone(),
+          kind: params[0].as_str().unwrap_or_default().to_owned(),
+          socket: socket.clone(),
+        });
+        Ok(Value::String(id))
+      }
+      (None, "eth_unsubscribe", Some(_)) => {
+        let before = state.subscribers.len();
+        state
+          .subscribers
+          .retain(|s| Value::String(s.id.clone()) != params[0]);
+        Ok(Value::Bool(state.subscribers.len() < before))
+      }
+      _ => Err(RpcError::method_not_found()),
+    };
+    Output::from(answer, id, Some(Version::V2))
+  }
+}
+
+fn rpc_error(code: i64, message: &str) -> RpcError {
+  RpcError {
+    code: ErrorCode::from(code),
+    message: message.to_owned(),
+    data: None,
+  }
+}
+
+/// A contract with a view, a state changing method and an event, enough to
+/// drive the blocks working on contracts.
+pub const ABI: &str = r#"[
+  {"type":"function","name":"totalSupply","inputs":[],"outputs":[{"name":"","type":"uint256"}],"stateMutability":"view"},
+  {"type":"function","name":"name","inputs":[],"outputs":[{"name":"","type":"string"}],"stateMutability":"view"},
+  {"type":"function","name":"poke","inputs":[],"outputs":[],"stateMutability":"nonpayable"},
+  {"type":"event","name":"Poked","inputs":[{"name":"who","type":"address","indexed":true}],"anonymous":false}
+]"#;
+
+pub const CONTRACT: &str = "5fbdb2315678afecb367f032d93f642f64180aa3";
+pub const ACCOUNT: &str = "f39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+// the well known first development account, which ACCOUNT belongs to
+pub const KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+
+impl MockNode {
+  /// A node over HTTP with the default Eth settings.
+  pub fn node(&self) -> Arc<NodeData> {
+    Arc::new(
+      RUNTIME
+        .block_on(connect(NodeConfig::new(vec![self.http_url()])))
+        .unwrap(),
+    )
+  }
+
+  /// What a contract user holds once Eth.Contract ran, calling method.
+  pub fn eth_data(&self, method: &str) -> EthData {
+    let node = self.node();
+    let contract = contract_data(node, CONTRACT.parse().unwrap(), ABI).unwrap();
+    EthData {
+      contract: Some(Arc::new(contract)),
+      method: CString::new(method).unwrap(),
+      from: Some(CString::new(format!("0x{}", ACCOUNT)).unwrap()),
+      input_types: Vec::new(),
+    }
+  }
+}
+
+/// Waits for what the mock or a transport does in the background.
+pub fn wait_until(condition: impl Fn() -> bool) {
+  let start = Instant::now();
+  while !condition() {
+    assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+    std::thread::sleep(Duration::from_millis(20));
+  }
+}
+
+pub fn hash(n: u8) -> String {
+  format!("0x{}", hex::encode([n; 32]))
+}
+
+pub fn quantity(n: u64) -> Value {
+  Value::String(format!("0x{:x}", n))
+}
+
+/// A mined legacy transaction.
+pub fn transaction(hash: &str, block: u64) -> Value {
+  serde_json::json!({
+    "hash": hash,
+    "nonce": "0x0",
+    "blockHash": self::hash(block as u8),
+    "blockNumber": quantity(block),
+    "transactionIndex": "0x0",
+    "from": format!("0x{}", ACCOUNT),
+    "to": format!("0x{}", CONTRACT),
+    "value": "0x0",
+    "gasPrice": "0x3b9aca00",
+    "gas": "0x5208",
+    "input": "0x",
+    "v": "0xa95",
+    "r": "0x1",
+    "s": "0x2",
+  })
+}
+
+pub fn receipt(hash: &str, block: u64) -> Value {
+  serde_json::json!({
+    "transactionHash": hash,
+    "transactionIndex": "0x0",
+    "blockHash": self::hash(block as u8),
+    "blockNumber": quantity(block),
+    "from": format!("0x{}", ACCOUNT),
+    "to": format!("0x{}", CONTRACT),
+    "cumulativeGasUsed": "0x5208",
+    "gasUsed": "0x5208",
+    "contractAddress": null,
+    "logs": [],
+    "status": "0x1",
+    "root": null,
+    "logsBloom": format!("0x{}", "0".repeat(512)),
+    "type": "0x0",
+    "effectiveGasPrice": "0x3b9aca00",
+  })
+}
+
+/// A block holding a single transaction, as hashes or as objects.
+pub fn block(number: u64, full: bool) -> Value {
+  let tx = self::hash(0xaa);
+  let transactions = if full {
+    serde_json::json!([transaction(&tx, number)])
+  } else {
+    serde_json::json!([tx])
+  };
+  serde_json::json!({
+    "hash": self::hash(number as u8),
+    "parentHash": self::hash(number as u8 - 1),
+    "sha3Uncles": self::hash(0x1d),
+    "miner": format!("0x{}", ACCOUNT),
+    "stateRoot": self::hash(0x5a),
+    "transactionsRoot": self::hash(0x7a),
+    "receiptsRoot": self::hash(0x7e),
+    "number": quantity(number),
+    "gasUsed": "0x5208",
+    "gasLimit": "0x1c9c380",
+    "baseFeePerGas": "0x7",
+    "extraData": "0x",
+    "logsBloom": format!("0x{}", "0".repeat(512)),
+    "timestamp": "0x6553f100",
+    "difficulty": "0x0",
+    "totalDifficulty": "0x0",
+    "sealFields": [],
+    "uncles": [],
+    "transactions": transactions,
+    "size": "0x220",
+    "mixHash": self::hash(0x3e),
+    "nonce": "0x0000000000000000",
+  })
+}
+
+/// A log of the Poked event.
+pub fn event_log(block: u64, index: u64) -> Value {
+  serde_json::json!({
+    "address": format!("0x{}", CONTRACT),
+    "topics": [self::hash(0x9c), format!("0x{:0>64}", ACCOUNT)],
+    "data": "0x",
+    "blockHash": self::hash(block as u8),
+    "blockNumber": quantity(block),
+    "transactionHash": self::hash(0xaa),
+    "transactionIndex": "0x0",
+    "logIndex": quantity(index),
+    "removed": false,
+  })
+}
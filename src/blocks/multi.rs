@@ -0,0 +1,1132 @@
+use crate::blocks::auth::redact_url;
+use crate::blocks::auth::Auth;
+use crate::blocks::cache::cache_key;
+use crate::blocks::cache::Cache;
+use crate::blocks::http::Http;
+use crate::blocks::limiter::Limiter;
+use crate::blocks::log;
+use crate::blocks::metrics::Metrics;
+use crate::blocks::record::Recorder;
+use crate::blocks::record::Replayer;
+use crate::blocks::ws::WebSocket;
+use chainblocks::cblog;
+use futures::future::join_all;
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use futures::stream::BoxStream;
+use futures::stream::StreamExt;
+use jsonrpc_core::types::Call;
+use jsonrpc_core::types::Params;
+use jsonrpc_core::types::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::iter;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::time::timeout;
+use web3::api::SubscriptionId;
+use web3::error::Error;
+use web3::error::TransportError;
+use web3::helpers::build_request;
+#[cfg(unix)]
+use web3::transports::Ipc;
+use web3::BatchTransport;
+use web3::DuplexTransport;
+use web3::RequestId;
+use web3::Transport;
+
+// how often we compare the head block of every endpoint
+const LAG_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+const MAX_COOLDOWN: Duration = Duration::from_secs(60);
+// head probes run before a request, a silent endpoint must not hold it for long
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+  /// Always prefer the first healthy endpoint, in the order given.
+  Failover,
+  /// Rotate the first endpoint tried on every request.
+  RoundRobin,
+  /// Ask every healthy endpoint and require this many identical answers for reads.
+  Quorum(usize),
+}
+
+impl Strategy {
+  pub fn parse<'a>(name: &str, quorum: usize) -> Result<Strategy, &'a str> {
+    match name {
+      "failover" => Ok(Strategy::Failover),
+      "round-robin" => Ok(Strategy::RoundRobin),
+      "quorum" => Ok(Strategy::Quorum(quorum.max(1))),
+      _ => Err("Invalid Strategy, expected failover, round-robin or quorum"),
+    }
+  }
+}
+
+/// A single connected endpoint.
+#[derive(Clone, Debug)]
+pub enum Connection {
+  Http(Http),
+  WebSocket(WebSocket),
+  #[cfg(unix)]
+  Ipc(Ipc),
+  Record(Recorder),
+  Replay(Replayer),
+}
+
+impl Connection {
+  /// Besides node addresses, record://<fixture>#<url> saves the traffic with
+  /// url to a fixture file and replay://<fixture> plays it back offline.
+  pub async fn open(uri: &str, auth: Arc<Auth>) -> web3::Result<Connection> {
+    if let Some(rest) = uri.strip_prefix("record://") {
+      let (path, url) = rest.split_once('#').ok_or_else(|| {
+        Error::Transport(TransportError::Message(
+          "Expected record://<fixture>#<url>".into(),
+        ))
+      })?;
+      let inner = Connection::open_endpoint(url, auth).await?;
+      Ok(Connection::Record(Recorder::new(path, inner)?))
+    } else if let Some(path) = uri.strip_prefix("replay://") {
+      Ok(Connection::Replay(Replayer::new(path)?))
+    } else {
+      Connection::open_endpoint(uri, auth).await
+    }
+  }
+
+  async fn open_endpoint(uri: &str, auth: Arc<Auth>) -> web3::Result<Connection> {
+    if uri.starts_with("ws://") || uri.starts_with("wss://") {
+      Ok(Connection::WebSocket(WebSocket::new(uri, auth).await?))
+    } else if let Some(path) = ipc_path(uri) {
+      Connection::open_ipc(path).await
+    } else {
+      Ok(Connection::Http(Http::new(uri, auth)?))
+    }
+  }
+
+  #[cfg(unix)]
+  async fn open_ipc(path: &str) -> web3::Result<Connection> {
+    Ok(Connection::Ipc(Ipc::new(path).await?))
+  }
+
+  #[cfg(not(unix))]
+  async fn open_ipc(_path: &str) -> web3::Result<Connection> {
+    Err(Error::Transport(TransportError::Message(
+      "IPC is only available on Unix".into(),
+    )))
+  }
+
+  fn is_duplex(&self) -> bool {
+    match self {
+      // fixtures hold no notifications
+      Connection::Http(_) | Connection::Record(_) | Connection::Replay(_) => false,
+      _ => true,
+    }
+  }
+
+  pub fn send(&self, id: RequestId, request: Call) -> BoxFuture<'static, web3::Result<Value>> {
+    match self {
+      Connection::Http(t) => Transport::send(t, id, request).boxed(),
+      Connection::WebSocket(t) => Transport::send(t, id, request).boxed(),
+      #[cfg(unix)]
+      Connection::Ipc(t) => Transport::send(t, id, request).boxed(),
+      Connection::Record(t) => t.send(id, request),
+      Connection::Replay(t) => t.send(id, request),
+    }
+  }
+
+  pub fn send_batch(
+    &self,
+    requests: Vec<(RequestId, Call)>,
+  ) -> BoxFuture<'static, web3::Result<Vec<web3::Result<Value>>>> {
+    match self {
+      Connection::Http(t) => BatchTransport::send_batch(t, requests).boxed(),
+      Connection::WebSocket(t) => BatchTransport::send_batch(t, requests).boxed(),
+      #[cfg(unix)]
+      Connection::Ipc(t) => BatchTransport::send_batch(t, requests).boxed(),
+      Connection::Record(t) => t.send_batch(requests),
+      Connection::Replay(t) => t.send_batch(requests),
+    }
+  }
+}
+
+#[derive(Default)]
+struct Health {
+  failures: u32,
+  down_until: Option<Instant>,
+  head: u64,
+  lagging: bool,
+}
+
+impl Health {
+  fn available(&self, now: Instant) -> bool {
+    !self.lagging && self.down_until.map_or(true, |until| until <= now)
+  }
+}
+
+struct Backend {
+  // redacted, only meant for logs
+  url: String,
+  connection: Connection,
+  health: Mutex<Health>,
+}
+
+impl Backend {
+  fn succeeded(&self) {
+    let mut health = self.health.lock().unwrap();
+    health.failures = 0;
+    health.down_until = None;
+  }
+
+  fn failed(&self) {
+    let mut health = self.health.lock().unwrap();
+    health.failures = health.failures.saturating_add(1);
+    let cooldown = Duration::from_secs(1 << health.failures.min(6)).min(MAX_COOLDOWN);
+    health.down_until = Some(Instant::now() + cooldown);
+  }
+
+  /// Waits for the answer of this endpoint only so long. An attempt that times out,
+  /// or that the caller stops waiting for, marks the endpoint as failed.
+  async fn attempt<T>(
+    &self,
+    request: BoxFuture<'static, web3::Result<T>>,
+    duration: Duration,
+  ) -> web3::Result<T> {
+    let mut pending = Pending(Some(self));
+    let result = timeout(duration, request).await.unwrap_or_else(|_| {
+      Err(Error::Transport(TransportError::Message(format!(
+        "No answer within {:?}",
+        duration
+      ))))
+    });
+    pending.0 = None;
+    result
+  }
+}
+
+// fails the endpoint if dropped before its answer arrived
+struct Pending<'a>(Option<&'a Backend>);
+
+impl<'a> Drop for Pending<'a> {
+  fn drop(&mut self) {
+    if let Some(backend) = self.0 {
+      cblog!("Eth endpoint {} was given up on", backend.url);
+      backend.failed();
+    }
+  }
+}
+
+struct Inner {
+  backends: Vec<Backend>,
+  strategy: Strategy,
+  max_lag: u64,
+  // the longest wait for a single endpoint
+  timeout: Duration,
+  id: AtomicUsize,
+  cursor: AtomicUsize,
+  heads_checked: Mutex<Option<Instant>>,
+  subscriptions: Mutex<BTreeMap<SubscriptionId, usize>>,
+  cache: Mutex<Option<Arc<Cache>>>,
+  limiter: Limiter,
+  metrics: Arc<Metrics>,
+}
+
+/// A transport spreading requests over one or more endpoints,
+/// skipping the ones that fail, rate limit us or fall behind.
+#[derive(Clone)]
+pub struct MultiTransport {
+  inner: Arc<Inner>,
+}
+
+impl fmt::Debug for MultiTransport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let urls: Vec<&str> = self.inner.backends.iter().map(|b| b.url.as_str()).collect();
+    f.debug_struct("MultiTransport")
+      .field("backends", &urls)
+      .field("strategy", &self.inner.strategy)
+      .finish()
+  }
+}
+
+impl MultiTransport {
+  pub fn new<'a>(
+    endpoints: Vec<(String, Connection)>,
+    strategy: Strategy,
+    max_lag: u64,
+    timeout: Duration,
+    limiter: Limiter,
+    metrics: Arc<Metrics>,
+  ) -> Result<MultiTransport, &'a str> {
+    if endpoints.is_empty() {
+      return Err("No endpoint to connect to");
+    }
+    if let Strategy::Quorum(quorum) = strategy {
+      // more matching answers than endpoints would fail every read
+      if quorum > endpoints.len() {
+        return Err("Quorum is larger than the number of Urls");
+      }
+    }
+    let backends = endpoints
+      .into_iter()
+      .map(|(url, connection)| Backend {
+        url: redact_url(&url),
+        connection,
+        health: Mutex::new(Health::default()),
+      })
+      .collect();
+    Ok(MultiTransport {
+      inner: Arc::new(Inner {
+        backends,
+        strategy,
+        max_lag,
+        timeout,
+        id: AtomicUsize::new(1),
+        cursor: AtomicUsize::new(0),
+        heads_checked: Mutex::new(None),
+        subscriptions: Mutex::new(BTreeMap::new()),
+        cache: Mutex::new(None),
+        limiter,
+        metrics,
+      }),
+    })
+  }
+
+  /// True if at least one endpoint can deliver subscriptions.
+  pub fn is_duplex(&self) -> bool {
+    self.inner.backends.iter().any(|b| b.connection.is_duplex())
+  }
+
+  /// Starts answering deterministic queries from the cache.
+  pub fn set_cache(&self, cache: Arc<Cache>) {
+    *self.inner.cache.lock().unwrap() = Some(cache);
+  }
+}
+
+impl Inner {
+  fn next_id(&self) -> RequestId {
+    self.id.fetch_add(1, Ordering::AcqRel)
+  }
+
+  // returns healthy and resting endpoints, in the order they should be tried
+  fn candidates(&self, duplex_only: bool) -> (Vec<usize>, Vec<usize>) {
+    let count = self.backends.len();
+    let start = match self.strategy {
+      Strategy::RoundRobin => self.cursor.fetch_add(1, Ordering::Relaxed) % count,
+      _ => 0,
+    };
+    let now = Instant::now();
+    let mut healthy = Vec::new();
+    let mut resting = Vec::new();
+    for i in 0..count {
+      let idx = (start + i) % count;
+      let backend = &self.backends[idx];
+      if duplex_only && !backend.connection.is_duplex() {
+        continue;
+      }
+      if backend.health.lock().unwrap().available(now) {
+        healthy.push(idx);
+      } else {
+        resting.push(idx);
+      }
+    }
+    (healthy, resting)
+  }
+
+  fn cache(&self) -> Option<Arc<Cache>> {
+    self.cache.lock().unwrap().clone()
+  }
+
+  fn order(&self, duplex_only: bool) -> Vec<usize> {
+    // resting endpoints are still tried, as a last resort
+    let (mut healthy, resting) = self.candidates(duplex_only);
+    healthy.extend(resting);
+    healthy
+  }
+
+  async fn dispatch(self: Arc<Self>, id: RequestId, request: Call) -> web3::Result<Value> {
+    let method = method_name(&request).to_owned();
+    match method.as_str() {
+      "eth_subscribe" => return self.subscribe(id, request).await,
+      "eth_unsubscribe" => return self.unsubscribe(id, request).await,
+      _ => {}
+    }
+    let cache = self.cache();
+    let key = cache.as_ref().and_then(|_| cache_key(&request));
+    if let (Some(cache), Some(key)) = (&cache, &key) {
+      if let Some(value) = cache.get(key) {
+        return Ok(value);
+      }
+    }
+    // before our own permit, the probes take theirs
+    self.check_heads().await;
+    let _permit = self.limiter.acquire(1).await;
+    let in_flight = self.metrics.start(vec![method.clone()]);
+    let result = match self.strategy {
+      Strategy::Quorum(quorum) if is_read(&method) => self.quorum(id, request, quorum).await,
+      _ => self
+        .failover(id, request, false)
+        .await
+        .map(|(_, value)| value),
+    };
+    in_flight.finish(vec![result.as_ref().err()]);
+    if let (Some(cache), Some(key), Ok(value)) = (&cache, key, &result) {
+      cache.insert(key, value);
+    }
+    result
+  }
+
+  async fn failover(
+    &self,
+    id: RequestId,
+    request: Call,
+    duplex_only: bool,
+  ) -> web3::Result<(usize, Value)> {
+    let order = self.order(duplex_only);
+    if order.is_empty() {
+      return Err(Error::Transport(TransportError::Message(
+        "No WebSocket or IPC endpoint available".into(),
+      )));
+    }
+    // a write might have gone through, only reads are tried elsewhere
+    let replayable = is_read(method_name(&request));
+    let mut last_error = Error::Unreachable;
+    for idx in order {
+      let backend = &self.backends[idx];
+      let attempt = backend.connection.send(id, request.clone());
+      match backend.attempt(attempt, self.timeout).await {
+        Ok(value) => {
+          backend.succeeded();
+          return Ok((idx, value));
+        }
+        Err(e) => {
+          if !should_failover(&e) {
+            return Err(e);
+          }
+          cblog!("Eth endpoint {} failed: {}", backend.url, e);
+          backend.failed();
+          if !replayable {
+            return Err(e);
+          }
+          last_error = e;
+        }
+      }
+    }
+    Err(last_error)
+  }
+
+  async fn quorum(&self, id: RequestId, request: Call, quorum: usize) -> web3::Result<Value> {
+    let (mut targets, resting) = self.candidates(false);
+    if targets.len() < quorum {
+      let missing = quorum - targets.len();
+      targets.extend(resting.into_iter().take(missing));
+    }
+
+    let results = join_all(targets.iter().map(|&idx| {
+      let backend = &self.backends[idx];
+      backend.attempt(backend.connection.send(id, request.clone()), self.timeout)
+    }))
+    .await;
+
+    let mut answers: Vec<(Value, usize)> = Vec::new();
+    let mut last_error = Error::Unreachable;
+    for (&idx, result) in targets.iter().zip(results) {
+      let backend = &self.backends[idx];
+      match result {
+        Ok(value) => {
+          backend.succeeded();
+          if let Some(answer) = answers.iter_mut().find(|(v, _)| *v == value) {
+            answer.1 += 1;
+          } else {
+            answers.push((value, 1));
+          }
+        }
+        Err(e) => {
+          if should_failover(&e) {
+            backend.failed();
+          }
+          last_error = e;
+        }
+      }
+    }
+
+    if answers.is_empty() {
+      Err(last_error)
+    } else if let Some((value, _)) = answers.into_iter().find(|(_, votes)| *votes >= quorum) {
+      Ok(value)
+    } else {
+      Err(Error::InvalidResponse(format!(
+        "Endpoints did not reach a quorum of {}",
+        quorum
+      )))
+    }
+  }
+
+  async fn dispatch_batch(
+    self: Arc<Self>,
+    requests: Vec<(RequestId, Call)>,
+  ) -> web3::Result<Vec<web3::Result<Value>>> {
+    let cache = match self.cache() {
+      Some(cache) => cache,
+      None => return self.send_batch(requests).await,
+    };
+    // only what is not cached goes to the node
+    let keys: Vec<Option<String>> = requests.iter().map(|(_, call)| cache_key(call)).collect();
+    let mut results: Vec<Option<web3::Result<Value>>> = keys
+      .iter()
+      .map(|key| key.as_ref().and_then(|key| cache.get(key)).map(Ok))
+      .collect();
+    let missing: Vec<(RequestId, Call)> = requests
+      .into_iter()
+      .zip(&results)
+      .filter(|(_, result)| result.is_none())
+      .map(|(request, _)| request)
+      .collect();
+    if !missing.is_empty() {
+      let mut answers = self.send_batch(missing).await?.into_iter();
+      for (result, key) in results.iter_mut().zip(keys) {
+        if result.is_none() {
+          let answer = answers.next().unwrap_or(Err(Error::Unreachable));
+          if let (Some(key), Ok(value)) = (key, &answer) {
+            cache.insert(key, value);
+          }
+          *result = Some(answer);
+        }
+      }
+    }
+    Ok(results.into_iter().flatten().collect())
+  }
+
+  async fn send_batch(
+    &self,
+    requests: Vec<(RequestId, Call)>,
+  ) -> web3::Result<Vec<web3::Result<Value>>> {
+    self.check_heads().await;
+    // a batch costs as much as its calls, but takes a single connection slot
+    let _permit = self.limiter.acquire(requests.len()).await;
+    let methods = requests
+      .iter()
+      .map(|(_, call)| method_name(call).to_owned())
+      .collect();
+    let in_flight = self.metrics.start(methods);
+    let results = self.failover_batch(requests).await;
+    match &results {
+      Ok(values) => in_flight.finish(values.iter().map(|value| value.as_ref().err())),
+      Err(e) => in_flight.finish(iter::repeat(Some(e))),
+    }
+    results
+  }
+
+  async fn failover_batch(
+    &self,
+    requests: Vec<(RequestId, Call)>,
+  ) -> web3::Result<Vec<web3::Result<Value>>> {
+    let replayable = requests.iter().all(|(_, call)| is_read(method_name(call)));
+    let mut last_error = Error::Unreachable;
+    for idx in self.order(false) {
+      let backend = &self.backends[idx];
+      let attempt = backend.connection.send_batch(requests.clone());
+      match backend.attempt(attempt, self.timeout).await {
+        Ok(values) => {
+          backend.succeeded();
+          return Ok(values);
+        }
+        Err(e) => {
+          if !should_failover(&e) {
+            return Err(e);
+          }
+          cblog!("Eth endpoint {} failed: {}", backend.url, e);
+          backend.failed();
+          if !replayable {
+            return Err(e);
+          }
+          last_error = e;
+        }
+      }
+    }
+    Err(last_error)
+  }
+
+  async fn subscribe(&self, id: RequestId, request: Call) -> web3::Result<Value> {
+    // subscriptions are bound to the endpoint that created them
+    let (idx, value) = self.failover(id, request, true).await?;
+    if let Value::String(sub) = &value {
+      self
+        .subscriptions
+        .lock()
+        .unwrap()
+        .insert(SubscriptionId::from(sub.clone()), idx);
+    }
+    Ok(value)
+  }
+
+  async fn unsubscribe(&self, id: RequestId, request: Call) -> web3::Result<Value> {
+    let idx = first_param(&request).and_then(|sub| {
+      self
+        .subscriptions
+        .lock()
+        .unwrap()
+        .remove(&SubscriptionId::from(sub))
+    });
+    match idx {
+      Some(idx) => {
+        let backend = &self.backends[idx];
+        backend
+          .attempt(backend.connection.send(id, request), self.timeout)
+          .await
+      }
+      None => self
+        .failover(id, request, true)
+        .await
+        .map(|(_, value)| value),
+    }
+  }
+
+  async fn check_heads(&self) {
+    if self.max_lag == 0 || self.backends.len() < 2 {
+      return;
+    }
+    {
+      let mut checked = self.heads_checked.lock().unwrap();
+      if let Some(at) = *checked {
+        if at.elapsed() < LAG_CHECK_INTERVAL {
+          return;
+        }
+      }
+      *checked = Some(Instant::now());
+    }
+
+    let results = join_all(self.backends.iter().map(|backend| async move {
+      // probes are requests like any other, limited and measured
+      let _permit = self.limiter.acquire(1).await;
+      let in_flight = self.metrics.start(vec!["eth_blockNumber".to_owned()]);
+      let id = self.next_id();
+      let probe = backend
+        .connection
+        .send(id, build_request(id, "eth_blockNumber", vec![]));
+      let result = backend
+        .attempt(probe, self.timeout.min(PROBE_TIMEOUT))
+        .await;
+      match &result {
+        Ok(_) => backend.succeeded(),
+        Err(e) if should_failover(e) => backend.failed(),
+        Err(_) => {}
+      }
+      in_flight.finish(vec![result.as_ref().err()]);
+      result
+    }))
+    .await;
+    let heads: Vec<Option<u64>> = results
+      .into_iter()
+      .map(|result| result.ok().and_then(|value| parse_quantity(&value)))
+      .collect();
+
+    let best = heads.iter().filter_map(|head| *head).max().unwrap_or(0);
+    for (backend, head) in self.backends.iter().zip(heads) {
+      if let Some(head) = head {
+        let mut health = backend.health.lock().unwrap();
+        health.head = head;
+        health.lagging = best.saturating_sub(head) > self.max_lag;
+        if health.lagging {
+          cblog!(
+            "Eth endpoint {} is lagging behind at block {} (best: {})",
+            backend.url,
+            head,
+            best
+          );
+        }
+      }
+    }
+  }
+}
+
+impl Transport for MultiTransport {
+  type Out = BoxFuture<'static, web3::Result<Value>>;
+
+  fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+    let id = self.inner.next_id();
+    (id, build_request(id, method, params))
+  }
+
+  fn send(&self, id: RequestId, request: Call) -> Self::Out {
+    self.inner.clone().dispatch(id, request).boxed()
+  }
+}
+
+impl BatchTransport for MultiTransport {
+  type Batch = BoxFuture<'static, web3::Result<Vec<web3::Result<Value>>>>;
+
+  fn send_batch<T>(&self, requests: T) -> Self::Batch
+  where
+    T: IntoIterator<Item = (RequestId, Call)>,
+  {
+    let requests = requests.into_iter().collect();
+    self.inner.clone().dispatch_batch(requests).boxed()
+  }
+}
+
+impl DuplexTransport for MultiTransport {
+  type NotificationStream = BoxStream<'static, Value>;
+
+  fn subscribe(&self, id: SubscriptionId) -> web3::Result<Self::NotificationStream> {
+    let idx = self.inner.subscriptions.lock().unwrap().get(&id).cloned();
+    match idx.map(|idx| &self.inner.backends[idx].connection) {
+      Some(Connection::WebSocket(ws)) => Ok(DuplexTransport::subscribe(ws, id)?.boxed()),
+      #[cfg(unix)]
+      Some(Connection::Ipc(ipc)) => Ok(DuplexTransport::subscribe(ipc, id)?.boxed()),
+      _ => Err(Error::Transport(TransportError::Message(
+        "Unknown subscription".into(),
+      ))),
+    }
+  }
+
+  fn unsubscribe(&self, id: SubscriptionId) -> web3::Result<()> {
+    let idx = self.inner.subscriptions.lock().unwrap().get(&id).cloned();
+    match idx.map(|idx| &self.inner.backends[idx].connection) {
+      Some(Connection::WebSocket(ws)) => DuplexTransport::unsubscribe(ws, id),
+      #[cfg(unix)]
+      Some(Connection::Ipc(ipc)) => DuplexTransport::unsubscribe(ipc, id),
+      _ => Ok(()),
+    }
+  }
+}
+
+// ipc:// urls, or plain paths to the node socket
+fn ipc_path(uri: &str) -> Option<&str> {
+  if let Some(path) = uri.strip_prefix("ipc://") {
+    Some(path)
+  } else if uri.starts_with('/') || uri.starts_with("./") || uri.ends_with(".ipc") {
+    Some(uri)
+  } else {
+    None
+  }
+}
+
+fn method_name(request: &Call) -> &str {
+  match request {
+    Call::MethodCall(call) => &call.method,
+    Call::Notification(notification) => &notification.method,
+    _ => "",
+  }
+}
+
+fn first_param(request: &Call) -> Option<String> {
+  match request {
+    Call::MethodCall(call) => match &call.params {
+      Params::Array(params) => params.first().and_then(|p| p.as_str()).map(String::from),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+// calls that only read, anything else might change state or live on a single endpoint
+// (filters, node specific methods), so it's never spread across endpoints
+pub fn is_read(method: &str) -> bool {
+  matches!(
+    method,
+    "eth_blockNumber"
+      | "eth_chainId"
+      | "eth_call"
+      | "eth_estimateGas"
+      | "eth_gasPrice"
+      | "eth_maxPriorityFeePerGas"
+      | "eth_feeHistory"
+      | "eth_getBalance"
+      | "eth_getCode"
+      | "eth_getStorageAt"
+      | "eth_getProof"
+      | "eth_getLogs"
+      | "eth_getBlockByNumber"
+      | "eth_getBlockByHash"
+      | "eth_getBlockTransactionCountByNumber"
+      | "eth_getBlockTransactionCountByHash"
+      | "eth_getTransactionByHash"
+      | "eth_getTransactionByBlockNumberAndIndex"
+      | "eth_getTransactionByBlockHashAndIndex"
+      | "eth_getTransactionCount"
+      | "eth_getTransactionReceipt"
+      | "eth_getUncleByBlockNumberAndIndex"
+      | "eth_getUncleByBlockHashAndIndex"
+      | "eth_getUncleCountByBlockNumber"
+      | "eth_getUncleCountByBlockHash"
+      | "eth_syncing"
+      | "net_version"
+      | "web3_clientVersion"
+      // subscribing only reads too, then the subscription stays on the endpoint that made it
+      | "eth_subscribe"
+      | "eth_unsubscribe"
+  )
+}
+
+fn is_rate_limited(code: i64, message: &str) -> bool {
+  let message = message.to_lowercase();
+  code == -32005
+    || code == 429
+    || message.contains("rate limit")
+    || message.contains("too many requests")
+}
+
+fn should_failover(error: &Error) -> bool {
+  match error {
+    Error::Unreachable | Error::Io(_) => true,
+    Error::Transport(TransportError::Code(code)) => *code == 429 || *code >= 500,
+    Error::Transport(TransportError::Message(_)) => true,
+    Error::Rpc(e) => is_rate_limited(e.code.code(), &e.message),
+    _ => false,
+  }
+}
+
+pub fn parse_quantity(value: &Value) -> Option<u64> {
+  let s = value.as_str()?;
+  let s = s.strip_prefix("0x").unwrap_or(s);
+  u64::from_str_radix(s, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::RUNTIME;
+  use jsonrpc_core::serde_json::json;
+  use tokio::time::timeout;
+
+  fn transport(urls: Vec<String>, strategy: Strategy, max_lag: u64) -> MultiTransport {
+    timed_transport(urls, strategy, max_lag, Duration::from_secs(5))
+  }
+
+  fn timed_transport(
+    urls: Vec<String>,
+    strategy: Strategy,
+    max_lag: u64,
+    wait: Duration,
+  ) -> MultiTransport {
+    RUNTIME.block_on(async {
+      let mut endpoints = Vec::new();
+      for url in urls {
+        let connection = Connection::open(&url, Arc::new(Auth::default()))
+          .await
+          .unwrap();
+        endpoints.push((url, connection));
+      }
+      MultiTransport::new(
+        endpoints,
+        strategy,
+        max_lag,
+        wait,
+        Limiter::new(0.0, 0, 0),
+        Arc::new(Metrics::default()),
+      )
+      .unwrap()
+    })
+  }
+
+  fn execute(transport: &MultiTransport, method: &str, params: Value) -> web3::Result<Value> {
+    let params = match params {
+      Value::Array(params) => params,
+      _ => Vec::new(),
+    };
+    RUNTIME.block_on(transport.execute(method, params))
+  }
+
+  #[test]
+  fn fails_over_to_the_next_endpoint() {
+    let (a, b) = (MockNode::start(), MockNode::start());
+    a.fail_http(Some(503));
+    b.expect("eth_gasPrice", quantity(7));
+    let transport = transport(vec![a.http_url(), b.http_url()], Strategy::Failover, 0);
+
+    assert_eq!(
+      execute(&transport, "eth_gasPrice", json!([])).unwrap(),
+      quantity(7)
+    );
+    // the failed endpoint rests, the next request goes straight to the other one
+    assert_eq!(
+      execute(&transport, "eth_gasPrice", json!([])).unwrap(),
+      quantity(7)
+    );
+    assert_eq!(a.count("eth_gasPrice"), 1);
+    assert_eq!(b.count("eth_gasPrice"), 2);
+  }
+
+  #[test]
+  fn silent_endpoints_are_failed_over() {
+    let (a, b) = (MockNode::start(), MockNode::start());
+    a.delay_http(Duration::from_secs(2));
+    a.expect("eth_gasPrice", quantity(1));
+    b.expect("eth_gasPrice", quantity(7));
+    let urls = vec![a.http_url(), b.http_url()];
+    let transport = timed_transport(urls, Strategy::Failover, 0, Duration::from_millis(200));
+
+    for _ in 0..2 {
+      assert_eq!(
+        execute(&transport, "eth_gasPrice", json!([])).unwrap(),
+        quantity(7)
+      );
+    }
+    assert_eq!(a.count("eth_gasPrice"), 1);
+  }
+
+  #[test]
+  fn abandoned_attempts_fail_the_endpoint() {
+    let (a, b) = (MockNode::start(), MockNode::start());
+    a.delay_http(Duration::from_secs(2));
+    a.expect("eth_gasPrice", quantity(1));
+    b.expect("eth_gasPrice", quantity(7));
+    let transport = transport(vec![a.http_url(), b.http_url()], Strategy::Failover, 0);
+
+    // like a caller whose own timeout is shorter than the endpoint one
+    let given_up = RUNTIME.block_on(async {
+      timeout(
+        Duration::from_millis(200),
+        transport.execute("eth_gasPrice", vec![]),
+      )
+      .await
+    });
+    assert!(given_up.is_err());
+    assert_eq!(
+      execute(&transport, "eth_gasPrice", json!([])).unwrap(),
+      quantity(7)
+    );
+    assert_eq!(a.count("eth_gasPrice"), 1);
+  }
+
+  #[test]
+  fn node_errors_are_answers() {
+    let (a, b) = (MockNode::start(), MockNode::start());
+    a.expect_error("eth_call", 3, "execution reverted");
+    b.expect("eth_call", json!("0x"));
+    let transport = transport(vec![a.http_url(), b.http_url()], Strategy::Failover, 0);
+
+    match execute(&transport, "eth_call", json!([{}, "latest"])) {
+      Err(Error::Rpc(e)) => assert_eq!(e.message, "execution reverted"),
+      other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(b.count("eth_call"), 0);
+  }
+
+  #[test]
+  fn rate_limited_endpoints_are_skipped() {
+    let (a, b) = (MockNode::start(), MockNode::start());
+    a.expect_error("eth_gasPrice", -32005, "rate limit exceeded");
+    b.expect("eth_gasPrice", quantity(7));
+    let transport = transport(vec![a.http_url(), b.http_url()], Strategy::Failover, 0);
+    assert_eq!(
+      execute(&transport, "eth_gasPrice", json!([])).unwrap(),
+      quantity(7)
+    );
+  }
+
+  #[test]
+  fn node_signed_transactions_are_never_sent_twice() {
+    let (a, b) = (MockNode::start(), MockNode::start());
+    a.fail_http(Some(502));
+    b.expect("eth_sendTransaction", json!(crate::blocks::mock::hash(1)));
+    let transport = transport(vec![a.http_url(), b.http_url()], Strategy::Failover, 0);
+    assert!(execute(&transport, "eth_sendTransaction", json!([{}])).is_err());
+    assert_eq!(b.count("eth_sendTransaction"), 0);
+  }
+
+  #[test]
+  fn round_robin_spreads_requests() {
+    let (a, b) = (MockNode::start(), MockNode::start());
+    a.expect("eth_gasPrice", quantity(1));
+    b.expect("eth_gasPrice", quantity(1));
+    let transport = transport(vec![a.http_url(), b.http_url()], Strategy::RoundRobin, 0);
+    for _ in 0..4 {
+      execute(&transport, "eth_gasPrice", json!([])).unwrap();
+    }
+    assert_eq!((a.count("eth_gasPrice"), b.count("eth_gasPrice")), (2, 2));
+  }
+
+  #[test]
+  fn quorum_requires_matching_answers() {
+    let nodes = vec![MockNode::start(), MockNode::start(), MockNode::start()];
+    nodes[0].expect("eth_getBalance", quantity(5));
+    nodes[1].expect("eth_getBalance", quantity(6));
+    nodes[2].expect("eth_getBalance", quantity(5));
+    let urls: Vec<String> = nodes.iter().map(MockNode::http_url).collect();
+
+    let two = transport(urls.clone(), Strategy::Quorum(2), 0);
+    let balance = execute(&two, "eth_getBalance", json!(["0x00", "latest"]));
+    assert_eq!(balance.unwrap(), quantity(5));
+
+    let three = transport(urls, Strategy::Quorum(3), 0);
+    let balance = execute(&three, "eth_getBalance", json!(["0x00", "latest"]));
+    assert!(matches!(balance, Err(Error::InvalidResponse(_))));
+  }
+
+  #[test]
+  fn quorum_needs_enough_endpoints() {
+    let node = MockNode::start();
+    let connection = RUNTIME
+      .block_on(Connection::open(
+        &node.http_url(),
+        Arc::new(Auth::default()),
+      ))
+      .unwrap();
+    let multi = MultiTransport::new(
+      vec![(node.http_url(), connection)],
+      Strategy::Quorum(2),
+      0,
+      Duration::from_secs(5),
+      Limiter::new(0.0, 0, 0),
+      Arc::new(Metrics::default()),
+    );
+    assert_eq!(
+      multi.err(),
+      Some("Quorum is larger than the number of Urls")
+    );
+  }
+
+  #[test]
+  fn quorum_sends_writes_once() {
+    let (a, b) = (MockNode::start(), MockNode::start());
+    a.expect(
+      "eth_sendRawTransaction",
+      json!(crate::blocks::mock::hash(1)),
+    );
+    b.expect(
+      "eth_sendRawTransaction",
+      json!(crate::blocks::mock::hash(1)),
+    );
+    let transport = transport(vec![a.http_url(), b.http_url()], Strategy::Quorum(2), 0);
+    execute(&transport, "eth_sendRawTransaction", json!(["0x00"])).unwrap();
+    assert_eq!(
+      a.count("eth_sendRawTransaction") + b.count("eth_sendRawTransaction"),
+      1
+    );
+  }
+
+  #[test]
+  fn unknown_methods_reach_a_single_endpoint_once() {
+    let (a, b) = (MockNode::start(), MockNode::start());
+    a.expect("anvil_mine", json!(null));
+    b.expect("anvil_mine", json!(null));
+    let quorum = transport(vec![a.http_url(), b.http_url()], Strategy::Quorum(2), 0);
+    execute(&quorum, "anvil_mine", json!(["0x5"])).unwrap();
+    assert_eq!(a.count("anvil_mine") + b.count("anvil_mine"), 1);
+
+    // nor do they fail over, the first endpoint might have mined already
+    let (a, b) = (MockNode::start(), MockNode::start());
+    a.fail_http(Some(503));
+    b.expect("anvil_mine", json!(null));
+    let failover = transport(vec![a.http_url(), b.http_url()], Strategy::Failover, 0);
+    assert!(execute(&failover, "anvil_mine", json!(["0x5"])).is_err());
+    assert_eq!(b.count("anvil_mine"), 0);
+  }
+
+  #[test]
+  fn lagging_endpoints_are_skipped() {
+    let (a, b) = (MockNode::start(), MockNode::start());
+    a.expect("eth_blockNumber", quantity(0x10));
+    b.expect("eth_blockNumber", quantity(0x100));
+    a.expect("eth_gasPrice", quantity(1));
+    b.expect("eth_gasPrice", quantity(2));
+    let transport = transport(vec![a.http_url(), b.http_url()], Strategy::Failover, 5);
+    assert_eq!(
+      execute(&transport, "eth_gasPrice", json!([])).unwrap(),
+      quantity(2)
+    );
+    assert_eq!(a.count("eth_gasPrice"), 0);
+  }
+
+  #[test]
+  fn silent_head_probes_do_not_hold_requests() {
+    let (a, b) = (MockNode::start(), MockNode::start());
+    a.delay_http(Duration::from_secs(10));
+    a.expect("eth_blockNumber", quantity(0x100));
+    b.expect("eth_blockNumber", quantity(0x100));
+    b.expect("eth_gasPrice", quantity(2));
+    let transport = transport(vec![a.http_url(), b.http_url()], Strategy::Failover, 5);
+
+    let started = Instant::now();
+    assert_eq!(
+      execute(&transport, "eth_gasPrice", json!([])).unwrap(),
+      quantity(2)
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(a.count("eth_gasPrice"), 0);
+    let (methods, _) = transport.inner.metrics.snapshot();
+    assert_eq!(methods["eth_blockNumber"].calls, 2);
+    assert_eq!(methods["eth_blockNumber"].errors, 1);
+  }
+
+  #[test]
+  fn fixed_queries_are_cached() {
+    let node = MockNode::start();
+    node.expect("eth_getBalance", quantity(5));
+    let transport = transport(vec![node.http_url()], Strategy::Failover, 0);
+    transport.set_cache(Arc::new(Cache::new(8, None, 1).unwrap()));
+
+    for _ in 0..3 {
+      execute(&transport, "eth_getBalance", json!(["0x00", "0x10"])).unwrap();
+    }
+    assert_eq!(node.count("eth_getBalance"), 1);
+    for _ in 0..2 {
+      execute(&transport, "eth_getBalance", json!(["0x00", "latest"])).unwrap();
+    }
+    assert_eq!(node.count("eth_getBalance"), 3);
+  }
+
+  #[test]
+  fn batches_only_send_what_is_not_cached() {
+    let node = MockNode::start();
+    node.expect("eth_getBalance", quantity(5));
+    node.expect("eth_blockNumber", quantity(0x20));
+    let transport = transport(vec![node.http_url()], Strategy::Failover, 0);
+    transport.set_cache(Arc::new(Cache::new(8, None, 1).unwrap()));
+
+    for _ in 0..2 {
+      let requests = vec![
+        transport.prepare("eth_getBalance", vec![json!("0x00"), json!("0x10")]),
+        transport.prepare("eth_blockNumber", vec![]),
+      ];
+      let results = RUNTIME.block_on(transport.send_batch(requests)).unwrap();
+      assert_eq!(results[0].as_ref().unwrap(), &quantity(5));
+      assert_eq!(results[1].as_ref().unwrap(), &quantity(0x20));
+    }
+    assert_eq!(node.count("eth_getBalance"), 1);
+    assert_eq!(node.count("eth_blockNumber"), 2);
+  }
+
+  #[test]
+  fn requests_are_measured() {
+    let node = MockNode::start();
+    node.expect("eth_gasPrice", quantity(1));
+    node.expect_error("eth_call", 3, "execution reverted");
+    let transport = transport(vec![node.http_url()], Strategy::Failover, 0);
+    execute(&transport, "eth_gasPrice", json!([])).unwrap();
+    execute(&transport, "eth_call", json!([{}, "latest"])).unwrap_err();
+
+    let (methods, errors) = transport.inner.metrics.snapshot();
+    assert_eq!(methods["eth_gasPrice"].calls, 1);
+    assert_eq!(methods["eth_call"].errors, 1);
+    assert_eq!(errors[0], 1);
+    assert_eq!(transport.inner.metrics.in_flight(), 0);
+  }
+
+  #[test]
+  fn subscriptions_use_duplex_endpoints() {
+    let node = MockNode::start();
+    let transport = transport(vec![node.http_url(), node.ws_url()], Strategy::Failover, 0);
+    assert!(transport.is_duplex());
+
+    let id = execute(&transport, "eth_subscribe", json!(["newHeads"])).unwrap();
+    let id = SubscriptionId::from(id.as_str().unwrap().to_owned());
+    let mut stream = DuplexTransport::subscribe(&transport, id).unwrap();
+    node.notify("newHeads", json!({ "number": "0x1" }));
+    let head = RUNTIME.block_on(async { timeout(Duration::from_secs(5), stream.next()).await });
+    assert_eq!(head.unwrap(), Some(json!({ "number": "0x1" })));
+  }
+
+  #[test]
+  fn http_only_nodes_cannot_subscribe() {
+    let node = MockNode::start();
+    let transport = transport(vec![node.http_url()], Strategy::Failover, 0);
+    assert!(!transport.is_duplex());
+    assert!(execute(&transport, "eth_subscribe", json!(["newHeads"])).is_err());
+  }
+}
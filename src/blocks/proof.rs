@@ -0,0 +1,646 @@
+use crate::blocks::ens::resolve_address;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::rlp::rlp_decode;
+use crate::blocks::rlp::rlp_hash;
+use crate::blocks::rlp::rlp_number;
+use crate::blocks::rlp::Rlp;
+use crate::blocks::selector::block_param;
+use crate::blocks::selector::BlockSelector;
+use crate::blocks::shared_from_var;
+use crate::blocks::slot::slot_from_var;
+use crate::blocks::trie::verify_proof;
+use crate::blocks::trie::EMPTY_TRIE;
+use crate::blocks::NodeData;
+use crate::blocks::BLOCK_HASH_TYPE;
+use crate::blocks::BLOCK_VAR;
+use crate::blocks::NODE_TYPE;
+use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
+use chainblocks::block::Block;
+use chainblocks::cblog;
+use chainblocks::cbstr;
+use chainblocks::core::activate_blocking;
+use chainblocks::core::BlockingBlock;
+use chainblocks::cstr;
+use chainblocks::types::common_type;
+use chainblocks::types::ClonedVar;
+use chainblocks::types::Context;
+use chainblocks::types::ExposedInfo;
+use chainblocks::types::ExposedTypes;
+use chainblocks::types::ParamVar;
+use chainblocks::types::Parameters;
+use chainblocks::types::RawString;
+use chainblocks::types::Seq;
+use chainblocks::types::Table;
+use chainblocks::types::Type;
+use chainblocks::types::Var;
+use jsonrpc_core::types::Value;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::Duration;
+use web3::helpers;
+use web3::types::Address;
+use web3::types::H256;
+use web3::types::U256;
+use web3::Transport as _;
+
+static TABLE_TYPES: &'static [Type] = &[
+  common_type::bytes,
+  common_type::bytes,
+  common_type::bytes,
+  common_type::bytes,
+  common_type::bytezs,
+];
+const TABLE_KEYS: &[RawString] = &[
+  cbstr!("balance"),
+  cbstr!("nonce"),
+  cbstr!("code_hash"),
+  cbstr!("storage_hash"),
+  cbstr!("storage"),
+];
+static TABLE_TYPE: Type = Type::table(TABLE_KEYS, TABLE_TYPES);
+
+#[derive(Debug, PartialEq)]
+pub struct Account {
+  pub nonce: U256,
+  pub balance: U256,
+  pub storage_hash: H256,
+  pub code_hash: H256,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StorageValue {
+  pub key: H256,
+  pub value: U256,
+  proof: Vec<Vec<u8>>,
+}
+
+/// An eth_getProof answer.
+#[derive(Debug, PartialEq)]
+pub struct AccountProof {
+  pub account: Account,
+  pub storage: Vec<StorageValue>,
+  proof: Vec<Vec<u8>>,
+}
+
+fn hex_field<'a>(value: &Value, key: &str) -> Result<Vec<u8>, &'a str> {
+  let text = value
+    .get(key)
+    .and_then(Value::as_str)
+    .ok_or("Malformed eth_getProof answer")?;
+  let digits = text.trim_start_matches("0x");
+  if digits.len() % 2 == 1 {
+    // quantities drop leading zeros
+    hex::decode(format!("0{}", digits))
+  } else {
+    hex::decode(digits)
+  }
+  .or_else(|_| Err("Malformed eth_getProof answer"))
+}
+
+fn hash_field<'a>(value: &Value, key: &str) -> Result<H256, &'a str> {
+  let bytes = hex_field(value, key)?;
+  if bytes.len() != 32 {
+    return Err("Malformed eth_getProof answer");
+  }
+  Ok(H256::from_slice(&bytes))
+}
+
+fn number_field<'a>(value: &Value, key: &str) -> Result<U256, &'a str> {
+  let bytes = hex_field(value, key)?;
+  if bytes.len() > 32 {
+    return Err("Malformed eth_getProof answer");
+  }
+  Ok(U256::from(bytes.as_slice()))
+}
+
+fn proof_nodes<'a>(value: &Value, key: &str) -> Result<Vec<Vec<u8>>, &'a str> {
+  let nodes = value
+    .get(key)
+    .and_then(Value::as_array)
+    .ok_or("Malformed eth_getProof answer")?;
+  let mut proof = Vec::new();
+  for node in nodes {
+    let text = node.as_str().ok_or("Malformed eth_getProof answer")?;
+    proof.push(
+      hex::decode(text.trim_start_matches("0x"))
+        .or_else(|_| Err("Malformed eth_getProof answer"))?,
+    );
+  }
+  Ok(proof)
+}
+
+/// Reads an eth_getProof answer for the storage keys asked, in the same order.
+pub fn parse_proof<'a>(value: &Value, keys: &[H256]) -> Result<AccountProof, &'a str> {
+  let entries = value
+    .get("storageProof")
+    .and_then(Value::as_array)
+    .ok_or("Malformed eth_getProof answer")?;
+  if entries.len() != keys.len() {
+    return Err("eth_getProof didn't answer every storage key");
+  }
+  let mut storage = Vec::new();
+  for (key, entry) in keys.iter().zip(entries) {
+    storage.push(StorageValue {
+      key: *key,
+      value: number_field(entry, "value")?,
+      proof: proof_nodes(entry, "proof")?,
+    });
+  }
+  Ok(AccountProof {
+    account: Account {
+      nonce: number_field(value, "nonce")?,
+      balance: number_field(value, "balance")?,
+      storage_hash: hash_field(value, "storageHash")?,
+      code_hash: hash_field(value, "codeHash")?,
+    },
+    storage,
+    proof: proof_nodes(value, "accountProof")?,
+  })
+}
+
+// keccak256 of no code at all
+pub const EMPTY_CODE: &str = "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470";
+
+/// Checks the account against the state root and its storage values against the account.
+/// Absent accounts get the hashes of an empty one, whatever the node reported.
+pub fn verify_account<'a>(
+  state_root: H256,
+  address: Address,
+  proof: &mut AccountProof,
+) -> Result<(), &'a str> {
+  let account = &mut proof.account;
+  match verify_proof(state_root, address.as_bytes(), &proof.proof)? {
+    Some(encoded) => {
+      let items = match rlp_decode(&encoded)? {
+        Rlp::List(_, items) if items.len() == 4 => items,
+        _ => return Err("Invalid account in the state trie"),
+      };
+      let proven = Account {
+        nonce: rlp_number(&items[0])?,
+        balance: rlp_number(&items[1])?,
+        storage_hash: rlp_hash(&items[2])?,
+        code_hash: rlp_hash(&items[3])?,
+      };
+      if proven != *account {
+        return Err("Account doesn't match its proof");
+      }
+    }
+    None => {
+      // nodes disagree on the hashes they report for absent accounts, not on them being empty
+      if !account.nonce.is_zero() || !account.balance.is_zero() {
+        return Err("Account doesn't match its proof");
+      }
+      if proof.storage.iter().any(|entry| !entry.value.is_zero()) {
+        return Err("Storage value doesn't match its proof");
+      }
+      account.storage_hash = EMPTY_TRIE.parse().unwrap();
+      account.code_hash = EMPTY_CODE.parse().unwrap();
+      return Ok(());
+    }
+  }
+  for entry in &proof.storage {
+    let proven = match verify_proof(account.storage_hash, entry.key.as_bytes(), &entry.proof)? {
+      Some(encoded) => rlp_number(&rlp_decode(&encoded)?)?,
+      None => U256::zero(),
+    };
+    if proven != entry.value {
+      return Err("Storage value doesn't match its proof");
+    }
+  }
+  Ok(())
+}
+
+async fn request<'a>(
+  node: &NodeData,
+  method: &str,
+  params: Vec<Value>,
+  timeout: Duration,
+) -> Result<Value, &'a str> {
+  let transport = node.web3.transport();
+  let result = node
+    .retry
+    .timeout(timeout, || transport.execute(method, params.clone()))
+    .await
+    .or_else(|_| Err("Proof request timed out"))?;
+  result.or_else(|e| {
+    cblog!("Proof error: {}", e);
+    Err("Proof request failed")
+  })
+}
+
+/// Fetches and verifies a proof, against the state root of the block from the node if none is given.
+pub async fn get_proof<'a>(
+  node: &NodeData,
+  address: Address,
+  keys: &[H256],
+  block: Option<BlockSelector>,
+  state_root: Option<H256>,
+  timeout: Duration,
+) -> Result<AccountProof, &'a str> {
+  let (state_root, block) = match state_root {
+    Some(root) => (root, block),
+    None => {
+      let (method, params) = block
+        .unwrap_or(BlockSelector::Tag("latest"))
+        .block_request(false);
+      let header = request(node, method, params, timeout).await?;
+      if header.is_null() {
+        return Err("Block not found");
+      }
+      // a tag could move on before the proof is asked
+      let number = number_field(&header, "number")?;
+      if number > u64::MAX.into() {
+        return Err("Malformed block header");
+      }
+      let number = BlockSelector::Number(number.as_u64());
+      (hash_field(&header, "stateRoot")?, Some(number))
+    }
+  };
+  let params = vec![
+    helpers::serialize(&address),
+    helpers::serialize(&keys),
+    block_param(block),
+  ];
+  let answer = request(node, "eth_getProof", params, timeout).await?;
+  let mut proof = parse_proof(&answer, keys)?;
+  verify_account(state_root, address, &mut proof)?;
+  Ok(proof)
+}
+
+pub struct Proof {
+  address: ParamVar,
+  keys: ParamVar,
+  block: ParamVar,
+  state_root: ParamVar,
+  node_param: ParamVar,
+  node: Option<Arc<NodeData>>,
+  output: Table,
+  timeout: ParamVar,
+  requiring: ExposedTypes,
+}
+
+impl Default for Proof {
+  fn default() -> Self {
+    Proof {
+      address: ParamVar::new(cstr!("").into()),
+      keys: ParamVar::new(().into()),
+      block: ParamVar::new(().into()),
+      state_root: ParamVar::new(().into()),
+      node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
+      node: None,
+      output: Table::new(),
+      timeout: ParamVar::new(().into()),
+      requiring: Vec::new(),
+    }
+  }
+}
+
+static KEYS_TYPES: &'static [Type] = &[common_type::anys];
+static KEYS_VAR: Type = Type::context_variable(KEYS_TYPES);
+
+lazy_static! {
+  static ref INPUT_TYPES: Vec<Type> = vec![common_type::none];
+  static ref OUTPUT_TYPES: Vec<Type> = vec![TABLE_TYPE];
+  static ref PARAMETERS: Parameters = vec![
+    (
+      cstr!("Address"),
+      cstr!("The address of the account to prove, or an ENS name."),
+      vec![
+        common_type::string,
+        common_type::string_var,
+        common_type::bytes,
+        common_type::bytes_var
+      ],
+    )
+      .into(),
+    (
+      cstr!("Keys"),
+      cstr!("The storage slots to prove, as ints or 32 bytes like the output of Eth.Slot."),
+      vec![common_type::none, common_type::anys, KEYS_VAR],
+    )
+      .into(),
+    (
+      cstr!("Block"),
+      cstr!(
+        "The optional block to prove at: a number, a tag like safe or finalized, or a block hash."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::string,
+        common_type::bytes,
+        BLOCK_HASH_TYPE,
+        BLOCK_VAR
+      ],
+    )
+      .into(),
+    (
+      cstr!("StateRoot"),
+      cstr!(
+        "The trusted state_root of the block, like the one Eth.Block outputs. None to take it from the same node."
+      ),
+      vec![
+        common_type::none,
+        common_type::bytes,
+        common_type::bytes_var
+      ],
+    )
+      .into(),
+    (
+      cstr!("Node"),
+      cstr!("The ethereum node block variable to use."),
+      vec![NODE_VAR],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
+  ];
+}
+
+impl Block for Proof {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.Proof-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.Proof")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.Proof"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &INPUT_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &OUTPUT_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.address.set_param(value),
+      1 => self.keys.set_param(value),
+      2 => self.block.set_param(value),
+      3 => self.state_root.set_param(value),
+      4 => self.node_param.set_param(value),
+      5 => self.timeout.set_param(value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.address.get_param(),
+      1 => self.keys.get_param(),
+      2 => self.block.get_param(),
+      3 => self.state_root.get_param(),
+      4 => self.node_param.get_param(),
+      5 => self.timeout.get_param(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.requiring.clear();
+    let exp_info = ExposedInfo {
+      exposedType: NODE_TYPE,
+      name: self.node_param.get_name(),
+      help: cstr!("The required ethereum node to use as gateway.").into(),
+      ..ExposedInfo::default()
+    };
+    self.requiring.push(exp_info);
+    Some(&self.requiring)
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.node_param.warmup(context);
+    self.address.warmup(context);
+    self.keys.warmup(context);
+    self.block.warmup(context);
+    self.state_root.warmup(context);
+    self.timeout.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.timeout.cleanup();
+    self.state_root.cleanup();
+    self.block.cleanup();
+    self.keys.cleanup();
+    self.address.cleanup();
+    self.node_param.cleanup();
+    self.node = None;
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(activate_blocking(self, context, input))
+  }
+}
+
+impl BlockingBlock for Proof {
+  fn activate_blocking(&mut self, _: &Context, _: &Var) -> Result<Var, &str> {
+    if self.node.is_none() {
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
+    }
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
+    let address = resolve_address(node, self.address.get(), timeout)?;
+    let block = BlockSelector::from_var(&self.block.get())?;
+    let mut keys = Vec::new();
+    let keys_var = self.keys.get();
+    if !keys_var.is_none() {
+      let seq: Seq = keys_var.as_ref().try_into()?;
+      for key in seq.iter() {
+        keys.push(slot_from_var(&key)?);
+      }
+    }
+    let state_root = {
+      let root = self.state_root.get();
+      if root.is_none() {
+        None
+      } else {
+        let bytes: &[u8] = root.as_ref().try_into()?;
+        if bytes.len() != 32 {
+          return Err("StateRoot must be 32 bytes long");
+        }
+        Some(H256::from_slice(bytes))
+      }
+    };
+    let proof = RUNTIME.block_on(get_proof(node, address, &keys, block, state_root, timeout))?;
+    let account = &proof.account;
+    let balance: [u8; 32] = account.balance.into();
+    self
+      .output
+      .insert_fast_static(cstr!("balance"), (&balance[..]).into());
+    let nonce: [u8; 32] = account.nonce.into();
+    self
+      .output
+      .insert_fast_static(cstr!("nonce"), (&nonce[..]).into());
+    self
+      .output
+      .insert_fast_static(cstr!("code_hash"), account.code_hash.as_bytes().into());
+    self.output.insert_fast_static(
+      cstr!("storage_hash"),
+      account.storage_hash.as_bytes().into(),
+    );
+    let mut values = Vec::<ClonedVar>::new();
+    for entry in &proof.storage {
+      let value: [u8; 32] = entry.value.into();
+      values.push((&value[..]).into());
+    }
+    let storage: ClonedVar = values.as_slice().into();
+    self.output.insert_fast_static(cstr!("storage"), storage.0);
+    Ok(self.output.as_ref().into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::rlp::encode_bytes;
+  use crate::blocks::rlp::encode_list;
+  use crate::blocks::rlp::encode_number;
+  use crate::blocks::trie::leaf_node;
+  use crate::blocks::trie::nibbles;
+  use jsonrpc_core::serde_json::json;
+  use web3::signing::keccak256;
+
+  fn leaf(key: &[u8], value: &[u8]) -> Vec<u8> {
+    leaf_node(&nibbles(&keccak256(key)), value)
+  }
+
+  fn root(node: &[u8]) -> H256 {
+    keccak256(node).into()
+  }
+
+  fn account_trie(address: Address, balance: U256, slot: H256, value: U256) -> (H256, Value) {
+    let storage_leaf = leaf(slot.as_bytes(), &encode_number(value));
+    let storage_hash = root(&storage_leaf);
+    let code_hash: H256 = EMPTY_CODE.parse().unwrap();
+    let account = encode_list(&[
+      encode_number(1.into()),
+      encode_number(balance),
+      encode_bytes(storage_hash.as_bytes()),
+      encode_bytes(code_hash.as_bytes()),
+    ]);
+    let account_leaf = leaf(address.as_bytes(), &account);
+    let answer = json!({
+      "balance": format!("{:#x}", balance),
+      "nonce": "0x1",
+      "codeHash": format!("{:?}", code_hash),
+      "storageHash": format!("{:?}", storage_hash),
+      "accountProof": [format!("0x{}", hex::encode(&account_leaf))],
+      "storageProof": [{
+        "key": format!("{:#x}", U256::from(slot.as_bytes())),
+        "value": format!("{:#x}", value),
+        "proof": [format!("0x{}", hex::encode(&storage_leaf))]
+      }]
+    });
+    (root(&account_leaf), answer)
+  }
+
+  #[test]
+  fn verifies_accounts_and_storage() {
+    let address = Address::repeat_byte(0x11);
+    let slot = H256::from_low_u64_be(3);
+    let (state_root, answer) = account_trie(address, 1000.into(), slot, 42.into());
+    let mut proof = parse_proof(&answer, &[slot]).unwrap();
+    assert_eq!(proof.storage[0].value, 42.into());
+    assert!(verify_account(state_root, address, &mut proof).is_ok());
+
+    let mut lying = answer.clone();
+    lying["balance"] = json!("0x3e9");
+    let mut proof = parse_proof(&lying, &[slot]).unwrap();
+    assert_eq!(
+      verify_account(state_root, address, &mut proof),
+      Err("Account doesn't match its proof")
+    );
+
+    let mut lying = answer.clone();
+    lying["storageProof"][0]["value"] = json!("0x2b");
+    let mut proof = parse_proof(&lying, &[slot]).unwrap();
+    assert_eq!(
+      verify_account(state_root, address, &mut proof),
+      Err("Storage value doesn't match its proof")
+    );
+
+    // an absent account can't hold anything
+    let stranger = Address::repeat_byte(0x22);
+    let mut proof = parse_proof(&answer, &[slot]).unwrap();
+    assert!(verify_account(state_root, stranger, &mut proof).is_err());
+    let mut empty = answer;
+    empty["balance"] = json!("0x0");
+    empty["nonce"] = json!("0x0");
+    empty["storageProof"][0]["value"] = json!("0x0");
+    let mut proof = parse_proof(&empty, &[slot]).unwrap();
+    assert!(verify_account(state_root, stranger, &mut proof).is_ok());
+    // not the hashes the node sent along
+    assert_eq!(proof.account.storage_hash, EMPTY_TRIE.parse().unwrap());
+    assert_eq!(proof.account.code_hash, EMPTY_CODE.parse().unwrap());
+  }
+
+  #[test]
+  fn proves_against_the_block_state_root() {
+    let node = MockNode::start();
+    let address = Address::repeat_byte(0x11);
+    let slot = H256::from_low_u64_be(3);
+    let (state_root, answer) = account_trie(address, 1000.into(), slot, 42.into());
+    node.expect(
+      "eth_getBlockByNumber",
+      json!({ "number": "0x10", "stateRoot": format!("{:?}", state_root) }),
+    );
+    node.expect("eth_getProof", answer);
+    let eth = node.node();
+    let proof = RUNTIME
+      .block_on(get_proof(
+        &eth,
+        address,
+        &[slot],
+        Some(BlockSelector::Tag("finalized")),
+        None,
+        Duration::from_secs(5),
+      ))
+      .unwrap();
+    assert_eq!(proof.account.balance, 1000.into());
+    assert_eq!(
+      node.requests("eth_getBlockByNumber")[0].params[0],
+      "finalized"
+    );
+    // pinned to the block the state root came from
+    let params = &node.requests("eth_getProof")[0].params;
+    assert_eq!(params[1], json!([format!("{:?}", slot)]));
+    assert_eq!(params[2], "0x10");
+
+    let other = RUNTIME.block_on(get_proof(
+      &eth,
+      address,
+      &[slot],
+      None,
+      Some(H256::repeat_byte(1)),
+      Duration::from_secs(5),
+    ));
+    assert_eq!(other.err(), Some("Proof doesn't match the trie root"));
+  }
+}
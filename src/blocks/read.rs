@@ -1,15 +1,29 @@
-use crate::blocks::get_timeout;
+use crate::blocks::address::parse_address;
+use crate::blocks::ens::input_names;
+use crate::blocks::ens::is_name;
+use crate::blocks::ens::resolve_name;
+use crate::blocks::ens::resolve_names;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::retry::RetryPolicy;
+use crate::blocks::selector::eth_call;
+use crate::blocks::selector::BlockSelector;
+use crate::blocks::shared_from_var;
 use crate::blocks::tokens::gather_inputs;
 use crate::blocks::tokens::tokens_to_var;
 use crate::blocks::tokens::var_to_tokens;
 use crate::blocks::tokens::MyTokens;
 use crate::blocks::ContractUser;
-use crate::blocks::{ContractData, EthData, NodeData};
-use crate::blocks::{CONTRACT_TYPE, CONTRACT_VAR, NODE_TYPE};
+use crate::blocks::EthData;
+use crate::blocks::BLOCK_HASH_TYPE;
+use crate::blocks::BLOCK_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
+use crate::blocks::{CONTRACT_TYPE, CONTRACT_VAR};
 use chainblocks::block::Block;
 use chainblocks::cblog;
 use chainblocks::core::do_blocking;
-use chainblocks::core::log;
 use chainblocks::cstr;
 use chainblocks::types::common_type;
 use chainblocks::types::Context;
@@ -20,24 +34,21 @@ use chainblocks::types::Parameters;
 use chainblocks::types::Table;
 use chainblocks::types::Types;
 use chainblocks::types::{ClonedVar, Var};
-use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::ffi::CStr;
 use std::ffi::CString;
 use std::str;
 use std::time::Duration;
-use tokio::time::timeout;
-use web3::contract::Error;
 use web3::contract::Options;
 use web3::types::Address;
-use web3::types::BlockId;
-use web3::types::BlockNumber;
+use web3::types::Bytes;
+use web3::types::CallRequest;
 use web3::types::U256;
 
 pub struct Read {
   cu: ContractUser,
-  block: Option<BlockId>,
-  timeout: Duration,
+  block: ParamVar,
+  timeout: ParamVar,
   options: ParamVar,
   output: ClonedVar,
 }
@@ -60,7 +71,7 @@ lazy_static! {
       .into(),
     (
       cstr!("From"),
-      cstr!("The optional address we are calling from"),
+      cstr!("The optional address we are calling from, or an ENS name"),
       vec![
         common_type::none,
         common_type::string,
@@ -70,8 +81,17 @@ lazy_static! {
       .into(),
     (
       cstr!("Block"),
-      cstr!("The optional block number to read from history."),
-      vec![common_type::none, common_type::int],
+      cstr!(
+        "The optional block to read from history: a number, a tag like safe or finalized, or a block hash."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::string,
+        common_type::bytes,
+        BLOCK_HASH_TYPE,
+        BLOCK_VAR
+      ],
     )
       .into(),
     (
@@ -83,7 +103,20 @@ lazy_static! {
         common_type::bytes_table_var
       ],
     )
-      .into()
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
   ];
 }
 
@@ -102,8 +135,8 @@ impl Default for Read {
         node: None,
         requiring: Vec::new(),
       },
-      block: None,
-      timeout: get_timeout(),
+      block: ParamVar::new(().into()),
+      timeout: ParamVar::new(().into()),
       options: ParamVar::new(().into()),
       output: ClonedVar(Var::default()),
     }
@@ -114,34 +147,25 @@ impl Read {
   async fn activate_async<'a>(
     data: &EthData,
     input: &Var,
-    block: Option<BlockId>,
+    block: Option<BlockSelector>,
     timeout_: Duration,
     options: Option<Table>,
+    retry: &RetryPolicy,
   ) -> Result<MyTokens, &'a str> {
     let method = data.method.to_str().or_else(|_| Err("Invalid string"))?;
-    let tokens = var_to_tokens(input, &data.input_types)?;
-    let contract_a = data
-      .contract
-      .as_ref()
-      .ok_or_else(|| "Failed to unwrap contract data shared pointer")?;
-    let contract_o = &**contract_a;
-    let contract = contract_o
-      .as_ref()
-      .ok_or_else(|| "Failed to unwrap contract data, was empty")?;
+    let contract = get_shared(&data.contract)?;
+    let names = input_names(input, &data.input_types);
+    let names = resolve_names(&contract.node, names, timeout_).await?;
+    let tokens = var_to_tokens(input, &data.input_types, &names)?;
 
     let from: Option<Address> = {
       if let Some(from_str) = &data.from {
         let s = from_str.to_str().or_else(|_| Err("Invalid string"))?;
         if s.len() > 0 {
-          if s.starts_with("0x") {
-            let subs: &str = &s[2..];
-            Some(
-              subs
-                .parse()
-                .or_else(|_| Err("Failed to parse From address"))?,
-            )
+          if is_name(s) {
+            Some(resolve_name(&contract.node, s, timeout_).await?)
           } else {
-            Some(s.parse().or_else(|_| Err("Failed to parse From address"))?)
+            Some(parse_address(s, "Failed to parse From address")?)
           }
         } else {
           None
@@ -189,21 +213,38 @@ impl Read {
       }
     };
 
-    let fut = contract.contract.query(
-      method,
-      // notice as_slice is necessary to make the "into" jigsaw fall into pieces
-      tokens.as_slice(),
+    let function = contract.contract.abi().function(method).or_else(|e| {
+      cblog!("web3 error: {}", e);
+      Err("Could not fetch function from contracts' abi")
+    })?;
+    // notice as_slice is necessary to make the "into" jigsaw fall into pieces
+    let encoded = function.encode_input(tokens.as_slice()).or_else(|e| {
+      cblog!("web3 error: {}", e);
+      Err("Failed to encode input")
+    })?;
+    let request = CallRequest {
       from,
-      opts,
-      block,
-    );
-    let timed_fut = timeout(timeout_, fut);
-    let result: Result<MyTokens, Error> =
+      to: Some(contract.contract.address()),
+      gas: opts.gas,
+      gas_price: opts.gas_price,
+      value: opts.value,
+      data: Some(Bytes(encoded)),
+      transaction_type: None,
+      access_list: None,
+    };
+    let transport = contract.node.web3.transport();
+    let timed_fut = retry.timeout(timeout_, || eth_call(transport, &request, block));
+    let result: Result<Bytes, web3::Error> =
       timed_fut.await.or_else(|_| Err("RPC request timed out"))?;
-    result.or_else(|e| {
+    let output = result.or_else(|e| {
+      cblog!("query error: {}", e);
+      Err("Failed to detokenize into Var")
+    })?;
+    let tokens = function.decode_output(&output.0).or_else(|e| {
       cblog!("query error: {}", e);
       Err("Failed to detokenize into Var")
-    })
+    })?;
+    Ok(MyTokens(tokens))
   }
 }
 
@@ -235,18 +276,9 @@ impl Block for Read {
       0 => self.cu.instance.set_param(value),
       1 => self.cu.data.method = value.try_into().unwrap_or(CString::new("").unwrap()),
       2 => self.cu.from.set_param(value),
-      3 => {
-        if value.is_none() {
-          self.block = None;
-        } else {
-          if let Ok(nblock) = u64::try_from(value) {
-            self.block = Some(BlockId::Number(nblock.into()));
-          } else {
-            self.block = None;
-          }
-        }
-      }
+      3 => self.block.set_param(value),
       4 => self.options.set_param(value),
+      5 => self.timeout.set_param(value),
       _ => unreachable!(),
     }
   }
@@ -256,20 +288,9 @@ impl Block for Read {
       0 => self.cu.instance.get_param(),
       1 => self.cu.data.method.as_ref().into(),
       2 => self.cu.from.get_param(),
-      3 => {
-        if let Some(blockid) = self.block {
-          match blockid {
-            BlockId::Number(n) => match n {
-              BlockNumber::Number(nn) => nn.as_u64().try_into().unwrap_or(Var::default()),
-              _ => unreachable!(),
-            },
-            _ => unreachable!(),
-          }
-        } else {
-          Var::default()
-        }
-      }
+      3 => self.block.get_param(),
       4 => self.options.get_param(),
+      5 => self.timeout.get_param(),
       _ => Var::default(),
     }
   }
@@ -293,13 +314,17 @@ impl Block for Read {
 
     self.cu.instance.warmup(context);
     self.cu.from.warmup(context);
+    self.block.warmup(context);
     self.options.warmup(context);
+    self.timeout.warmup(context);
 
     Ok(())
   }
 
   fn cleanup(&mut self) {
+    self.timeout.cleanup();
     self.options.cleanup();
+    self.block.cleanup();
     self.cu.from.cleanup();
     self.cu.instance.cleanup();
 
@@ -309,14 +334,11 @@ impl Block for Read {
 
   fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
     if self.cu.data.contract.is_none() {
-      self.cu.data.contract = Some(Var::from_object_as_clone::<Option<ContractData>>(
-        self.cu.instance.get(),
-        &CONTRACT_TYPE,
-      )?);
+      self.cu.data.contract = Some(shared_from_var(self.cu.instance.get(), &CONTRACT_TYPE)?);
 
       self.cu.data.from = (&self.cu.from.get()).try_into()?;
 
-      let contract = Var::get_mut_from_clone(&self.cu.data.contract)?;
+      let contract = get_shared(&self.cu.data.contract)?;
       let method = self
         .cu
         .data
@@ -325,14 +347,14 @@ impl Block for Read {
         .or_else(|_| Err("Invalid string"))?;
       self.cu.data.input_types = gather_inputs(method, &contract.json_abi)?;
       // also populate node data here
-      self.cu.node = Some(Var::from_object_as_clone::<Option<NodeData>>(
-        contract.node,
-        &NODE_TYPE,
-      )?);
+      self.cu.node = Some(contract.node.clone());
     }
 
     Ok(do_blocking(context, || -> Result<Var, &str> {
-      let node = Var::get_mut_from_clone(&self.cu.node)?;
+      let node = get_shared(&self.cu.node)?;
+
+      let timeout = get_block_timeout(&self.timeout, node)?;
+      let block = BlockSelector::from_var(&self.block.get())?;
 
       let options: Option<Table> = {
         let optvar = self.options.get();
@@ -343,12 +365,13 @@ impl Block for Read {
         }
       };
 
-      let tokens = node.scheduler.block_on(Read::activate_async(
+      let tokens = RUNTIME.block_on(Read::activate_async(
         &mut self.cu.data,
         input,
-        self.block,
-        self.timeout,
+        block,
+        timeout,
         options,
+        &node.retry,
       ))?;
       match tokens_to_var(tokens, &mut self.output) {
         Err(error) => Err(error),
@@ -357,3 +380,62 @@ impl Block for Read {
     }))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::CONTRACT;
+  use ethabi::Token;
+  use jsonrpc_core::serde_json::json;
+
+  fn read(data: &EthData, block: Option<BlockSelector>) -> Result<MyTokens, &'static str> {
+    let retry = RetryPolicy::default();
+    let input = Var::default();
+    RUNTIME.block_on(Read::activate_async(
+      data,
+      &input,
+      block,
+      Duration::from_secs(5),
+      None,
+      &retry,
+    ))
+  }
+
+  #[test]
+  fn calls_the_contract() {
+    let node = MockNode::start();
+    node.expect("eth_call", json!(format!("0x{:064x}", 42)));
+    let data = node.eth_data("totalSupply");
+    assert_eq!(read(&data, None).unwrap().0, vec![Token::Uint(42.into())]);
+
+    let call = &node.requests("eth_call")[0];
+    assert_eq!(call.params[0]["to"], format!("0x{}", CONTRACT));
+    assert_eq!(call.params[0]["data"], "0x18160ddd");
+    assert_eq!(call.params[1], "latest");
+  }
+
+  #[test]
+  fn reads_at_a_given_block() {
+    let node = MockNode::start();
+    node.expect("eth_call", json!(format!("0x{:064x}", 42)));
+    let data = node.eth_data("totalSupply");
+    read(&data, Some(BlockSelector::Number(0x10))).unwrap();
+    read(&data, Some(BlockSelector::Tag("finalized"))).unwrap();
+    let calls = node.requests("eth_call");
+    assert_eq!(calls[0].params[1], "0x10");
+    assert_eq!(calls[1].params[1], "finalized");
+  }
+
+  #[test]
+  fn reverts_are_errors() {
+    let node = MockNode::start();
+    node.expect_error("eth_call", 3, "execution reverted");
+    let data = node.eth_data("totalSupply");
+    assert_eq!(
+      read(&data, None).err(),
+      Some("Failed to detokenize into Var")
+    );
+    assert_eq!(node.count("eth_call"), 1);
+  }
+}
@@ -1,16 +1,30 @@
-use crate::blocks::get_timeout;
+use crate::blocks::address::parse_address;
+use crate::blocks::ens::input_names;
+use crate::blocks::ens::is_name;
+use crate::blocks::ens::resolve_name;
+use crate::blocks::ens::resolve_names;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::retry::RetryPolicy;
+use crate::blocks::selector::eth_call;
+use crate::blocks::selector::BlockSelector;
+use crate::blocks::shared_from_var;
 use crate::blocks::tokens::gather_inputs;
 use crate::blocks::tokens::tokens_to_var;
 use crate::blocks::tokens::var_to_tokens;
 use crate::blocks::tokens::MyTokens;
 use crate::blocks::ContractUser;
+use crate::blocks::EthData;
 use crate::blocks::Transport;
-use crate::blocks::{ContractData, EthData, NodeData};
-use crate::blocks::{CONTRACT_TYPE, CONTRACT_VAR, NODE_TYPE};
+use crate::blocks::BLOCK_HASH_TYPE;
+use crate::blocks::BLOCK_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
+use crate::blocks::{CONTRACT_TYPE, CONTRACT_VAR};
 use chainblocks::block::Block;
 use chainblocks::cblog;
 use chainblocks::core::do_blocking;
-use chainblocks::core::log;
 use chainblocks::cstr;
 use chainblocks::types::common_type;
 use chainblocks::types::Context;
@@ -28,20 +42,17 @@ use std::ffi::CStr;
 use std::ffi::CString;
 use std::str;
 use std::time::Duration;
-use tokio::time::timeout;
 use web3::contract::Options;
 use web3::transports::Batch;
 use web3::types::Address;
-use web3::types::BlockId;
-use web3::types::BlockNumber;
 use web3::types::Bytes;
 use web3::types::CallRequest;
 use web3::types::U256;
 
 pub struct ReadBatch {
   cu: ContractUser,
-  block: Option<BlockId>,
-  timeout: Duration,
+  block: ParamVar,
+  timeout: ParamVar,
   options: ParamVar,
   output: Vec<ClonedVar>,
 }
@@ -64,7 +75,7 @@ lazy_static! {
       .into(),
     (
       cstr!("From"),
-      cstr!("The optional address we are calling from"),
+      cstr!("The optional address we are calling from, or an ENS name"),
       vec![
         common_type::none,
         common_type::string,
@@ -74,8 +85,17 @@ lazy_static! {
       .into(),
     (
       cstr!("Block"),
-      cstr!("The optional block number to read from history."),
-      vec![common_type::none, common_type::int],
+      cstr!(
+        "The optional block to read from history: a number, a tag like safe or finalized, or a block hash."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::string,
+        common_type::bytes,
+        BLOCK_HASH_TYPE,
+        BLOCK_VAR
+      ],
     )
       .into(),
     (
@@ -87,7 +107,20 @@ lazy_static! {
         common_type::bytes_table_var
       ],
     )
-      .into()
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
   ];
 }
 
@@ -106,8 +139,8 @@ impl Default for ReadBatch {
         node: None,
         requiring: Vec::new(),
       },
-      block: None,
-      timeout: get_timeout(),
+      block: ParamVar::new(().into()),
+      timeout: ParamVar::new(().into()),
       options: ParamVar::new(().into()),
       output: Vec::new(),
     }
@@ -118,34 +151,23 @@ impl ReadBatch {
   async fn activate_async<'a>(
     data: &EthData,
     input: &Var,
-    block: Option<BlockId>,
+    block: Option<BlockSelector>,
     timeout_: Duration,
     options: Option<Table>,
-    transport: Batch<&Transport>,
+    transport: &Transport,
+    retry: &RetryPolicy,
   ) -> Result<Vec<MyTokens>, &'a str> {
     let method = data.method.to_str().or_else(|_| Err("Invalid string"))?;
-    let contract_a = data
-      .contract
-      .as_ref()
-      .ok_or_else(|| "Failed to unwrap contract data shared pointer")?;
-    let contract_o = &**contract_a;
-    let contract = contract_o
-      .as_ref()
-      .ok_or_else(|| "Failed to unwrap contract data, was empty")?;
+    let contract = get_shared(&data.contract)?;
 
     let from: Option<Address> = {
       if let Some(from_str) = &data.from {
         let s = from_str.to_str().or_else(|_| Err("Invalid string"))?;
         if s.len() > 0 {
-          if s.starts_with("0x") {
-            let subs: &str = &s[2..];
-            Some(
-              subs
-                .parse()
-                .or_else(|_| Err("Failed to parse From address"))?,
-            )
+          if is_name(s) {
+            Some(resolve_name(&contract.node, s, timeout_).await?)
           } else {
-            Some(s.parse().or_else(|_| Err("Failed to parse From address"))?)
+            Some(parse_address(s, "Failed to parse From address")?)
           }
         } else {
           None
@@ -194,15 +216,16 @@ impl ReadBatch {
     };
 
     let contract_addr = contract.contract.address();
-    let web3 = web3::Web3::new(transport.clone());
     let func = contract.contract.abi().function(method).or_else(|e| {
       cblog!("web3 error: {}", e);
       Err("Could not fetch function from contracts' abi")
     })?;
     if let Ok(datas) = Seq::try_from(input) {
-      let mut results = Vec::new();
+      let mut requests = Vec::new();
       for single in datas {
-        let tokens = var_to_tokens(&single, &data.input_types)?;
+        let names = input_names(&single, &data.input_types);
+        let names = resolve_names(&contract.node, names, timeout_).await?;
+        let tokens = var_to_tokens(&single, &data.input_types, &names)?;
         let encoded = func.encode_input(&tokens).or_else(|e| {
           cblog!("web3 error: {}", e);
           Err("Failed to encode input")
@@ -217,11 +240,10 @@ impl ReadBatch {
           transaction_type: None, // I think this is one of the latest EIPs/updates, might be useful
           access_list: None,
         };
-        results.push(web3.eth().call(req, block));
+        requests.push(req);
       }
-      let fut = transport.submit_batch();
-      let timed_fut = timeout(timeout_, fut);
-      timed_fut
+      let timed_fut = retry.timeout(timeout_, || call_batch(transport, &requests, block));
+      let results = timed_fut
         .await
         .or_else(|_| Err("Batch request timed out"))?
         .or_else(|e| {
@@ -229,11 +251,7 @@ impl ReadBatch {
           Err("Failed to execute batch")
         })?;
       let mut vars = Vec::new();
-      for result in results {
-        let bytes = result.await.or_else(|e| {
-          cblog!("web3 error: {}", e);
-          Err("A batch operation has failed")
-        })?;
+      for bytes in results {
         let output = func.decode_output(&bytes.0).or_else(|e| {
           cblog!("web3 error: {}", e);
           Err("A batch operation has failed")
@@ -247,6 +265,25 @@ impl ReadBatch {
   }
 }
 
+// a fresh batch for every attempt, as a submitted one can't be sent again
+async fn call_batch(
+  transport: &Transport,
+  requests: &[CallRequest],
+  block: Option<BlockSelector>,
+) -> web3::Result<Vec<Bytes>> {
+  let batch = Batch::new(transport);
+  let results: Vec<_> = requests
+    .iter()
+    .map(|req| eth_call(&batch, req, block))
+    .collect();
+  batch.submit_batch().await?;
+  let mut outputs = Vec::new();
+  for result in results {
+    outputs.push(result.await?);
+  }
+  Ok(outputs)
+}
+
 impl Block for ReadBatch {
   fn hash() -> u32 {
     compile_time_crc32::crc32!("Eth.ReadBatch-rust-0x20200101")
@@ -275,18 +312,9 @@ impl Block for ReadBatch {
       0 => self.cu.instance.set_param(value),
       1 => self.cu.data.method = value.try_into().unwrap_or(CString::new("").unwrap()),
       2 => self.cu.from.set_param(value),
-      3 => {
-        if value.is_none() {
-          self.block = None;
-        } else {
-          if let Ok(nblock) = u64::try_from(value) {
-            self.block = Some(BlockId::Number(nblock.into()));
-          } else {
-            self.block = None;
-          }
-        }
-      }
+      3 => self.block.set_param(value),
       4 => self.options.set_param(value),
+      5 => self.timeout.set_param(value),
       _ => unreachable!(),
     }
   }
@@ -296,20 +324,9 @@ impl Block for ReadBatch {
       0 => self.cu.instance.get_param(),
       1 => self.cu.data.method.as_ref().into(),
       2 => self.cu.from.get_param(),
-      3 => {
-        if let Some(blockid) = self.block {
-          match blockid {
-            BlockId::Number(n) => match n {
-              BlockNumber::Number(nn) => nn.as_u64().try_into().unwrap_or(Var::default()),
-              _ => unreachable!(),
-            },
-            _ => unreachable!(),
-          }
-        } else {
-          Var::default()
-        }
-      }
+      3 => self.block.get_param(),
       4 => self.options.get_param(),
+      5 => self.timeout.get_param(),
       _ => Var::default(),
     }
   }
@@ -333,13 +350,17 @@ impl Block for ReadBatch {
 
     self.cu.instance.warmup(context);
     self.cu.from.warmup(context);
+    self.block.warmup(context);
     self.options.warmup(context);
+    self.timeout.warmup(context);
 
     Ok(())
   }
 
   fn cleanup(&mut self) {
+    self.timeout.cleanup();
     self.options.cleanup();
+    self.block.cleanup();
     self.cu.from.cleanup();
     self.cu.instance.cleanup();
 
@@ -349,15 +370,12 @@ impl Block for ReadBatch {
 
   fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
     if self.cu.data.contract.is_none() {
-      self.cu.data.contract = Some(Var::from_object_as_clone::<Option<ContractData>>(
-        self.cu.instance.get(),
-        &CONTRACT_TYPE,
-      )?);
+      self.cu.data.contract = Some(shared_from_var(self.cu.instance.get(), &CONTRACT_TYPE)?);
 
       self.cu.data.from = (&self.cu.from.get()).try_into()?;
 
       // also init input_types from json
-      let contract = Var::get_mut_from_clone(&self.cu.data.contract)?;
+      let contract = get_shared(&self.cu.data.contract)?;
       let method = self
         .cu
         .data
@@ -366,17 +384,14 @@ impl Block for ReadBatch {
         .or_else(|_| Err("Invalid string"))?;
       self.cu.data.input_types = gather_inputs(method, &contract.json_abi)?;
       // also populate node data here
-      self.cu.node = Some(Var::from_object_as_clone::<Option<NodeData>>(
-        contract.node,
-        &NODE_TYPE,
-      )?);
+      self.cu.node = Some(contract.node.clone());
     }
 
     Ok(do_blocking(context, || -> Result<Var, &str> {
-      let node = Var::get_mut_from_clone(&self.cu.node)?;
-      let (scheduler, t) = (&mut node.scheduler, node.web3.transport());
-
-      let bt = Batch::new(t);
+      let node = get_shared(&self.cu.node)?;
+      let timeout = get_block_timeout(&self.timeout, node)?;
+      let (t, retry) = (node.web3.transport(), &node.retry);
+      let block = BlockSelector::from_var(&self.block.get())?;
 
       let options: Option<Table> = {
         let optvar = self.options.get();
@@ -387,13 +402,14 @@ impl Block for ReadBatch {
         }
       };
 
-      let tokens_seq = scheduler.block_on(ReadBatch::activate_async(
+      let tokens_seq = RUNTIME.block_on(ReadBatch::activate_async(
         &mut self.cu.data,
         input,
-        self.block,
-        self.timeout,
+        block,
+        timeout,
         options,
-        bt,
+        t,
+        retry,
       ))?;
       self.output.clear();
       for tokens in tokens_seq {
@@ -408,3 +424,51 @@ impl Block for ReadBatch {
     }))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::CONTRACT;
+  use jsonrpc_core::serde_json::json;
+  use web3::types::H256;
+
+  #[test]
+  fn sends_every_call_in_one_batch() {
+    let node = MockNode::start();
+    node.expect("eth_call", json!(format!("0x{:064x}", 42)));
+    let eth = node.node();
+    let request = CallRequest {
+      to: Some(CONTRACT.parse().unwrap()),
+      data: Some(vec![0x18, 0x16, 0x0d, 0xdd].into()),
+      ..Default::default()
+    };
+    let requests = vec![request.clone(), request];
+    let hash = format!("0x{}", "ab".repeat(32));
+    let block = Some(BlockSelector::Hash {
+      hash: H256::repeat_byte(0xab),
+      require_canonical: true,
+    });
+
+    let outputs = RUNTIME.block_on(call_batch(eth.web3.transport(), &requests, block));
+    let outputs = outputs.unwrap();
+    assert_eq!(outputs.len(), 2);
+    assert_eq!(U256::from(&outputs[1].0[..]), 42.into());
+    let calls = node.requests("eth_call");
+    assert_eq!(calls.len(), 2);
+    assert_eq!(
+      calls[0].params[1],
+      json!({ "blockHash": hash, "requireCanonical": true })
+    );
+  }
+
+  #[test]
+  fn any_failure_fails_the_batch() {
+    let node = MockNode::start();
+    node.expect_error("eth_call", 3, "execution reverted");
+    let eth = node.node();
+    let requests = vec![CallRequest::default()];
+    let outputs = RUNTIME.block_on(call_batch(eth.web3.transport(), &requests, None));
+    assert!(outputs.is_err());
+  }
+}
@@ -0,0 +1,322 @@
+use crate::blocks::log;
+use crate::blocks::multi::Connection;
+use chainblocks::cblog;
+use futures::future;
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use jsonrpc_core::serde_json;
+use jsonrpc_core::types::Call;
+use jsonrpc_core::types::Error as RpcError;
+use jsonrpc_core::types::Params;
+use jsonrpc_core::types::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use web3::error::Error;
+use web3::error::TransportError;
+use web3::RequestId;
+
+/// Forwards requests to another connection and writes every call with its
+/// answer to a fixture file, one JSON object per line.
+#[derive(Clone)]
+pub struct Recorder {
+  path: String,
+  inner: Box<Connection>,
+  file: Arc<Mutex<File>>,
+}
+
+impl fmt::Debug for Recorder {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Recorder")
+      .field("path", &self.path)
+      .finish()
+  }
+}
+
+impl Recorder {
+  pub fn new(path: &str, inner: Connection) -> web3::Result<Recorder> {
+    let file = File::create(path).or_else(|e| {
+      Err(message_error(format!(
+        "Failed to create fixture {}: {}",
+        path, e
+      )))
+    })?;
+    Ok(Recorder {
+      path: path.to_owned(),
+      inner: Box::new(inner),
+      file: Arc::new(Mutex::new(file)),
+    })
+  }
+
+  pub fn send(&self, id: RequestId, request: Call) -> BoxFuture<'static, web3::Result<Value>> {
+    let response = self.inner.send(id, request.clone());
+    let file = self.file.clone();
+    async move {
+      let result = response.await;
+      record(&file, &request, &result);
+      result
+    }
+    .boxed()
+  }
+
+  pub fn send_batch(
+    &self,
+    requests: Vec<(RequestId, Call)>,
+  ) -> BoxFuture<'static, web3::Result<Vec<web3::Result<Value>>>> {
+    let calls: Vec<Call> = requests.iter().map(|(_, call)| call.clone()).collect();
+    let response = self.inner.send_batch(requests);
+    let file = self.file.clone();
+    async move {
+      let results = response.await?;
+      for (call, result) in calls.iter().zip(&results) {
+        record(&file, call, result);
+      }
+      Ok(results)
+    }
+    .boxed()
+  }
+}
+
+// node errors are part of the fixture, transport failures are not
+fn record(file: &Mutex<File>, request: &Call, result: &web3::Result<Value>) {
+  let (method, params) = match request {
+    Call::MethodCall(call) => (&call.method, &call.params),
+    _ => return,
+  };
+  let entry = match result {
+    Ok(value) => serde_json::json!({ "method": method, "params": params, "result": value }),
+    Err(Error::Rpc(error)) => {
+      serde_json::json!({ "method": method, "params": params, "error": error })
+    }
+    Err(_) => return,
+  };
+  if let Err(e) = writeln!(file.lock().unwrap(), "{}", entry) {
+    cblog!("Failed to write fixture: {}", e);
+  }
+}
+
+struct Answers {
+  answers: Vec<Result<Value, RpcError>>,
+  next: usize,
+}
+
+/// Answers requests from a fixture written by a Recorder, without any network.
+/// The same call gets its recorded answers in order, then the last one again.
+#[derive(Clone)]
+pub struct Replayer {
+  path: String,
+  calls: Arc<Mutex<BTreeMap<String, Answers>>>,
+}
+
+impl fmt::Debug for Replayer {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Replayer")
+      .field("path", &self.path)
+      .finish()
+  }
+}
+
+impl Replayer {
+  pub fn new(path: &str) -> web3::Result<Replayer> {
+    let file = File::open(path).or_else(|e| {
+      Err(message_error(format!(
+        "Failed to open fixture {}: {}",
+        path, e
+      )))
+    })?;
+    let mut calls: BTreeMap<String, Answers> = BTreeMap::new();
+    for line in BufReader::new(file).lines() {
+      let line = line.or_else(|e| Err(message_error(format!("Failed to read fixture: {}", e))))?;
+      if line.trim().is_empty() {
+        continue;
+      }
+      let mut entry: serde_json::Map<String, Value> = serde_json::from_str(&line)
+        .or_else(|e| Err(message_error(format!("Invalid fixture line: {}", e))))?;
+      let method = match entry.remove("method") {
+        Some(Value::String(method)) => method,
+        _ => return Err(message_error("Fixture line without a method".into())),
+      };
+      let params: Params = serde_json::from_value(entry.remove("params").unwrap_or(Value::Null))
+        .or_else(|e| Err(message_error(format!("Invalid fixture params: {}", e))))?;
+      let answer = match (entry.remove("result"), entry.remove("error")) {
+        (_, Some(error)) => Err(
+          serde_json::from_value(error)
+            .or_else(|e| Err(message_error(format!("Invalid fixture error: {}", e))))?,
+        ),
+        (Some(result), None) => Ok(result),
+        (None, None) => return Err(message_error("Fixture line without an answer".into())),
+      };
+      calls
+        .entry(key(&method, &params))
+        .or_insert_with(|| Answers {
+          answers: Vec::new(),
+          next: 0,
+        })
+        .answers
+        .push(answer);
+    }
+    Ok(Replayer {
+      path: path.to_owned(),
+      calls: Arc::new(Mutex::new(calls)),
+    })
+  }
+
+  fn answer(&self, request: &Call) -> web3::Result<Value> {
+    let (method, params) = match request {
+      Call::MethodCall(call) => (&call.method, &call.params),
+      _ => return Ok(Value::Null),
+    };
+    let key = key(method, params);
+    let mut calls = self.calls.lock().unwrap();
+    match calls.get_mut(&key) {
+      Some(call) => {
+        let idx = call.next.min(call.answers.len() - 1);
+        call.next += 1;
+        call.answers[idx].clone().map_err(Error::Rpc)
+      }
+      None => {
+        cblog!("No recorded answer in {} for {}", self.path, key);
+        // not worth retrying, the fixture will not change
+        Err(Error::InvalidResponse(format!(
+          "No recorded answer for {}",
+          method
+        )))
+      }
+    }
+  }
+
+  pub fn send(&self, _id: RequestId, request: Call) -> BoxFuture<'static, web3::Result<Value>> {
+    future::ready(self.answer(&request)).boxed()
+  }
+
+  pub fn send_batch(
+    &self,
+    requests: Vec<(RequestId, Call)>,
+  ) -> BoxFuture<'static, web3::Result<Vec<web3::Result<Value>>>> {
+    let results = requests
+      .iter()
+      .map(|(_, request)| self.answer(request))
+      .collect();
+    future::ready(Ok(results)).boxed()
+  }
+}
+
+// ids change from run to run, calls are matched on method and params only
+fn key(method: &str, params: &Params) -> String {
+  format!(
+    "{}:{}",
+    method,
+    serde_json::to_string(params).unwrap_or_default()
+  )
+}
+
+fn message_error(message: String) -> Error {
+  Error::Transport(TransportError::Message(message))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::auth::Auth;
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::retry::RetryPolicy;
+  use crate::blocks::RUNTIME;
+  use std::env;
+  use web3::helpers::build_request;
+
+  fn fixture(name: &str) -> String {
+    let path = env::temp_dir().join(format!("cb-{}-{}.jsonl", name, std::process::id()));
+    path.to_str().unwrap().to_owned()
+  }
+
+  fn send(connection: &Connection, method: &str, params: Vec<Value>) -> web3::Result<Value> {
+    RUNTIME.block_on(connection.send(1, build_request(1, method, params)))
+  }
+
+  fn open(uri: &str) -> Connection {
+    RUNTIME
+      .block_on(Connection::open(uri, Arc::new(Auth::default())))
+      .unwrap()
+  }
+
+  #[test]
+  fn replays_what_was_recorded() {
+    let node = MockNode::start();
+    node.expect_times("eth_blockNumber", 1, quantity(1));
+    node.expect_times("eth_blockNumber", 1, quantity(2));
+    node.expect_error("eth_call", 3, "execution reverted");
+    let path = fixture("record");
+
+    let recorder = open(&format!("record://{}#{}", path, node.http_url()));
+    assert_eq!(
+      send(&recorder, "eth_blockNumber", vec![]).unwrap(),
+      quantity(2)
+    );
+    assert_eq!(
+      send(&recorder, "eth_blockNumber", vec![]).unwrap(),
+      quantity(1)
+    );
+    assert!(send(&recorder, "eth_call", vec![Value::Null]).is_err());
+    drop(recorder);
+
+    let replayer = open(&format!("replay://{}", path));
+    assert_eq!(
+      send(&replayer, "eth_blockNumber", vec![]).unwrap(),
+      quantity(2)
+    );
+    assert_eq!(
+      send(&replayer, "eth_blockNumber", vec![]).unwrap(),
+      quantity(1)
+    );
+    // the last answer repeats once the recorded ones are used up
+    assert_eq!(
+      send(&replayer, "eth_blockNumber", vec![]).unwrap(),
+      quantity(1)
+    );
+    match send(&replayer, "eth_call", vec![Value::Null]) {
+      Err(Error::Rpc(e)) => assert_eq!(e.message, "execution reverted"),
+      other => panic!("unexpected {:?}", other),
+    }
+    // params are part of the call, and a miss fails right away
+    let miss = send(&replayer, "eth_call", vec![Value::Bool(true)]).unwrap_err();
+    assert!(matches!(miss, Error::InvalidResponse(_)));
+    assert!(!RetryPolicy::default().is_retryable(&miss));
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn records_batches() {
+    let node = MockNode::start();
+    node.expect("eth_gasPrice", quantity(7));
+    let path = fixture("batch");
+
+    let recorder = open(&format!("record://{}#{}", path, node.http_url()));
+    let batch = vec![
+      (1, build_request(1, "eth_gasPrice", vec![])),
+      (2, build_request(2, "eth_chainId", vec![])),
+    ];
+    RUNTIME
+      .block_on(recorder.send_batch(batch.clone()))
+      .unwrap();
+    drop(recorder);
+
+    let replayer = open(&format!("replay://{}", path));
+    let results = RUNTIME.block_on(replayer.send_batch(batch)).unwrap();
+    assert_eq!(results[0].as_ref().unwrap(), &quantity(7));
+    assert_eq!(results[1].as_ref().unwrap(), &quantity(1337));
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn missing_fixtures_are_reported() {
+    let uri = format!("replay://{}", fixture("missing"));
+    let opened = RUNTIME.block_on(Connection::open(&uri, Arc::new(Auth::default())));
+    assert!(opened.is_err());
+  }
+}
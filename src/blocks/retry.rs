@@ -0,0 +1,290 @@
+use crate::blocks::log;
+use chainblocks::cblog;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::time::sleep;
+use tokio::time::timeout;
+use web3::api::Eth;
+use web3::error::Error;
+use web3::error::TransportError;
+use web3::signing::keccak256;
+use web3::types::Bytes;
+use web3::types::TransactionId;
+use web3::types::H256;
+use web3::Transport;
+
+/// Returned when every attempt of a request timed out.
+#[derive(Debug)]
+pub struct TimedOut;
+
+/// Errors that might wrap a transport/RPC failure worth retrying.
+pub trait Retryable {
+  fn api_error(&self) -> Option<&Error>;
+}
+
+impl Retryable for Error {
+  fn api_error(&self) -> Option<&Error> {
+    Some(self)
+  }
+}
+
+impl Retryable for web3::contract::Error {
+  fn api_error(&self) -> Option<&Error> {
+    match self {
+      web3::contract::Error::Api(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+pub struct RetryPolicy {
+  /// Total attempts, including the first one.
+  pub max_attempts: u32,
+  pub backoff: Duration,
+  pub max_backoff: Duration,
+  /// Fraction of the delay randomly added or removed, 0.0 to 1.0.
+  pub jitter: f64,
+  pub rpc_codes: Vec<i64>,
+  pub http_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy {
+      max_attempts: 3,
+      backoff: Duration::from_millis(250),
+      max_backoff: Duration::from_secs(10),
+      jitter: 0.2,
+      rpc_codes: vec![-32005, -32603],
+      http_statuses: vec![429, 502, 503],
+    }
+  }
+}
+
+impl RetryPolicy {
+  pub fn is_retryable<E: Retryable>(&self, error: &E) -> bool {
+    match error.api_error() {
+      Some(Error::Unreachable) | Some(Error::Io(_)) => true,
+      Some(Error::Transport(TransportError::Code(code))) => self.http_statuses.contains(code),
+      // connection level failures
+      Some(Error::Transport(TransportError::Message(_))) => true,
+      Some(Error::Rpc(e)) => self.rpc_codes.contains(&e.code.code()),
+      _ => false,
+    }
+  }
+
+  /// The wait before the attempt after this one.
+  pub fn delay(&self, attempt: u32) -> Duration {
+    let exp = self.backoff.as_secs_f64() * 2f64.powi(attempt.saturating_sub(1).min(16) as i32);
+    let exp = exp.min(self.max_backoff.as_secs_f64());
+    let jitter = self.jitter.max(0.0).min(1.0) * (random_unit() * 2.0 - 1.0);
+    Duration::from_secs_f64((exp * (1.0 + jitter)).max(0.0))
+  }
+
+  /// Like `tokio::time::timeout` but calls `request` again on retryable failures.
+  /// The timeout applies to every single attempt.
+  pub async fn timeout<T, E, F, Fut>(
+    &self,
+    duration: Duration,
+    mut request: F,
+  ) -> Result<Result<T, E>, TimedOut>
+  where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Retryable + Display,
+  {
+    let mut attempt = 1;
+    loop {
+      let outcome = timeout(duration, request()).await;
+      let retry = match &outcome {
+        Ok(Ok(_)) => false,
+        Ok(Err(e)) => self.is_retryable(e),
+        Err(_) => true,
+      };
+      if !retry || attempt >= self.max_attempts {
+        return outcome.or_else(|_| Err(TimedOut));
+      }
+      let delay = self.delay(attempt);
+      match &outcome {
+        Ok(Err(e)) => cblog!("RPC request failed: {}, retrying in {:?}", e, delay),
+        _ => cblog!("RPC request timed out, retrying in {:?}", delay),
+      }
+      sleep(delay).await;
+      attempt += 1;
+    }
+  }
+
+  /// Broadcasts a signed transaction, retrying only when it can be proven
+  /// the node does not know about it yet, so it is never sent twice.
+  pub async fn send_raw<T: Transport>(
+    &self,
+    eth: &Eth<T>,
+    raw: Bytes,
+    duration: Duration,
+  ) -> Result<Result<H256, Error>, TimedOut> {
+    let hash: H256 = keccak256(&raw.0).into();
+    let mut attempt = 1;
+    loop {
+      let outcome = timeout(duration, eth.send_raw_transaction(raw.clone())).await;
+      match outcome {
+        Ok(Ok(hash)) => return Ok(Ok(hash)),
+        Ok(Err(e)) if is_already_known(&e) => return Ok(Ok(hash)),
+        Ok(Err(e)) if !self.is_retryable(&e) || attempt >= self.max_attempts => return Ok(Err(e)),
+        Err(_) if attempt >= self.max_attempts => return Err(TimedOut),
+        _ => {}
+      }
+
+      sleep(self.delay(attempt)).await;
+
+      // the node might have accepted it even if we lost the answer
+      let known = timeout(duration, eth.transaction(TransactionId::Hash(hash))).await;
+      if let Ok(Ok(Some(_))) = known {
+        return Ok(Ok(hash));
+      }
+      cblog!(
+        "Transaction {:?} not found on node, broadcasting again",
+        hash
+      );
+      attempt += 1;
+    }
+  }
+}
+
+fn is_already_known(error: &Error) -> bool {
+  match error {
+    Error::Rpc(e) => {
+      let message = e.message.to_lowercase();
+      message.contains("already known")
+        || message.contains("known transaction")
+        || message.contains("already imported")
+    }
+    _ => false,
+  }
+}
+
+// cheap randomness for jitter, no need for anything stronger
+fn random_unit() -> f64 {
+  let nanos = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.subsec_nanos())
+    .unwrap_or(0);
+  let mut x = nanos as u64 ^ 0x9E37_79B9_7F4A_7C15;
+  x ^= x << 13;
+  x ^= x >> 7;
+  x ^= x << 17;
+  (x % 1_000_000) as f64 / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::auth::Auth;
+  use crate::blocks::http::Http;
+  use crate::blocks::mock::hash;
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::transaction;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::RUNTIME;
+  use jsonrpc_core::serde_json::json;
+  use std::sync::Arc;
+  use web3::types::U256;
+  use web3::Web3;
+
+  fn policy() -> RetryPolicy {
+    RetryPolicy {
+      backoff: Duration::from_millis(1),
+      ..RetryPolicy::default()
+    }
+  }
+
+  fn eth(node: &MockNode) -> Eth<Http> {
+    Web3::new(Http::new(&node.http_url(), Arc::new(Auth::default())).unwrap()).eth()
+  }
+
+  fn gas_price(node: &MockNode, policy: &RetryPolicy) -> Result<Result<U256, Error>, TimedOut> {
+    let eth = eth(node);
+    RUNTIME.block_on(policy.timeout(Duration::from_secs(5), || eth.gas_price()))
+  }
+
+  #[test]
+  fn retries_rate_limited_requests() {
+    let node = MockNode::start();
+    node.expect("eth_gasPrice", quantity(7));
+    node.expect_error_times("eth_gasPrice", 2, -32005, "rate limit exceeded");
+    assert_eq!(gas_price(&node, &policy()).unwrap().unwrap(), 7.into());
+    assert_eq!(node.count("eth_gasPrice"), 3);
+  }
+
+  #[test]
+  fn gives_up_after_max_attempts() {
+    let node = MockNode::start();
+    node.expect_error("eth_gasPrice", -32005, "rate limit exceeded");
+    assert!(gas_price(&node, &policy()).unwrap().is_err());
+    assert_eq!(node.count("eth_gasPrice"), 3);
+  }
+
+  #[test]
+  fn node_errors_are_not_retried() {
+    let node = MockNode::start();
+    node.expect_error("eth_gasPrice", 3, "execution reverted");
+    assert!(gas_price(&node, &policy()).unwrap().is_err());
+    assert_eq!(node.count("eth_gasPrice"), 1);
+  }
+
+  #[test]
+  fn every_attempt_has_its_own_timeout() {
+    let node = MockNode::start();
+    node.expect("eth_gasPrice", quantity(7));
+    node.delay_http(Duration::from_millis(500));
+    let eth = eth(&node);
+    let attempts = policy();
+    let outcome = RUNTIME.block_on(attempts.timeout(Duration::from_millis(50), || eth.gas_price()));
+    assert!(outcome.is_err());
+    assert_eq!(node.count("eth_gasPrice"), 3);
+  }
+
+  #[test]
+  fn known_transactions_are_not_sent_again() {
+    let node = MockNode::start();
+    node.expect_error("eth_sendRawTransaction", -32000, "already known");
+    let raw = Bytes(vec![1, 2, 3]);
+    let expected: H256 = keccak256(&raw.0).into();
+    let eth = eth(&node);
+    let sent = RUNTIME.block_on(policy().send_raw(&eth, raw, Duration::from_secs(5)));
+    assert_eq!(sent.unwrap().unwrap(), expected);
+    assert_eq!(node.count("eth_sendRawTransaction"), 1);
+  }
+
+  #[test]
+  fn lost_answers_are_looked_up_before_sending_again() {
+    let node = MockNode::start();
+    node.expect_error("eth_sendRawTransaction", -32603, "internal error");
+    let raw = Bytes(vec![1, 2, 3]);
+    let expected: H256 = keccak256(&raw.0).into();
+    node.expect(
+      "eth_getTransactionByHash",
+      transaction(&format!("{:?}", expected), 1),
+    );
+    let eth = eth(&node);
+    let sent = RUNTIME.block_on(policy().send_raw(&eth, raw, Duration::from_secs(5)));
+    assert_eq!(sent.unwrap().unwrap(), expected);
+    assert_eq!(node.count("eth_sendRawTransaction"), 1);
+  }
+
+  #[test]
+  fn unknown_transactions_are_sent_again() {
+    let node = MockNode::start();
+    node.expect("eth_sendRawTransaction", json!(hash(1)));
+    node.expect_error_times("eth_sendRawTransaction", 1, -32603, "internal error");
+    node.expect("eth_getTransactionByHash", json!(null));
+    let eth = eth(&node);
+    let raw = Bytes(vec![1, 2, 3]);
+    let sent = RUNTIME.block_on(policy().send_raw(&eth, raw, Duration::from_secs(5)));
+    assert_eq!(format!("{:?}", sent.unwrap().unwrap()), hash(1));
+    assert_eq!(node.count("eth_sendRawTransaction"), 2);
+    assert_eq!(node.count("eth_getTransactionByHash"), 1);
+  }
+}
@@ -0,0 +1,166 @@
+use web3::types::H256;
+use web3::types::U256;
+
+pub enum Rlp<'a> {
+  Bytes(&'a [u8]),
+  // keeps its whole encoding, nodes under 32 bytes are embedded in their parent
+  List(&'a [u8], Vec<Rlp<'a>>),
+}
+
+fn rlp_item<'a>(data: &'a [u8]) -> Result<(Rlp<'a>, usize), &'static str> {
+  let first = *data.first().ok_or("Truncated RLP")?;
+  let (header, length, list) = match first {
+    0x00..=0x7f => return Ok((Rlp::Bytes(&data[..1]), 1)),
+    0x80..=0xb7 => (1, (first - 0x80) as usize, false),
+    0xb8..=0xbf => {
+      let size = (first - 0xb7) as usize;
+      (1 + size, rlp_length(data.get(1..1 + size))?, false)
+    }
+    0xc0..=0xf7 => (1, (first - 0xc0) as usize, true),
+    _ => {
+      let size = (first - 0xf7) as usize;
+      (1 + size, rlp_length(data.get(1..1 + size))?, true)
+    }
+  };
+  let end = header
+    .checked_add(length)
+    .filter(|end| *end <= data.len())
+    .ok_or("Truncated RLP")?;
+  let payload = &data[header..end];
+  if list {
+    let mut items = Vec::new();
+    let mut rest = payload;
+    while !rest.is_empty() {
+      let (item, used) = rlp_item(rest)?;
+      items.push(item);
+      rest = &rest[used..];
+    }
+    Ok((Rlp::List(&data[..end], items), end))
+  } else {
+    Ok((Rlp::Bytes(payload), end))
+  }
+}
+
+fn rlp_length(bytes: Option<&[u8]>) -> Result<usize, &'static str> {
+  let bytes = bytes.ok_or("Truncated RLP")?;
+  if bytes.len() > 4 {
+    return Err("RLP item is too long");
+  }
+  Ok(
+    bytes
+      .iter()
+      .fold(0, |length, byte| length << 8 | *byte as usize),
+  )
+}
+
+pub fn rlp_decode(data: &[u8]) -> Result<Rlp<'_>, &'static str> {
+  let (item, used) = rlp_item(data)?;
+  if used != data.len() {
+    return Err("Trailing bytes after RLP item");
+  }
+  Ok(item)
+}
+
+pub fn rlp_bytes<'a>(item: &Rlp<'a>) -> Result<&'a [u8], &'static str> {
+  match item {
+    Rlp::Bytes(bytes) => Ok(bytes),
+    _ => Err("Expected RLP bytes"),
+  }
+}
+
+pub fn rlp_number(item: &Rlp) -> Result<U256, &'static str> {
+  let bytes = rlp_bytes(item)?;
+  if bytes.len() > 32 {
+    return Err("RLP number is too long");
+  }
+  Ok(U256::from(bytes))
+}
+
+pub fn rlp_hash(item: &Rlp) -> Result<H256, &'static str> {
+  let bytes = rlp_bytes(item)?;
+  if bytes.len() != 32 {
+    return Err("Expected a 32 bytes RLP hash");
+  }
+  Ok(H256::from_slice(bytes))
+}
+
+fn header(offset: u8, length: usize) -> Vec<u8> {
+  if length < 56 {
+    vec![offset + length as u8]
+  } else {
+    let bytes: Vec<u8> = length
+      .to_be_bytes()
+      .iter()
+      .copied()
+      .skip_while(|byte| *byte == 0)
+      .collect();
+    let mut out = vec![offset + 55 + bytes.len() as u8];
+    out.extend(bytes);
+    out
+  }
+}
+
+pub fn encode_bytes(bytes: &[u8]) -> Vec<u8> {
+  if bytes.len() == 1 && bytes[0] < 0x80 {
+    return bytes.to_vec();
+  }
+  let mut out = header(0x80, bytes.len());
+  out.extend_from_slice(bytes);
+  out
+}
+
+/// A list of already encoded items.
+pub fn encode_list(items: &[Vec<u8>]) -> Vec<u8> {
+  let payload: Vec<u8> = items.concat();
+  let mut out = header(0xc0, payload.len());
+  out.extend(payload);
+  out
+}
+
+/// Numbers are big endian without leading zeros, zero is the empty string.
+pub fn encode_number(value: U256) -> Vec<u8> {
+  let bytes: [u8; 32] = value.into();
+  let trimmed: Vec<u8> = bytes
+    .iter()
+    .copied()
+    .skip_while(|byte| *byte == 0)
+    .collect();
+  encode_bytes(&trimmed)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn decodes_rlp() {
+    let long = vec![7u8; 60];
+    let list = encode_list(&[encode_bytes(b"dog"), encode_bytes(&long), encode_bytes(&[])]);
+    match rlp_decode(&list).unwrap() {
+      Rlp::List(raw, items) => {
+        assert_eq!(raw, list.as_slice());
+        assert_eq!(rlp_bytes(&items[0]).unwrap(), b"dog");
+        assert_eq!(rlp_bytes(&items[1]).unwrap(), long.as_slice());
+        assert!(rlp_bytes(&items[2]).unwrap().is_empty());
+      }
+      _ => panic!("expected a list"),
+    }
+    assert!(rlp_decode(&list[..list.len() - 1]).is_err());
+    assert!(rlp_decode(&[0x83, b'd', b'o', b'g', 0]).is_err());
+  }
+
+  #[test]
+  fn encodes_like_the_spec() {
+    assert_eq!(encode_bytes(b"dog"), vec![0x83, b'd', b'o', b'g']);
+    assert_eq!(
+      encode_list(&[encode_bytes(b"cat"), encode_bytes(b"dog")]),
+      vec![0xc8, 0x83, b'c', b'a', b't', 0x83, b'd', b'o', b'g']
+    );
+    assert_eq!(encode_number(0.into()), vec![0x80]);
+    assert_eq!(encode_number(15.into()), vec![0x0f]);
+    assert_eq!(encode_number(1024.into()), vec![0x82, 0x04, 0x00]);
+    assert_eq!(encode_list(&[]), vec![0xc0]);
+    let long = encode_bytes(&[0x61; 56]);
+    assert_eq!(&long[..2], &[0xb8, 56]);
+  }
+}
@@ -0,0 +1,512 @@
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::multi::is_read;
+use crate::blocks::retry::RetryPolicy;
+use crate::blocks::shared_from_var;
+use crate::blocks::NodeData;
+use crate::blocks::Transport;
+use crate::blocks::NODE_TYPE;
+use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
+use chainblocks::block::Block;
+use chainblocks::cblog;
+use chainblocks::core::activate_blocking;
+use chainblocks::core::BlockingBlock;
+use chainblocks::cstr;
+use chainblocks::types::common_type;
+use chainblocks::types::ClonedVar;
+use chainblocks::types::Context;
+use chainblocks::types::ExposedInfo;
+use chainblocks::types::ExposedTypes;
+use chainblocks::types::ParamVar;
+use chainblocks::types::Parameters;
+use chainblocks::types::Seq;
+use chainblocks::types::Table;
+use chainblocks::types::Type;
+use chainblocks::types::Var;
+use jsonrpc_core::serde_json;
+use jsonrpc_core::types::Value;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+use web3::transports::Batch;
+use web3::Transport as _;
+
+pub struct Rpc {
+  method: CString,
+  batch: bool,
+  json: bool,
+  node_param: ParamVar,
+  node: Option<Arc<NodeData>>,
+  timeout: ParamVar,
+  requiring: ExposedTypes,
+  output: ClonedVar,
+  output_json: CString,
+}
+
+impl Default for Rpc {
+  fn default() -> Self {
+    Rpc {
+      method: CString::new("").unwrap(),
+      batch: false,
+      json: false,
+      node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
+      node: None,
+      timeout: ParamVar::new(().into()),
+      requiring: Vec::new(),
+      output: ClonedVar(Var::default()),
+      output_json: CString::new("").unwrap(),
+    }
+  }
+}
+
+lazy_static! {
+  static ref INPUT_TYPES: Vec<Type> = vec![common_type::any];
+  static ref OUTPUT_TYPES: Vec<Type> = vec![common_type::any];
+  static ref JSON_OUTPUT_TYPES: Vec<Type> = vec![common_type::string];
+  static ref PARAMETERS: Parameters = vec![
+    (
+      cstr!("Method"),
+      cstr!("The JSON-RPC method to call, e.g. eth_getProof or anvil_mine."),
+      vec![common_type::string],
+    )
+      .into(),
+    (
+      cstr!("Batch"),
+      cstr!(
+        "If the input is a sequence of params, each sent as a call of Method in a single batch."
+      ),
+      vec![common_type::bool],
+    )
+      .into(),
+    (
+      cstr!("Json"),
+      cstr!("If the result should be output as JSON text instead of chainblocks values."),
+      vec![common_type::bool],
+    )
+      .into(),
+    (
+      cstr!("Node"),
+      cstr!("The ethereum node block variable to use."),
+      vec![NODE_VAR],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
+  ];
+}
+
+impl Rpc {
+  async fn activate_async<'a>(
+    node: &NodeData,
+    method: &str,
+    calls: Vec<Vec<Value>>,
+    batch: bool,
+    timeout_: Duration,
+  ) -> Result<Value, &'a str> {
+    let transport = node.web3.transport();
+    // anything could be called here, only what surely reads is tried again
+    let once = RetryPolicy {
+      max_attempts: 1,
+      ..RetryPolicy::default()
+    };
+    let retry = if is_read(method) { &node.retry } else { &once };
+    let result = if batch {
+      retry
+        .timeout(timeout_, || execute_batch(transport, method, &calls))
+        .await
+        .map(|result| result.map(Value::Array))
+    } else {
+      let params = calls.into_iter().next().unwrap_or_default();
+      retry
+        .timeout(timeout_, || transport.execute(method, params.clone()))
+        .await
+    };
+    result
+      .or_else(|_| Err("RPC request timed out"))?
+      .or_else(|e| {
+        cblog!("{} error: {}", method, e);
+        Err("RPC request failed")
+      })
+  }
+}
+
+// a fresh batch for every attempt, as a submitted one can't be sent again
+pub async fn execute_batch(
+  transport: &Transport,
+  method: &str,
+  calls: &[Vec<Value>],
+) -> web3::Result<Vec<Value>> {
+  let batch = Batch::new(transport);
+  let results: Vec<_> = calls
+    .iter()
+    .map(|params| batch.execute(method, params.clone()))
+    .collect();
+  batch.submit_batch().await?;
+  let mut outputs = Vec::new();
+  for result in results {
+    outputs.push(result.await?);
+  }
+  Ok(outputs)
+}
+
+/// The params of a single call: JSON text, or chainblocks values.
+/// Anything but a list is sent as the only param, none as no params.
+fn params_from_var<'a>(input: &Var) -> Result<Vec<Value>, &'a str> {
+  let value = if let Ok(text) = String::try_from(input) {
+    serde_json::from_str(&text).or_else(|_| Err("Params string is not valid JSON"))?
+  } else {
+    var_to_json(input)?
+  };
+  Ok(match value {
+    Value::Null => Vec::new(),
+    Value::Array(params) => params,
+    param => vec![param],
+  })
+}
+
+/// Converts chainblocks values to JSON, bytes become 0x prefixed hex strings.
+pub fn var_to_json<'a>(value: &Var) -> Result<Value, &'a str> {
+  if value.is_none() {
+    Ok(Value::Null)
+  } else if value.is_seq() {
+    let seq: Seq = value.try_into()?;
+    let mut values = Vec::new();
+    for item in seq.iter() {
+      values.push(var_to_json(&item)?);
+    }
+    Ok(Value::Array(values))
+  } else if let Ok(table) = Table::try_from(value) {
+    let mut map = serde_json::Map::new();
+    for (key, item) in table.iter() {
+      let key = unsafe { CStr::from_ptr(key.0) }
+        .to_str()
+        .or_else(|_| Err("Invalid table key"))?;
+      map.insert(key.to_owned(), var_to_json(&item)?);
+    }
+    Ok(Value::Object(map))
+  } else if let Ok(text) = String::try_from(value) {
+    Ok(Value::String(text))
+  } else if let Ok(flag) = bool::try_from(value) {
+    Ok(Value::Bool(flag))
+  } else if let Ok(number) = i64::try_from(value) {
+    Ok(number.into())
+  } else if let Ok(number) = f64::try_from(value) {
+    serde_json::Number::from_f64(number)
+      .map(Value::Number)
+      .ok_or("Invalid float value")
+  } else if let Ok(bytes) = <&[u8]>::try_from(value) {
+    Ok(Value::String(format!("0x{}", hex::encode(bytes))))
+  } else {
+    Err("Unsupported value type in params")
+  }
+}
+
+/// Converts JSON to chainblocks values, objects become tables and arrays sequences.
+/// Quantities stay hex strings, as they are sent by the node.
+pub fn json_to_var<'a>(value: &Value) -> Result<ClonedVar, &'a str> {
+  match value {
+    Value::Null => Ok(ClonedVar(Var::default())),
+    Value::Bool(flag) => Ok((*flag).into()),
+    Value::Number(number) => match number.as_i64() {
+      Some(number) => Ok(number.into()),
+      None => Ok(number.as_f64().unwrap_or_default().into()),
+    },
+    Value::String(text) => {
+      let text = CString::new(text.as_str()).or_else(|_| Err("Invalid string in result"))?;
+      Ok(text.as_ref().into())
+    }
+    Value::Array(values) => {
+      let mut vars = Vec::<ClonedVar>::new();
+      for value in values {
+        vars.push(json_to_var(value)?);
+      }
+      Ok(vars.as_slice().into())
+    }
+    Value::Object(map) => {
+      let mut table = Table::new();
+      for (key, value) in map {
+        let key = CString::new(key.as_str()).or_else(|_| Err("Invalid key in result"))?;
+        let value = json_to_var(value)?;
+        table.insert_fast(&key, value.0);
+      }
+      let var: Var = table.as_ref().into();
+      Ok(var.into())
+    }
+  }
+}
+
+impl Block for Rpc {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.RPC-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.RPC")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.RPC"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &INPUT_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    if self.json {
+      &JSON_OUTPUT_TYPES
+    } else {
+      &OUTPUT_TYPES
+    }
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.method = value.try_into().unwrap_or(CString::new("").unwrap()),
+      1 => self.batch = value.try_into().unwrap_or(false),
+      2 => self.json = value.try_into().unwrap_or(false),
+      3 => self.node_param.set_param(value),
+      4 => self.timeout.set_param(value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.method.as_ref().into(),
+      1 => self.batch.into(),
+      2 => self.json.into(),
+      3 => self.node_param.get_param(),
+      4 => self.timeout.get_param(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.requiring.clear();
+    let exp_info = ExposedInfo {
+      exposedType: NODE_TYPE,
+      name: self.node_param.get_name(),
+      help: cstr!("The required ethereum node to use as gateway.").into(),
+      ..ExposedInfo::default()
+    };
+    self.requiring.push(exp_info);
+    Some(&self.requiring)
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.node_param.warmup(context);
+    self.timeout.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.timeout.cleanup();
+    self.node_param.cleanup();
+    self.node = None;
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(activate_blocking(self, context, input))
+  }
+}
+
+impl BlockingBlock for Rpc {
+  fn activate_blocking(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
+    let method = self.method.to_str().or_else(|_| Err("Invalid string"))?;
+    if method.is_empty() {
+      return Err("Method is required");
+    }
+    let calls = if self.batch {
+      let seq: Seq = input
+        .try_into()
+        .or_else(|_| Err("Batch expects a sequence of params"))?;
+      let mut calls = Vec::new();
+      for params in seq.iter() {
+        calls.push(params_from_var(&params)?);
+      }
+      calls
+    } else {
+      vec![params_from_var(input)?]
+    };
+
+    if self.node.is_none() {
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
+    }
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
+    let result = RUNTIME.block_on(Rpc::activate_async(
+      node, method, calls, self.batch, timeout,
+    ))?;
+
+    if self.json {
+      self.output_json =
+        CString::new(result.to_string()).or_else(|_| Err("Invalid string in result"))?;
+      Ok(self.output_json.as_ref().into())
+    } else {
+      self.output = json_to_var(&result)?;
+      Ok(self.output.0)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::eth::connect;
+  use crate::blocks::eth::NodeConfig;
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::multi::Strategy;
+  use jsonrpc_core::serde_json::json;
+
+  fn call(
+    node: &MockNode,
+    method: &str,
+    calls: Vec<Vec<Value>>,
+    batch: bool,
+  ) -> Result<Value, &'static str> {
+    let data = node.node();
+    RUNTIME.block_on(Rpc::activate_async(
+      &data,
+      method,
+      calls,
+      batch,
+      Duration::from_secs(5),
+    ))
+  }
+
+  #[test]
+  fn calls_any_method() {
+    let node = MockNode::start();
+    node.expect("anvil_mine", json!(null));
+    node.expect("txpool_content", json!({ "pending": {}, "queued": {} }));
+
+    assert_eq!(
+      call(&node, "anvil_mine", vec![vec![quantity(5)]], false).unwrap(),
+      json!(null)
+    );
+    assert_eq!(node.requests("anvil_mine")[0].params, json!(["0x5"]));
+    let pool = call(&node, "txpool_content", vec![vec![]], false).unwrap();
+    assert_eq!(pool["pending"], json!({}));
+    assert_eq!(node.requests("txpool_content")[0].params, json!([]));
+  }
+
+  #[test]
+  fn node_errors_fail_the_call() {
+    let node = MockNode::start();
+    assert_eq!(
+      call(&node, "alchemy_nothing", vec![vec![]], false),
+      Err("RPC request failed")
+    );
+  }
+
+  #[test]
+  fn reads_are_retried() {
+    let node = MockNode::start();
+    node.expect("eth_blockNumber", quantity(7));
+    node.expect_error_times("eth_blockNumber", 1, -32603, "internal error");
+    let number = call(&node, "eth_blockNumber", vec![vec![]], false);
+    assert_eq!(number.unwrap(), quantity(7));
+    assert_eq!(node.count("eth_blockNumber"), 2);
+  }
+
+  #[test]
+  fn writes_are_sent_once() {
+    let node = MockNode::start();
+    node.expect_error("eth_sendRawTransaction", -32603, "internal error");
+    let sent = call(
+      &node,
+      "eth_sendRawTransaction",
+      vec![vec![json!("0x01")]],
+      false,
+    );
+    assert_eq!(sent, Err("RPC request failed"));
+    assert_eq!(node.count("eth_sendRawTransaction"), 1);
+
+    let calls = vec![vec![json!("0x01")], vec![json!("0x02")]];
+    assert!(call(&node, "eth_sendRawTransaction", calls, true).is_err());
+    assert_eq!(node.count("eth_sendRawTransaction"), 3);
+  }
+
+  #[test]
+  fn unknown_methods_are_sent_once_to_one_endpoint() {
+    let (a, b) = (MockNode::start(), MockNode::start());
+    a.expect_error("anvil_mine", -32603, "internal error");
+    b.expect_error("anvil_mine", -32603, "internal error");
+    let mut config = NodeConfig::new(vec![a.http_url(), b.http_url()]);
+    config.strategy = Strategy::Quorum(2);
+    let data = RUNTIME.block_on(connect(config)).unwrap();
+
+    let mined = RUNTIME.block_on(Rpc::activate_async(
+      &data,
+      "anvil_mine",
+      vec![vec![quantity(5)]],
+      false,
+      Duration::from_secs(5),
+    ));
+    assert_eq!(mined, Err("RPC request failed"));
+    assert_eq!(a.count("anvil_mine") + b.count("anvil_mine"), 1);
+  }
+
+  #[test]
+  fn batches_calls_of_the_same_method() {
+    let node = MockNode::start();
+    node.expect_params("eth_getBalance", json!(["0x01", "latest"]), quantity(1));
+    node.expect_params("eth_getBalance", json!(["0x02", "latest"]), quantity(2));
+    let calls = vec![
+      vec![json!("0x01"), json!("latest")],
+      vec![json!("0x02"), json!("latest")],
+    ];
+    let results = call(&node, "eth_getBalance", calls, true).unwrap();
+    assert_eq!(results, json!(["0x1", "0x2"]));
+  }
+
+  #[test]
+  fn any_failure_fails_the_batch() {
+    let node = MockNode::start();
+    node.expect_params("eth_getBalance", json!(["0x01"]), quantity(1));
+    let calls = vec![vec![json!("0x01")], vec![json!("0x02")]];
+    assert!(call(&node, "eth_getBalance", calls, true).is_err());
+  }
+
+  #[test]
+  fn params_come_from_json_text() {
+    let text = CString::new(r#"["0x01", {"fromBlock": "latest"}]"#).unwrap();
+    let params = params_from_var(&text.as_ref().into()).unwrap();
+    assert_eq!(
+      params,
+      vec![json!("0x01"), json!({ "fromBlock": "latest" })]
+    );
+
+    let object = CString::new(r#"{"to": "0x01"}"#).unwrap();
+    let params = params_from_var(&object.as_ref().into()).unwrap();
+    assert_eq!(params, vec![json!({ "to": "0x01" })]);
+
+    assert!(params_from_var(&Var::default()).unwrap().is_empty());
+    let invalid = CString::new("latest").unwrap();
+    assert!(params_from_var(&invalid.as_ref().into()).is_err());
+  }
+}
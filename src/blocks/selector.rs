@@ -0,0 +1,225 @@
+use chainblocks::types::Table;
+use chainblocks::types::Var;
+use jsonrpc_core::serde_json;
+use jsonrpc_core::types::Value;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::ffi::CStr;
+use std::str::FromStr;
+use web3::helpers;
+use web3::helpers::CallFuture;
+use web3::types::Bytes;
+use web3::types::CallRequest;
+use web3::types::H256;
+use web3::Transport;
+
+const TAGS: &[&str] = &["latest", "pending", "safe", "finalized", "earliest"];
+
+/// The block a request looks at: a number, a tag, or a hash (EIP-1898).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockSelector {
+  Number(u64),
+  Tag(&'static str),
+  Hash { hash: H256, require_canonical: bool },
+}
+
+impl BlockSelector {
+  /// Reads a Block parameter or input, none if unset.
+  /// Besides numbers, tags and hashes (as strings or bytes) it takes
+  /// an EIP-1898 table: {blockHash: <hash> requireCanonical: true}.
+  pub fn from_var<'a>(value: &Var) -> Result<Option<BlockSelector>, &'a str> {
+    if value.is_none() {
+      Ok(None)
+    } else if let Ok(number) = i64::try_from(value) {
+      if number < 0 {
+        return Err("Block number can't be negative");
+      }
+      Ok(Some(BlockSelector::Number(number as u64)))
+    } else if let Ok(text) = <&str>::try_from(value) {
+      Ok(Some(text.parse()?))
+    } else if let Ok(bytes) = <&[u8]>::try_from(value) {
+      if bytes.len() != 32 {
+        return Err("Block hash must be 32 bytes long");
+      }
+      Ok(Some(BlockSelector::Hash {
+        hash: H256::from_slice(bytes),
+        require_canonical: false,
+      }))
+    } else if let Ok(table) = Table::try_from(value) {
+      let mut hash = None;
+      let mut require_canonical = false;
+      for (key, item) in table.iter() {
+        let key = unsafe { CStr::from_ptr(key.0) }
+          .to_str()
+          .or_else(|_| Err("Invalid block table key"))?;
+        match key {
+          "blockHash" => hash = BlockSelector::from_var(&item)?,
+          "requireCanonical" => require_canonical = (&item).try_into()?,
+          _ => return Err("Block table only takes blockHash and requireCanonical"),
+        }
+      }
+      match hash {
+        Some(BlockSelector::Hash { hash, .. }) => Ok(Some(BlockSelector::Hash {
+          hash,
+          require_canonical,
+        })),
+        _ => Err("Block table requires a blockHash"),
+      }
+    } else {
+      Err("Invalid block, expected a number, a tag or a hash")
+    }
+  }
+
+  /// The block param of eth_call, eth_getStorageAt and alike.
+  pub fn to_value(&self) -> Value {
+    match self {
+      BlockSelector::Number(number) => Value::String(format!("0x{:x}", number)),
+      BlockSelector::Tag(tag) => Value::String(tag.to_string()),
+      BlockSelector::Hash {
+        hash,
+        require_canonical: true,
+      } => serde_json::json!({ "blockHash": format!("{:?}", hash), "requireCanonical": true }),
+      BlockSelector::Hash { hash, .. } => serde_json::json!({ "blockHash": format!("{:?}", hash) }),
+    }
+  }
+
+  /// The method and params fetching this block, full with transaction objects.
+  pub fn block_request(&self, full: bool) -> (&'static str, Vec<Value>) {
+    match self {
+      BlockSelector::Hash { hash, .. } => (
+        "eth_getBlockByHash",
+        vec![Value::String(format!("{:?}", hash)), Value::Bool(full)],
+      ),
+      _ => (
+        "eth_getBlockByNumber",
+        vec![self.to_value(), Value::Bool(full)],
+      ),
+    }
+  }
+}
+
+impl FromStr for BlockSelector {
+  type Err = &'static str;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if let Some(tag) = TAGS.iter().find(|tag| **tag == s) {
+      Ok(BlockSelector::Tag(tag))
+    } else if let Some(digits) = s.strip_prefix("0x") {
+      if digits.len() == 64 {
+        let hash = digits
+          .parse()
+          .or_else(|_| Err("Failed to parse block hash"))?;
+        Ok(BlockSelector::Hash {
+          hash,
+          require_canonical: false,
+        })
+      } else {
+        let number =
+          u64::from_str_radix(digits, 16).or_else(|_| Err("Failed to parse block number"))?;
+        Ok(BlockSelector::Number(number))
+      }
+    } else if let Ok(number) = s.parse() {
+      Ok(BlockSelector::Number(number))
+    } else {
+      Err("Invalid block, expected a number, a tag or a hash")
+    }
+  }
+}
+
+/// The block param for an optional selector, the latest block if none.
+pub fn block_param(block: Option<BlockSelector>) -> Value {
+  block.map_or(Value::String("latest".into()), |block| block.to_value())
+}
+
+/// eth_call at any block, web3 itself can't send the safe and finalized tags.
+pub fn eth_call<T: Transport>(
+  transport: &T,
+  request: &CallRequest,
+  block: Option<BlockSelector>,
+) -> CallFuture<Bytes, T::Out> {
+  let params = vec![helpers::serialize(request), block_param(block)];
+  CallFuture::new(transport.execute("eth_call", params))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::RUNTIME;
+  use serde_json::json;
+
+  #[test]
+  fn parses_tags_numbers_and_hashes() {
+    assert_eq!("finalized".parse(), Ok(BlockSelector::Tag("finalized")));
+    assert_eq!("safe".parse(), Ok(BlockSelector::Tag("safe")));
+    assert_eq!("0x10".parse(), Ok(BlockSelector::Number(16)));
+    assert_eq!("16".parse(), Ok(BlockSelector::Number(16)));
+    let hash = format!("0x{}", "ab".repeat(32));
+    assert_eq!(
+      hash.parse(),
+      Ok(BlockSelector::Hash {
+        hash: H256::repeat_byte(0xab),
+        require_canonical: false
+      })
+    );
+    assert!("Latest".parse::<BlockSelector>().is_err());
+    assert!("0xzz".parse::<BlockSelector>().is_err());
+  }
+
+  #[test]
+  fn hashes_follow_eip1898() {
+    let hash = H256::repeat_byte(0xab);
+    let canonical = BlockSelector::Hash {
+      hash,
+      require_canonical: true,
+    };
+    assert_eq!(
+      canonical.to_value(),
+      json!({ "blockHash": format!("0x{}", "ab".repeat(32)), "requireCanonical": true })
+    );
+    let any = BlockSelector::Hash {
+      hash,
+      require_canonical: false,
+    };
+    assert_eq!(
+      any.to_value(),
+      json!({ "blockHash": format!("0x{}", "ab".repeat(32)) })
+    );
+    assert_eq!(block_param(None), json!("latest"));
+    assert_eq!(block_param(Some(BlockSelector::Number(255))), json!("0xff"));
+  }
+
+  #[test]
+  fn blocks_are_fetched_by_number_or_hash() {
+    let (method, params) = BlockSelector::Tag("safe").block_request(true);
+    assert_eq!(
+      (method, params),
+      ("eth_getBlockByNumber", vec![json!("safe"), json!(true)])
+    );
+    let hash = BlockSelector::Hash {
+      hash: H256::repeat_byte(1),
+      require_canonical: true,
+    };
+    let (method, params) = hash.block_request(false);
+    assert_eq!(method, "eth_getBlockByHash");
+    assert_eq!(
+      params,
+      vec![json!(format!("0x{}", "01".repeat(32))), json!(false)]
+    );
+  }
+
+  #[test]
+  fn calls_at_tags_web3_lacks() {
+    let node = MockNode::start();
+    node.expect("eth_call", json!("0x2a"));
+    let eth = node.node();
+    let safe = Some(BlockSelector::Tag("safe"));
+    let output = RUNTIME.block_on(eth_call(
+      eth.web3.transport(),
+      &CallRequest::default(),
+      safe,
+    ));
+    assert_eq!(output.unwrap().0, vec![0x2a]);
+    assert_eq!(node.requests("eth_call")[0].params[1], "safe");
+  }
+}
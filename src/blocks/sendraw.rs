@@ -1,11 +1,15 @@
-use crate::blocks::get_timeout;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::shared_from_var;
 use crate::blocks::NodeData;
 use crate::blocks::NODE_TYPE;
 use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
 use chainblocks::block::Block;
 use chainblocks::cblog;
 use chainblocks::core::activate_blocking;
-use chainblocks::core::log;
 use chainblocks::core::BlockingBlock;
 use chainblocks::cstr;
 use chainblocks::types::common_type;
@@ -17,15 +21,31 @@ use chainblocks::types::Parameters;
 use chainblocks::types::Type;
 use chainblocks::types::Var;
 use std::convert::TryInto;
-use std::rc::Rc;
 use std::str;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::timeout;
+use web3::types::Bytes;
+use web3::types::H256;
+
+/// Broadcasts a signed transaction, returning its hash.
+pub async fn send_raw<'a>(node: &NodeData, raw: Bytes, timeout: Duration) -> Result<H256, &'a str> {
+  let eth = node.web3.eth();
+  // TODO, use https://docs.rs/web3/0.15.0/web3/confirm/fn.send_raw_transaction_with_confirmation.html
+  node
+    .retry
+    .send_raw(&eth, raw, timeout)
+    .await
+    .or_else(|_| Err("Timed out"))?
+    .or_else(|e| {
+      cblog!("SendRaw error: {}", e);
+      Err("Failed to send raw transaction")
+    })
+}
 
 pub struct SendRaw {
   node_param: ParamVar,
-  node: Option<Rc<Option<NodeData>>>,
-  timeout: Duration,
+  node: Option<Arc<NodeData>>,
+  timeout: ParamVar,
   requiring: ExposedTypes,
   output_hash: Option<[u8; 32]>,
 }
@@ -33,12 +53,27 @@ pub struct SendRaw {
 lazy_static! {
   static ref IN_TYPES: Vec<Type> = vec![common_type::bytes];
   static ref OUT_TYPES: Vec<Type> = vec![common_type::bytes];
-  static ref PARAMETERS: Parameters = vec![(
-    cstr!("Node"),
-    cstr!("The ethereum node block variable to use."),
-    vec![NODE_VAR],
-  )
-    .into(),];
+  static ref PARAMETERS: Parameters = vec![
+    (
+      cstr!("Node"),
+      cstr!("The ethereum node block variable to use."),
+      vec![NODE_VAR],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
+  ];
 }
 
 impl Default for SendRaw {
@@ -46,7 +81,7 @@ impl Default for SendRaw {
     SendRaw {
       node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
       node: None,
-      timeout: get_timeout(),
+      timeout: ParamVar::new(().into()),
       requiring: Vec::new(),
       output_hash: None,
     }
@@ -79,6 +114,7 @@ impl Block for SendRaw {
   fn setParam(&mut self, index: i32, value: &Var) {
     match index {
       0 => self.node_param.set_param(value),
+      1 => self.timeout.set_param(value),
       _ => unreachable!(),
     }
   }
@@ -86,6 +122,7 @@ impl Block for SendRaw {
   fn getParam(&mut self, index: i32) -> Var {
     match index {
       0 => self.node_param.get_param(),
+      1 => self.timeout.get_param(),
       _ => unreachable!(),
     }
   }
@@ -103,9 +140,11 @@ impl Block for SendRaw {
 
   fn warmup(&mut self, context: &Context) -> Result<(), &str> {
     self.node_param.warmup(context);
+    self.timeout.warmup(context);
     Ok(())
   }
   fn cleanup(&mut self) {
+    self.timeout.cleanup();
     self.node_param.cleanup();
     self.node = None;
   }
@@ -118,31 +157,43 @@ impl BlockingBlock for SendRaw {
   fn activate_blocking(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
     let bytes: &[u8] = input.try_into()?;
     if self.node.is_none() {
-      self.node = Some(Var::from_object_as_clone::<Option<NodeData>>(
-        self.node_param.get(),
-        &NODE_TYPE,
-      )?);
-    }
-    let node = Var::get_mut_from_clone(&self.node)?;
-    let (scheduler, eth) = (&mut node.scheduler, &mut node.web3.eth());
-    let res = scheduler
-      .block_on(async {
-        // TODO, use https://docs.rs/web3/0.15.0/web3/confirm/fn.send_raw_transaction_with_confirmation.html
-        let fut = eth.send_raw_transaction(bytes.into());
-        let timed_fut = timeout(self.timeout, fut);
-        timed_fut.await
-      })
-      .or_else(|_| Err("Timed out"))?;
-    match res {
-      Ok(value) => {
-        self.output_hash = Some(value.into());
-        let slice = &self.output_hash.unwrap()[..];
-        Ok(slice.into())
-      }
-      Err(e) => {
-        cblog!("SendRaw error: {}", e);
-        Err("Failed to send raw transaction")
-      }
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
     }
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
+    let sent = RUNTIME.block_on(send_raw(node, bytes.into(), timeout))?;
+    // the output points into the block, keep the bytes around
+    let hash = self.output_hash.get_or_insert([0; 32]);
+    *hash = sent.into();
+    Ok(hash[..].into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::hash;
+  use crate::blocks::mock::MockNode;
+  use jsonrpc_core::serde_json::json;
+
+  #[test]
+  fn broadcasts_the_transaction() {
+    let node = MockNode::start();
+    node.expect("eth_sendRawTransaction", json!(hash(7)));
+    let eth = node.node();
+    let sent = RUNTIME.block_on(send_raw(&eth, Bytes(vec![1, 2, 3]), Duration::from_secs(5)));
+    assert_eq!(format!("{:?}", sent.unwrap()), hash(7));
+    let request = &node.requests("eth_sendRawTransaction")[0];
+    assert_eq!(request.params, json!(["0x010203"]));
+  }
+
+  #[test]
+  fn rejected_transactions_are_reported() {
+    let node = MockNode::start();
+    node.expect_error("eth_sendRawTransaction", -32000, "nonce too low");
+    let eth = node.node();
+    let sent = RUNTIME.block_on(send_raw(&eth, Bytes(vec![1, 2, 3]), Duration::from_secs(5)));
+    assert_eq!(sent, Err("Failed to send raw transaction"));
+    assert_eq!(node.count("eth_sendRawTransaction"), 1);
   }
 }
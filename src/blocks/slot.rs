@@ -0,0 +1,424 @@
+use chainblocks::block::Block;
+use chainblocks::cstr;
+use chainblocks::types::common_type;
+use chainblocks::types::ClonedVar;
+use chainblocks::types::Context;
+use chainblocks::types::ParamVar;
+use chainblocks::types::Parameters;
+use chainblocks::types::Seq;
+use chainblocks::types::Type;
+use chainblocks::types::Var;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use web3::signing::keccak256;
+use web3::types::H256;
+use web3::types::U256;
+
+static SLOT_TYPES: &'static [Type] = &[common_type::int, common_type::bytes, common_type::string];
+pub static SLOT_VAR: Type = Type::context_variable(SLOT_TYPES);
+static KEYS_TYPES: &'static [Type] = &[common_type::anys];
+static KEYS_VAR: Type = Type::context_variable(KEYS_TYPES);
+
+/// A slot given as an int, up to 32 big endian bytes, or a hex or decimal string.
+pub fn slot_from_var<'a>(value: &Var) -> Result<H256, &'a str> {
+  if let Ok(number) = i64::try_from(value) {
+    if number < 0 {
+      return Err("Storage slot can't be negative");
+    }
+    Ok(word(number.into()))
+  } else if let Ok(bytes) = <&[u8]>::try_from(value) {
+    left_pad(bytes)
+  } else if let Ok(text) = <&str>::try_from(value) {
+    slot_from_str(text)
+  } else {
+    Err("Invalid storage slot, expected an int, bytes or a string")
+  }
+}
+
+pub fn slot_from_str<'a>(text: &str) -> Result<H256, &'a str> {
+  if let Some(digits) = text.strip_prefix("0x") {
+    let bytes = decode_hex(digits).or_else(|_| Err("Failed to parse storage slot"))?;
+    left_pad(&bytes)
+  } else {
+    let number = U256::from_dec_str(text).or_else(|_| Err("Failed to parse storage slot"))?;
+    Ok(word(number))
+  }
+}
+
+// "0x5" is as good a number as "0x05"
+fn decode_hex(digits: &str) -> Result<Vec<u8>, hex::FromHexError> {
+  if digits.len() % 2 == 1 {
+    hex::decode(format!("0{}", digits))
+  } else {
+    hex::decode(digits)
+  }
+}
+
+fn word(value: U256) -> H256 {
+  let bytes: [u8; 32] = value.into();
+  bytes.into()
+}
+
+fn left_pad<'a>(bytes: &[u8]) -> Result<H256, &'a str> {
+  if bytes.len() > 32 {
+    return Err("Storage slot can't be longer than 32 bytes");
+  }
+  let mut padded = [0u8; 32];
+  padded[32 - bytes.len()..].copy_from_slice(bytes);
+  Ok(padded.into())
+}
+
+/// The slot of the entry at key of a mapping declared at slot.
+pub fn mapping_slot(key: &[u8], slot: H256) -> H256 {
+  let mut data = key.to_vec();
+  data.extend_from_slice(slot.as_bytes());
+  keccak256(&data).into()
+}
+
+/// The first slot of an element of a dynamic array declared at slot.
+pub fn array_slot(slot: H256, index: U256, element_slots: U256) -> H256 {
+  let start = U256::from(keccak256(slot.as_bytes()));
+  offset_slot(word(start), index.overflowing_mul(element_slots).0)
+}
+
+/// Slots wrap around like in the EVM.
+pub fn offset_slot(slot: H256, offset: U256) -> H256 {
+  word(U256::from(slot.as_bytes()).overflowing_add(offset).0)
+}
+
+/// A value packed with others in a slot, offset counts bytes from the right as solidity packs them.
+pub fn packed<'a>(word: &[u8], offset: usize, length: usize) -> Result<&[u8], &'a str> {
+  if length == 0 || offset + length > word.len() {
+    return Err("Packed value doesn't fit in the slot");
+  }
+  let end = word.len() - offset;
+  Ok(&word[end - length..end])
+}
+
+/// How solidity hashes a mapping key of the given type.
+pub fn encode_key<'a>(key: &Var, key_type: &str) -> Result<Vec<u8>, &'a str> {
+  if let Ok(value) = bool::try_from(key) {
+    int_key(value as i64, key_type)
+  } else if let Ok(value) = i64::try_from(key) {
+    int_key(value, key_type)
+  } else if let Ok(bytes) = <&[u8]>::try_from(key) {
+    bytes_key(bytes, key_type)
+  } else if let Ok(text) = <&str>::try_from(key) {
+    text_key(text, key_type)
+  } else {
+    Err("Invalid mapping key, expected an int, bool, bytes or a string")
+  }
+}
+
+fn int_key<'a>(value: i64, key_type: &str) -> Result<Vec<u8>, &'a str> {
+  let number = if key_type == "bool" || key_type.starts_with("uint") {
+    if value < 0 {
+      return Err("Unsigned mapping key can't be negative");
+    }
+    U256::from(value)
+  } else if key_type.starts_with("int") {
+    // two's complement, sign extended to 32 bytes
+    if value < 0 {
+      !U256::from(-(value + 1))
+    } else {
+      U256::from(value)
+    }
+  } else {
+    return Err("Int mapping keys need an int, uint or bool key type");
+  };
+  Ok(word(number).as_bytes().to_vec())
+}
+
+fn bytes_key<'a>(bytes: &[u8], key_type: &str) -> Result<Vec<u8>, &'a str> {
+  match key_type {
+    // dynamic keys are hashed as they are
+    "bytes" | "string" => Ok(bytes.to_vec()),
+    "address" if bytes.len() == 20 => Ok(left_pad(bytes)?.as_bytes().to_vec()),
+    "address" => Err("Address mapping key must be 20 bytes long"),
+    _ if key_type.starts_with("bytes") => {
+      let size: usize = key_type[5..]
+        .parse()
+        .or_else(|_| Err("Unsupported mapping key type"))?;
+      if size == 0 || size > 32 || bytes.len() > size {
+        return Err("Mapping key is longer than its fixed bytes type");
+      }
+      let mut padded = bytes.to_vec();
+      padded.resize(32, 0);
+      Ok(padded)
+    }
+    _ if key_type == "bool" || key_type.starts_with("uint") || key_type.starts_with("int") => {
+      Ok(left_pad(bytes)?.as_bytes().to_vec())
+    }
+    _ => Err("Unsupported mapping key type"),
+  }
+}
+
+pub fn text_key<'a>(text: &str, key_type: &str) -> Result<Vec<u8>, &'a str> {
+  if key_type == "string" {
+    Ok(text.as_bytes().to_vec())
+  } else if let Some(digits) = text.strip_prefix("0x") {
+    let bytes = decode_hex(digits).or_else(|_| Err("Failed to parse hex mapping key"))?;
+    bytes_key(&bytes, key_type)
+  } else if key_type == "bool" || key_type.starts_with("uint") {
+    let number = U256::from_dec_str(text).or_else(|_| Err("Failed to parse mapping key"))?;
+    Ok(word(number).as_bytes().to_vec())
+  } else {
+    Err("String mapping keys must be hex unless the key type is string")
+  }
+}
+
+pub struct Slot {
+  slot: ParamVar,
+  keys: ParamVar,
+  key_types: ClonedVar,
+  types: Vec<String>,
+  index: ParamVar,
+  element_slots: i64,
+  member: i64,
+  output: ClonedVar,
+}
+
+impl Default for Slot {
+  fn default() -> Self {
+    Slot {
+      slot: ParamVar::new(0i64.into()),
+      keys: ParamVar::new(().into()),
+      key_types: ClonedVar(Var::default()),
+      types: Vec::new(),
+      index: ParamVar::new(().into()),
+      element_slots: 1,
+      member: 0,
+      output: ClonedVar(Var::default()),
+    }
+  }
+}
+
+lazy_static! {
+  static ref INPUT_TYPES: Vec<Type> = vec![common_type::none];
+  static ref OUTPUT_TYPES: Vec<Type> = vec![common_type::bytes];
+  static ref PARAMETERS: Parameters = vec![
+    (
+      cstr!("Slot"),
+      cstr!("The slot the variable is declared at."),
+      vec![
+        common_type::int,
+        common_type::bytes,
+        common_type::string,
+        SLOT_VAR
+      ],
+    )
+      .into(),
+    (
+      cstr!("Keys"),
+      cstr!("The mapping keys, outermost first for nested mappings."),
+      vec![common_type::none, common_type::anys, KEYS_VAR],
+    )
+      .into(),
+    (
+      cstr!("KeyTypes"),
+      cstr!("The solidity types of the mapping keys, like address, uint256, bytes32 or string."),
+      vec![common_type::none, common_type::strings],
+    )
+      .into(),
+    (
+      cstr!("Index"),
+      cstr!("The element of a dynamic array, after any mapping keys."),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::bytes,
+        common_type::string,
+        SLOT_VAR
+      ],
+    )
+      .into(),
+    (
+      cstr!("ElementSlots"),
+      cstr!("How many slots every element of the array takes."),
+      vec![common_type::int],
+    )
+      .into(),
+    (
+      cstr!("Member"),
+      cstr!("The slot offset of a struct member, added last."),
+      vec![common_type::int],
+    )
+      .into(),
+  ];
+}
+
+impl Block for Slot {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.Slot-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.Slot")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.Slot"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &INPUT_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &OUTPUT_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.slot.set_param(value),
+      1 => self.keys.set_param(value),
+      2 => self.key_types = value.into(),
+      3 => self.index.set_param(value),
+      4 => self.element_slots = value.try_into().unwrap_or(1),
+      5 => self.member = value.try_into().unwrap_or(0),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.slot.get_param(),
+      1 => self.keys.get_param(),
+      2 => self.key_types.0,
+      3 => self.index.get_param(),
+      4 => self.element_slots.into(),
+      5 => self.member.into(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.types.clear();
+    if !self.key_types.0.is_none() {
+      let types: Seq = self.key_types.0.as_ref().try_into()?;
+      for key_type in types.iter() {
+        self.types.push(String::try_from(&key_type)?);
+      }
+    }
+    self.slot.warmup(context);
+    self.keys.warmup(context);
+    self.index.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.index.cleanup();
+    self.keys.cleanup();
+    self.slot.cleanup();
+  }
+
+  fn activate(&mut self, _: &Context, _: &Var) -> Result<Var, &str> {
+    let mut slot = slot_from_var(&self.slot.get())?;
+    let keys = self.keys.get();
+    if !keys.is_none() {
+      let keys: Seq = keys.as_ref().try_into()?;
+      let mut count = 0;
+      for key in keys.iter() {
+        let key_type = self
+          .types
+          .get(count)
+          .ok_or("Every mapping key needs its KeyTypes entry")?;
+        slot = mapping_slot(&encode_key(&key, key_type)?, slot);
+        count += 1;
+      }
+      if count != self.types.len() {
+        return Err("Every mapping key needs its KeyTypes entry");
+      }
+    }
+    let index = self.index.get();
+    if !index.is_none() {
+      let index = slot_from_var(&index)?;
+      slot = array_slot(
+        slot,
+        U256::from(index.as_bytes()),
+        self.element_slots.max(1).into(),
+      );
+    }
+    let slot = offset_slot(slot, self.member.max(0).into());
+    self.output = slot.as_bytes().into();
+    Ok(self.output.0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn slot(n: u64) -> H256 {
+    word(n.into())
+  }
+
+  #[test]
+  fn mapping_and_array_slots_match_solidity() {
+    // keccak256 of two and one zero words
+    let zero_key = "ad3228b676f7d3cd4284a5443f17f1962b36e491b30a40b2405849e597ba5fb5";
+    let array_start = "290decd9548b62a8d60345a988386fc84ba6bc95484008f6362f93160ef3e563";
+    assert_eq!(mapping_slot(&[0u8; 32], slot(0)), zero_key.parse().unwrap());
+    assert_eq!(
+      array_slot(slot(0), 0.into(), 1.into()),
+      array_start.parse().unwrap()
+    );
+    let third = array_slot(slot(0), 2.into(), 3.into());
+    assert_eq!(third, offset_slot(array_start.parse().unwrap(), 6.into()));
+  }
+
+  #[test]
+  fn slots_wrap_around() {
+    let last = word(U256::MAX);
+    assert_eq!(offset_slot(last, 2.into()), slot(1));
+  }
+
+  #[test]
+  fn keys_are_encoded_like_solidity() {
+    let address = [0x11u8; 20];
+    let encoded = bytes_key(&address, "address").unwrap();
+    assert_eq!(&encoded[..12], &[0u8; 12]);
+    assert_eq!(&encoded[12..], &address);
+    assert_eq!(bytes_key(b"ab", "bytes4").unwrap()[..3], [b'a', b'b', 0]);
+    assert_eq!(bytes_key(b"ab", "bytes").unwrap(), b"ab".to_vec());
+    assert_eq!(text_key("ab", "string").unwrap(), b"ab".to_vec());
+    assert_eq!(int_key(-1, "int256").unwrap(), vec![0xff; 32]);
+    assert_eq!(int_key(7, "uint8").unwrap()[31], 7);
+    assert_eq!(
+      text_key("0x0a", "uint256").unwrap(),
+      text_key("10", "uint256").unwrap()
+    );
+    assert_eq!(
+      text_key("0xa", "uint256").unwrap(),
+      text_key("10", "uint256").unwrap()
+    );
+    assert!(int_key(-1, "uint256").is_err());
+    assert!(bytes_key(&[0; 19], "address").is_err());
+    assert!(bytes_key(b"abc", "bytes2").is_err());
+  }
+
+  #[test]
+  fn packed_values_count_from_the_right() {
+    let mut word = [0u8; 32];
+    // an address at offset 0 followed by a uint96 is how solidity packs them
+    word[..12].copy_from_slice(&[0x22; 12]);
+    word[12..].copy_from_slice(&[0x11; 20]);
+    assert_eq!(packed(&word, 0, 20).unwrap(), &[0x11; 20]);
+    assert_eq!(packed(&word, 20, 12).unwrap(), &[0x22; 12]);
+    assert!(packed(&word, 20, 13).is_err());
+    assert!(packed(&word, 0, 0).is_err());
+  }
+
+  #[test]
+  fn slots_parse_from_strings() {
+    assert_eq!(slot_from_str("0x05").unwrap(), slot(5));
+    assert_eq!(slot_from_str("5").unwrap(), slot(5));
+    assert_eq!(slot_from_str("0x5").unwrap(), slot(5));
+    assert_eq!(slot_from_str("0x105").unwrap(), slot(0x105));
+    assert!(slot_from_str(&format!("0x{}", "00".repeat(33))).is_err());
+  }
+}
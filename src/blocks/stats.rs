@@ -0,0 +1,294 @@
+use crate::blocks::get_shared;
+use crate::blocks::metrics::MethodStats;
+use crate::blocks::shared_from_var;
+use crate::blocks::NodeData;
+use crate::blocks::NODE_TYPE;
+use crate::blocks::NODE_VAR;
+use chainblocks::block::Block;
+use chainblocks::cbstr;
+use chainblocks::cstr;
+use chainblocks::types::common_type;
+use chainblocks::types::Context;
+use chainblocks::types::ExposedInfo;
+use chainblocks::types::ExposedTypes;
+use chainblocks::types::ParamVar;
+use chainblocks::types::Parameters;
+use chainblocks::types::RawString;
+use chainblocks::types::Seq;
+use chainblocks::types::Table;
+use chainblocks::types::Type;
+use chainblocks::types::Var;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::sync::Arc;
+
+/// What a node did so far, per method and overall.
+pub struct Summary {
+  pub requests: u64,
+  pub in_flight: i64,
+  pub errors: [u64; 6],
+  pub methods: BTreeMap<String, MethodStats>,
+  pub cache_hits: u64,
+  pub cache_misses: u64,
+  pub cache_entries: u64,
+}
+
+pub fn summary(node: &NodeData) -> Summary {
+  let (methods, errors) = node.metrics.snapshot();
+  // all zeroes when the node has no cache
+  let (cache_hits, cache_misses, cache_entries) = match &node.cache {
+    Some(cache) => (cache.hits(), cache.misses(), cache.len() as u64),
+    None => (0, 0, 0),
+  };
+  Summary {
+    requests: methods.values().map(|stats| stats.calls).sum(),
+    in_flight: node.metrics.in_flight(),
+    errors,
+    methods,
+    cache_hits,
+    cache_misses,
+    cache_entries,
+  }
+}
+
+static ERRORS_TYPES: &'static [Type] = &[
+  common_type::int,
+  common_type::int,
+  common_type::int,
+  common_type::int,
+  common_type::int,
+  common_type::int,
+];
+// same order as metrics::ERROR_KINDS
+const ERRORS_KEYS: &[RawString] = &[
+  cbstr!("rpc"),
+  cbstr!("transport"),
+  cbstr!("unreachable"),
+  cbstr!("invalid_response"),
+  cbstr!("cancelled"),
+  cbstr!("other"),
+];
+static ERRORS_TYPE: Type = Type::table(ERRORS_KEYS, ERRORS_TYPES);
+const ERRORS_FIELDS: [&str; 6] = [
+  cstr!("rpc"),
+  cstr!("transport"),
+  cstr!("unreachable"),
+  cstr!("invalid_response"),
+  cstr!("cancelled"),
+  cstr!("other"),
+];
+
+static TABLE_TYPES: &'static [Type] = &[
+  common_type::int,
+  common_type::int,
+  ERRORS_TYPE,
+  common_type::anys,
+  common_type::int,
+  common_type::int,
+  common_type::int,
+];
+const TABLE_KEYS: &[RawString] = &[
+  cbstr!("requests"),
+  cbstr!("in_flight"),
+  cbstr!("errors"),
+  cbstr!("methods"),
+  cbstr!("cache_hits"),
+  cbstr!("cache_misses"),
+  cbstr!("cache_entries"),
+];
+static TABLE_TYPE: Type = Type::table(TABLE_KEYS, TABLE_TYPES);
+
+pub struct Stats {
+  node_param: ParamVar,
+  node: Option<Arc<NodeData>>,
+  output: Table,
+  errors: Table,
+  methods: Seq,
+  names: Vec<CString>,
+  requiring: ExposedTypes,
+}
+
+lazy_static! {
+  static ref IN_TYPES: Vec<Type> = vec![common_type::any];
+  static ref OUT_TYPES: Vec<Type> = vec![TABLE_TYPE];
+  static ref PARAMETERS: Parameters = vec![(
+    cstr!("Node"),
+    cstr!("The ethereum node block variable to use."),
+    vec![NODE_VAR],
+  )
+    .into(),];
+}
+
+impl Default for Stats {
+  fn default() -> Self {
+    Stats {
+      node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
+      node: None,
+      output: Table::new(),
+      errors: Table::new(),
+      methods: Seq::new(),
+      names: Vec::new(),
+      requiring: Vec::new(),
+    }
+  }
+}
+
+impl Block for Stats {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.Stats-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.Stats")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.Stats"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &IN_TYPES
+  }
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &OUT_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.node_param.set_param(value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.node_param.get_param(),
+      _ => unreachable!(),
+    }
+  }
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.requiring.clear();
+    let exp_info = ExposedInfo {
+      exposedType: NODE_TYPE,
+      name: self.node_param.get_name(),
+      help: cstr!("The required ethereum node to use as gateway.").into(),
+      ..ExposedInfo::default()
+    };
+    self.requiring.push(exp_info);
+    Some(&self.requiring)
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    self.node_param.warmup(context);
+    Ok(())
+  }
+  fn cleanup(&mut self) {
+    self.node_param.cleanup();
+    self.node = None;
+    self.output = Table::new();
+    self.errors = Table::new();
+    self.methods = Seq::new();
+    self.names.clear();
+  }
+  fn activate(&mut self, _: &Context, _input: &Var) -> Result<Var, &str> {
+    if self.node.is_none() {
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
+    }
+    let node = get_shared(&self.node)?;
+    let summary = summary(node);
+
+    self.methods = Seq::new();
+    self.names.clear();
+    for (method, stats) in summary.methods {
+      let name = CString::new(method).or_else(|_| Err("Invalid method name"))?;
+      let mut tab = Table::new();
+      tab.insert_fast_static(cstr!("method"), name.as_ref().into());
+      tab.insert_fast_static(cstr!("calls"), stats.calls.try_into()?);
+      tab.insert_fast_static(cstr!("errors"), stats.errors.try_into()?);
+      tab.insert_fast_static(cstr!("seconds"), stats.seconds.into());
+      // upper bounds of the histogram buckets, not exact values
+      tab.insert_fast_static(cstr!("p50"), stats.quantile(0.5).into());
+      tab.insert_fast_static(cstr!("p95"), stats.quantile(0.95).into());
+      self.methods.push(tab.as_ref().into());
+      self.names.push(name);
+    }
+    for (key, count) in ERRORS_FIELDS.iter().zip(summary.errors.iter()) {
+      self.errors.insert_fast_static(key, (*count).try_into()?);
+    }
+    self
+      .output
+      .insert_fast_static(cstr!("requests"), summary.requests.try_into()?);
+    self
+      .output
+      .insert_fast_static(cstr!("in_flight"), summary.in_flight.into());
+    self
+      .output
+      .insert_fast_static(cstr!("errors"), self.errors.as_ref().into());
+    self
+      .output
+      .insert_fast_static(cstr!("methods"), self.methods.as_ref().into());
+    self
+      .output
+      .insert_fast_static(cstr!("cache_hits"), summary.cache_hits.try_into()?);
+    self
+      .output
+      .insert_fast_static(cstr!("cache_misses"), summary.cache_misses.try_into()?);
+    self
+      .output
+      .insert_fast_static(cstr!("cache_entries"), summary.cache_entries.try_into()?);
+    Ok((&self.output).into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::eth::connect;
+  use crate::blocks::eth::NodeConfig;
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::RUNTIME;
+  use web3::types::BlockNumber;
+
+  #[test]
+  fn sums_up_calls_and_errors() {
+    let node = MockNode::start();
+    node.expect("eth_gasPrice", quantity(7));
+    node.expect_error_times("eth_gasPrice", 1, 3, "execution reverted");
+    let eth = node.node();
+    for _ in 0..2 {
+      let _ = RUNTIME.block_on(eth.web3.eth().gas_price());
+    }
+
+    let summary = summary(&eth);
+    assert_eq!(summary.methods["eth_gasPrice"].calls, 2);
+    assert_eq!(summary.methods["eth_gasPrice"].errors, 1);
+    let calls: u64 = summary.methods.values().map(|stats| stats.calls).sum();
+    assert_eq!(summary.requests, calls);
+    assert_eq!(summary.errors, [1, 0, 0, 0, 0, 0]);
+    assert_eq!(summary.in_flight, 0);
+    assert_eq!(summary.cache_entries, 0);
+  }
+
+  #[test]
+  fn counts_cache_hits() {
+    let node = MockNode::start();
+    node.expect("eth_getBalance", quantity(5));
+    let mut config = NodeConfig::new(vec![node.http_url()]);
+    config.cache_size = 16;
+    let eth = RUNTIME.block_on(connect(config)).unwrap();
+    let block = Some(BlockNumber::Number(0x10.into()));
+    for _ in 0..2 {
+      let _ = RUNTIME.block_on(eth.web3.eth().balance(Default::default(), block));
+    }
+
+    let summary = summary(&eth);
+    assert_eq!(summary.cache_hits, 1);
+    assert_eq!(summary.cache_misses, 1);
+    assert_eq!(summary.cache_entries, 1);
+  }
+}
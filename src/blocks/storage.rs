@@ -1,12 +1,23 @@
-use crate::blocks::get_address;
-use crate::blocks::get_timeout;
+use crate::blocks::ens::resolve_address;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::selector::block_param;
+use crate::blocks::selector::BlockSelector;
+use crate::blocks::shared_from_var;
+use crate::blocks::slot::packed;
+use crate::blocks::slot::slot_from_var;
+use crate::blocks::slot::SLOT_VAR;
 use crate::blocks::NodeData;
+use crate::blocks::BLOCK_HASH_TYPE;
+use crate::blocks::BLOCK_VAR;
 use crate::blocks::NODE_TYPE;
 use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
 use chainblocks::block::Block;
 use chainblocks::cblog;
 use chainblocks::core::activate_blocking;
-use chainblocks::core::log;
 use chainblocks::core::BlockingBlock;
 use chainblocks::cstr;
 use chainblocks::types::common_type;
@@ -19,21 +30,53 @@ use chainblocks::types::Parameters;
 use chainblocks::types::Type;
 use chainblocks::types::Var;
 use std::convert::TryInto;
-use std::rc::Rc;
 use std::str;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::timeout;
-use web3::types::BlockNumber;
+use web3::helpers;
+use web3::helpers::CallFuture;
+use web3::types::Address;
 use web3::types::H256;
+use web3::Transport as _;
+
+/// The word stored at the slot of the account.
+pub async fn get_storage<'a>(
+  node: &NodeData,
+  address: Address,
+  slot: H256,
+  block: Option<BlockSelector>,
+  timeout: Duration,
+) -> Result<H256, &'a str> {
+  let transport = node.web3.transport();
+  // web3 eth.storage only takes block numbers
+  let params = vec![
+    helpers::serialize(&address),
+    helpers::serialize(&slot),
+    block_param(block),
+  ];
+  node
+    .retry
+    .timeout(timeout, || {
+      CallFuture::<H256, _>::new(transport.execute("eth_getStorageAt", params.clone()))
+    })
+    .await
+    .or_else(|_| Err("Storage request timedout"))?
+    .or_else(|e| {
+      cblog!("Storage error: {}", e);
+      Err("Storage request failed")
+    })
+}
 
 pub struct Storage {
   address: ParamVar,
-  index: i64,
-  block: Option<BlockNumber>,
+  index: ParamVar,
+  offset: i64,
+  length: i64,
+  block: ParamVar,
   node_param: ParamVar,
-  node: Option<Rc<Option<NodeData>>>,
+  node: Option<Arc<NodeData>>,
   output: ClonedVar,
-  timeout: Duration,
+  timeout: ParamVar,
   requiring: ExposedTypes,
 }
 
@@ -41,12 +84,14 @@ impl Default for Storage {
   fn default() -> Self {
     Storage {
       address: ParamVar::new(cstr!("").into()),
-      index: 0,
-      block: None,
+      index: ParamVar::new(0i64.into()),
+      offset: 0,
+      length: 32,
+      block: ParamVar::new(().into()),
       node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
       node: None,
       output: ClonedVar(Var::default()),
-      timeout: get_timeout(),
+      timeout: ParamVar::new(().into()),
       requiring: Vec::new(),
     }
   }
@@ -58,7 +103,7 @@ lazy_static! {
   static ref PARAMETERS: Parameters = vec![
     (
       cstr!("Address"),
-      cstr!("The address to read from."),
+      cstr!("The address to read from, or an ENS name."),
       vec![
         common_type::string,
         common_type::string_var,
@@ -69,14 +114,40 @@ lazy_static! {
       .into(),
     (
       cstr!("Index"),
-      cstr!("The storage index to read."),
-      vec![common_type::int],
+      cstr!("The storage slot to read, as an int or 32 bytes like the output of Eth.Slot."),
+      vec![
+        common_type::int,
+        common_type::bytes,
+        common_type::string,
+        SLOT_VAR
+      ],
     )
       .into(),
     (
       cstr!("Block"),
-      cstr!("The optional block number to read from history."),
-      vec![common_type::none, common_type::int],
+      cstr!(
+        "The optional block to read from history: a number, a tag like safe or finalized, or a block hash."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::string,
+        common_type::bytes,
+        BLOCK_HASH_TYPE,
+        BLOCK_VAR
+      ],
+    )
+      .into(),
+    (
+      cstr!("Offset"),
+      cstr!("The byte offset from the right of a value packed with others in the slot."),
+      vec![common_type::int],
+    )
+      .into(),
+    (
+      cstr!("Length"),
+      cstr!("The byte length of the packed value, 32 for the whole slot."),
+      vec![common_type::int],
     )
       .into(),
     (
@@ -85,6 +156,19 @@ lazy_static! {
       vec![NODE_VAR],
     )
       .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
   ];
 }
 
@@ -116,16 +200,12 @@ impl Block for Storage {
   fn setParam(&mut self, index: i32, value: &Var) {
     match index {
       0 => self.address.set_param(value),
-      1 => self.index = value.try_into().unwrap(),
-      2 => {
-        if value.is_none() {
-          self.block = None;
-        } else {
-          let u: u64 = value.try_into().unwrap();
-          self.block = Some(BlockNumber::Number(u.into()));
-        }
-      }
-      3 => self.node_param.set_param(value),
+      1 => self.index.set_param(value),
+      2 => self.block.set_param(value),
+      3 => self.offset = value.try_into().unwrap_or(0),
+      4 => self.length = value.try_into().unwrap_or(32),
+      5 => self.node_param.set_param(value),
+      6 => self.timeout.set_param(value),
       _ => unreachable!(),
     }
   }
@@ -133,18 +213,12 @@ impl Block for Storage {
   fn getParam(&mut self, index: i32) -> Var {
     match index {
       0 => self.address.get_param(),
-      1 => self.index.into(),
-      2 => {
-        if let Some(blk) = self.block {
-          match blk {
-            BlockNumber::Number(n) => n.as_u64().try_into().unwrap(),
-            _ => unreachable!(),
-          }
-        } else {
-          Var::default()
-        }
-      }
-      3 => self.node_param.get_param(),
+      1 => self.index.get_param(),
+      2 => self.block.get_param(),
+      3 => self.offset.into(),
+      4 => self.length.into(),
+      5 => self.node_param.get_param(),
+      6 => self.timeout.get_param(),
       _ => unreachable!(),
     }
   }
@@ -164,11 +238,17 @@ impl Block for Storage {
   fn warmup(&mut self, context: &Context) -> Result<(), &str> {
     self.node_param.warmup(context);
     self.address.warmup(context);
+    self.index.warmup(context);
+    self.block.warmup(context);
+    self.timeout.warmup(context);
     Ok(())
   }
 
   fn cleanup(&mut self) {
+    self.timeout.cleanup();
     self.node_param.cleanup();
+    self.block.cleanup();
+    self.index.cleanup();
     self.address.cleanup();
     self.node = None;
   }
@@ -181,35 +261,70 @@ impl Block for Storage {
 impl BlockingBlock for Storage {
   fn activate_blocking(&mut self, _: &Context, _: &Var) -> Result<Var, &str> {
     if self.node.is_none() {
-      self.node = Some(Var::from_object_as_clone::<Option<NodeData>>(
-        self.node_param.get(),
-        &NODE_TYPE,
-      )?);
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
     }
-    let node = Var::get_mut_from_clone(&self.node)?;
-    let (scheduler, eth) = (&mut node.scheduler, &mut node.web3.eth());
-    let address = get_address(self.address.get())?;
-    let fut = eth.storage(address, self.index.into(), self.block);
-    scheduler.block_on(async {
-      let timed_fut = timeout(self.timeout, fut);
-      let fut_res = timed_fut.await;
-      if let Ok(res) = fut_res {
-        match res {
-          Ok(value) => {
-            let value: H256 = value.into();
-            let value: [u8; 32] = value.into();
-            let value = &value[..];
-            self.output = value.into();
-            Ok(self.output.0)
-          }
-          Err(e) => {
-            cblog!("Storage error: {}", e);
-            Err("Storage request failed")
-          }
-        }
-      } else {
-        Err("Storage request timedout")
-      }
-    })
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
+    let address = resolve_address(node, self.address.get(), timeout)?;
+    let block = BlockSelector::from_var(&self.block.get())?;
+    let slot = slot_from_var(&self.index.get())?;
+    if self.offset < 0 || self.length < 0 {
+      return Err("Offset and Length can't be negative");
+    }
+    let (offset, length) = (self.offset as usize, self.length as usize);
+    let value: [u8; 32] = RUNTIME
+      .block_on(get_storage(node, address, slot, block, timeout))?
+      .into();
+    self.output = packed(&value, offset, length)?.into();
+    Ok(self.output.0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::CONTRACT;
+  use jsonrpc_core::serde_json::json;
+
+  fn read(node: &MockNode, block: Option<BlockSelector>) -> Result<H256, &'static str> {
+    let eth = node.node();
+    let address = CONTRACT.parse().unwrap();
+    let slot = H256::from_low_u64_be(2);
+    RUNTIME.block_on(get_storage(
+      &eth,
+      address,
+      slot,
+      block,
+      Duration::from_secs(5),
+    ))
+  }
+
+  #[test]
+  fn reads_a_storage_slot() {
+    let node = MockNode::start();
+    let word = H256::from_low_u64_be(0x2a);
+    node.expect_params(
+      "eth_getStorageAt",
+      json!([
+        format!("0x{}", CONTRACT),
+        format!("{:?}", H256::from_low_u64_be(2)),
+        "latest"
+      ]),
+      json!(format!("{:?}", word)),
+    );
+    assert_eq!(read(&node, None), Ok(word));
+  }
+
+  #[test]
+  fn reads_at_a_given_block() {
+    let node = MockNode::start();
+    node.expect("eth_getStorageAt", json!(format!("{:?}", H256::zero())));
+    assert_eq!(
+      read(&node, Some(BlockSelector::Number(0x10))),
+      Ok(H256::zero())
+    );
+    let request = &node.requests("eth_getStorageAt")[0];
+    assert_eq!(request.params[2], "0x10");
   }
 }
@@ -0,0 +1,1009 @@
+use crate::blocks::ens::resolve_address;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::rpc::execute_batch;
+use crate::blocks::selector::block_param;
+use crate::blocks::selector::BlockSelector;
+use crate::blocks::shared_from_var;
+use crate::blocks::slot::mapping_slot;
+use crate::blocks::slot::offset_slot;
+use crate::blocks::slot::packed;
+use crate::blocks::slot::slot_from_str;
+use crate::blocks::slot::text_key;
+use crate::blocks::NodeData;
+use crate::blocks::Transport;
+use crate::blocks::BLOCK_HASH_TYPE;
+use crate::blocks::BLOCK_VAR;
+use crate::blocks::NODE_TYPE;
+use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
+use chainblocks::block::Block;
+use chainblocks::cblog;
+use chainblocks::core::activate_blocking;
+use chainblocks::core::BlockingBlock;
+use chainblocks::cstr;
+use chainblocks::types::common_type;
+use chainblocks::types::ClonedVar;
+use chainblocks::types::Context;
+use chainblocks::types::ExposedInfo;
+use chainblocks::types::ExposedTypes;
+use chainblocks::types::ParamVar;
+use chainblocks::types::Parameters;
+use chainblocks::types::Table;
+use chainblocks::types::Type;
+use chainblocks::types::Var;
+use jsonrpc_core::serde_json;
+use jsonrpc_core::types::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::sync::Arc;
+use std::time::Duration;
+use web3::helpers;
+use web3::signing::keccak256;
+use web3::types::Address;
+use web3::types::H256;
+use web3::types::U256;
+
+// arrays and byte strings spanning more slots have to be read piece by piece
+const MAX_SLOTS: u64 = 1024;
+// every round fetches what the previous one pointed at, like the data of a long string
+const MAX_ROUNDS: usize = 8;
+
+/// The storageLayout output of solc for a contract.
+pub struct Layout {
+  storage: Vec<Value>,
+  types: serde_json::Map<String, Value>,
+}
+
+/// Where a variable lives and what its layout type is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+  pub slot: H256,
+  pub offset: usize,
+  pub type_id: String,
+}
+
+/// A value decoded from storage, ints come as 32 bytes words like in Eth.Read.
+#[derive(Debug, PartialEq)]
+pub enum Decoded {
+  Bool(bool),
+  Bytes(Vec<u8>),
+  Text(String),
+  List(Vec<Decoded>),
+  Struct(Vec<(String, Decoded)>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Step {
+  Member(String),
+  Key(String),
+}
+
+fn field<'a>(object: &'a Value, key: &str) -> Result<&'a str, &'static str> {
+  object
+    .get(key)
+    .and_then(Value::as_str)
+    .ok_or("Malformed storageLayout")
+}
+
+// solc writes slots and sizes as decimal strings and offsets as numbers
+fn number<'a>(object: &Value, key: &str) -> Result<u64, &'a str> {
+  match object.get(key) {
+    Some(Value::Number(number)) => number.as_u64().ok_or("Malformed storageLayout"),
+    Some(Value::String(text)) => text.parse().or_else(|_| Err("Malformed storageLayout")),
+    _ => Err("Malformed storageLayout"),
+  }
+}
+
+impl Layout {
+  pub fn parse<'a>(text: &str) -> Result<Layout, &'a str> {
+    let layout: Value =
+      serde_json::from_str(text).or_else(|_| Err("Invalid storageLayout JSON"))?;
+    let storage = layout
+      .get("storage")
+      .and_then(Value::as_array)
+      .ok_or("storageLayout has no storage")?;
+    let types = match layout.get("types") {
+      Some(Value::Object(types)) => types.clone(),
+      // a contract without variables
+      _ => serde_json::Map::new(),
+    };
+    Ok(Layout {
+      storage: storage.clone(),
+      types,
+    })
+  }
+
+  fn type_info<'a>(&self, type_id: &str) -> Result<&Value, &'a str> {
+    self
+      .types
+      .get(type_id)
+      .ok_or("Type missing from storageLayout")
+  }
+
+  /// Follows a path like balances[0xabc].amount to the slot it reads.
+  pub fn locate<'a>(&self, path: &str) -> Result<Location, &'a str> {
+    let steps = parse_path(path)?;
+    let name = match steps.first() {
+      Some(Step::Member(name)) => name,
+      _ => return Err("Variable path must start with a variable name"),
+    };
+    let entry = self
+      .storage
+      .iter()
+      .find(|entry| entry.get("label").and_then(Value::as_str) == Some(name))
+      .ok_or("Variable not found in storageLayout")?;
+    let mut location = Location {
+      slot: slot_from_str(field(entry, "slot")?)?,
+      offset: number(entry, "offset")? as usize,
+      type_id: field(entry, "type")?.to_owned(),
+    };
+    for step in &steps[1..] {
+      let info = self.type_info(&location.type_id)?;
+      location = match step {
+        Step::Member(name) => {
+          let member = info
+            .get("members")
+            .and_then(Value::as_array)
+            .ok_or("Only structs have members")?
+            .iter()
+            .find(|member| member.get("label").and_then(Value::as_str) == Some(name))
+            .ok_or("Member not found in struct")?;
+          Location {
+            slot: offset_slot(location.slot, number(member, "slot")?.into()),
+            offset: number(member, "offset")? as usize,
+            type_id: field(member, "type")?.to_owned(),
+          }
+        }
+        Step::Key(key) => match field(info, "encoding")? {
+          "mapping" => {
+            let key_info = self.type_info(field(info, "key")?)?;
+            let key = text_key(key, key_type(field(key_info, "label")?))?;
+            Location {
+              slot: mapping_slot(&key, location.slot),
+              offset: 0,
+              type_id: field(info, "value")?.to_owned(),
+            }
+          }
+          "dynamic_array" => self.element(
+            array_start(location.slot),
+            field(info, "base")?,
+            index(key)?,
+          )?,
+          "inplace" if info.get("base").is_some() => {
+            let index = index(key)?;
+            if index >= fixed_length(&location.type_id)? {
+              return Err("Index out of the array bounds");
+            }
+            self.element(location.slot, field(info, "base")?, index)?
+          }
+          _ => return Err("Only mappings and arrays take keys"),
+        },
+      };
+    }
+    Ok(location)
+  }
+
+  // elements of up to 16 bytes share slots, bigger ones start a new slot
+  fn element<'a>(&self, start: H256, base: &str, index: u64) -> Result<Location, &'a str> {
+    let size = number(self.type_info(base)?, "numberOfBytes")?;
+    let (slot, offset) = if size <= 16 {
+      let per_slot = 32 / size.max(1);
+      (
+        U256::from(index / per_slot),
+        (index % per_slot * size) as usize,
+      )
+    } else {
+      let slots = (size + 31) / 32;
+      (U256::from(index).overflowing_mul(slots.into()).0, 0)
+    };
+    Ok(Location {
+      slot: offset_slot(start, slot),
+      offset,
+      type_id: base.to_owned(),
+    })
+  }
+
+  /// Decodes the value at location, None if it needs slots not in words yet.
+  pub fn decode<'a>(
+    &self,
+    location: &Location,
+    words: &mut Words,
+  ) -> Result<Option<Decoded>, &'a str> {
+    let info = self.type_info(&location.type_id)?;
+    let label = field(info, "label")?;
+    match field(info, "encoding")? {
+      "mapping" => Err("Mappings can only be read at a key"),
+      "bytes" => self.decode_bytes(label == "string", location.slot, words),
+      "dynamic_array" => {
+        let length = match words.get(location.slot) {
+          Some(word) => U256::from(&word[..]),
+          None => return Ok(None),
+        };
+        if length > MAX_SLOTS.into() {
+          return Err("Array is too long to be read at once, read its elements");
+        }
+        let start = array_start(location.slot);
+        self.decode_list(start, field(info, "base")?, length.as_u64(), words)
+      }
+      "inplace" => {
+        if let Some(members) = info.get("members").and_then(Value::as_array) {
+          let mut values = Vec::new();
+          let mut complete = true;
+          // keeps going to gather every missing slot in one round
+          for member in members {
+            let type_id = field(member, "type")?;
+            // mappings have entries but no value of their own
+            if field(self.type_info(type_id)?, "encoding")? == "mapping" {
+              continue;
+            }
+            let member_location = Location {
+              slot: offset_slot(location.slot, number(member, "slot")?.into()),
+              offset: number(member, "offset")? as usize,
+              type_id: type_id.to_owned(),
+            };
+            match self.decode(&member_location, words)? {
+              Some(value) => values.push((field(member, "label")?.to_owned(), value)),
+              None => complete = false,
+            }
+          }
+          Ok(if complete {
+            Some(Decoded::Struct(values))
+          } else {
+            None
+          })
+        } else if info.get("base").is_some() {
+          let length = fixed_length(&location.type_id)?;
+          self.decode_list(location.slot, field(info, "base")?, length, words)
+        } else {
+          let size = number(info, "numberOfBytes")? as usize;
+          match words.get(location.slot) {
+            Some(word) => Ok(Some(value(label, packed(&word, location.offset, size)?))),
+            None => Ok(None),
+          }
+        }
+      }
+      _ => Err("Unsupported storageLayout encoding"),
+    }
+  }
+
+  fn decode_list<'a>(
+    &self,
+    start: H256,
+    base: &str,
+    length: u64,
+    words: &mut Words,
+  ) -> Result<Option<Decoded>, &'a str> {
+    if length > MAX_SLOTS {
+      return Err("Array is too long to be read at once, read its elements");
+    }
+    let mut values = Vec::new();
+    let mut complete = true;
+    for index in 0..length {
+      let location = self.element(start, base, index)?;
+      match self.decode(&location, words)? {
+        Some(value) => values.push(value),
+        None => complete = false,
+      }
+    }
+    Ok(if complete {
+      Some(Decoded::List(values))
+    } else {
+      None
+    })
+  }
+
+  // short values keep their length * 2 in the last byte, long ones length * 2 + 1 in the slot
+  fn decode_bytes<'a>(
+    &self,
+    string: bool,
+    slot: H256,
+    words: &mut Words,
+  ) -> Result<Option<Decoded>, &'a str> {
+    let word = match words.get(slot) {
+      Some(word) => word,
+      None => return Ok(None),
+    };
+    let data = if word[31] & 1 == 0 {
+      let length = (word[31] / 2) as usize;
+      word[..length.min(31)].to_vec()
+    } else {
+      let length = (U256::from(&word[..]) - 1) / 2;
+      if length > (MAX_SLOTS * 32).into() {
+        return Err("Bytes are too long to be read at once");
+      }
+      let length = length.as_usize();
+      let start = array_start(slot);
+      let mut data = Vec::new();
+      let mut complete = true;
+      for index in 0..(length + 31) / 32 {
+        match words.get(offset_slot(start, index.into())) {
+          Some(word) => data.extend_from_slice(&word),
+          None => complete = false,
+        }
+      }
+      if !complete {
+        return Ok(None);
+      }
+      data.truncate(length);
+      data
+    };
+    if string {
+      let text = String::from_utf8(data).or_else(|_| Err("Invalid utf8 string in storage"))?;
+      Ok(Some(Decoded::Text(text)))
+    } else {
+      Ok(Some(Decoded::Bytes(data)))
+    }
+  }
+}
+
+/// The storage words fetched so far and the ones still needed.
+#[derive(Default)]
+pub struct Words {
+  known: HashMap<H256, [u8; 32]>,
+  missing: Vec<H256>,
+}
+
+impl Words {
+  fn get(&mut self, slot: H256) -> Option<[u8; 32]> {
+    let word = self.known.get(&slot).copied();
+    if word.is_none() && !self.missing.contains(&slot) {
+      self.missing.push(slot);
+    }
+    word
+  }
+
+  /// Takes the slots the last decode was missing.
+  pub fn missing(&mut self) -> Vec<H256> {
+    std::mem::take(&mut self.missing)
+  }
+
+  pub fn insert(&mut self, slot: H256, word: H256) {
+    self.known.insert(slot, word.0);
+  }
+}
+
+fn array_start(slot: H256) -> H256 {
+  keccak256(slot.as_bytes()).into()
+}
+
+// t_array(t_uint256)3_storage holds 3 elements
+fn fixed_length<'a>(type_id: &str) -> Result<u64, &'a str> {
+  let length = type_id
+    .rsplit(')')
+    .next()
+    .and_then(|rest| rest.split('_').next())
+    .ok_or("Malformed storageLayout")?;
+  length.parse().or_else(|_| Err("Malformed storageLayout"))
+}
+
+fn index<'a>(key: &str) -> Result<u64, &'a str> {
+  let index = match key.strip_prefix("0x") {
+    Some(digits) => u64::from_str_radix(digits, 16),
+    None => key.parse(),
+  };
+  index.or_else(|_| Err("Array index must be a number"))
+}
+
+// contracts are addresses and enums uint8 as far as keys go
+fn key_type(label: &str) -> &str {
+  if label.starts_with("contract ") || label == "address payable" {
+    "address"
+  } else if label.starts_with("enum ") {
+    "uint8"
+  } else {
+    label
+  }
+}
+
+fn value(label: &str, bytes: &[u8]) -> Decoded {
+  if label == "bool" {
+    Decoded::Bool(bytes.iter().any(|byte| *byte != 0))
+  } else if label.starts_with("uint") || label.starts_with("enum ") {
+    let mut word = vec![0u8; 32 - bytes.len()];
+    word.extend_from_slice(bytes);
+    Decoded::Bytes(word)
+  } else if label.starts_with("int") {
+    let fill = if bytes[0] & 0x80 != 0 { 0xff } else { 0 };
+    let mut word = vec![fill; 32 - bytes.len()];
+    word.extend_from_slice(bytes);
+    Decoded::Bytes(word)
+  } else {
+    // addresses, contracts and fixed bytes as they are
+    Decoded::Bytes(bytes.to_vec())
+  }
+}
+
+fn parse_path<'a>(path: &str) -> Result<Vec<Step>, &'a str> {
+  let mut steps = Vec::new();
+  let mut chars = path.trim().chars().peekable();
+  let mut name = String::new();
+  let mut expect_name = true;
+  while let Some(c) = chars.next() {
+    match c {
+      '.' | '[' if expect_name => return Err("Invalid variable path"),
+      '.' => expect_name = true,
+      '[' => {
+        let mut key = String::new();
+        let quoted = chars.peek() == Some(&'"');
+        if quoted {
+          chars.next();
+        }
+        loop {
+          match chars.next() {
+            Some('"') if quoted => break,
+            Some(']') if !quoted => break,
+            Some(c) => key.push(c),
+            None => return Err("Unterminated key in variable path"),
+          }
+        }
+        if quoted && chars.next() != Some(']') {
+          return Err("Unterminated key in variable path");
+        }
+        let key = if quoted { key } else { key.trim().to_owned() };
+        steps.push(Step::Key(key));
+      }
+      c if expect_name && (c.is_ascii_alphanumeric() || c == '_' || c == '$') => {
+        name.push(c);
+        let next = chars.peek();
+        if next.map_or(true, |next| *next == '.' || *next == '[') {
+          steps.push(Step::Member(std::mem::take(&mut name)));
+          expect_name = false;
+        }
+      }
+      _ => return Err("Invalid variable path"),
+    }
+  }
+  if expect_name {
+    return Err("Invalid variable path");
+  }
+  Ok(steps)
+}
+
+/// Reads every slot of the address in one batch.
+pub async fn fetch_slots(
+  transport: &Transport,
+  address: Address,
+  slots: &[H256],
+  block: Option<BlockSelector>,
+) -> web3::Result<Vec<H256>> {
+  let calls: Vec<Vec<Value>> = slots
+    .iter()
+    .map(|slot| {
+      vec![
+        helpers::serialize(&address),
+        helpers::serialize(slot),
+        block_param(block),
+      ]
+    })
+    .collect();
+  let mut words = Vec::new();
+  for value in execute_batch(transport, "eth_getStorageAt", &calls).await? {
+    words.push(helpers::decode(value)?);
+  }
+  Ok(words)
+}
+
+/// Reads a variable, every round fetches the slots decoding ran into in one batch.
+pub async fn read_var<'a>(
+  node: &NodeData,
+  layout: &Layout,
+  location: &Location,
+  address: Address,
+  block: Option<BlockSelector>,
+  timeout: Duration,
+) -> Result<Decoded, &'a str> {
+  let (transport, retry) = (node.web3.transport(), &node.retry);
+  let mut words = Words::default();
+  for _ in 0..MAX_ROUNDS {
+    if let Some(value) = layout.decode(location, &mut words)? {
+      return Ok(value);
+    }
+    let slots = words.missing();
+    let fetched = retry
+      .timeout(timeout, || fetch_slots(transport, address, &slots, block))
+      .await
+      .or_else(|_| Err("Storage request timed out"))?
+      .or_else(|e| {
+        cblog!("Storage error: {}", e);
+        Err("Storage request failed")
+      })?;
+    for (slot, word) in slots.into_iter().zip(fetched) {
+      words.insert(slot, word);
+    }
+  }
+  Err("Variable is nested too deeply to be read at once")
+}
+
+fn decoded_to_var<'a>(value: &Decoded) -> Result<ClonedVar, &'a str> {
+  match value {
+    Decoded::Bool(flag) => Ok((*flag).into()),
+    Decoded::Bytes(bytes) => Ok(bytes.as_slice().into()),
+    Decoded::Text(text) => {
+      let text = CString::new(text.as_str()).or_else(|_| Err("Invalid string in storage"))?;
+      Ok(text.as_ref().into())
+    }
+    Decoded::List(values) => {
+      let mut vars = Vec::<ClonedVar>::new();
+      for value in values {
+        vars.push(decoded_to_var(value)?);
+      }
+      Ok(vars.as_slice().into())
+    }
+    Decoded::Struct(members) => {
+      let mut table = Table::new();
+      for (name, value) in members {
+        let name = CString::new(name.as_str()).or_else(|_| Err("Invalid member name"))?;
+        let value = decoded_to_var(value)?;
+        table.insert_fast(&name, value.0);
+      }
+      let var: Var = table.as_ref().into();
+      Ok(var.into())
+    }
+  }
+}
+
+pub struct StorageVar {
+  address: ParamVar,
+  layout_text: ClonedVar,
+  layout: Option<Layout>,
+  variable: ParamVar,
+  block: ParamVar,
+  node_param: ParamVar,
+  node: Option<Arc<NodeData>>,
+  output: ClonedVar,
+  timeout: ParamVar,
+  requiring: ExposedTypes,
+}
+
+impl Default for StorageVar {
+  fn default() -> Self {
+    StorageVar {
+      address: ParamVar::new(cstr!("").into()),
+      layout_text: ClonedVar(Var::default()),
+      layout: None,
+      variable: ParamVar::new(cstr!("").into()),
+      block: ParamVar::new(().into()),
+      node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
+      node: None,
+      output: ClonedVar(Var::default()),
+      timeout: ParamVar::new(().into()),
+      requiring: Vec::new(),
+    }
+  }
+}
+
+lazy_static! {
+  static ref INPUT_TYPES: Vec<Type> = vec![common_type::none];
+  static ref OUTPUT_TYPES: Vec<Type> = vec![common_type::any];
+  static ref PARAMETERS: Parameters = vec![
+    (
+      cstr!("Address"),
+      cstr!("The address of the contract to read from, or an ENS name."),
+      vec![
+        common_type::string,
+        common_type::string_var,
+        common_type::bytes,
+        common_type::bytes_var
+      ],
+    )
+      .into(),
+    (
+      cstr!("Layout"),
+      cstr!("The storageLayout JSON solc outputs for the contract."),
+      vec![common_type::string],
+    )
+      .into(),
+    (
+      cstr!("Variable"),
+      cstr!("The path of the variable to read, like balances[0xabc].amount or owners[2]."),
+      vec![common_type::string, common_type::string_var],
+    )
+      .into(),
+    (
+      cstr!("Block"),
+      cstr!(
+        "The optional block to read from history: a number, a tag like safe or finalized, or a block hash."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::string,
+        common_type::bytes,
+        BLOCK_HASH_TYPE,
+        BLOCK_VAR
+      ],
+    )
+      .into(),
+    (
+      cstr!("Node"),
+      cstr!("The ethereum node block variable to use."),
+      vec![NODE_VAR],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
+  ];
+}
+
+impl Block for StorageVar {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.StorageVar-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.StorageVar")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.StorageVar"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &INPUT_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &OUTPUT_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.address.set_param(value),
+      1 => {
+        self.layout_text = value.into();
+        self.layout = None;
+      }
+      2 => self.variable.set_param(value),
+      3 => self.block.set_param(value),
+      4 => self.node_param.set_param(value),
+      5 => self.timeout.set_param(value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.address.get_param(),
+      1 => self.layout_text.0,
+      2 => self.variable.get_param(),
+      3 => self.block.get_param(),
+      4 => self.node_param.get_param(),
+      5 => self.timeout.get_param(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.requiring.clear();
+    let exp_info = ExposedInfo {
+      exposedType: NODE_TYPE,
+      name: self.node_param.get_name(),
+      help: cstr!("The required ethereum node to use as gateway.").into(),
+      ..ExposedInfo::default()
+    };
+    self.requiring.push(exp_info);
+    Some(&self.requiring)
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    let text = String::try_from(&self.layout_text.0).or_else(|_| Err("Layout is required"))?;
+    self.layout = Some(Layout::parse(&text)?);
+    self.node_param.warmup(context);
+    self.address.warmup(context);
+    self.variable.warmup(context);
+    self.block.warmup(context);
+    self.timeout.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.timeout.cleanup();
+    self.block.cleanup();
+    self.variable.cleanup();
+    self.address.cleanup();
+    self.node_param.cleanup();
+    self.node = None;
+    self.layout = None;
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(activate_blocking(self, context, input))
+  }
+}
+
+impl BlockingBlock for StorageVar {
+  fn activate_blocking(&mut self, _: &Context, _: &Var) -> Result<Var, &str> {
+    if self.node.is_none() {
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
+    }
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
+    let layout = self.layout.as_ref().ok_or("Layout is required")?;
+    let address = resolve_address(node, self.address.get(), timeout)?;
+    let block = BlockSelector::from_var(&self.block.get())?;
+    let path = String::try_from(&self.variable.get())?;
+    let location = layout.locate(&path)?;
+    let value = RUNTIME.block_on(read_var(node, layout, &location, address, block, timeout))?;
+    self.output = decoded_to_var(&value)?;
+    Ok(self.output.0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::CONTRACT;
+  use serde_json::json;
+
+  // contract C {
+  //   struct Account { uint128 amount; bool frozen; address owner; }
+  //   address owner; bool paused;
+  //   mapping(address => Account) accounts;
+  //   uint64[] ids;
+  //   string name;
+  //   mapping(uint256 => mapping(string => uint256)) nested;
+  //   uint256[2] pair;
+  // }
+  const LAYOUT: &str = r#"{
+    "storage": [
+      {"label": "owner", "offset": 0, "slot": "0", "type": "t_address"},
+      {"label": "paused", "offset": 20, "slot": "0", "type": "t_bool"},
+      {"label": "accounts", "offset": 0, "slot": "1", "type": "t_mapping(t_address,t_struct(Account)5_storage)"},
+      {"label": "ids", "offset": 0, "slot": "2", "type": "t_array(t_uint64)dyn_storage"},
+      {"label": "name", "offset": 0, "slot": "3", "type": "t_string_storage"},
+      {"label": "nested", "offset": 0, "slot": "4", "type": "t_mapping(t_uint256,t_mapping(t_string_memory_ptr,t_uint256))"},
+      {"label": "pair", "offset": 0, "slot": "5", "type": "t_array(t_uint256)2_storage"}
+    ],
+    "types": {
+      "t_address": {"encoding": "inplace", "label": "address", "numberOfBytes": "20"},
+      "t_bool": {"encoding": "inplace", "label": "bool", "numberOfBytes": "1"},
+      "t_uint64": {"encoding": "inplace", "label": "uint64", "numberOfBytes": "8"},
+      "t_uint128": {"encoding": "inplace", "label": "uint128", "numberOfBytes": "16"},
+      "t_uint256": {"encoding": "inplace", "label": "uint256", "numberOfBytes": "32"},
+      "t_string_storage": {"encoding": "bytes", "label": "string", "numberOfBytes": "32"},
+      "t_string_memory_ptr": {"encoding": "bytes", "label": "string", "numberOfBytes": "32"},
+      "t_array(t_uint64)dyn_storage": {"encoding": "dynamic_array", "base": "t_uint64", "label": "uint64[]", "numberOfBytes": "32"},
+      "t_array(t_uint256)2_storage": {"encoding": "inplace", "base": "t_uint256", "label": "uint256[2]", "numberOfBytes": "64"},
+      "t_mapping(t_address,t_struct(Account)5_storage)": {"encoding": "mapping", "key": "t_address", "label": "mapping(address => struct C.Account)", "numberOfBytes": "32", "value": "t_struct(Account)5_storage"},
+      "t_mapping(t_uint256,t_mapping(t_string_memory_ptr,t_uint256))": {"encoding": "mapping", "key": "t_uint256", "label": "mapping(uint256 => mapping(string => uint256))", "numberOfBytes": "32", "value": "t_mapping(t_string_memory_ptr,t_uint256)"},
+      "t_mapping(t_string_memory_ptr,t_uint256)": {"encoding": "mapping", "key": "t_string_memory_ptr", "label": "mapping(string => uint256)", "numberOfBytes": "32", "value": "t_uint256"},
+      "t_struct(Account)5_storage": {"encoding": "inplace", "label": "struct C.Account", "numberOfBytes": "64", "members": [
+        {"label": "amount", "offset": 0, "slot": "0", "type": "t_uint128"},
+        {"label": "frozen", "offset": 16, "slot": "0", "type": "t_bool"},
+        {"label": "owner", "offset": 0, "slot": "1", "type": "t_address"}
+      ]}
+    }
+  }"#;
+
+  fn slot(n: u64) -> H256 {
+    H256::from_low_u64_be(n)
+  }
+
+  fn word(hex: &str) -> Value {
+    json!(format!("0x{:0>64}", hex))
+  }
+
+  #[test]
+  fn parses_variable_paths() {
+    assert_eq!(
+      parse_path("accounts[0xabc].amount").unwrap(),
+      vec![
+        Step::Member("accounts".into()),
+        Step::Key("0xabc".into()),
+        Step::Member("amount".into())
+      ]
+    );
+    assert_eq!(
+      parse_path(r#"nested[1]["a]b"]"#).unwrap(),
+      vec![
+        Step::Member("nested".into()),
+        Step::Key("1".into()),
+        Step::Key("a]b".into())
+      ]
+    );
+    assert!(parse_path("accounts.").is_err());
+    assert!(parse_path("accounts[1").is_err());
+    assert!(parse_path("[1]").is_err());
+  }
+
+  #[test]
+  fn locates_members_keys_and_elements() {
+    let layout = Layout::parse(LAYOUT).unwrap();
+    let paused = layout.locate("paused").unwrap();
+    assert_eq!((paused.slot, paused.offset), (slot(0), 20));
+
+    let key = format!("0x{}", "11".repeat(20));
+    let account = mapping_slot(&text_key(&key, "address").unwrap(), slot(1));
+    let owner = layout.locate(&format!("accounts[{}].owner", key)).unwrap();
+    assert_eq!(owner.slot, offset_slot(account, 1.into()));
+    let frozen = layout.locate(&format!("accounts[{}].frozen", key)).unwrap();
+    assert_eq!((frozen.slot, frozen.offset), (account, 16));
+
+    // four uint64 share a slot
+    let fifth = layout.locate("ids[5]").unwrap();
+    assert_eq!(fifth.slot, offset_slot(array_start(slot(2)), 1.into()));
+    assert_eq!(fifth.offset, 8);
+
+    let inner = mapping_slot(&text_key("7", "uint256").unwrap(), slot(4));
+    let nested = layout.locate(r#"nested[7]["alice"]"#).unwrap();
+    assert_eq!(nested.slot, mapping_slot(b"alice", inner));
+
+    assert_eq!(layout.locate("pair[1]").unwrap().slot, slot(6));
+    assert!(layout.locate("pair[2]").is_err());
+    assert!(layout.locate("missing").is_err());
+    assert!(layout.locate("owner.amount").is_err());
+  }
+
+  #[test]
+  fn decodes_what_it_was_given() {
+    let layout = Layout::parse(LAYOUT).unwrap();
+    let mut words = Words::default();
+    let location = layout.locate("name").unwrap();
+    assert_eq!(layout.decode(&location, &mut words).unwrap(), None);
+    assert_eq!(words.missing(), vec![slot(3)]);
+    // a short string keeps its bytes and length * 2 in one slot
+    let mut short = [0u8; 32];
+    short[..2].copy_from_slice(b"hi");
+    short[31] = 4;
+    words.insert(slot(3), short.into());
+    assert_eq!(
+      layout.decode(&location, &mut words).unwrap(),
+      Some(Decoded::Text("hi".into()))
+    );
+  }
+
+  #[test]
+  fn struct_mappings_are_left_out() {
+    // struct Vault { bool open; mapping(address => uint256) shares; bool paused; }
+    // Vault vault;
+    let layout = Layout::parse(
+      r#"{
+      "storage": [
+        {"label": "vault", "offset": 0, "slot": "0", "type": "t_struct(Vault)1_storage"}
+      ],
+      "types": {
+        "t_address": {"encoding": "inplace", "label": "address", "numberOfBytes": "20"},
+        "t_bool": {"encoding": "inplace", "label": "bool", "numberOfBytes": "1"},
+        "t_uint256": {"encoding": "inplace", "label": "uint256", "numberOfBytes": "32"},
+        "t_mapping(t_address,t_uint256)": {"encoding": "mapping", "key": "t_address", "label": "mapping(address => uint256)", "numberOfBytes": "32", "value": "t_uint256"},
+        "t_struct(Vault)1_storage": {"encoding": "inplace", "label": "struct C.Vault", "numberOfBytes": "96", "members": [
+          {"label": "open", "offset": 0, "slot": "0", "type": "t_bool"},
+          {"label": "shares", "offset": 0, "slot": "1", "type": "t_mapping(t_address,t_uint256)"},
+          {"label": "paused", "offset": 0, "slot": "2", "type": "t_bool"}
+        ]}
+      }
+    }"#,
+    )
+    .unwrap();
+    let location = layout.locate("vault").unwrap();
+    let mut words = Words::default();
+    assert_eq!(layout.decode(&location, &mut words).unwrap(), None);
+    assert_eq!(words.missing(), vec![slot(0), slot(2)]);
+
+    words.insert(slot(0), H256::from_low_u64_be(1));
+    words.insert(slot(2), H256::zero());
+    assert_eq!(
+      layout.decode(&location, &mut words).unwrap(),
+      Some(Decoded::Struct(vec![
+        ("open".into(), Decoded::Bool(true)),
+        ("paused".into(), Decoded::Bool(false)),
+      ]))
+    );
+  }
+
+  #[test]
+  fn reads_structs_in_one_batch() {
+    let node = MockNode::start();
+    let layout = Layout::parse(LAYOUT).unwrap();
+    let key = format!("0x{}", "11".repeat(20));
+    let location = layout.locate(&format!("accounts[{}]", key)).unwrap();
+    let address = format!("0x{}", CONTRACT);
+    let first = format!("{:?}", location.slot);
+    let second = format!("{:?}", offset_slot(location.slot, 1.into()));
+    // frozen packed after a uint128 amount of 5
+    node.expect_params(
+      "eth_getStorageAt",
+      json!([address, first, "latest"]),
+      word(&format!("01{:032x}", 5)),
+    );
+    node.expect_params(
+      "eth_getStorageAt",
+      json!([address, second, "latest"]),
+      word(&"22".repeat(20)),
+    );
+    let eth = node.node();
+    let value = RUNTIME
+      .block_on(read_var(
+        &eth,
+        &layout,
+        &location,
+        CONTRACT.parse().unwrap(),
+        None,
+        Duration::from_secs(5),
+      ))
+      .unwrap();
+    let mut amount = vec![0u8; 32];
+    amount[31] = 5;
+    assert_eq!(
+      value,
+      Decoded::Struct(vec![
+        ("amount".into(), Decoded::Bytes(amount)),
+        ("frozen".into(), Decoded::Bool(true)),
+        ("owner".into(), Decoded::Bytes(vec![0x22; 20])),
+      ])
+    );
+    assert_eq!(node.count("eth_getStorageAt"), 2);
+  }
+
+  #[test]
+  fn follows_dynamic_data_in_more_rounds() {
+    let node = MockNode::start();
+    let layout = Layout::parse(LAYOUT).unwrap();
+    let location = layout.locate("ids").unwrap();
+    let address = format!("0x{}", CONTRACT);
+    node.expect_params(
+      "eth_getStorageAt",
+      json!([address, format!("{:?}", slot(2)), "latest"]),
+      word("5"),
+    );
+    let start = array_start(slot(2));
+    node.expect_params(
+      "eth_getStorageAt",
+      json!([address, format!("{:?}", start), "latest"]),
+      word(&format!("{:016x}{:016x}{:016x}{:016x}", 4, 3, 2, 1)),
+    );
+    node.expect_params(
+      "eth_getStorageAt",
+      json!([
+        address,
+        format!("{:?}", offset_slot(start, 1.into())),
+        "latest"
+      ]),
+      word("9"),
+    );
+    let eth = node.node();
+    let value = RUNTIME
+      .block_on(read_var(
+        &eth,
+        &layout,
+        &location,
+        CONTRACT.parse().unwrap(),
+        None,
+        Duration::from_secs(5),
+      ))
+      .unwrap();
+    let ids: Vec<u8> = match value {
+      Decoded::List(values) => values
+        .into_iter()
+        .map(|value| match value {
+          Decoded::Bytes(word) => word[31],
+          _ => unreachable!(),
+        })
+        .collect(),
+      _ => unreachable!(),
+    };
+    assert_eq!(ids, vec![1, 2, 3, 4, 9]);
+    // the length, then both element slots in one batch
+    assert_eq!(node.count("eth_getStorageAt"), 3);
+  }
+}
@@ -1,3 +1,4 @@
+use crate::blocks::address::parse_address;
 // use chainblocks::cblog;
 // use chainblocks::core::log;
 use chainblocks::types::Seq;
@@ -5,6 +6,7 @@ use chainblocks::types::{ClonedVar, Var};
 use ethabi::token::Token;
 use json::JsonValue;
 use regex::Regex;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::str;
@@ -27,7 +29,12 @@ impl Detokenize for MyTokens {
     }
 }
 
-fn var_seq_to_token<'a>(input: &Var, matching: &str, input_type: &str) -> Result<Token, &'a str> {
+fn var_seq_to_token<'a>(
+    input: &Var,
+    matching: &str,
+    input_type: &str,
+    names: &HashMap<String, Address>,
+) -> Result<Token, &'a str> {
     lazy_static! {
         static ref RE: Regex = Regex::new(r"\[\d*?\]").unwrap();
     }
@@ -51,7 +58,7 @@ fn var_seq_to_token<'a>(input: &Var, matching: &str, input_type: &str) -> Result
             let mut sub_tokens = Vec::<Token>::new();
             let slice: Seq = input.try_into().unwrap();
             for v in slice.iter() {
-                let token = var_to_token(&v, &input_type[..matching.len() + last.start()])?;
+                let token = var_to_token(&v, &input_type[..matching.len() + last.start()], names)?;
                 sub_tokens.push(token);
             }
             if s.len() == 2 {
@@ -67,15 +74,20 @@ fn var_seq_to_token<'a>(input: &Var, matching: &str, input_type: &str) -> Result
     }
 }
 
-pub fn var_to_token<'a>(input: &Var, input_type: &str) -> Result<Token, &'a str> {
+/// Converts an argument, address strings can also be ENS names resolved in names.
+pub fn var_to_token<'a>(
+    input: &Var,
+    input_type: &str,
+    names: &HashMap<String, Address>,
+) -> Result<Token, &'a str> {
     // TODO/WIP handle more types
     if input.is_seq() {
         if input_type.starts_with("uint256") {
-            var_seq_to_token(input, "uint256", input_type)
+            var_seq_to_token(input, "uint256", input_type, names)
         } else if input_type.starts_with("address") {
-            var_seq_to_token(input, "address", input_type)
+            var_seq_to_token(input, "address", input_type, names)
         } else if input_type.starts_with("bytes") {
-            var_seq_to_token(input, "bytes", input_type)
+            var_seq_to_token(input, "bytes", input_type, names)
         } else {
             Err("Not implemented input seq into token")
         }
@@ -91,9 +103,10 @@ pub fn var_to_token<'a>(input: &Var, input_type: &str) -> Result<Token, &'a str>
             };
 
             if input_type == "address" {
-                let address: Address = svalue
-                    .parse()
-                    .or_else(|_| Err("Failed to parse an input address"))?;
+                if let Some(address) = names.get(&value) {
+                    return Ok(address.into_token());
+                }
+                let address = parse_address(&value, "Failed to parse an input address")?;
                 Ok(address.into_token())
             } else if input_type == "uint256" {
                 let uvalue: U256 = svalue
@@ -134,7 +147,11 @@ pub fn var_to_token<'a>(input: &Var, input_type: &str) -> Result<Token, &'a str>
     }
 }
 
-pub fn var_to_tokens<'a>(input: &Var, input_types: &Vec<String>) -> Result<Vec<Token>, &'a str> {
+pub fn var_to_tokens<'a>(
+    input: &Var,
+    input_types: &Vec<String>,
+    names: &HashMap<String, Address>,
+) -> Result<Vec<Token>, &'a str> {
     let args: &[Var] = input.try_into().unwrap_or(&[]);
     if args.len() != input_types.len() {
         return Err("Invalid number of inputs, please check the abi again");
@@ -144,7 +161,7 @@ pub fn var_to_tokens<'a>(input: &Var, input_types: &Vec<String>) -> Result<Vec<T
     for i in 0..args.len() {
         let arg = &args[i];
         let input_type = &input_types[i];
-        tokens.push(var_to_token(arg, input_type)?);
+        tokens.push(var_to_token(arg, input_type, names)?);
     }
 
     // for token in &tokens {
@@ -265,3 +282,44 @@ pub fn hash_event<'a>(event: &str, json_abi: &JsonValue) -> Result<H256, &'a str
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::mock::ABI;
+
+    const ERC20_EVENTS: &str = r#"[
+        {"type":"event","name":"Transfer","inputs":[
+            {"name":"from","type":"address","indexed":true},
+            {"name":"to","type":"address","indexed":true},
+            {"name":"value","type":"uint256","indexed":false}]}
+    ]"#;
+
+    #[test]
+    fn gathers_method_inputs() {
+        let abi = json::parse(ABI).unwrap();
+        assert_eq!(
+            gather_inputs("totalSupply", &abi).unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(gather_inputs("Poked", &abi).unwrap(), vec!["address"]);
+        assert_eq!(
+            gather_inputs("nothing", &abi),
+            Err("Method not found in contract")
+        );
+        assert!(gather_inputs("name", &json::parse("{}").unwrap()).is_err());
+    }
+
+    #[test]
+    fn hashes_event_signatures() {
+        let abi = json::parse(ERC20_EVENTS).unwrap();
+        let topic: H256 = "ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"
+            .parse()
+            .unwrap();
+        assert_eq!(hash_event("Transfer", &abi).unwrap(), topic);
+        assert_eq!(
+            hash_event("Approval", &abi),
+            Err("Event not found in contract")
+        );
+    }
+}
@@ -1,11 +1,15 @@
-use crate::blocks::get_timeout;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::shared_from_var;
 use crate::blocks::NodeData;
 use crate::blocks::NODE_TYPE;
 use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
 use chainblocks::block::Block;
 use chainblocks::cblog;
 use chainblocks::core::activate_blocking;
-use chainblocks::core::log;
 use chainblocks::core::BlockingBlock;
 use chainblocks::cstr;
 use chainblocks::types::common_type;
@@ -18,18 +22,36 @@ use chainblocks::types::Table;
 use chainblocks::types::Type;
 use chainblocks::types::Var;
 use std::convert::TryInto;
-use std::rc::Rc;
 use std::str;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::timeout;
 use web3::types::TransactionId;
 use web3::types::H256;
 
+/// The transaction with the given hash, pending or mined.
+pub async fn get_transaction<'a>(
+  node: &NodeData,
+  hash: H256,
+  timeout: Duration,
+) -> Result<web3::types::Transaction, &'a str> {
+  let eth = node.web3.eth();
+  node
+    .retry
+    .timeout(timeout, || eth.transaction(TransactionId::Hash(hash)))
+    .await
+    .or_else(|_| Err("Transaction request timedout"))?
+    .or_else(|e| {
+      cblog!("Transaction error: {}", e);
+      Err("Transaction request failed")
+    })?
+    .ok_or("Transaction not found")
+}
+
 pub struct Transaction {
   node_param: ParamVar,
-  node: Option<Rc<Option<NodeData>>>,
+  node: Option<Arc<NodeData>>,
   output: Table,
-  timeout: Duration,
+  timeout: ParamVar,
   requiring: ExposedTypes,
 }
 
@@ -39,7 +61,7 @@ impl Default for Transaction {
       node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
       node: None,
       output: Table::new(),
-      timeout: get_timeout(),
+      timeout: ParamVar::new(().into()),
       requiring: Vec::new(),
     }
   }
@@ -48,12 +70,27 @@ impl Default for Transaction {
 lazy_static! {
   static ref INPUT_TYPES: Vec<Type> = vec![common_type::string, common_type::bytes];
   static ref OUTPUT_TYPES: Vec<Type> = vec![crate::blocks::TX_TABLE_TYPE];
-  static ref PARAMETERS: Parameters = vec![(
-    cstr!("Node"),
-    cstr!("The ethereum node block variable to use."),
-    vec![NODE_VAR],
-  )
-    .into(),];
+  static ref PARAMETERS: Parameters = vec![
+    (
+      cstr!("Node"),
+      cstr!("The ethereum node block variable to use."),
+      vec![NODE_VAR],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
+  ];
 }
 
 impl Block for Transaction {
@@ -84,6 +121,7 @@ impl Block for Transaction {
   fn setParam(&mut self, index: i32, value: &Var) {
     match index {
       0 => self.node_param.set_param(value),
+      1 => self.timeout.set_param(value),
       _ => unreachable!(),
     }
   }
@@ -91,6 +129,7 @@ impl Block for Transaction {
   fn getParam(&mut self, index: i32) -> Var {
     match index {
       0 => self.node_param.get_param(),
+      1 => self.timeout.get_param(),
       _ => unreachable!(),
     }
   }
@@ -109,10 +148,12 @@ impl Block for Transaction {
 
   fn warmup(&mut self, context: &Context) -> Result<(), &str> {
     self.node_param.warmup(context);
+    self.timeout.warmup(context);
     Ok(())
   }
 
   fn cleanup(&mut self) {
+    self.timeout.cleanup();
     self.node_param.cleanup();
     self.node = None;
     self.output = Table::new();
@@ -126,67 +167,82 @@ impl Block for Transaction {
 impl BlockingBlock for Transaction {
   fn activate_blocking(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
     if self.node.is_none() {
-      self.node = Some(Var::from_object_as_clone::<Option<NodeData>>(
-        self.node_param.get(),
-        &NODE_TYPE,
-      )?);
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
     }
-    let node = Var::get_mut_from_clone(&self.node)?;
-    let (scheduler, eth) = (&mut node.scheduler, &mut node.web3.eth());
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
     let hash: &[u8] = input.try_into()?;
-    let mut tx_hash = H256::zero();
-    tx_hash.assign_from_slice(hash);
-    let fut = eth.transaction(TransactionId::Hash(tx_hash));
-    scheduler.block_on(async {
-      let timed_fut = timeout(self.timeout, fut);
-      let fut_res = timed_fut.await;
-      if let Ok(res) = fut_res {
-        match res {
-          Ok(value) => {
-            if let Some(value) = value {
-              // mandatory keys
-              self
-                .output
-                .insert_fast_static(cstr!("input"), value.input.0.as_slice().into());
-              let bytes: [u8; 32] = value.gas.into();
-              let bytes = &bytes[..];
-              self.output.insert_fast_static(cstr!("gas"), bytes.into());
-              let bytes: [u8; 32] = value.gas_price.into();
-              let bytes = &bytes[..];
-              self
-                .output
-                .insert_fast_static(cstr!("gas_price"), bytes.into());
-              let bytes: [u8; 32] = value.value.into();
-              let bytes = &bytes[..];
-              self.output.insert_fast_static(cstr!("value"), bytes.into());
-              let bytes: [u8; 32] = value.nonce.into();
-              let bytes = &bytes[..];
-              self.output.insert_fast_static(cstr!("nonce"), bytes.into());
-              // optional keys
-              bytes_to_var!(self.output, value.from, 20, "from");
-              bytes_to_var!(self.output, value.to, 20, "to");
-              bytes_to_var!(self.output, value.v, 8, "v");
-              bytes_to_var!(self.output, value.r, 32, "r");
-              bytes_to_var!(self.output, value.s, 32, "s");
-              bytes_to_var!(self.output, value.block_hash, 32, "block_hash");
-              if let Some(block_number) = value.block_number {
-                self
-                  .output
-                  .insert_fast_static(cstr!("block_number"), block_number.as_u64().try_into()?);
-              }
-              Ok((&self.output).into())
-            } else {
-              Err("Transaction not found")
-            }
-          }
-          Err(e) => {
-            cblog!("Transaction error: {}", e);
-            Err("Transaction request failed")
-          }
-        }
-      } else {
-        Err("Transaction request timedout")
-      }
-    })
+    if hash.len() != 32 {
+      return Err("Expected a 32 bytes transaction hash");
+    }
+    let value = RUNTIME.block_on(get_transaction(node, H256::from_slice(hash), timeout))?;
+    // mandatory keys
+    self
+      .output
+      .insert_fast_static(cstr!("input"), value.input.0.as_slice().into());
+    let bytes: [u8; 32] = value.gas.into();
+    let bytes = &bytes[..];
+    self.output.insert_fast_static(cstr!("gas"), bytes.into());
+    let bytes: [u8; 32] = value.gas_price.into();
+    let bytes = &bytes[..];
+    self
+      .output
+      .insert_fast_static(cstr!("gas_price"), bytes.into());
+    let bytes: [u8; 32] = value.value.into();
+    let bytes = &bytes[..];
+    self.output.insert_fast_static(cstr!("value"), bytes.into());
+    let bytes: [u8; 32] = value.nonce.into();
+    let bytes = &bytes[..];
+    self.output.insert_fast_static(cstr!("nonce"), bytes.into());
+    // optional keys
+    bytes_to_var!(self.output, value.from, 20, "from");
+    bytes_to_var!(self.output, value.to, 20, "to");
+    bytes_to_var!(self.output, value.v, 8, "v");
+    bytes_to_var!(self.output, value.r, 32, "r");
+    bytes_to_var!(self.output, value.s, 32, "s");
+    bytes_to_var!(self.output, value.block_hash, 32, "block_hash");
+    if let Some(block_number) = value.block_number {
+      self
+        .output
+        .insert_fast_static(cstr!("block_number"), block_number.as_u64().try_into()?);
+    }
+    Ok((&self.output).into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::hash;
+  use crate::blocks::mock::transaction;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::ACCOUNT;
+  use jsonrpc_core::serde_json::json;
+
+  fn fetch(node: &MockNode, n: u8) -> Result<web3::types::Transaction, &'static str> {
+    let eth = node.node();
+    let hash = hash(n).parse().unwrap();
+    RUNTIME.block_on(get_transaction(&eth, hash, Duration::from_secs(5)))
+  }
+
+  #[test]
+  fn fetches_transactions_by_hash() {
+    let node = MockNode::start();
+    node.expect_params(
+      "eth_getTransactionByHash",
+      json!([hash(7)]),
+      transaction(&hash(7), 3),
+    );
+    let tx = fetch(&node, 7).unwrap();
+    assert_eq!(tx.from, Some(ACCOUNT.parse().unwrap()));
+    assert_eq!(tx.gas, 21000.into());
+    assert_eq!(tx.block_number, Some(3.into()));
+  }
+
+  #[test]
+  fn unknown_transactions_are_not_found() {
+    let node = MockNode::start();
+    node.expect("eth_getTransactionByHash", json!(null));
+    assert_eq!(fetch(&node, 7), Err("Transaction not found"));
   }
 }
@@ -0,0 +1,262 @@
+use crate::blocks::rlp::encode_bytes;
+use crate::blocks::rlp::encode_list;
+use crate::blocks::rlp::rlp_bytes;
+use crate::blocks::rlp::rlp_decode;
+use crate::blocks::rlp::Rlp;
+use web3::signing::keccak256;
+use web3::types::H256;
+
+// keccak256(rlp(""))
+pub const EMPTY_TRIE: &str = "56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421";
+
+enum Child {
+  Empty,
+  Hash(H256),
+  Inline(Vec<u8>),
+}
+
+fn child(item: &Rlp) -> Result<Child, &'static str> {
+  match item {
+    Rlp::Bytes(bytes) if bytes.is_empty() => Ok(Child::Empty),
+    Rlp::Bytes(bytes) if bytes.len() == 32 => Ok(Child::Hash(H256::from_slice(bytes))),
+    Rlp::List(raw, _) => Ok(Child::Inline(raw.to_vec())),
+    _ => Err("Invalid trie node reference"),
+  }
+}
+
+pub fn nibbles(bytes: &[u8]) -> Vec<u8> {
+  bytes
+    .iter()
+    .flat_map(|byte| vec![byte >> 4, byte & 0x0f])
+    .collect()
+}
+
+// hex prefix encoding, the first nibble flags leaves and odd lengths
+fn compact_path(encoded: &[u8]) -> Result<(Vec<u8>, bool), &'static str> {
+  let first = *encoded.first().ok_or("Invalid trie node path")?;
+  let flag = first >> 4;
+  if flag > 3 {
+    return Err("Invalid trie node path");
+  }
+  let mut path = Vec::new();
+  if flag & 1 == 1 {
+    path.push(first & 0x0f);
+  }
+  path.extend(nibbles(&encoded[1..]));
+  Ok((path, flag & 2 == 2))
+}
+
+fn hex_prefix(path: &[u8], leaf: bool) -> Vec<u8> {
+  let flag = if leaf { 0x20 } else { 0x00 };
+  let (mut encoded, rest) = if path.len() % 2 == 1 {
+    (vec![flag | 0x10 | path[0]], &path[1..])
+  } else {
+    (vec![flag], path)
+  };
+  for pair in rest.chunks(2) {
+    encoded.push(pair[0] << 4 | pair[1]);
+  }
+  encoded
+}
+
+/// A leaf node holding the rest of a path, in nibbles.
+pub fn leaf_node(path: &[u8], value: &[u8]) -> Vec<u8> {
+  encode_list(&[encode_bytes(&hex_prefix(path, true)), encode_bytes(value)])
+}
+
+/// The value at keccak256(key) in the secure trie with this root, None if the proof shows there is none.
+pub fn verify_proof<'a>(
+  root: H256,
+  key: &[u8],
+  proof: &[Vec<u8>],
+) -> Result<Option<Vec<u8>>, &'a str> {
+  if root == EMPTY_TRIE.parse().unwrap() {
+    return Ok(None);
+  }
+  let path = nibbles(&keccak256(key));
+  let mut position = 0;
+  let mut nodes = proof.iter();
+  let mut next = Child::Hash(root);
+  loop {
+    let node = match next {
+      Child::Empty => return Ok(None),
+      Child::Hash(hash) => {
+        let node = nodes.next().ok_or("Proof is missing trie nodes")?;
+        if H256::from(keccak256(node)) != hash {
+          return Err("Proof doesn't match the trie root");
+        }
+        node.clone()
+      }
+      Child::Inline(node) => node,
+    };
+    let items = match rlp_decode(&node)? {
+      Rlp::List(_, items) => items,
+      _ => return Err("Invalid trie node"),
+    };
+    next = match items.len() {
+      17 => {
+        // every key is a 32 bytes hash, so values only live in leaves
+        let nibble = *path.get(position).ok_or("Invalid trie node")?;
+        position += 1;
+        child(&items[nibble as usize])?
+      }
+      2 => {
+        let (partial, leaf) = compact_path(rlp_bytes(&items[0])?)?;
+        let rest = &path[position..];
+        if leaf {
+          return Ok(if rest == partial.as_slice() {
+            Some(rlp_bytes(&items[1])?.to_vec())
+          } else {
+            None
+          });
+        }
+        if !rest.starts_with(&partial) {
+          return Ok(None);
+        }
+        position += partial.len();
+        child(&items[1])?
+      }
+      _ => return Err("Invalid trie node"),
+    };
+  }
+}
+
+// nodes under 32 bytes are embedded in their parent instead of hashed
+fn reference(node: Vec<u8>) -> Vec<u8> {
+  if node.len() < 32 {
+    node
+  } else {
+    encode_bytes(&keccak256(&node))
+  }
+}
+
+fn trie_node(entries: &[(Vec<u8>, &[u8])], depth: usize) -> Vec<u8> {
+  if entries.len() == 1 {
+    let (path, value) = &entries[0];
+    return leaf_node(&path[depth..], value);
+  }
+  let first = &entries[0].0;
+  let mut shared = 0;
+  while entries
+    .iter()
+    .all(|(path, _)| path.len() > depth + shared && path[depth + shared] == first[depth + shared])
+  {
+    shared += 1;
+  }
+  if shared > 0 {
+    let path = &first[depth..depth + shared];
+    return encode_list(&[
+      encode_bytes(&hex_prefix(path, false)),
+      reference(trie_node(entries, depth + shared)),
+    ]);
+  }
+  let mut items = Vec::new();
+  for nibble in 0..16 {
+    let branch: Vec<(Vec<u8>, &[u8])> = entries
+      .iter()
+      .filter(|(path, _)| path.len() > depth && path[depth] == nibble)
+      .cloned()
+      .collect();
+    items.push(if branch.is_empty() {
+      encode_bytes(&[])
+    } else {
+      reference(trie_node(&branch, depth + 1))
+    });
+  }
+  // a key ending here, one prefixing others
+  let value = entries
+    .iter()
+    .find(|(path, _)| path.len() == depth)
+    .map_or(&[][..], |(_, value)| value);
+  items.push(encode_bytes(value));
+  encode_list(&items)
+}
+
+/// The root of the trie holding these distinct keys, as they are, not hashed.
+pub fn trie_root(entries: &[(Vec<u8>, Vec<u8>)]) -> H256 {
+  if entries.is_empty() {
+    return EMPTY_TRIE.parse().unwrap();
+  }
+  let entries: Vec<(Vec<u8>, &[u8])> = entries
+    .iter()
+    .map(|(key, value)| (nibbles(key), value.as_slice()))
+    .collect();
+  keccak256(&trie_node(&entries, 0)).into()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // a leaf holding the rest of the hashed key from position on
+  fn leaf(key: &[u8], position: usize, value: &[u8]) -> Vec<u8> {
+    leaf_node(&nibbles(&keccak256(key))[position..], value)
+  }
+
+  fn root(node: &[u8]) -> H256 {
+    keccak256(node).into()
+  }
+
+  #[test]
+  fn verifies_leaves_and_absences() {
+    let node = leaf(b"key", 0, b"value");
+    let proof = vec![node.clone()];
+    assert_eq!(
+      verify_proof(root(&node), b"key", &proof).unwrap(),
+      Some(b"value".to_vec())
+    );
+    assert_eq!(verify_proof(root(&node), b"other", &proof).unwrap(), None);
+    assert!(verify_proof(H256::zero(), b"key", &proof).is_err());
+    assert!(verify_proof(root(&node), b"key", &[]).is_err());
+  }
+
+  #[test]
+  fn follows_branches() {
+    // two keys splitting at the first nibble, and one more nibble still empty
+    let first = b"a".to_vec();
+    let second = (0u8..)
+      .map(|n| vec![n])
+      .find(|key| keccak256(key)[0] >> 4 != keccak256(&first)[0] >> 4)
+      .unwrap();
+    let (left, right) = (leaf(&first, 1, b"one"), leaf(&second, 1, b"two"));
+    let mut items = vec![encode_bytes(&[]); 17];
+    items[(keccak256(&first)[0] >> 4) as usize] = encode_bytes(&keccak256(&left));
+    items[(keccak256(&second)[0] >> 4) as usize] = encode_bytes(&keccak256(&right));
+    let branch = encode_list(&items);
+    let trie = root(&branch);
+    assert_eq!(
+      verify_proof(trie, &second, &[branch.clone(), right.clone()]).unwrap(),
+      Some(b"two".to_vec())
+    );
+    assert!(verify_proof(trie, &second, &[branch.clone(), left.clone()]).is_err());
+    let missing = (0u8..)
+      .map(|n| vec![n, n])
+      .find(|key| items[(keccak256(key)[0] >> 4) as usize] == encode_bytes(&[]))
+      .unwrap();
+    assert_eq!(verify_proof(trie, &missing, &[branch]).unwrap(), None);
+  }
+
+  #[test]
+  fn builds_roots() {
+    assert_eq!(trie_root(&[]), EMPTY_TRIE.parse().unwrap());
+    // the example of the yellow paper wiki, with a key prefixing others
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = vec![
+      (b"do".to_vec(), b"verb".to_vec()),
+      (b"dog".to_vec(), b"puppy".to_vec()),
+      (b"doge".to_vec(), b"coin".to_vec()),
+      (b"horse".to_vec(), b"stallion".to_vec()),
+    ];
+    assert_eq!(
+      trie_root(&entries),
+      "5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84"
+        .parse()
+        .unwrap()
+    );
+    // what verify_proof walks
+    let key = keccak256(b"key").to_vec();
+    assert_eq!(
+      trie_root(&[(key, b"value".to_vec())]),
+      root(&leaf(b"key", 0, b"value"))
+    );
+  }
+}
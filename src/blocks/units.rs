@@ -0,0 +1,312 @@
+use chainblocks::block::Block;
+use chainblocks::cstr;
+use chainblocks::types::common_type;
+use chainblocks::types::ClonedVar;
+use chainblocks::types::Context;
+use chainblocks::types::Parameters;
+use chainblocks::types::Type;
+use chainblocks::types::Var;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::ffi::CString;
+use web3::types::U256;
+
+// 10^77 is the largest power of ten under 2^256
+const MAX_DECIMALS: u32 = 77;
+
+/// The decimals of a named unit, from wei to ether.
+pub fn unit_decimals(name: &str) -> Option<u32> {
+  match name {
+    "wei" => Some(0),
+    "kwei" => Some(3),
+    "mwei" => Some(6),
+    "gwei" => Some(9),
+    "szabo" => Some(12),
+    "finney" => Some(15),
+    "ether" => Some(18),
+    _ => None,
+  }
+}
+
+/// The exact integer amount of a decimal string like "1.2345" with this many decimals.
+pub fn parse_units<'a>(text: &str, decimals: u32) -> Result<U256, &'a str> {
+  let text = text.trim();
+  if text.starts_with('-') {
+    return Err("Amounts can't be negative");
+  }
+  let (whole, fraction) = match text.find('.') {
+    Some(dot) => (&text[..dot], &text[dot + 1..]),
+    None => (text, ""),
+  };
+  let digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+  if (whole.is_empty() && fraction.is_empty()) || !digits(whole) || !digits(fraction) {
+    return Err("Failed to parse a decimal amount");
+  }
+  // trailing zeros past the unit lose nothing
+  let fraction = fraction.trim_end_matches('0');
+  if fraction.len() > decimals as usize {
+    return Err("Amount has more decimals than the unit, it would lose precision");
+  }
+  let scaled = format!(
+    "{}{}{}",
+    whole,
+    fraction,
+    "0".repeat(decimals as usize - fraction.len())
+  );
+  let scaled = scaled.trim_start_matches('0');
+  if scaled.is_empty() {
+    return Ok(U256::zero());
+  }
+  U256::from_dec_str(scaled).or_else(|_| Err("Amount overflows 256 bits"))
+}
+
+/// The decimal string of an integer amount with this many decimals, "1.0" rather than "1".
+pub fn format_units(value: U256, decimals: u32) -> String {
+  let digits = value.to_string();
+  let decimals = decimals as usize;
+  let (whole, fraction) = if digits.len() > decimals {
+    let split = digits.len() - decimals;
+    (digits[..split].to_string(), digits[split..].to_string())
+  } else {
+    ("0".to_string(), format!("{:0>1$}", digits, decimals))
+  };
+  let fraction = fraction.trim_end_matches('0');
+  format!(
+    "{}.{}",
+    whole,
+    if fraction.is_empty() { "0" } else { fraction }
+  )
+}
+
+fn decimals_from_var<'a>(unit: &Var) -> Result<u32, &'a str> {
+  if let Ok(name) = <&str>::try_from(unit) {
+    unit_decimals(name).ok_or(
+      "Unknown unit, use wei, kwei, mwei, gwei, szabo, finney, ether or a number of decimals",
+    )
+  } else {
+    let decimals: i64 = unit.try_into()?;
+    u32::try_from(decimals)
+      .ok()
+      .filter(|decimals| *decimals <= MAX_DECIMALS)
+      .ok_or("Unit decimals must be between 0 and 77")
+  }
+}
+
+lazy_static! {
+  static ref AMOUNT_TYPES: Vec<Type> = vec![common_type::string, common_type::int];
+  static ref VALUE_TYPES: Vec<Type> = vec![common_type::bytes, common_type::int];
+  static ref BYTES_TYPES: Vec<Type> = vec![common_type::bytes];
+  static ref STRING_TYPES: Vec<Type> = vec![common_type::string];
+  static ref PARAMETERS: Parameters = vec![(
+    cstr!("Unit"),
+    cstr!("A unit name like wei, gwei or ether, or the decimals of a token like 6 or 8."),
+    vec![common_type::string, common_type::int],
+  )
+    .into(),];
+}
+
+pub struct ParseUnits {
+  unit: ClonedVar,
+  decimals: u32,
+  output: ClonedVar,
+}
+
+impl Default for ParseUnits {
+  fn default() -> Self {
+    ParseUnits {
+      unit: Var::from(cstr!("ether")).into(),
+      decimals: 18,
+      output: ClonedVar(Var::default()),
+    }
+  }
+}
+
+impl Block for ParseUnits {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.ParseUnits-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.ParseUnits")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.ParseUnits"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &AMOUNT_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &BYTES_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.unit = value.into(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.unit.0,
+      _ => unreachable!(),
+    }
+  }
+
+  fn warmup(&mut self, _: &Context) -> Result<(), &str> {
+    self.decimals = decimals_from_var(&self.unit.0)?;
+    Ok(())
+  }
+
+  fn activate(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
+    let value = if let Ok(text) = <&str>::try_from(input) {
+      parse_units(text, self.decimals)?
+    } else {
+      let whole: i64 = input.try_into()?;
+      parse_units(&whole.to_string(), self.decimals)?
+    };
+    let bytes: [u8; 32] = value.into();
+    self.output = bytes[..].into();
+    Ok(self.output.0)
+  }
+}
+
+pub struct FormatUnits {
+  unit: ClonedVar,
+  decimals: u32,
+  output: ClonedVar,
+}
+
+impl Default for FormatUnits {
+  fn default() -> Self {
+    FormatUnits {
+      unit: Var::from(cstr!("ether")).into(),
+      decimals: 18,
+      output: ClonedVar(Var::default()),
+    }
+  }
+}
+
+impl Block for FormatUnits {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.FormatUnits-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.FormatUnits")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.FormatUnits"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &VALUE_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &STRING_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.unit = value.into(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.unit.0,
+      _ => unreachable!(),
+    }
+  }
+
+  fn warmup(&mut self, _: &Context) -> Result<(), &str> {
+    self.decimals = decimals_from_var(&self.unit.0)?;
+    Ok(())
+  }
+
+  fn activate(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
+    let value: U256 = if let Ok(bytes) = <&[u8]>::try_from(input) {
+      if bytes.len() > 32 {
+        return Err("Amount overflows 256 bits");
+      }
+      bytes.into()
+    } else {
+      let value: i64 = input.try_into()?;
+      u64::try_from(value)
+        .or_else(|_| Err("Amounts can't be negative"))?
+        .into()
+    };
+    let text = CString::new(format_units(value, self.decimals)).unwrap();
+    self.output = text.as_ref().into();
+    Ok(self.output.0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_exact_amounts() {
+    let ether = unit_decimals("ether").unwrap();
+    assert_eq!(
+      parse_units("1.2345", ether),
+      Ok(U256::from_dec_str("1234500000000000000").unwrap())
+    );
+    assert_eq!(parse_units("1", ether), Ok(U256::exp10(18)));
+    assert_eq!(parse_units(".5", 6), Ok(500_000.into()));
+    assert_eq!(parse_units("2.", 6), Ok(2_000_000.into()));
+    assert_eq!(parse_units("0.000", 0), Ok(U256::zero()));
+    assert_eq!(parse_units("1.10000000", 6), Ok(1_100_000.into()));
+    assert_eq!(parse_units("12.34", 8), Ok(1_234_000_000u64.into()));
+    assert_eq!(
+      parse_units("0.0000001", 6),
+      Err("Amount has more decimals than the unit, it would lose precision")
+    );
+    assert_eq!(
+      parse_units("1e18", 18),
+      Err("Failed to parse a decimal amount")
+    );
+    assert_eq!(
+      parse_units("1.2.3", 18),
+      Err("Failed to parse a decimal amount")
+    );
+    assert_eq!(
+      parse_units(".", 18),
+      Err("Failed to parse a decimal amount")
+    );
+    assert_eq!(parse_units("-1", 18), Err("Amounts can't be negative"));
+    let max = U256::max_value().to_string();
+    assert_eq!(parse_units(&max, 0), Ok(U256::max_value()));
+    assert_eq!(parse_units(&max, 1), Err("Amount overflows 256 bits"));
+  }
+
+  #[test]
+  fn formats_exact_amounts() {
+    assert_eq!(format_units(U256::exp10(18), 18), "1.0");
+    assert_eq!(
+      format_units(U256::from_dec_str("1234500000000000000").unwrap(), 18),
+      "1.2345"
+    );
+    assert_eq!(format_units(1.into(), 18), "0.000000000000000001");
+    assert_eq!(format_units(1_500_000.into(), 6), "1.5");
+    assert_eq!(format_units(U256::zero(), 6), "0.0");
+    assert_eq!(format_units(42.into(), 0), "42.0");
+    let max = U256::max_value();
+    assert_eq!(parse_units(&format_units(max, 77), 77), Ok(max));
+  }
+}
@@ -1,11 +1,16 @@
-use crate::blocks::get_timeout;
+use crate::blocks::address::parse_address;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::shared_from_var;
 use crate::blocks::NodeData;
 use crate::blocks::NODE_TYPE;
 use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
 use chainblocks::block::Block;
 use chainblocks::cblog;
 use chainblocks::core::activate_blocking;
-use chainblocks::core::log;
 use chainblocks::core::BlockingBlock;
 use chainblocks::cstr;
 use chainblocks::types::common_type;
@@ -16,17 +21,41 @@ use chainblocks::types::Type;
 use chainblocks::types::Var;
 use std::convert::TryInto;
 use std::ffi::CString;
-use std::rc::Rc;
 use std::str;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::timeout;
+use tokio::time;
+use web3::types::Address;
+
+/// Unlocks a node managed account for a single transaction.
+pub async fn unlock_account<'a>(
+  node: &NodeData,
+  address: Address,
+  password: &str,
+  timeout: Duration,
+) -> Result<(), &'a str> {
+  let personal = node.web3.personal();
+  // not retried, a wrong password would only be tried again
+  let unlocked = time::timeout(timeout, personal.unlock_account(address, password, None))
+    .await
+    .or_else(|_| Err("Timed out"))?
+    .or_else(|e| {
+      cblog!("Account unlock error: {}", e);
+      Err("Failed to unlock account")
+    })?;
+  if unlocked {
+    Ok(())
+  } else {
+    Err("Failed to unlock account")
+  }
+}
 
 pub struct Unlock {
   node_param: ParamVar,
-  node: Option<Rc<Option<NodeData>>>,
+  node: Option<Arc<NodeData>>,
   password_param: ParamVar,
   address: CString,
-  timeout: Duration,
+  timeout: ParamVar,
 }
 
 impl Default for Unlock {
@@ -36,7 +65,7 @@ impl Default for Unlock {
       node: None,
       password_param: ParamVar::new(cstr!("").into()),
       address: CString::new("").unwrap(),
-      timeout: get_timeout(),
+      timeout: ParamVar::new(().into()),
     }
   }
 }
@@ -62,6 +91,19 @@ lazy_static! {
       vec![NODE_VAR],
     )
       .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
   ];
 }
 
@@ -91,6 +133,7 @@ impl Block for Unlock {
       0 => self.address = value.try_into().unwrap(),
       1 => self.password_param.set_param(value),
       2 => self.node_param.set_param(value),
+      3 => self.timeout.set_param(value),
       _ => unreachable!(),
     }
   }
@@ -99,15 +142,18 @@ impl Block for Unlock {
       0 => self.address.as_ref().into(),
       1 => self.password_param.get_param(),
       2 => self.node_param.get_param(),
+      3 => self.timeout.get_param(),
       _ => unreachable!(),
     }
   }
   fn warmup(&mut self, context: &Context) -> Result<(), &str> {
     self.node_param.warmup(context);
     self.password_param.warmup(context);
+    self.timeout.warmup(context);
     Ok(())
   }
   fn cleanup(&mut self) {
+    self.timeout.cleanup();
     self.node_param.cleanup();
     self.password_param.cleanup();
     self.node = None;
@@ -120,24 +166,14 @@ impl Block for Unlock {
 impl BlockingBlock for Unlock {
   fn activate_blocking(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
     if self.node.is_none() {
-      self.node = Some(Var::from_object_as_clone::<Option<NodeData>>(
-        self.node_param.get(),
-        &NODE_TYPE,
-      )?);
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
     }
-    let node = Var::get_mut_from_clone(&self.node)?;
-    let (scheduler, personal) = (&mut node.scheduler, node.web3.personal());
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
     let address = {
       if let Ok(s) = self.address.to_str() {
         if s.len() > 0 {
-          if s.starts_with("0x") {
-            let subs: &str = &s[2..];
-            subs
-              .parse()
-              .or_else(|_| Err("Failed to parse From address"))
-          } else {
-            s.parse().or_else(|_| Err("Failed to parse From address"))
-          }
+          parse_address(s, "Failed to parse From address")
         } else {
           Err("Expected a publickey, got an empty string")
         }
@@ -146,25 +182,45 @@ impl BlockingBlock for Unlock {
       }
     }?;
     let passwd = self.password_param.get().as_ref().try_into()?;
-    let res = scheduler
-      .block_on(async {
-        let fut = personal.unlock_account(address, passwd, None);
-        let timed_fut = timeout(self.timeout, fut);
-        timed_fut.await
-      })
-      .or_else(|_| Err("Timed out"))?;
-    match res {
-      Ok(success) => {
-        if success {
-          Ok(*input)
-        } else {
-          Err("Failed to unlock account")
-        }
-      }
-      Err(e) => {
-        cblog!("Account unlock error: {}", e);
-        Err("Failed to unlock account")
-      }
-    }
+    RUNTIME.block_on(unlock_account(node, address, passwd, timeout))?;
+    Ok(*input)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::ACCOUNT;
+  use jsonrpc_core::serde_json::json;
+
+  fn unlock(node: &MockNode, password: &str) -> Result<(), &'static str> {
+    let eth = node.node();
+    let address = ACCOUNT.parse().unwrap();
+    RUNTIME.block_on(unlock_account(
+      &eth,
+      address,
+      password,
+      Duration::from_secs(5),
+    ))
+  }
+
+  #[test]
+  fn unlocks_node_accounts() {
+    let node = MockNode::start();
+    node.expect("personal_unlockAccount", json!(true));
+    assert_eq!(unlock(&node, "hunter2"), Ok(()));
+    let request = &node.requests("personal_unlockAccount")[0];
+    assert_eq!(
+      request.params,
+      json!([format!("0x{}", ACCOUNT), "hunter2", null])
+    );
+  }
+
+  #[test]
+  fn refusals_are_errors() {
+    let node = MockNode::start();
+    node.expect("personal_unlockAccount", json!(false));
+    assert_eq!(unlock(&node, "wrong"), Err("Failed to unlock account"));
   }
 }
@@ -0,0 +1,918 @@
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::rlp::encode_bytes;
+use crate::blocks::rlp::encode_list;
+use crate::blocks::rlp::encode_number;
+use crate::blocks::rpc::execute_batch;
+use crate::blocks::selector::BlockSelector;
+use crate::blocks::shared_from_var;
+use crate::blocks::trie::trie_root;
+use crate::blocks::NodeData;
+use crate::blocks::BLOCK_HASH_TYPE;
+use crate::blocks::NODE_TYPE;
+use crate::blocks::NODE_VAR;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
+use chainblocks::block::Block;
+use chainblocks::cblog;
+use chainblocks::core::activate_blocking;
+use chainblocks::core::BlockingBlock;
+use chainblocks::cstr;
+use chainblocks::types::common_type;
+use chainblocks::types::ClonedVar;
+use chainblocks::types::Context;
+use chainblocks::types::ExposedInfo;
+use chainblocks::types::ExposedTypes;
+use chainblocks::types::ParamVar;
+use chainblocks::types::Parameters;
+use chainblocks::types::Type;
+use chainblocks::types::Var;
+use jsonrpc_core::types::Value;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::Duration;
+use web3::signing::keccak256;
+use web3::types::H256;
+use web3::Transport as _;
+
+const MAX_COUNT: i64 = 1024;
+
+#[derive(Clone, Copy)]
+enum Field {
+  Number,
+  Bytes,
+}
+
+const HEADER_FIELDS: &[(&str, Field)] = &[
+  ("parentHash", Field::Bytes),
+  ("sha3Uncles", Field::Bytes),
+  ("miner", Field::Bytes),
+  ("stateRoot", Field::Bytes),
+  ("transactionsRoot", Field::Bytes),
+  ("receiptsRoot", Field::Bytes),
+  ("logsBloom", Field::Bytes),
+  ("difficulty", Field::Number),
+  ("number", Field::Number),
+  ("gasLimit", Field::Number),
+  ("gasUsed", Field::Number),
+  ("timestamp", Field::Number),
+  ("extraData", Field::Bytes),
+  ("mixHash", Field::Bytes),
+  ("nonce", Field::Bytes),
+];
+
+// appended by London, Shanghai, Cancun and Prague, in this order
+const FORK_FIELDS: &[(&str, Field)] = &[
+  ("baseFeePerGas", Field::Number),
+  ("withdrawalsRoot", Field::Bytes),
+  ("blobGasUsed", Field::Number),
+  ("excessBlobGas", Field::Number),
+  ("parentBeaconBlockRoot", Field::Bytes),
+  ("requestsHash", Field::Bytes),
+];
+
+fn has(value: &Value, key: &str) -> bool {
+  value.get(key).map_or(false, |item| !item.is_null())
+}
+
+fn field<'a, 'b>(value: &'b Value, key: &str) -> Result<&'b Value, &'a str> {
+  value
+    .get(key)
+    .filter(|item| !item.is_null())
+    .ok_or("Malformed block answer")
+}
+
+fn array<'a, 'b>(value: &'b Value, key: &str) -> Result<&'b Vec<Value>, &'a str> {
+  field(value, key)?
+    .as_array()
+    .ok_or("Malformed block answer")
+}
+
+fn hex_value<'a>(value: &Value) -> Result<Vec<u8>, &'a str> {
+  let digits = value
+    .as_str()
+    .and_then(|text| text.strip_prefix("0x"))
+    .ok_or("Malformed block answer")?;
+  if digits.len() % 2 == 1 {
+    // quantities drop leading zeros
+    hex::decode(format!("0{}", digits))
+  } else {
+    hex::decode(digits)
+  }
+  .or_else(|_| Err("Malformed block answer"))
+}
+
+fn quantity<'a>(value: &Value) -> Result<u64, &'a str> {
+  let bytes = hex_value(value)?;
+  let bytes: Vec<u8> = bytes.into_iter().skip_while(|byte| *byte == 0).collect();
+  if bytes.len() > 8 {
+    return Err("Malformed block answer");
+  }
+  Ok(
+    bytes
+      .iter()
+      .fold(0, |number, byte| number << 8 | *byte as u64),
+  )
+}
+
+fn hash_field<'a>(value: &Value, key: &str) -> Result<H256, &'a str> {
+  let bytes = hex_value(field(value, key)?)?;
+  if bytes.len() != 32 {
+    return Err("Malformed block answer");
+  }
+  Ok(H256::from_slice(&bytes))
+}
+
+fn item<'a>(value: &Value, key: &str, kind: Field) -> Result<Vec<u8>, &'a str> {
+  let bytes = hex_value(field(value, key)?)?;
+  Ok(match kind {
+    Field::Number => {
+      let trimmed: Vec<u8> = bytes.into_iter().skip_while(|byte| *byte == 0).collect();
+      encode_bytes(&trimmed)
+    }
+    Field::Bytes => encode_bytes(&bytes),
+  })
+}
+
+fn number<'a>(value: &Value, key: &str) -> Result<Vec<u8>, &'a str> {
+  item(value, key, Field::Number)
+}
+
+fn bytes<'a>(value: &Value, key: &str) -> Result<Vec<u8>, &'a str> {
+  item(value, key, Field::Bytes)
+}
+
+fn hashes<'a>(value: &Value, key: &str) -> Result<Vec<u8>, &'a str> {
+  let mut items = Vec::new();
+  for hash in array(value, key)? {
+    items.push(encode_bytes(&hex_value(hash)?));
+  }
+  Ok(encode_list(&items))
+}
+
+fn kind<'a>(value: &Value) -> Result<u64, &'a str> {
+  match value.get("type") {
+    Some(kind) if !kind.is_null() => quantity(kind),
+    _ => Ok(0),
+  }
+}
+
+// typed envelopes (EIP-2718) prefix the RLP with their type
+fn envelope(kind: u64, encoded: Vec<u8>) -> Vec<u8> {
+  if kind == 0 {
+    encoded
+  } else {
+    [vec![kind as u8], encoded].concat()
+  }
+}
+
+/// The RLP of a block header as the node answered it, with the fields of every fork it has.
+pub fn header_rlp<'a>(block: &Value) -> Result<Vec<u8>, &'a str> {
+  let mut items = Vec::new();
+  for (key, kind) in HEADER_FIELDS {
+    items.push(item(block, key, *kind)?);
+  }
+  for (key, kind) in FORK_FIELDS.iter().take_while(|(key, _)| has(block, key)) {
+    items.push(item(block, key, *kind)?);
+  }
+  Ok(encode_list(&items))
+}
+
+fn access_list<'a>(tx: &Value) -> Result<Vec<u8>, &'a str> {
+  let mut entries = Vec::new();
+  for entry in array(tx, "accessList")? {
+    entries.push(encode_list(&[
+      bytes(entry, "address")?,
+      hashes(entry, "storageKeys")?,
+    ]));
+  }
+  Ok(encode_list(&entries))
+}
+
+fn authorization_list<'a>(tx: &Value) -> Result<Vec<u8>, &'a str> {
+  let mut entries = Vec::new();
+  for entry in array(tx, "authorizationList")? {
+    entries.push(encode_list(&[
+      number(entry, "chainId")?,
+      bytes(entry, "address")?,
+      number(entry, "nonce")?,
+      number(entry, "yParity")?,
+      number(entry, "r")?,
+      number(entry, "s")?,
+    ]));
+  }
+  Ok(encode_list(&entries))
+}
+
+/// The signed encoding of a transaction, the one its hash and the transactions root are taken of.
+pub fn transaction_rlp<'a>(tx: &Value) -> Result<Vec<u8>, &'a str> {
+  let kind = kind(tx)?;
+  // contract creations have no recipient
+  let to = if has(tx, "to") {
+    bytes(tx, "to")?
+  } else {
+    encode_bytes(&[])
+  };
+  // typed transactions sign over yParity, some nodes only answer v
+  let parity = || {
+    if has(tx, "yParity") {
+      number(tx, "yParity")
+    } else {
+      number(tx, "v")
+    }
+  };
+  let fields = match kind {
+    0 => vec![
+      number(tx, "nonce")?,
+      number(tx, "gasPrice")?,
+      number(tx, "gas")?,
+      to,
+      number(tx, "value")?,
+      bytes(tx, "input")?,
+      number(tx, "v")?,
+      number(tx, "r")?,
+      number(tx, "s")?,
+    ],
+    1 => vec![
+      number(tx, "chainId")?,
+      number(tx, "nonce")?,
+      number(tx, "gasPrice")?,
+      number(tx, "gas")?,
+      to,
+      number(tx, "value")?,
+      bytes(tx, "input")?,
+      access_list(tx)?,
+      parity()?,
+      number(tx, "r")?,
+      number(tx, "s")?,
+    ],
+    2..=4 => {
+      let mut fields = vec![
+        number(tx, "chainId")?,
+        number(tx, "nonce")?,
+        number(tx, "maxPriorityFeePerGas")?,
+        number(tx, "maxFeePerGas")?,
+        number(tx, "gas")?,
+        to,
+        number(tx, "value")?,
+        bytes(tx, "input")?,
+        access_list(tx)?,
+      ];
+      if kind == 3 {
+        fields.push(number(tx, "maxFeePerBlobGas")?);
+        fields.push(hashes(tx, "blobVersionedHashes")?);
+      } else if kind == 4 {
+        fields.push(authorization_list(tx)?);
+      }
+      fields.push(parity()?);
+      fields.push(number(tx, "r")?);
+      fields.push(number(tx, "s")?);
+      fields
+    }
+    _ => return Err("Unsupported transaction type"),
+  };
+  Ok(envelope(kind, encode_list(&fields)))
+}
+
+/// The consensus encoding of a receipt, the one the receipts root is taken of.
+pub fn receipt_rlp<'a>(receipt: &Value) -> Result<Vec<u8>, &'a str> {
+  // receipts before Byzantium hold the state root instead of a status
+  let outcome = if has(receipt, "status") {
+    number(receipt, "status")?
+  } else {
+    bytes(receipt, "root")?
+  };
+  let mut logs = Vec::new();
+  for log in array(receipt, "logs")? {
+    logs.push(encode_list(&[
+      bytes(log, "address")?,
+      hashes(log, "topics")?,
+      bytes(log, "data")?,
+    ]));
+  }
+  let encoded = encode_list(&[
+    outcome,
+    number(receipt, "cumulativeGasUsed")?,
+    bytes(receipt, "logsBloom")?,
+    encode_list(&logs),
+  ]);
+  Ok(envelope(kind(receipt)?, encoded))
+}
+
+fn withdrawal_rlp<'a>(withdrawal: &Value) -> Result<Vec<u8>, &'a str> {
+  Ok(encode_list(&[
+    number(withdrawal, "index")?,
+    number(withdrawal, "validatorIndex")?,
+    bytes(withdrawal, "address")?,
+    number(withdrawal, "amount")?,
+  ]))
+}
+
+// transactions, receipts and withdrawals are keyed by the RLP of their index
+fn ordered_root(items: Vec<Vec<u8>>) -> H256 {
+  let entries: Vec<(Vec<u8>, Vec<u8>)> = items
+    .into_iter()
+    .enumerate()
+    .map(|(index, item)| (encode_number(index.into()), item))
+    .collect();
+  trie_root(&entries)
+}
+
+/// The hash of a header, checked against the one the node claims.
+pub fn verify_header<'a>(block: &Value) -> Result<H256, &'a str> {
+  let hash: H256 = keccak256(&header_rlp(block)?).into();
+  if hash != hash_field(block, "hash")? {
+    return Err("Block doesn't match its hash");
+  }
+  Ok(hash)
+}
+
+/// Checks the transactions, receipts and withdrawals of a full block against the roots of its header.
+pub fn verify_body<'a>(block: &Value, receipts: &[Value]) -> Result<(), &'a str> {
+  let transactions = array(block, "transactions")?;
+  if transactions.len() != receipts.len() {
+    return Err("Every transaction needs its receipt");
+  }
+  let mut encoded = Vec::new();
+  for tx in transactions {
+    let tx_rlp = transaction_rlp(tx)?;
+    if H256::from(keccak256(&tx_rlp)) != hash_field(tx, "hash")? {
+      return Err("Transaction doesn't match its hash");
+    }
+    encoded.push(tx_rlp);
+  }
+  if ordered_root(encoded) != hash_field(block, "transactionsRoot")? {
+    return Err("Transactions don't match the transactions root");
+  }
+  let mut encoded = Vec::new();
+  for receipt in receipts {
+    encoded.push(receipt_rlp(receipt)?);
+  }
+  if ordered_root(encoded) != hash_field(block, "receiptsRoot")? {
+    return Err("Receipts don't match the receipts root");
+  }
+  if has(block, "withdrawals") {
+    let mut encoded = Vec::new();
+    for withdrawal in array(block, "withdrawals")? {
+      encoded.push(withdrawal_rlp(withdrawal)?);
+    }
+    if ordered_root(encoded) != hash_field(block, "withdrawalsRoot")? {
+      return Err("Withdrawals don't match the withdrawals root");
+    }
+  }
+  // uncles are hashed as full headers, only an empty list can be checked from here
+  let no_uncles = block
+    .get("uncles")
+    .and_then(Value::as_array)
+    .map_or(false, Vec::is_empty);
+  if no_uncles && hash_field(block, "sha3Uncles")? != keccak256(&encode_list(&[])).into() {
+    return Err("Uncles don't match the uncles hash");
+  }
+  Ok(())
+}
+
+/// Checks every header against its hash and each one against its parent, oldest first.
+pub fn verify_chain<'a>(blocks: &[Value]) -> Result<Vec<H256>, &'a str> {
+  let mut hashes: Vec<H256> = Vec::new();
+  for block in blocks {
+    let hash = verify_header(block)?;
+    if let Some(parent) = hashes.last() {
+      if hash_field(block, "parentHash")? != *parent {
+        return Err("Blocks don't chain up through their parent hashes");
+      }
+    }
+    hashes.push(hash);
+  }
+  Ok(hashes)
+}
+
+async fn request<'a>(
+  node: &NodeData,
+  method: &str,
+  params: Vec<Value>,
+  timeout: Duration,
+) -> Result<Value, &'a str> {
+  let transport = node.web3.transport();
+  let result = node
+    .retry
+    .timeout(timeout, || transport.execute(method, params.clone()))
+    .await
+    .or_else(|_| Err("Block request timed out"))?;
+  result.or_else(|e| {
+    cblog!("VerifyBlock error: {}", e);
+    Err("Block request failed")
+  })
+}
+
+async fn batch<'a>(
+  node: &NodeData,
+  method: &str,
+  calls: &[Vec<Value>],
+  timeout: Duration,
+) -> Result<Vec<Value>, &'a str> {
+  if calls.is_empty() {
+    return Ok(Vec::new());
+  }
+  let transport = node.web3.transport();
+  let result = node
+    .retry
+    .timeout(timeout, || execute_batch(transport, method, calls))
+    .await
+    .or_else(|_| Err("Block request timed out"))?;
+  result.or_else(|e| {
+    cblog!("VerifyBlock error: {}", e);
+    Err("Block request failed")
+  })
+}
+
+/// Fetches count blocks up to the one selected and verifies them, returning their hashes oldest first.
+/// Full blocks also get their transactions and receipts checked against the roots of their headers.
+pub async fn verify_blocks<'a>(
+  node: &NodeData,
+  block: BlockSelector,
+  count: u64,
+  full: bool,
+  timeout: Duration,
+) -> Result<Vec<H256>, &'a str> {
+  let (method, params) = block.block_request(full);
+  let last = request(node, method, params, timeout).await?;
+  if last.is_null() {
+    return Err("Block not found");
+  }
+  let number = quantity(field(&last, "number")?)?;
+  if let BlockSelector::Number(asked) = block {
+    if number != asked {
+      return Err("Block doesn't match the number asked");
+    }
+  }
+  if count > number + 1 {
+    return Err("Count goes past the genesis block");
+  }
+  let calls: Vec<Vec<Value>> = (number + 1 - count..number)
+    .map(|number| vec![Value::String(format!("0x{:x}", number)), Value::Bool(full)])
+    .collect();
+  let mut blocks = batch(node, "eth_getBlockByNumber", &calls, timeout).await?;
+  if blocks.iter().any(Value::is_null) {
+    return Err("Block not found");
+  }
+  blocks.push(last);
+  let hashes = verify_chain(&blocks)?;
+  if let BlockSelector::Hash { hash, .. } = block {
+    if hashes.last() != Some(&hash) {
+      return Err("Block doesn't match the hash asked");
+    }
+  }
+  if full {
+    let mut calls = Vec::new();
+    for block in &blocks {
+      for tx in array(block, "transactions")? {
+        calls.push(vec![field(tx, "hash")?.clone()]);
+      }
+    }
+    let receipts = batch(node, "eth_getTransactionReceipt", &calls, timeout).await?;
+    if receipts.iter().any(Value::is_null) {
+      return Err("Receipt not found");
+    }
+    let mut receipts = receipts.into_iter();
+    for block in &blocks {
+      let count = array(block, "transactions")?.len();
+      let block_receipts: Vec<Value> = receipts.by_ref().take(count).collect();
+      verify_body(block, &block_receipts)?;
+    }
+  }
+  Ok(hashes)
+}
+
+pub struct VerifyBlock {
+  count: i64,
+  full: bool,
+  node_param: ParamVar,
+  node: Option<Arc<NodeData>>,
+  output: Vec<ClonedVar>,
+  timeout: ParamVar,
+  requiring: ExposedTypes,
+}
+
+impl Default for VerifyBlock {
+  fn default() -> Self {
+    VerifyBlock {
+      count: 1,
+      full: false,
+      node_param: ParamVar::new(Var::context_variable(cstr!("default.Eth"))),
+      node: None,
+      output: Vec::new(),
+      timeout: ParamVar::new(().into()),
+      requiring: Vec::new(),
+    }
+  }
+}
+
+lazy_static! {
+  static ref INPUT_TYPES: Vec<Type> = vec![
+    common_type::none,
+    common_type::int,
+    common_type::string,
+    common_type::bytes,
+    BLOCK_HASH_TYPE
+  ];
+  static ref OUTPUT_TYPES: Vec<Type> = vec![common_type::bytezs];
+  static ref PARAMETERS: Parameters = vec![
+    (
+      cstr!("Count"),
+      cstr!(
+        "How many blocks to verify, the input one and its ancestors, each against its parent hash."
+      ),
+      vec![common_type::int],
+    )
+      .into(),
+    (
+      cstr!("Full"),
+      cstr!("If transactions, receipts and withdrawals should be checked against their roots too."),
+      vec![common_type::bool],
+    )
+      .into(),
+    (
+      cstr!("Node"),
+      cstr!("The ethereum node block variable to use."),
+      vec![NODE_VAR],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
+  ];
+}
+
+impl Block for VerifyBlock {
+  fn hash() -> u32 {
+    compile_time_crc32::crc32!("Eth.VerifyBlock-rust-0x20200101")
+  }
+
+  fn registerName() -> &'static str {
+    cstr!("Eth.VerifyBlock")
+  }
+
+  fn name(&mut self) -> &str {
+    "Eth.VerifyBlock"
+  }
+
+  fn inputTypes(&mut self) -> &Vec<Type> {
+    &INPUT_TYPES
+  }
+
+  fn outputTypes(&mut self) -> &Vec<Type> {
+    &OUTPUT_TYPES
+  }
+
+  fn parameters(&mut self) -> Option<&Parameters> {
+    Some(&PARAMETERS)
+  }
+
+  fn setParam(&mut self, index: i32, value: &Var) {
+    match index {
+      0 => self.count = value.try_into().unwrap(),
+      1 => self.full = value.try_into().unwrap(),
+      2 => self.node_param.set_param(value),
+      3 => self.timeout.set_param(value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn getParam(&mut self, index: i32) -> Var {
+    match index {
+      0 => self.count.into(),
+      1 => self.full.into(),
+      2 => self.node_param.get_param(),
+      3 => self.timeout.get_param(),
+      _ => unreachable!(),
+    }
+  }
+
+  fn requiredVariables(&mut self) -> Option<&ExposedTypes> {
+    self.requiring.clear();
+    let exp_info = ExposedInfo {
+      exposedType: NODE_TYPE,
+      name: self.node_param.get_name(),
+      help: cstr!("The required ethereum node to use as gateway.").into(),
+      ..ExposedInfo::default()
+    };
+    self.requiring.push(exp_info);
+    Some(&self.requiring)
+  }
+
+  fn warmup(&mut self, context: &Context) -> Result<(), &str> {
+    if self.count < 1 || self.count > MAX_COUNT {
+      return Err("Count must be between 1 and 1024");
+    }
+    self.node_param.warmup(context);
+    self.timeout.warmup(context);
+    Ok(())
+  }
+
+  fn cleanup(&mut self) {
+    self.timeout.cleanup();
+    self.node_param.cleanup();
+    self.node = None;
+  }
+
+  fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
+    Ok(activate_blocking(self, context, input))
+  }
+}
+
+impl BlockingBlock for VerifyBlock {
+  fn activate_blocking(&mut self, _: &Context, input: &Var) -> Result<Var, &str> {
+    if self.node.is_none() {
+      self.node = Some(shared_from_var(self.node_param.get(), &NODE_TYPE)?);
+    }
+    // a number, tag or hash, the latest block if none
+    let block = BlockSelector::from_var(input)?.unwrap_or(BlockSelector::Tag("latest"));
+    let node = get_shared(&self.node)?;
+    let timeout = get_block_timeout(&self.timeout, node)?;
+    let hashes = RUNTIME.block_on(verify_blocks(
+      node,
+      block,
+      self.count as u64,
+      self.full,
+      timeout,
+    ))?;
+    self.output.clear();
+    for hash in hashes {
+      self.output.push(hash.as_bytes().into());
+    }
+    Ok((&self.output).into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::MockNode;
+  use jsonrpc_core::serde_json::json;
+
+  const EMPTY_TRIE: &str = "0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421";
+  const EMPTY_UNCLES: &str = "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347";
+
+  fn zeros(length: usize) -> String {
+    format!("0x{}", "00".repeat(length))
+  }
+
+  fn genesis() -> Value {
+    json!({
+      "parentHash": zeros(32),
+      "sha3Uncles": EMPTY_UNCLES,
+      "miner": zeros(20),
+      "stateRoot": "0xd7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544",
+      "transactionsRoot": EMPTY_TRIE,
+      "receiptsRoot": EMPTY_TRIE,
+      "logsBloom": zeros(256),
+      "difficulty": "0x400000000",
+      "number": "0x0",
+      "gasLimit": "0x1388",
+      "gasUsed": "0x0",
+      "timestamp": "0x0",
+      "extraData": "0x11bbe8db4e347b4e8c937c1c8370e4b5ed33adb3db69cbdb7a38e1e50b1b82fa",
+      "mixHash": zeros(32),
+      "nonce": "0x0000000000000042",
+      "hash": "0xd4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3",
+      "uncles": [],
+      "transactions": []
+    })
+  }
+
+  // a post Cancun block on top of parent, its hash taken from its own fields
+  fn child(parent: &Value, transactions: Vec<Value>, receipts: &[Value]) -> Value {
+    let mut block = genesis();
+    let number = quantity(&parent["number"]).unwrap() + 1;
+    block["number"] = json!(format!("0x{:x}", number));
+    block["parentHash"] = parent["hash"].clone();
+    block["difficulty"] = json!("0x0");
+    block["baseFeePerGas"] = json!("0x7");
+    block["withdrawals"] = json!([{
+      "index": "0x1",
+      "validatorIndex": "0x2",
+      "address": zeros(20),
+      "amount": "0x3"
+    }]);
+    block["blobGasUsed"] = json!("0x0");
+    block["excessBlobGas"] = json!("0x0");
+    block["parentBeaconBlockRoot"] = json!(zeros(32));
+    let root = |items: Vec<Vec<u8>>| json!(format!("{:?}", ordered_root(items)));
+    block["withdrawalsRoot"] = root(vec![withdrawal_rlp(&block["withdrawals"][0]).unwrap()]);
+    block["transactionsRoot"] = root(
+      transactions
+        .iter()
+        .map(|tx| transaction_rlp(tx).unwrap())
+        .collect(),
+    );
+    block["receiptsRoot"] = root(
+      receipts
+        .iter()
+        .map(|receipt| receipt_rlp(receipt).unwrap())
+        .collect(),
+    );
+    block["transactions"] = json!(transactions);
+    let hash = H256::from(keccak256(&header_rlp(&block).unwrap()));
+    block["hash"] = json!(format!("{:?}", hash));
+    block
+  }
+
+  // the signed example of EIP-155
+  fn legacy() -> Value {
+    json!({
+      "hash": "0x33469b22e9f636356c4160a87eb19df52b7412e8eac32a4a55ffe88ea8350788",
+      "nonce": "0x9",
+      "gasPrice": "0x4a817c800",
+      "gas": "0x5208",
+      "to": "0x3535353535353535353535353535353535353535",
+      "value": "0xde0b6b3a7640000",
+      "input": "0x",
+      "v": "0x25",
+      "r": "0x28ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276",
+      "s": "0x67cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"
+    })
+  }
+
+  fn blob() -> Value {
+    let mut tx = json!({
+      "type": "0x3",
+      "chainId": "0x1",
+      "nonce": "0x0",
+      "maxPriorityFeePerGas": "0x1",
+      "maxFeePerGas": "0x2",
+      "gas": "0x5208",
+      "to": "0x3535353535353535353535353535353535353535",
+      "value": "0x0",
+      "input": "0x",
+      "accessList": [{ "address": zeros(20), "storageKeys": [zeros(32)] }],
+      "maxFeePerBlobGas": "0x3",
+      "blobVersionedHashes": [format!("0x01{}", "00".repeat(31))],
+      "yParity": "0x1",
+      "v": "0x1",
+      "r": "0x1",
+      "s": "0x2"
+    });
+    let hash = H256::from(keccak256(&transaction_rlp(&tx).unwrap()));
+    tx["hash"] = json!(format!("{:?}", hash));
+    tx
+  }
+
+  fn receipt(kind: &str, gas: &str) -> Value {
+    json!({
+      "type": kind,
+      "status": "0x1",
+      "cumulativeGasUsed": gas,
+      "logsBloom": zeros(256),
+      "logs": [{
+        "address": zeros(20),
+        "topics": [zeros(32)],
+        "data": "0x2a"
+      }]
+    })
+  }
+
+  #[test]
+  fn hashes_the_genesis_header() {
+    let block = genesis();
+    assert_eq!(
+      verify_header(&block).unwrap(),
+      "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"
+        .parse()
+        .unwrap()
+    );
+    let mut lying = block;
+    lying["gasLimit"] = json!("0x1389");
+    assert_eq!(verify_header(&lying), Err("Block doesn't match its hash"));
+  }
+
+  #[test]
+  fn encodes_signed_transactions() {
+    let encoded = transaction_rlp(&legacy()).unwrap();
+    assert_eq!(
+      hex::encode(&encoded),
+      "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"
+    );
+    let encoded = transaction_rlp(&blob()).unwrap();
+    assert_eq!(encoded[0], 3);
+    let mut creation = legacy();
+    creation["to"] = Value::Null;
+    assert_eq!(transaction_rlp(&creation).unwrap()[12], 0x80);
+    let mut deposit = legacy();
+    deposit["type"] = json!("0x7e");
+    assert_eq!(
+      transaction_rlp(&deposit),
+      Err("Unsupported transaction type")
+    );
+  }
+
+  #[test]
+  fn checks_bodies_against_roots() {
+    let receipts = vec![receipt("0x0", "0x5208"), receipt("0x3", "0xa410")];
+    let block = child(&genesis(), vec![legacy(), blob()], &receipts);
+    assert!(verify_header(&block).is_ok());
+    assert!(verify_body(&block, &receipts).is_ok());
+
+    let mut lying = receipts.clone();
+    lying[1]["status"] = json!("0x0");
+    assert_eq!(
+      verify_body(&block, &lying),
+      Err("Receipts don't match the receipts root")
+    );
+    let mut swapped = block.clone();
+    swapped["transactions"] = json!([blob(), legacy()]);
+    assert_eq!(
+      verify_body(&swapped, &receipts),
+      Err("Transactions don't match the transactions root")
+    );
+    let mut tampered = block.clone();
+    tampered["transactions"][0]["value"] = json!("0x1");
+    assert_eq!(
+      verify_body(&tampered, &receipts),
+      Err("Transaction doesn't match its hash")
+    );
+    let mut withdrawn = block;
+    withdrawn["withdrawals"][0]["amount"] = json!("0x4");
+    assert_eq!(
+      verify_body(&withdrawn, &receipts),
+      Err("Withdrawals don't match the withdrawals root")
+    );
+  }
+
+  #[test]
+  fn verifies_ranges_from_the_node() {
+    let node = MockNode::start();
+    let first = child(&genesis(), Vec::new(), &[]);
+    let receipts = vec![receipt("0x0", "0x5208")];
+    let second = child(&first, vec![legacy()], &receipts);
+    node.expect("eth_getBlockByNumber", second.clone());
+    node.expect_params("eth_getBlockByNumber", json!(["0x1", true]), first.clone());
+    node.expect("eth_getTransactionReceipt", receipts[0].clone());
+    let eth = node.node();
+    let hashes = RUNTIME
+      .block_on(verify_blocks(
+        &eth,
+        BlockSelector::Tag("latest"),
+        2,
+        true,
+        Duration::from_secs(5),
+      ))
+      .unwrap();
+    assert_eq!(
+      hashes,
+      vec![
+        first["hash"].as_str().unwrap().parse().unwrap(),
+        second["hash"].as_str().unwrap().parse().unwrap()
+      ]
+    );
+    assert_eq!(
+      node.requests("eth_getTransactionReceipt")[0].params,
+      json!([legacy()["hash"]])
+    );
+
+    node.expect("eth_getBlockByHash", second);
+    let asked = BlockSelector::Hash {
+      hash: H256::repeat_byte(1),
+      require_canonical: false,
+    };
+    let other = RUNTIME.block_on(verify_blocks(&eth, asked, 1, false, Duration::from_secs(5)));
+    assert_eq!(other, Err("Block doesn't match the hash asked"));
+    let past = RUNTIME.block_on(verify_blocks(
+      &eth,
+      BlockSelector::Tag("latest"),
+      4,
+      false,
+      Duration::from_secs(5),
+    ));
+    assert_eq!(past, Err("Count goes past the genesis block"));
+  }
+
+  #[test]
+  fn breaks_on_unlinked_parents() {
+    let first = child(&genesis(), Vec::new(), &[]);
+    let mut orphan = child(&first, Vec::new(), &[]);
+    assert_eq!(
+      verify_chain(&[first.clone(), orphan.clone()])
+        .unwrap()
+        .len(),
+      2
+    );
+    orphan["parentHash"] = json!(zeros(32));
+    let hash = H256::from(keccak256(&header_rlp(&orphan).unwrap()));
+    orphan["hash"] = json!(format!("{:?}", hash));
+    assert_eq!(
+      verify_chain(&[first, orphan]),
+      Err("Blocks don't chain up through their parent hashes")
+    );
+  }
+}
@@ -1,7 +1,14 @@
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::shared_from_var;
 use crate::blocks::tokens::hash_event;
 use crate::blocks::ContractUser;
-use crate::blocks::{ContractData, EthData, NodeData};
-use crate::blocks::{CONTRACT_TYPE, CONTRACT_VAR, NODE_TYPE};
+use crate::blocks::EthData;
+use crate::blocks::NodeData;
+use crate::blocks::Transport;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
+use crate::blocks::{CONTRACT_TYPE, CONTRACT_VAR};
 use chainblocks::block::Block;
 // use chainblocks::cblog;
 use chainblocks::cbstr;
@@ -28,15 +35,79 @@ use std::convert::TryInto;
 use std::ffi::CString;
 use std::str;
 use std::time::Duration;
+use tokio::time::sleep;
 use tokio::time::timeout;
-use web3::transports::Either;
+use web3::api::SubscriptionStream;
+use web3::types::Address;
 use web3::types::FilterBuilder;
+use web3::types::Log;
 use web3::types::H256;
 
+/// Subscribes to the logs of an event of the contract, only over WS or IPC.
+pub async fn subscribe_event<'a>(
+  node: &NodeData,
+  address: Address,
+  event_hash: H256,
+  timeout_: Duration,
+) -> Result<SubscriptionStream<Transport, Log>, &'a str> {
+  let transport = node.web3.transport();
+  if !transport.is_duplex() {
+    return Err("WaitEvents needs a WebSocket or IPC backed Eth node block");
+  }
+  let filter = FilterBuilder::default()
+    .address(vec![address])
+    .topics(Some(vec![event_hash]), None, None, None)
+    .build();
+  let eth_subscribe = web3::Web3::new(transport.clone()).eth_subscribe();
+  timeout(timeout_, eth_subscribe.subscribe_logs(filter))
+    .await
+    .or_else(|_| Err("Timed out"))?
+    .or_else(|_| Err("Failed to subscribe to topic"))
+}
+
+/// The next log of the event, subscribing first if needed, None once `stopped`.
+/// The transport ends a subscription it can't restore, then we start a new one
+/// after a while, a node that keeps ending it is an error.
+pub async fn next_event<'a>(
+  node: &NodeData,
+  sub: &mut Option<SubscriptionStream<Transport, Log>>,
+  address: Address,
+  event_hash: H256,
+  timeout_: Duration,
+  stopped: impl Fn() -> bool,
+) -> Result<Option<Log>, &'a str> {
+  let mut resubscribed = 0;
+  loop {
+    if sub.is_none() {
+      *sub = Some(subscribe_event(node, address, event_hash, timeout_).await?);
+    }
+    let stream = sub.as_mut().ok_or("Subscription was empty")?;
+
+    // wake up every second to check on the chain
+    loop {
+      match timeout(Duration::from_secs(1), stream.next()).await {
+        _ if stopped() => return Ok(None),
+        Ok(Some(Ok(logs))) => return Ok(Some(logs)),
+        Ok(Some(Err(_))) => return Err("Empty logs"),
+        Ok(None) => break,
+        Err(_) => continue,
+      }
+    }
+
+    *sub = None;
+    resubscribed += 1;
+    if resubscribed >= node.retry.max_attempts {
+      return Err("Event subscription keeps ending");
+    }
+    sleep(node.retry.delay(resubscribed)).await;
+  }
+}
+
 pub struct WaitEvent {
   cu: ContractUser,
   event_hash: H256,
-  sub: Option<web3::api::SubscriptionStream<web3::transports::WebSocket, web3::types::Log>>,
+  timeout: ParamVar,
+  sub: Option<SubscriptionStream<Transport, Log>>,
   output: Table,
   scratch: Seq,
 }
@@ -61,6 +132,19 @@ lazy_static! {
       vec![CONTRACT_VAR],
     )
       .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of the subscription request, none to use the one of the Eth node. Events are waited for as long as it takes."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
+    )
+      .into(),
   ];
 }
 
@@ -80,6 +164,7 @@ impl Default for WaitEvent {
         requiring: Vec::new(),
       },
       event_hash: H256::zero(),
+      timeout: ParamVar::new(().into()),
       sub: None,
       output: Table::new(),
       scratch: Seq::new(),
@@ -114,6 +199,7 @@ impl Block for WaitEvent {
     match index {
       0 => self.cu.data.method = value.try_into().unwrap_or(CString::new("").unwrap()),
       1 => self.cu.instance.set_param(value),
+      2 => self.timeout.set_param(value),
       _ => unreachable!(),
     }
   }
@@ -122,6 +208,7 @@ impl Block for WaitEvent {
     match index {
       0 => self.cu.data.method.as_ref().into(),
       1 => self.cu.instance.get_param(),
+      2 => self.timeout.get_param(),
       _ => Var::default(),
     }
   }
@@ -143,11 +230,13 @@ impl Block for WaitEvent {
       Err("Contract instance is empty or not valid")
     } else {
       self.cu.instance.warmup(context);
+      self.timeout.warmup(context);
       Ok(())
     }
   }
 
   fn cleanup(&mut self) {
+    self.timeout.cleanup();
     self.cu.instance.cleanup();
     self.cu.node = None;
     self.cu.data.contract = None;
@@ -156,11 +245,8 @@ impl Block for WaitEvent {
 
   fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
     if self.cu.data.contract.is_none() {
-      self.cu.data.contract = Some(Var::from_object_as_clone::<Option<ContractData>>(
-        self.cu.instance.get(),
-        &CONTRACT_TYPE,
-      )?);
-      let contract = Var::get_mut_from_clone(&self.cu.data.contract)?;
+      self.cu.data.contract = Some(shared_from_var(self.cu.instance.get(), &CONTRACT_TYPE)?);
+      let contract = get_shared(&self.cu.data.contract)?;
       let method = self
         .cu
         .data
@@ -172,123 +258,186 @@ impl Block for WaitEvent {
       self.event_hash = hash_event(method, &contract.json_abi)?;
 
       // also populate node data here
-      self.cu.node = Some(Var::from_object_as_clone::<Option<NodeData>>(
-        contract.node,
-        &NODE_TYPE,
-      )?);
+      self.cu.node = Some(contract.node.clone());
     }
     Ok(activate_blocking(self, context, input))
   }
 }
 
 impl BlockingBlock for WaitEvent {
-  fn activate_blocking(
-    &mut self,
-    context: &Context,
-    _input: &Var,
-  ) -> std::result::Result<Var, &str> {
-    // subscribe if needed
-    if self.sub.is_none() {
-      let node = Var::get_mut_from_clone(&self.cu.node)?;
-      let (scheduler, transport) = (&mut node.scheduler, node.web3.transport());
-
-      let contract = Var::get_mut_from_clone(&self.cu.data.contract)?;
-      let event_hash = self.event_hash;
-      self.sub = Some(scheduler.block_on(async {
-        let filter = FilterBuilder::default()
-          .address(vec![contract.contract.address()])
-          .topics(Some(vec![event_hash]), None, None, None)
-          .build();
-        match transport {
-          Either::Right(ws) => {
-            let web3 = web3::Web3::new(ws.clone());
-            let sub = web3
-              .eth_subscribe()
-              .subscribe_logs(filter)
-              .await
-              .or_else(|_| Err("Failed to subscribe to topic"))?;
-            Ok(sub)
-          }
-          _ => Err("WaitEvents needs a WebSocket backed Eth node block"),
-        }
-      })?);
+  fn activate_blocking(&mut self, context: &Context, _: &Var) -> std::result::Result<Var, &str> {
+    let node = get_shared(&self.cu.node)?;
+    let contract = get_shared(&self.cu.data.contract)?;
+    let address = contract.contract.address();
+    let timeout_ = get_block_timeout(&self.timeout, node)?;
+    let stopped = || getState(context) == ChainState::Stop;
+    let event = next_event(
+      node,
+      &mut self.sub,
+      address,
+      self.event_hash,
+      timeout_,
+      stopped,
+    );
+    // handle chain state asap, the output stays as it was
+    if let Some(logs) = RUNTIME.block_on(event)? {
+      log_to_table(logs, &mut self.output, &mut self.scratch)?;
     }
+    Ok((&self.output).into())
+  }
+}
 
-    // wait for an event
-    if let Some(sub) = &mut self.sub {
-      let node = Var::get_mut_from_clone(&self.cu.node)?;
-      node.scheduler.block_on(work_async(
-        sub,
-        context,
-        &mut self.output,
-        &mut self.scratch,
-      ))?;
-      Ok((&self.output).into())
-    } else {
-      Err("Subscription was empty")
-    }
+fn log_to_table<'a>(logs: Log, output: &mut Table, scratch: &mut Seq) -> Result<(), &'a str> {
+  // mandatory stuff
+  output.insert_fast_static(cstr!("data"), logs.data.0.as_slice().into());
+
+  scratch.clear();
+  for topic in logs.topics {
+    scratch.push(topic.as_bytes().into());
+  }
+  output.insert_fast_static(cstr!("topics"), scratch.as_ref().into());
+
+  // optional stuff
+  if let Some(block_hash) = logs.block_hash {
+    output.insert_fast_static(cstr!("block_hash"), block_hash.as_bytes().into());
+  }
+  if let Some(block_number) = logs.block_number {
+    output.insert_fast_static(cstr!("block_number"), block_number.as_u64().try_into()?);
+  }
+  if let Some(transaction_hash) = logs.transaction_hash {
+    output.insert_fast_static(
+      cstr!("transaction_hash"),
+      transaction_hash.as_bytes().into(),
+    );
   }
+  if let Some(transaction_index) = logs.transaction_index {
+    output.insert_fast_static(
+      cstr!("transaction_index"),
+      transaction_index.as_u64().try_into()?,
+    );
+  }
+  if let Some(removed) = logs.removed {
+    output.insert_fast_static(cstr!("removed"), removed.into());
+  }
+  Ok(())
 }
 
-async fn work_async<'a>(
-  sub: &mut web3::api::SubscriptionStream<web3::transports::WebSocket, web3::types::Log>,
-  context: &Context,
-  output: &mut Table,
-  scratch: &mut Seq,
-) -> Result<(), &'a str> {
-  loop {
-    let fut = sub.next();
-    let timed_fut = timeout(Duration::from_secs(1), fut);
-    let fut_res = timed_fut.await;
-    // handle chain state asap
-    if getState(context) == ChainState::Stop {
-      return Ok(());
-    }
-    // continue if timedout
-    if let Ok(opt_data) = fut_res {
-      if let Some(data) = opt_data {
-        if let Ok(logs) = data {
-          // mandatory stuff
-          output.insert_fast_static(cstr!("data"), logs.data.0.as_slice().into());
-
-          scratch.clear();
-          for topic in logs.topics {
-            scratch.push(topic.as_bytes().into());
-          }
-          output.insert_fast_static(cstr!("topics"), scratch.as_ref().into());
-
-          // optional stuff
-          if let Some(block_hash) = logs.block_hash {
-            output.insert_fast_static(cstr!("block_hash"), block_hash.as_bytes().into());
-          }
-          if let Some(block_number) = logs.block_number {
-            output.insert_fast_static(cstr!("block_number"), block_number.as_u64().try_into()?);
-          }
-          if let Some(transaction_hash) = logs.transaction_hash {
-            output.insert_fast_static(
-              cstr!("transaction_hash"),
-              transaction_hash.as_bytes().into(),
-            );
-          }
-          if let Some(transaction_index) = logs.transaction_index {
-            output.insert_fast_static(
-              cstr!("transaction_index"),
-              transaction_index.as_u64().try_into()?,
-            );
-          }
-          if let Some(removed) = logs.removed {
-            output.insert_fast_static(cstr!("removed"), removed.into());
-          }
-          return Ok(());
-        } else {
-          return Err("Empty logs");
-        }
-      } else {
-        return Err("Failed to unwrap logs");
-      }
-    } else {
-      // cblog!("event polling timedout");
-      continue;
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::eth::connect;
+  use crate::blocks::eth::NodeConfig;
+  use crate::blocks::mock::event_log;
+  use crate::blocks::mock::hash;
+  use crate::blocks::mock::wait_until;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::CONTRACT;
+  use jsonrpc_core::serde_json::json;
+  use std::sync::Arc;
+  use std::thread;
+  use std::thread::JoinHandle;
+
+  fn subscribe(node: &NodeData) -> Result<SubscriptionStream<Transport, Log>, &'static str> {
+    let address = CONTRACT.parse().unwrap();
+    let event_hash = hash(0x9c).parse().unwrap();
+    RUNTIME.block_on(subscribe_event(
+      node,
+      address,
+      event_hash,
+      Duration::from_secs(5),
+    ))
+  }
+
+  fn ws_node(node: &MockNode) -> Arc<NodeData> {
+    let config = NodeConfig::new(vec![node.ws_url()]);
+    Arc::new(RUNTIME.block_on(connect(config)).unwrap())
+  }
+
+  // waits for the next event like the block does, on its own thread
+  fn wait_event(eth: Arc<NodeData>) -> JoinHandle<Result<Option<Log>, &'static str>> {
+    thread::spawn(move || {
+      let mut sub = None;
+      let address = CONTRACT.parse().unwrap();
+      let event_hash = hash(0x9c).parse().unwrap();
+      let timeout_ = Duration::from_secs(5);
+      let event = next_event(&eth, &mut sub, address, event_hash, timeout_, || false);
+      RUNTIME.block_on(event)
+    })
+  }
+
+  // the transport reconnects but fails to restore the subscription, ending its stream
+  fn end_subscription(node: &MockNode) {
+    wait_until(|| node.subscriptions() == 1);
+    node.expect_error_times("eth_subscribe", 1, -32000, "too many subscriptions");
+    node.drop_connections();
+  }
+
+  #[test]
+  fn subscribes_again_when_the_subscription_ends() {
+    let node = MockNode::start();
+    let waiting = wait_event(ws_node(&node));
+
+    end_subscription(&node);
+    wait_until(|| node.count("eth_subscribe") == 3 && node.subscriptions() == 1);
+    node.notify("logs", event_log(5, 0));
+    let log = waiting.join().unwrap().unwrap().unwrap();
+    assert_eq!(log.block_number, Some(5.into()));
+  }
+
+  #[test]
+  fn gives_up_on_subscriptions_that_keep_ending() {
+    let node = MockNode::start();
+    let eth = ws_node(&node);
+    let attempts = eth.retry.max_attempts;
+    let waiting = wait_event(eth);
+
+    for _ in 0..attempts {
+      end_subscription(&node);
     }
+    let outcome = waiting.join().unwrap();
+    assert_eq!(outcome.err(), Some("Event subscription keeps ending"));
+    // the first one and a failed restore for each, then a new one but for the last
+    assert_eq!(node.count("eth_subscribe"), 2 * attempts as usize);
+  }
+
+  #[test]
+  fn stops_waiting_with_the_chain() {
+    let node = MockNode::start();
+    let eth = ws_node(&node);
+    let mut sub = None;
+    let address = CONTRACT.parse().unwrap();
+    let event_hash = hash(0x9c).parse().unwrap();
+    let timeout_ = Duration::from_secs(5);
+    let event = next_event(&eth, &mut sub, address, event_hash, timeout_, || true);
+    assert_eq!(RUNTIME.block_on(event), Ok(None));
+    // the subscription is kept for the next activation
+    assert!(sub.is_some());
+  }
+
+  #[test]
+  fn receives_the_logs_of_the_event() {
+    let node = MockNode::start();
+    let eth = RUNTIME
+      .block_on(connect(NodeConfig::new(vec![node.ws_url()])))
+      .unwrap();
+    let mut sub = subscribe(&eth).unwrap();
+    let request = &node.requests("eth_subscribe")[0];
+    assert_eq!(request.params[0], "logs");
+    assert_eq!(request.params[1]["topics"], json!([hash(0x9c)]));
+
+    node.notify("logs", event_log(5, 0));
+    let log = RUNTIME.block_on(async { timeout(Duration::from_secs(5), sub.next()).await });
+    let log = log.unwrap().unwrap().unwrap();
+    assert_eq!(log.block_number, Some(5.into()));
+    assert_eq!(log.address, CONTRACT.parse().unwrap());
+  }
+
+  #[test]
+  fn needs_a_duplex_transport() {
+    let node = MockNode::start();
+    assert_eq!(
+      subscribe(&node.node()).err(),
+      Some("WaitEvents needs a WebSocket or IPC backed Eth node block")
+    );
   }
 }
@@ -1,13 +1,27 @@
+use crate::blocks::address::parse_address;
+use crate::blocks::address::BAD_CHECKSUM;
+use crate::blocks::ens::input_names;
+use crate::blocks::ens::is_name;
+use crate::blocks::ens::resolve_name;
+use crate::blocks::ens::resolve_names;
+use crate::blocks::get_block_timeout;
+use crate::blocks::get_shared;
+use crate::blocks::log;
+use crate::blocks::shared_from_var;
 use crate::blocks::tokens::gather_inputs;
 use crate::blocks::tokens::var_to_tokens;
+use crate::blocks::ContractData;
 use crate::blocks::ContractUser;
-use crate::blocks::{ContractData, EthData, NodeData};
-use crate::blocks::{CONTRACT_TYPE, CONTRACT_VAR, NODE_TYPE};
+use crate::blocks::EthData;
+use crate::blocks::NodeData;
+use crate::blocks::Transport;
+use crate::blocks::RUNTIME;
+use crate::blocks::TIMEOUT_VAR;
+use crate::blocks::{CONTRACT_TYPE, CONTRACT_VAR};
 use chainblocks::block::Block;
 use chainblocks::cblog;
 use chainblocks::cbstr;
 use chainblocks::core::do_blocking;
-use chainblocks::core::log;
 use chainblocks::cstr;
 use chainblocks::types::common_type;
 use chainblocks::types::Context;
@@ -20,14 +34,25 @@ use chainblocks::types::Table;
 use chainblocks::types::Type;
 use chainblocks::types::Types;
 use chainblocks::types::Var;
+use ethabi::token::Token;
 use secp256k1::SecretKey;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::ffi::CStr;
 use std::ffi::CString;
 use std::fs;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::time::sleep;
 use web3::contract::Options;
+use web3::signing::Key;
 use web3::signing::SecretKeyRef;
 use web3::types::Address;
+use web3::types::BlockNumber;
+use web3::types::TransactionParameters;
+use web3::types::TransactionReceipt;
+use web3::types::H256;
 use web3::types::U256;
 use zeroize::Zeroize;
 
@@ -35,13 +60,15 @@ pub struct Write {
   cu: ContractUser,
   confirmations: usize,
   options: ParamVar,
+  timeout: ParamVar,
   output: Table,
 }
 
 static TRANSACTION_TABLE_TYPES: &'static [Type] = &[common_type::bytes, common_type::int];
 const TRANSACTION_TABLE_KEYS: &[RawString] =
   &[cbstr!("transaction_hash"), cbstr!("transaction_index")];
-static TRANSACTION_TABLE_TYPE: Type = Type::table(TRANSACTION_TABLE_KEYS, TRANSACTION_TABLE_TYPES);
+pub static TRANSACTION_TABLE_TYPE: Type =
+  Type::table(TRANSACTION_TABLE_KEYS, TRANSACTION_TABLE_TYPES);
 
 lazy_static! {
   static ref INPUT_TYPES: Vec<Type> = vec![common_type::anys];
@@ -61,7 +88,7 @@ lazy_static! {
       .into(),
     (
       cstr!("From"),
-      cstr!("The sender secret key or an unlocked account's public key. In the case of a secret key, using a file is safer as it won't be kept in memory."),
+      cstr!("The sender secret key or an unlocked account's public key or ENS name. In the case of a secret key, using a file is safer as it won't be kept in memory."),
       vec![
         common_type::path,
         common_type::path_var,
@@ -80,16 +107,367 @@ lazy_static! {
       cstr!("Options"),
       cstr!("Various options to add to this call. (avail: gas, gas-price, value, nonce)"),
       vec![common_type::none, common_type::bytes_table, common_type::bytes_table_var],
+    )
+      .into(),
+    (
+      cstr!("Timeout"),
+      cstr!(
+        "The timeout in seconds of every request attempt, and of the wait for the transaction to be mined and for every confirmation after it, none to use the one of the Eth node."
+      ),
+      vec![
+        common_type::none,
+        common_type::int,
+        common_type::float,
+        TIMEOUT_VAR
+      ],
     )
       .into()
   ];
 }
 
-enum Caller {
+pub enum Caller {
   PrivateKey(SecretKey),
   PublicKey(Address),
 }
 
+impl Caller {
+  pub fn address(&self) -> Address {
+    match self {
+      Caller::PrivateKey(key) => SecretKeyRef::new(key).address(),
+      Caller::PublicKey(address) => *address,
+    }
+  }
+}
+
+/// Reads From as an address or ENS name the node signs for, or as a secret key, in a file or not.
+pub fn caller_from_var<'a>(
+  node: &NodeData,
+  from: &Var,
+  timeout: Duration,
+) -> Result<Caller, &'a str> {
+  let address: Result<Address, &str> = {
+    if let Ok(s) = TryInto::<String>::try_into(from) {
+      if s.len() > 0 {
+        // key files have dots in their names too
+        if !from.is_path() && is_name(&s) {
+          Ok(RUNTIME.block_on(resolve_name(node, &s, timeout))?)
+        } else {
+          parse_address(&s, "Failed to parse From address")
+        }
+      } else {
+        Err("Expected a publickey, got an empty string")
+      }
+    } else {
+      Err("Expected a publickey, got an invalid string")
+    }
+  };
+  if address == Err(BAD_CHECKSUM) {
+    // not a key either, tell what is wrong with it
+    return Err(BAD_CHECKSUM);
+  }
+  Ok(if let Ok(address) = address {
+    Caller::PublicKey(address)
+  } else {
+    let from_key = {
+      if let Ok(mut s) = TryInto::<String>::try_into(from) {
+        if from.is_path() {
+          let mut data = {
+            let mut key_str = fs::read_to_string(s).or_else(|_| Err("Failed to read key file"))?;
+            let key_slice = if key_str.starts_with("0x") {
+              &key_str[2..]
+            } else {
+              &key_str[..]
+            };
+            let bytes = hex::decode(key_slice).or_else(|_| Err("Failed to decode key"))?;
+            key_str.zeroize();
+            bytes
+          };
+          let key = SecretKey::from_slice(data.as_slice())
+            .or_else(|_| Err("Failed to create SecretKey from file contents"))?;
+          data.zeroize();
+          Ok(key)
+        } else {
+          let key_slice = if s.starts_with("0x") { &s[2..] } else { &s[..] };
+          let mut bytes = hex::decode(key_slice).or_else(|_| Err("Failed to decode key"))?;
+          let key = SecretKey::from_slice(bytes.as_slice())
+            .or_else(|_| Err("Failed to create SecretKey from string"))?;
+          bytes.zeroize();
+          s.zeroize();
+          Ok(key)
+        }
+      } else {
+        Err("SecretKey parameter is invalid")
+      }
+    }?;
+    Caller::PrivateKey(from_key)
+  })
+}
+
+/// The gas, gas-price, value and nonce options of a write.
+pub fn options_from_table<'a>(options: Option<Table>) -> Result<Options, &'a str> {
+  Ok(if let Some(options) = options {
+    let mut opts = Options::default();
+    for (key, value) in options.iter() {
+      let key = unsafe { CStr::from_ptr(key.0) };
+      let key = key.to_str().unwrap();
+      match key {
+        "gas" => {
+          let slice: &[u8] = value.as_ref().try_into()?;
+          let u: U256 = slice.into();
+          opts.gas = Some(u);
+        }
+        "gas-price" => {
+          let slice: &[u8] = value.as_ref().try_into()?;
+          let u: U256 = slice.into();
+          opts.gas_price = Some(u);
+        }
+        "value" => {
+          let slice: &[u8] = value.as_ref().try_into()?;
+          let u: U256 = slice.into();
+          opts.value = Some(u);
+        }
+        "nonce" => {
+          let slice: &[u8] = value.as_ref().try_into()?;
+          let u: U256 = slice.into();
+          opts.nonce = Some(u);
+        }
+        _ => {
+          cblog!("Ignored an invalid option label: {}", key);
+        }
+      }
+    }
+    opts
+  } else {
+    Options::default()
+  })
+}
+
+/// The last nonce each local key of a node used, so concurrent writes don't take the same one.
+#[derive(Default)]
+pub struct Nonces(Mutex<HashMap<Address, U256>>);
+
+impl Nonces {
+  /// The pending count of the node, unless we already handed it out.
+  fn take(&self, address: Address, pending: U256) -> U256 {
+    let mut nonces = self.0.lock().unwrap();
+    let nonce = match nonces.get(&address) {
+      Some(last) if *last >= pending => *last + 1,
+      _ => pending,
+    };
+    nonces.insert(address, nonce);
+    nonce
+  }
+
+  /// A transaction the node refused gives its nonce back, unless a later one took the next.
+  fn give_back(&self, address: Address, nonce: U256) {
+    let mut nonces = self.0.lock().unwrap();
+    if nonces.get(&address) == Some(&nonce) {
+      match nonce.checked_sub(U256::one()) {
+        Some(last) => nonces.insert(address, last),
+        None => nonces.remove(&address),
+      };
+    }
+  }
+}
+
+/// Signs locally with a secret key or lets the node sign for an unlocked account,
+/// then waits for the receipt.
+pub async fn send_call<'a>(
+  contract: &ContractData,
+  web3: &web3::Web3<Transport>,
+  chain_id: u64,
+  method: &str,
+  tokens: &[Token],
+  from: Caller,
+  confirmations: usize,
+  opts: Options,
+  timeout: Duration,
+) -> Result<TransactionReceipt, &'a str> {
+  let transaction = match from {
+    Caller::PrivateKey(key) => {
+      let key_ref = SecretKeyRef::new(&key);
+      let address = key_ref.address();
+      let node = &contract.node;
+
+      let function = contract
+        .contract
+        .abi()
+        .function(method)
+        .or_else(|_| Err("Method not found in contract abi"))?;
+      let call_data = function
+        .encode_input(tokens)
+        .or_else(|_| Err("Failed to encode method inputs"))?;
+
+      // the pending count doesn't know about writes still on their way
+      let nonce = match opts.nonce {
+        Some(nonce) => nonce,
+        None => {
+          let eth = web3.eth();
+          let pending = node
+            .retry
+            .timeout(timeout, || {
+              eth.transaction_count(address, Some(BlockNumber::Pending))
+            })
+            .await
+            .or_else(|_| Err("Nonce request timed out"))?
+            .or_else(|e| {
+              cblog!("web3 error: {}", e);
+              Err("Failed to get the nonce")
+            })?;
+          node.nonces.take(address, pending)
+        }
+      };
+      let give_back = || {
+        if opts.nonce.is_none() {
+          node.nonces.give_back(address, nonce);
+        }
+      };
+
+      // sign with the chain id verified by Eth (EIP-155), replay protected
+      let mut tx = TransactionParameters {
+        nonce: Some(nonce),
+        to: Some(contract.contract.address()),
+        gas_price: opts.gas_price,
+        data: call_data.into(),
+        chain_id: Some(chain_id),
+        ..Default::default()
+      };
+      if let Some(gas) = opts.gas {
+        tx.gas = gas;
+      }
+      if let Some(value) = opts.value {
+        tx.value = value;
+      }
+
+      let signed = web3
+        .accounts()
+        .sign_transaction(tx, key_ref)
+        .await
+        .or_else(|e| {
+          cblog!("web3 error: {}", e);
+          give_back();
+          Err("Failed to sign transaction")
+        })?;
+
+      // an endpoint might take it and fail to answer, then another one knows it already,
+      // so only a refusal gives the nonce back
+      node
+        .retry
+        .send_raw(&web3.eth(), signed.raw_transaction, timeout)
+        .await
+        .or_else(|_| Err("Write timed out"))?
+        .or_else(|e| {
+          cblog!("web3 error: {}", e);
+          give_back();
+          Err("Write failed")
+        })?
+    }
+    Caller::PublicKey(from) => {
+      let fut = contract.contract.call(method, tokens, from, opts);
+      tokio::time::timeout(timeout, fut)
+        .await
+        .or_else(|_| Err("Write timed out"))?
+        .or_else(|e| {
+          cblog!("web3 error: {}", e);
+          Err("Write failed")
+        })?
+    }
+  };
+
+  wait_for_receipt(&contract.node, transaction, confirmations, timeout).await
+}
+
+// how often receipts are asked for while waiting
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Polls the receipt of a sent transaction until it is mined under enough blocks.
+/// Gives up when the transaction is not mined, or no block comes on top of it, within timeout.
+pub async fn wait_for_receipt<'a>(
+  node: &NodeData,
+  hash: H256,
+  confirmations: usize,
+  timeout: Duration,
+) -> Result<TransactionReceipt, &'a str> {
+  let eth = node.web3.eth();
+  let mut progress = Instant::now();
+  let mut last_seen = None;
+  loop {
+    let receipt = node
+      .retry
+      .timeout(timeout, || eth.transaction_receipt(hash))
+      .await
+      .or_else(|_| Err("Timed out"))?
+      .or_else(|e| {
+        cblog!("web3 error: {}", e);
+        Err("Failed to fetch transaction receipt")
+      })?;
+    // the receipt is asked again every time, a reorg might move it
+    if let Some(mined) = receipt.as_ref().and_then(|r| r.block_number) {
+      let head = if confirmations == 0 {
+        mined
+      } else {
+        node
+          .retry
+          .timeout(timeout, || eth.block_number())
+          .await
+          .or_else(|_| Err("Timed out"))?
+          .or_else(|e| {
+            cblog!("web3 error: {}", e);
+            Err("Failed to fetch current block number")
+          })?
+      };
+      if mined.as_u64() + confirmations as u64 <= head.as_u64() {
+        return receipt.ok_or("Transaction receipt was empty");
+      }
+      if last_seen != Some((mined, head)) {
+        last_seen = Some((mined, head));
+        progress = Instant::now();
+      }
+    }
+    if progress.elapsed() >= timeout {
+      return Err("Transaction was not confirmed in time");
+    }
+    sleep(POLL_INTERVAL).await;
+  }
+}
+
+/// Fills the output table of a write from its receipt.
+pub fn receipt_to_table<'a>(
+  transaction: &TransactionReceipt,
+  output: &mut Table,
+) -> Result<(), &'a str> {
+  output.insert_fast_static(
+    cstr!("transaction_hash"),
+    transaction.transaction_hash.as_bytes().into(),
+  );
+
+  output.insert_fast_static(
+    cstr!("transaction_index"),
+    transaction.transaction_index.as_u64().try_into()?,
+  );
+
+  if let Some(block_hash) = transaction.block_hash {
+    output.insert_fast_static(cstr!("block_hash"), block_hash.as_bytes().into());
+  }
+
+  if let Some(block_number) = transaction.block_number {
+    output.insert_fast_static(cstr!("block_number"), block_number.as_u64().try_into()?);
+  }
+
+  if let Some(gas_used) = transaction.gas_used {
+    let bytes: [u8; 32] = gas_used.into();
+    output.insert_fast_static(cstr!("gas_used"), (&bytes[..]).into());
+  }
+
+  if let Some(status) = transaction.status {
+    output.insert_fast_static(cstr!("status"), status.as_u64().try_into()?);
+  }
+
+  // TODO, add logs vector
+
+  Ok(())
+}
+
 impl Default for Write {
   fn default() -> Self {
     Write {
@@ -107,136 +485,69 @@ impl Default for Write {
       },
       confirmations: 12,
       options: ParamVar::new(().into()),
+      timeout: ParamVar::new(().into()),
       output: Table::new(),
     }
   }
 }
 
 impl Write {
+  /// Sends the call and waits for its receipt.
+  async fn transact<'a>(
+    data: &EthData,
+    web3: &web3::Web3<Transport>,
+    chain_id: u64,
+    from: Caller,
+    confirmations: usize,
+    input: &Var,
+    options: Option<Table>,
+    timeout: Duration,
+  ) -> Result<TransactionReceipt, &'a str> {
+    let method = data.method.to_str().or_else(|_| Err("Invalid string"))?;
+    let contract = get_shared(&data.contract)?;
+    let names = input_names(input, &data.input_types);
+    let names = resolve_names(&contract.node, names, timeout).await?;
+    let tokens = var_to_tokens(input, &data.input_types, &names)?;
+
+    let opts = options_from_table(options)?;
+    send_call(
+      contract,
+      web3,
+      chain_id,
+      method,
+      tokens.as_slice(),
+      from,
+      confirmations,
+      opts,
+      timeout,
+    )
+    .await
+  }
+
   async fn activate_async<'a>(
     data: &EthData,
+    web3: &web3::Web3<Transport>,
+    chain_id: u64,
     from: Caller,
     confirmations: usize,
     input: &Var,
     options: Option<Table>,
     output: &mut Table,
+    timeout: Duration,
   ) -> Result<(), &'a str> {
-    let method = data.method.to_str().or_else(|_| Err("Invalid string"))?;
-    let tokens = var_to_tokens(input, &data.input_types)?;
-    let contract_a = data
-      .contract
-      .as_ref()
-      .ok_or_else(|| "Failed to unwrap contract data shared pointer")?;
-    let contract_o = &**contract_a;
-    let contract = contract_o
-      .as_ref()
-      .ok_or_else(|| "Failed to unwrap contract data, was empty")?;
-
-    // no timeout here as we deal with moneys likely... but TODO check if there is an internal timeout
-    // let timed_fut = timeout(Duration::from_secs(30), fut);
-
-    let opts = {
-      if let Some(options) = options {
-        let mut opts = Options::default();
-        for (key, value) in options.iter() {
-          let key = unsafe { CStr::from_ptr(key.0) };
-          let key = key.to_str().unwrap();
-          match key {
-            "gas" => {
-              let slice: &[u8] = value.as_ref().try_into()?;
-              let u: U256 = slice.into();
-              opts.gas = Some(u);
-            }
-            "gas-price" => {
-              let slice: &[u8] = value.as_ref().try_into()?;
-              let u: U256 = slice.into();
-              opts.gas_price = Some(u);
-            }
-            "value" => {
-              let slice: &[u8] = value.as_ref().try_into()?;
-              let u: U256 = slice.into();
-              opts.value = Some(u);
-            }
-            "nonce" => {
-              let slice: &[u8] = value.as_ref().try_into()?;
-              let u: U256 = slice.into();
-              opts.nonce = Some(u);
-            }
-            _ => {
-              cblog!("Ignored an invalid option label: {}", key);
-            }
-          }
-        }
-        opts
-      } else {
-        Options::default()
-      }
-    };
-
-    let transaction = {
-      match from {
-        Caller::PrivateKey(key) => {
-          let key_ref = SecretKeyRef::new(&key);
-
-          let fut = contract.contract.signed_call_with_confirmations(
-            method,
-            // notice as_slice is necessary to make the "into" jigsaw fall into pieces
-            tokens.as_slice(),
-            opts,
-            confirmations,
-            key_ref,
-          );
-
-          fut.await.or_else(|e| {
-            cblog!("web3 error: {}", e);
-            Err("Write failed")
-          })?
-        }
-        Caller::PublicKey(from) => {
-          let fut = contract.contract.call_with_confirmations(
-            method,
-            // notice as_slice is necessary to make the "into" jigsaw fall into pieces
-            tokens.as_slice(),
-            from,
-            opts,
-            confirmations,
-          );
-          fut.await.or_else(|e| {
-            cblog!("web3 error: {}", e);
-            Err("Write failed")
-          })?
-        }
-      }
-    };
-
-    output.insert_fast_static(
-      cstr!("transaction_hash"),
-      transaction.transaction_hash.as_bytes().into(),
-    );
-
-    output.insert_fast_static(
-      cstr!("transaction_index"),
-      transaction.transaction_index.as_u64().try_into()?,
-    );
-
-    if let Some(block_hash) = transaction.block_hash {
-      output.insert_fast_static(cstr!("block_hash"), block_hash.as_bytes().into());
-    }
-
-    if let Some(block_number) = transaction.block_number {
-      output.insert_fast_static(cstr!("block_number"), block_number.as_u64().try_into()?);
-    }
-
-    if let Some(gas_used) = transaction.gas_used {
-      let bytes: [u8; 32] = gas_used.into();
-      output.insert_fast_static(cstr!("gas_used"), (&bytes[..]).into());
-    }
-
-    if let Some(status) = transaction.status {
-      output.insert_fast_static(cstr!("status"), status.as_u64().try_into()?);
-    }
+    let transaction = Write::transact(
+      data,
+      web3,
+      chain_id,
+      from,
+      confirmations,
+      input,
+      options,
+      timeout,
+    )
+    .await?;
 
-    // TODO, add logs vector
+    receipt_to_table(&transaction, output)?;
 
     Ok(())
   }
@@ -272,6 +583,7 @@ impl Block for Write {
       2 => self.cu.from.set_param(value),
       3 => self.confirmations = value.try_into().unwrap_or(12),
       4 => self.options.set_param(value),
+      5 => self.timeout.set_param(value),
       _ => unreachable!(),
     }
   }
@@ -286,6 +598,7 @@ impl Block for Write {
         .try_into()
         .expect("a proper int var, mitigared in setParam, fixme"),
       4 => self.options.get_param(),
+      5 => self.timeout.get_param(),
       _ => Var::default(),
     }
   }
@@ -310,11 +623,13 @@ impl Block for Write {
     self.cu.instance.warmup(context);
     self.cu.from.warmup(context);
     self.options.warmup(context);
+    self.timeout.warmup(context);
 
     Ok(())
   }
 
   fn cleanup(&mut self) {
+    self.timeout.cleanup();
     self.options.cleanup();
     self.cu.instance.cleanup();
     self.cu.from.cleanup();
@@ -325,15 +640,12 @@ impl Block for Write {
 
   fn activate(&mut self, context: &Context, input: &Var) -> Result<Var, &str> {
     if self.cu.data.contract.is_none() {
-      self.cu.data.contract = Some(Var::from_object_as_clone::<Option<ContractData>>(
-        self.cu.instance.get(),
-        &CONTRACT_TYPE,
-      )?);
+      self.cu.data.contract = Some(shared_from_var(self.cu.instance.get(), &CONTRACT_TYPE)?);
 
       self.cu.data.from = (&self.cu.from.get()).try_into()?;
 
       // also init input_types from json
-      let contract = Var::get_mut_from_clone(&self.cu.data.contract)?;
+      let contract = get_shared(&self.cu.data.contract)?;
       let method = self
         .cu
         .data
@@ -342,74 +654,14 @@ impl Block for Write {
         .or_else(|_| Err("Invalid string"))?;
       self.cu.data.input_types = gather_inputs(method, &contract.json_abi)?;
       // also populate node data here
-      self.cu.node = Some(Var::from_object_as_clone::<Option<NodeData>>(
-        contract.node,
-        &NODE_TYPE,
-      )?);
+      self.cu.node = Some(contract.node.clone());
     }
 
     Ok(do_blocking(context, || -> Result<Var, &str> {
-     let node = Var::get_mut_from_clone(&self.cu.node)?;
-
-      let caller = {
-        let from: Result<Address, &str> = {
-          let from = &self.cu.from.get();
-          if let Ok(s) = TryInto::<String>::try_into(from) {
-            if s.len() > 0 {
-              if s.starts_with("0x") {
-                let subs: &str = &s[2..];
-                subs
-                  .parse()
-                  .or_else(|_| Err("Failed to parse From address"))
-              } else {
-                s.parse().or_else(|_| Err("Failed to parse From address"))
-              }
-            } else {
-              Err("Expected a publickey, got an empty string")
-            }
-          } else {
-            Err("Expected a publickey, got an invalid string")
-          }
-        };
-        if let Ok(from) = from {
-          Caller::PublicKey(from)
-        } else {
-          let from_key = {
-            let from = &self.cu.from.get();
-            if let Ok(mut s) = TryInto::<String>::try_into(from) {
-              if from.is_path() {
-                let mut data = {
-                  let mut key_str =
-                    fs::read_to_string(s).or_else(|_| Err("Failed to read key file"))?;
-                  let key_slice = if key_str.starts_with("0x") {
-                    &key_str[2..]
-                  } else {
-                    &key_str[..]
-                  };
-                  let bytes = hex::decode(key_slice).or_else(|_| Err("Failed to decode key"))?;
-                  key_str.zeroize();
-                  bytes
-                };
-                let key = SecretKey::from_slice(data.as_slice())
-                  .or_else(|_| Err("Failed to create SecretKey from file contents"))?;
-                data.zeroize();
-                Ok(key)
-              } else {
-                let key_slice = if s.starts_with("0x") { &s[2..] } else { &s[..] };
-                let mut bytes = hex::decode(key_slice).or_else(|_| Err("Failed to decode key"))?;
-                let key = SecretKey::from_slice(bytes.as_slice())
-                  .or_else(|_| Err("Failed to create SecretKey from string"))?;
-                bytes.zeroize();
-                s.zeroize();
-                Ok(key)
-              }
-            } else {
-              Err("SecretKey parameter is invalid")
-            }
-          }?;
-          Caller::PrivateKey(from_key)
-        }
-      };
+      let node = get_shared(&self.cu.node)?;
+      let timeout = get_block_timeout(&self.timeout, node)?;
+
+      let caller = caller_from_var(node, &self.cu.from.get(), timeout)?;
 
       let options: Option<Table> = {
         let optvar = self.options.get();
@@ -420,15 +672,221 @@ impl Block for Write {
         }
       };
 
-      node.scheduler.block_on(Write::activate_async(
+      RUNTIME.block_on(Write::activate_async(
         &mut self.cu.data,
+        &node.web3,
+        node.chain_id,
         caller,
         self.confirmations,
         input,
         options,
         &mut self.output,
+        timeout,
       ))?;
       Ok((&self.output).into())
     }))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::hash;
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::receipt;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::ACCOUNT;
+  use crate::blocks::mock::CHAIN_ID;
+  use crate::blocks::mock::CONTRACT;
+  use crate::blocks::mock::KEY;
+  use crate::blocks::rlp::rlp_decode;
+  use crate::blocks::rlp::rlp_number;
+  use crate::blocks::rlp::Rlp;
+  use jsonrpc_core::serde_json::json;
+  use std::sync::Arc;
+  use std::thread;
+
+  fn transact(data: &EthData, from: Caller) -> Result<TransactionReceipt, &'static str> {
+    let contract = data.contract.as_ref().unwrap();
+    let input = Var::default();
+    RUNTIME.block_on(Write::transact(
+      data,
+      &contract.node.web3,
+      CHAIN_ID,
+      from,
+      0,
+      &input,
+      None,
+      Duration::from_secs(5),
+    ))
+  }
+
+  #[test]
+  fn signs_locally_for_the_node_chain() {
+    let node = MockNode::start();
+    node.expect("eth_getTransactionCount", quantity(3));
+    node.expect("eth_gasPrice", quantity(1_000_000_000));
+    node.expect("eth_sendRawTransaction", json!(hash(0xaa)));
+    node.expect("eth_getTransactionReceipt", receipt(&hash(0xaa), 2));
+    let data = node.eth_data("poke");
+    let key: SecretKey = KEY.parse().unwrap();
+
+    let receipt = transact(&data, Caller::PrivateKey(key)).unwrap();
+    assert_eq!(format!("{:?}", receipt.transaction_hash), hash(0xaa));
+
+    // what went out is what we would sign for the mock chain ourselves
+    let contract = data.contract.as_ref().unwrap();
+    let call_data = contract
+      .contract
+      .abi()
+      .function("poke")
+      .unwrap()
+      .encode_input(&[]);
+    let tx = TransactionParameters {
+      nonce: Some(3.into()),
+      to: Some(CONTRACT.parse().unwrap()),
+      gas_price: Some(1_000_000_000.into()),
+      data: call_data.unwrap().into(),
+      chain_id: Some(CHAIN_ID),
+      ..Default::default()
+    };
+    let accounts = contract.node.web3.accounts();
+    let signed = RUNTIME.block_on(accounts.sign_transaction(tx, SecretKeyRef::new(&key)));
+    let sent = &node.requests("eth_sendRawTransaction")[0];
+    assert_eq!(sent.params[0], json!(signed.unwrap().raw_transaction));
+    assert_eq!(node.count("eth_chainId"), 1);
+  }
+
+  #[test]
+  fn lets_the_node_sign_for_known_accounts() {
+    let node = MockNode::start();
+    node.expect("eth_sendTransaction", json!(hash(0xaa)));
+    node.expect("eth_getTransactionReceipt", receipt(&hash(0xaa), 2));
+    let data = node.eth_data("poke");
+
+    let receipt = transact(&data, Caller::PublicKey(ACCOUNT.parse().unwrap())).unwrap();
+    assert_eq!(receipt.status, Some(1.into()));
+    let sent = &node.requests("eth_sendTransaction")[0];
+    assert_eq!(sent.params[0]["from"], format!("0x{}", ACCOUNT));
+    assert_eq!(sent.params[0]["to"], format!("0x{}", CONTRACT));
+  }
+
+  #[test]
+  fn transactions_known_by_the_node_are_not_failures() {
+    let node = MockNode::start();
+    node.expect("eth_getTransactionCount", quantity(3));
+    node.expect("eth_gasPrice", quantity(1_000_000_000));
+    node.expect_error("eth_sendRawTransaction", -32000, "already known");
+    let data = node.eth_data("poke");
+    let key: SecretKey = KEY.parse().unwrap();
+    // whatever the hash, the one we signed is the one to wait for
+    node.expect("eth_getTransactionReceipt", receipt(&hash(0xaa), 2));
+
+    let receipt = transact(&data, Caller::PrivateKey(key)).unwrap();
+    assert_eq!(receipt.status, Some(1.into()));
+    assert_eq!(node.count("eth_sendRawTransaction"), 1);
+    let asked = &node.requests("eth_getTransactionReceipt")[0];
+    let sent = &node.requests("eth_sendRawTransaction")[0];
+    let raw = hex::decode(&sent.params[0].as_str().unwrap()[2..]).unwrap();
+    let signed: H256 = web3::signing::keccak256(&raw).into();
+    assert_eq!(asked.params[0], json!(signed));
+  }
+
+  // the nonce of every legacy transaction sent, in order
+  fn sent_nonces(node: &MockNode) -> Vec<U256> {
+    let sent = node.requests("eth_sendRawTransaction");
+    sent
+      .iter()
+      .map(|request| {
+        let raw = hex::decode(&request.params[0].as_str().unwrap()[2..]).unwrap();
+        match rlp_decode(&raw).unwrap() {
+          Rlp::List(_, fields) => rlp_number(&fields[0]).unwrap(),
+          Rlp::Bytes(_) => panic!("not a transaction"),
+        }
+      })
+      .collect()
+  }
+
+  #[test]
+  fn concurrent_writes_from_one_key_take_their_own_nonce() {
+    let node = MockNode::start();
+    // the node doesn't count any of them as pending yet
+    node.expect("eth_getTransactionCount", quantity(3));
+    node.expect("eth_gasPrice", quantity(1_000_000_000));
+    node.expect("eth_sendRawTransaction", json!(hash(0xaa)));
+    node.expect("eth_getTransactionReceipt", receipt(&hash(0xaa), 2));
+    let data = Arc::new(node.eth_data("poke"));
+    let key: SecretKey = KEY.parse().unwrap();
+
+    let writes: Vec<_> = (0..4)
+      .map(|_| {
+        let data = data.clone();
+        thread::spawn(move || transact(&data, Caller::PrivateKey(key)).unwrap())
+      })
+      .collect();
+    for write in writes {
+      write.join().unwrap();
+    }
+    let mut nonces = sent_nonces(&node);
+    nonces.sort();
+    assert_eq!(nonces, vec![3.into(), 4.into(), 5.into(), 6.into()]);
+    let asked = &node.requests("eth_getTransactionCount")[0];
+    assert_eq!(asked.params[1], "pending");
+  }
+
+  #[test]
+  fn refused_writes_give_their_nonce_back() {
+    let nonces = Nonces::default();
+    let address = Address::from_low_u64_be(1);
+    assert_eq!(nonces.take(address, 3.into()), 3.into());
+    assert_eq!(nonces.take(address, 3.into()), 4.into());
+    nonces.give_back(address, 4.into());
+    assert_eq!(nonces.take(address, 3.into()), 4.into());
+    // a later write holds on to its own
+    assert_eq!(nonces.take(address, 3.into()), 5.into());
+    nonces.give_back(address, 4.into());
+    assert_eq!(nonces.take(address, 3.into()), 6.into());
+    // the node moved on without us
+    assert_eq!(nonces.take(address, 10.into()), 10.into());
+  }
+
+  #[test]
+  fn waits_for_confirmations() {
+    let node = MockNode::start();
+    // not mined yet, then mined at 2 but with a single block on top
+    node.expect("eth_getTransactionReceipt", receipt(&hash(0xaa), 2));
+    node.expect_times("eth_getTransactionReceipt", 1, json!(null));
+    node.expect("eth_blockNumber", quantity(4));
+    node.expect_times("eth_blockNumber", 1, quantity(3));
+    let eth = node.node();
+    let hash: H256 = hash(0xaa).parse().unwrap();
+    let wait = wait_for_receipt(&eth, hash, 2, Duration::from_secs(5));
+    let receipt = RUNTIME.block_on(wait).unwrap();
+    assert_eq!(receipt.block_number, Some(2.into()));
+    assert_eq!(node.count("eth_blockNumber"), 2);
+  }
+
+  #[test]
+  fn gives_up_on_stalled_transactions() {
+    let node = MockNode::start();
+    node.expect("eth_getTransactionReceipt", receipt(&hash(0xaa), 2));
+    node.expect("eth_blockNumber", quantity(2));
+    let eth = node.node();
+    let hash: H256 = hash(0xaa).parse().unwrap();
+    let wait = wait_for_receipt(&eth, hash, 1, Duration::from_millis(1500));
+    assert_eq!(
+      RUNTIME.block_on(wait).err(),
+      Some("Transaction was not confirmed in time")
+    );
+  }
+
+  #[test]
+  fn failed_sends_are_errors() {
+    let node = MockNode::start();
+    node.expect_error("eth_sendTransaction", -32000, "insufficient funds");
+    let data = node.eth_data("poke");
+    let from = Caller::PublicKey(ACCOUNT.parse().unwrap());
+    assert_eq!(transact(&data, from).err(), Some("Write failed"));
+    assert_eq!(node.count("eth_getTransactionReceipt"), 0);
+  }
+}
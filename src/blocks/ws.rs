@@ -0,0 +1,967 @@
+use crate::blocks::auth::redact_url;
+use crate::blocks::auth::Auth;
+use crate::blocks::http::batch_results;
+use crate::blocks::http::output_result;
+use crate::blocks::log;
+use crate::blocks::multi::parse_quantity;
+use chainblocks::cblog;
+use futures::channel::mpsc;
+use futures::channel::oneshot;
+use futures::future::BoxFuture;
+use futures::future::FutureExt;
+use futures::io::AsyncRead;
+use futures::io::AsyncWrite;
+use futures::stream::Stream;
+use futures::stream::StreamExt;
+use jsonrpc_core::serde_json;
+use jsonrpc_core::types::Call;
+use jsonrpc_core::types::Id;
+use jsonrpc_core::types::MethodCall;
+use jsonrpc_core::types::Output;
+use jsonrpc_core::types::Params;
+use jsonrpc_core::types::Request;
+use jsonrpc_core::types::Response;
+use jsonrpc_core::types::Value;
+use jsonrpc_core::types::Version;
+use soketto::connection;
+use soketto::handshake::client::Header;
+use soketto::handshake::Client;
+use soketto::handshake::ServerResponse;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use tokio::time::timeout;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use url::Url;
+use web3::api::SubscriptionId;
+use web3::error::Error;
+use web3::error::TransportError;
+use web3::helpers::build_request;
+use web3::BatchTransport;
+use web3::DuplexTransport;
+use web3::RequestId;
+use web3::Transport;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Socket for T {}
+
+type Sender = connection::Sender<Box<dyn Socket>>;
+type Receiver = connection::Receiver<Box<dyn Socket>>;
+
+type Single = oneshot::Sender<web3::Result<Value>>;
+type Batch = oneshot::Sender<web3::Result<Vec<web3::Result<Value>>>>;
+
+enum Message {
+  Request {
+    id: RequestId,
+    request: String,
+    sender: Single,
+  },
+  Batch {
+    ids: Vec<RequestId>,
+    request: String,
+    sender: Batch,
+  },
+  Subscribe {
+    id: RequestId,
+    params: Vec<Value>,
+    request: String,
+    sender: Single,
+  },
+  Unsubscribe {
+    id: RequestId,
+    sub: String,
+    sender: Single,
+  },
+  Attach {
+    sub: SubscriptionId,
+    sink: mpsc::UnboundedSender<Value>,
+  },
+  Detach {
+    sub: SubscriptionId,
+  },
+}
+
+/// A WebSocket transport that survives the connection dropping.
+/// It reconnects with backoff and sets up again every active subscription,
+/// which keeps the id it was first given so streams never notice.
+#[derive(Clone)]
+pub struct WebSocket {
+  url: String,
+  id: Arc<AtomicUsize>,
+  requests: mpsc::UnboundedSender<Message>,
+}
+
+impl fmt::Debug for WebSocket {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("WebSocket").field("url", &self.url).finish()
+  }
+}
+
+impl WebSocket {
+  pub async fn new(url: &str, auth: Arc<Auth>) -> web3::Result<WebSocket> {
+    let parsed = Url::parse(url).or_else(|e| Err(message_error(format!("Invalid url: {}", e))))?;
+    let socket = connect(&parsed, &auth).await?;
+    let (requests, receiver) = mpsc::unbounded();
+    tokio::spawn(Task::new(parsed, auth).run(socket, receiver));
+    Ok(WebSocket {
+      url: redact_url(url),
+      id: Arc::new(AtomicUsize::new(1)),
+      requests,
+    })
+  }
+
+  fn call<T: Send + 'static>(
+    &self,
+    message: impl FnOnce(oneshot::Sender<web3::Result<T>>) -> Message,
+  ) -> BoxFuture<'static, web3::Result<T>> {
+    let (sender, receiver) = oneshot::channel();
+    let sent = self.requests.unbounded_send(message(sender));
+    async move {
+      sent.or_else(|_| Err(Error::Unreachable))?;
+      receiver.await.unwrap_or(Err(Error::Unreachable))
+    }
+    .boxed()
+  }
+}
+
+impl Transport for WebSocket {
+  type Out = BoxFuture<'static, web3::Result<Value>>;
+
+  fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+    let id = self.id.fetch_add(1, Ordering::AcqRel);
+    (id, build_request(id, method, params))
+  }
+
+  fn send(&self, id: RequestId, request: Call) -> Self::Out {
+    let (method, params) = match &request {
+      Call::MethodCall(call) => (call.method.clone(), params_of(&call.params)),
+      _ => (String::new(), Vec::new()),
+    };
+    let request = match serde_json::to_string(&Request::Single(request)) {
+      Ok(request) => request,
+      Err(e) => return futures::future::ready(Err(e.into())).boxed(),
+    };
+    let first = params.first().and_then(|p| p.as_str()).map(String::from);
+    match (method.as_str(), first) {
+      ("eth_subscribe", _) => self.call(|sender| Message::Subscribe {
+        id,
+        params,
+        request,
+        sender,
+      }),
+      ("eth_unsubscribe", Some(sub)) => {
+        self.call(|sender| Message::Unsubscribe { id, sub, sender })
+      }
+      _ => self.call(|sender| Message::Request {
+        id,
+        request,
+        sender,
+      }),
+    }
+  }
+}
+
+impl BatchTransport for WebSocket {
+  type Batch = BoxFuture<'static, web3::Result<Vec<web3::Result<Value>>>>;
+
+  fn send_batch<T>(&self, requests: T) -> Self::Batch
+  where
+    T: IntoIterator<Item = (RequestId, Call)>,
+  {
+    let (ids, calls): (Vec<RequestId>, Vec<Call>) = requests.into_iter().unzip();
+    let request = match serde_json::to_string(&Request::Batch(calls)) {
+      Ok(request) => request,
+      Err(e) => return futures::future::ready(Err(e.into())).boxed(),
+    };
+    self.call(|sender| Message::Batch {
+      ids,
+      request,
+      sender,
+    })
+  }
+}
+
+impl DuplexTransport for WebSocket {
+  type NotificationStream = mpsc::UnboundedReceiver<Value>;
+
+  fn subscribe(&self, id: SubscriptionId) -> web3::Result<Self::NotificationStream> {
+    let (sink, stream) = mpsc::unbounded();
+    self
+      .requests
+      .unbounded_send(Message::Attach { sub: id, sink })
+      .or_else(|_| Err(Error::Unreachable))?;
+    Ok(stream)
+  }
+
+  fn unsubscribe(&self, id: SubscriptionId) -> web3::Result<()> {
+    self
+      .requests
+      .unbounded_send(Message::Detach { sub: id })
+      .or_else(|_| Err(Error::Unreachable))
+  }
+}
+
+struct Subscription {
+  params: Vec<Value>,
+  sink: Option<mpsc::UnboundedSender<Value>>,
+  // notifications held back while there is no sink or a backfill is running
+  queued: Vec<Value>,
+  // block number and log index of the last log delivered
+  last_log: Option<(u64, u64)>,
+  // head block when the subscription started, to backfill before the first log
+  start_block: Option<u64>,
+  backfilling: bool,
+}
+
+impl Subscription {
+  fn is_logs(&self) -> bool {
+    self.params.first().and_then(|p| p.as_str()) == Some("logs")
+  }
+
+  fn push(&mut self, value: Value) {
+    if self.backfilling || self.sink.is_none() {
+      self.queued.push(value);
+      return;
+    }
+
+    if self.is_logs() {
+      let removed = value.get("removed").and_then(|r| r.as_bool()) == Some(true);
+      if let Some(key) = log_key(&value) {
+        // backfilled logs overlap with what we saw before the gap
+        if !removed && self.last_log.map_or(false, |last| key <= last) {
+          return;
+        }
+        if self.last_log.map_or(true, |last| key > last) {
+          self.last_log = Some(key);
+        }
+      }
+    }
+
+    if let Some(sink) = &self.sink {
+      let _ = sink.unbounded_send(value);
+    }
+  }
+
+  fn flush(&mut self) {
+    for value in std::mem::take(&mut self.queued) {
+      self.push(value);
+    }
+  }
+}
+
+enum Pending {
+  Single(Single),
+  Batch(Vec<RequestId>, Batch),
+  // the head block is filled in if the node answers before the subscription
+  Subscribe(Vec<Value>, Single, Option<u64>),
+}
+
+// requests issued by the transport itself, around subscriptions
+enum Internal {
+  StartBlock(RequestId),
+  Resubscribe(SubscriptionId),
+  Backfill(SubscriptionId),
+}
+
+enum Exit {
+  Disconnected,
+  Dropped,
+}
+
+struct Task {
+  url: Url,
+  auth: Arc<Auth>,
+  // what we print in logs
+  name: String,
+  pending: BTreeMap<RequestId, Pending>,
+  internal: BTreeMap<String, Internal>,
+  // keyed by the id handed out to the user, which never changes
+  subscriptions: BTreeMap<SubscriptionId, Subscription>,
+  // id given by the current connection -> id handed out to the user
+  server_ids: BTreeMap<String, SubscriptionId>,
+  counter: u64,
+}
+
+impl Task {
+  fn new(url: Url, auth: Arc<Auth>) -> Task {
+    Task {
+      name: redact_url(url.as_str()),
+      url,
+      auth,
+      pending: BTreeMap::new(),
+      internal: BTreeMap::new(),
+      subscriptions: BTreeMap::new(),
+      server_ids: BTreeMap::new(),
+      counter: 0,
+    }
+  }
+
+  async fn run(
+    mut self,
+    mut socket: (Sender, Receiver),
+    mut requests: mpsc::UnboundedReceiver<Message>,
+  ) {
+    loop {
+      let (mut sender, receiver) = socket;
+      let exit = match self.resubscribe(&mut sender).await {
+        Ok(_) => self.serve(&mut sender, receiver, &mut requests).await,
+        Err(_) => Exit::Disconnected,
+      };
+      if let Exit::Dropped = exit {
+        return;
+      }
+
+      self.disconnected();
+      socket = match self.reconnect(&mut requests).await {
+        Some(socket) => socket,
+        None => return,
+      };
+    }
+  }
+
+  async fn serve(
+    &mut self,
+    sender: &mut Sender,
+    receiver: Receiver,
+    requests: &mut mpsc::UnboundedReceiver<Message>,
+  ) -> Exit {
+    let incoming = data_stream(receiver);
+    futures::pin_mut!(incoming);
+    loop {
+      tokio::select! {
+        message = requests.next() => match message {
+          Some(message) => {
+            if let Err(e) = self.handle(message, sender).await {
+              cblog!("WebSocket {} write failed: {}", self.name, e);
+              return Exit::Disconnected;
+            }
+          }
+          None => {
+            let _ = sender.close().await;
+            return Exit::Dropped;
+          }
+        },
+        data = incoming.next() => match data {
+          Some(Ok(data)) => {
+            if let Err(e) = self.received(&data, sender).await {
+              cblog!("WebSocket {} write failed: {}", self.name, e);
+              return Exit::Disconnected;
+            }
+          }
+          Some(Err(e)) => {
+            cblog!("WebSocket {} connection lost: {}", self.name, e);
+            return Exit::Disconnected;
+          }
+          None => return Exit::Disconnected,
+        },
+      }
+    }
+  }
+
+  async fn reconnect(
+    &mut self,
+    requests: &mut mpsc::UnboundedReceiver<Message>,
+  ) -> Option<(Sender, Receiver)> {
+    let mut attempt: u32 = 0;
+    loop {
+      let delay = (MIN_RECONNECT_DELAY * 2u32.pow(attempt.min(6))).min(MAX_RECONNECT_DELAY);
+      cblog!(
+        "WebSocket {} disconnected, reconnecting in {:?}",
+        self.name,
+        delay
+      );
+      let wait = sleep(delay);
+      tokio::pin!(wait);
+      loop {
+        tokio::select! {
+          _ = &mut wait => break,
+          message = requests.next() => match message {
+            Some(message) => self.offline(message),
+            None => return None,
+          },
+        }
+      }
+
+      match connect(&self.url, &self.auth).await {
+        Ok(socket) => {
+          cblog!("WebSocket {} reconnected", self.name);
+          return Some(socket);
+        }
+        Err(e) => cblog!("WebSocket {} reconnection failed: {}", self.name, e),
+      }
+      attempt += 1;
+    }
+  }
+
+  fn disconnected(&mut self) {
+    for (_, pending) in std::mem::take(&mut self.pending) {
+      match pending {
+        Pending::Single(sender) | Pending::Subscribe(_, sender, _) => {
+          let _ = sender.send(Err(Error::Unreachable));
+        }
+        Pending::Batch(_, sender) => {
+          let _ = sender.send(Err(Error::Unreachable));
+        }
+      }
+    }
+    self.internal.clear();
+    self.server_ids.clear();
+    for sub in self.subscriptions.values_mut() {
+      // anything held back for a backfill can go out now, the next one will cover the rest
+      sub.backfilling = false;
+      sub.flush();
+    }
+  }
+
+  async fn resubscribe(&mut self, sender: &mut Sender) -> Result<(), connection::Error> {
+    let subs: Vec<(SubscriptionId, Vec<Value>)> = self
+      .subscriptions
+      .iter()
+      .map(|(sub, subscription)| (sub.clone(), subscription.params.clone()))
+      .collect();
+    for (sub, params) in subs {
+      let id = self.internal_id();
+      self.internal.insert(id.clone(), Internal::Resubscribe(sub));
+      write(sender, internal_call(id, "eth_subscribe", params)).await?;
+    }
+    Ok(())
+  }
+
+  fn offline(&mut self, message: Message) {
+    match message {
+      Message::Request { sender, .. } | Message::Subscribe { sender, .. } => {
+        let _ = sender.send(Err(Error::Unreachable));
+      }
+      Message::Batch { sender, .. } => {
+        let _ = sender.send(Err(Error::Unreachable));
+      }
+      Message::Unsubscribe { sub, sender, .. } => {
+        // nothing to tell the node, the subscription died with the connection
+        self.subscriptions.remove(&SubscriptionId::from(sub));
+        let _ = sender.send(Ok(Value::Bool(true)));
+      }
+      Message::Attach { sub, sink } => self.attach(sub, sink),
+      Message::Detach { sub } => {
+        self.detach(&sub);
+      }
+    }
+  }
+
+  async fn handle(
+    &mut self,
+    message: Message,
+    sender: &mut Sender,
+  ) -> Result<(), connection::Error> {
+    match message {
+      Message::Request {
+        id,
+        request,
+        sender: reply,
+      } => {
+        self.pending.insert(id, Pending::Single(reply));
+        write(sender, request).await
+      }
+      Message::Batch {
+        ids,
+        request,
+        sender: reply,
+      } => {
+        if let Some(&id) = ids.first() {
+          self.pending.insert(id, Pending::Batch(ids, reply));
+          write(sender, request).await
+        } else {
+          let _ = reply.send(Ok(Vec::new()));
+          Ok(())
+        }
+      }
+      Message::Subscribe {
+        id,
+        params,
+        request,
+        sender: reply,
+      } => {
+        if params.first().and_then(|p| p.as_str()) == Some("logs") {
+          // where a backfill starts if we lose the connection before any log
+          let internal_id = self.internal_id();
+          self
+            .internal
+            .insert(internal_id.clone(), Internal::StartBlock(id));
+          write(
+            sender,
+            internal_call(internal_id, "eth_blockNumber", Vec::new()),
+          )
+          .await?;
+        }
+        self
+          .pending
+          .insert(id, Pending::Subscribe(params, reply, None));
+        write(sender, request).await
+      }
+      Message::Unsubscribe {
+        id,
+        sub,
+        sender: reply,
+      } => {
+        let server_id = self
+          .detach(&SubscriptionId::from(sub.clone()))
+          .unwrap_or(sub);
+        self.pending.insert(id, Pending::Single(reply));
+        let request = Request::Single(build_request(
+          id,
+          "eth_unsubscribe",
+          vec![Value::String(server_id)],
+        ));
+        write(sender, serde_json::to_string(&request).unwrap()).await
+      }
+      Message::Attach { sub, sink } => {
+        self.attach(sub, sink);
+        Ok(())
+      }
+      Message::Detach { sub } => {
+        self.detach(&sub);
+        Ok(())
+      }
+    }
+  }
+
+  fn attach(&mut self, sub: SubscriptionId, sink: mpsc::UnboundedSender<Value>) {
+    // an unknown id drops the sink, ending the stream right away
+    if let Some(subscription) = self.subscriptions.get_mut(&sub) {
+      subscription.sink = Some(sink);
+      subscription.flush();
+    }
+  }
+
+  // returns the id the current connection knows the subscription by
+  fn detach(&mut self, sub: &SubscriptionId) -> Option<String> {
+    self.subscriptions.remove(sub)?;
+    let server_id = self
+      .server_ids
+      .iter()
+      .find(|(_, user_id)| *user_id == sub)
+      .map(|(server_id, _)| server_id.clone())?;
+    self.server_ids.remove(&server_id);
+    Some(server_id)
+  }
+
+  async fn received(&mut self, data: &[u8], sender: &mut Sender) -> Result<(), connection::Error> {
+    let value: Value = match serde_json::from_slice(data) {
+      Ok(value) => value,
+      Err(e) => {
+        cblog!("WebSocket {} sent invalid JSON: {}", self.name, e);
+        return Ok(());
+      }
+    };
+
+    if value.get("method").and_then(|m| m.as_str()) == Some("eth_subscription") {
+      self.notify(&value["params"]);
+      return Ok(());
+    }
+
+    match serde_json::from_value(value) {
+      Ok(Response::Single(output)) => self.respond(output, sender).await,
+      Ok(Response::Batch(outputs)) => {
+        self.respond_batch(outputs);
+        Ok(())
+      }
+      Err(e) => {
+        cblog!("WebSocket {} sent an unsupported message: {}", self.name, e);
+        Ok(())
+      }
+    }
+  }
+
+  fn notify(&mut self, params: &Value) {
+    let server_id = params.get("subscription").and_then(|s| s.as_str());
+    let sub = server_id.and_then(|id| self.server_ids.get(id)).cloned();
+    if let Some(subscription) = sub.and_then(|sub| self.subscriptions.get_mut(&sub)) {
+      subscription.push(params.get("result").cloned().unwrap_or(Value::Null));
+    }
+  }
+
+  async fn respond(
+    &mut self,
+    output: Output,
+    sender: &mut Sender,
+  ) -> Result<(), connection::Error> {
+    match output.id().clone() {
+      Id::Num(id) => {
+        match self.pending.remove(&(id as RequestId)) {
+          Some(Pending::Single(reply)) => {
+            let _ = reply.send(output_result(output));
+          }
+          Some(Pending::Subscribe(params, reply, start_block)) => {
+            let result = output_result(output);
+            if let Ok(Value::String(sub)) = &result {
+              self
+                .server_ids
+                .insert(sub.clone(), SubscriptionId::from(sub.clone()));
+              self.subscriptions.insert(
+                SubscriptionId::from(sub.clone()),
+                Subscription {
+                  params,
+                  sink: None,
+                  queued: Vec::new(),
+                  last_log: None,
+                  start_block,
+                  backfilling: false,
+                },
+              );
+            }
+            let _ = reply.send(result);
+          }
+          Some(Pending::Batch(_, reply)) => {
+            // a single answer to a batch means the whole batch was refused
+            let error = match output_result(output) {
+              Err(e) => e,
+              Ok(_) => Error::InvalidResponse("Expected a batch response".into()),
+            };
+            let _ = reply.send(Err(error));
+          }
+          None => {}
+        }
+        Ok(())
+      }
+      Id::Str(id) => match self.internal.remove(&id) {
+        Some(Internal::StartBlock(id)) => {
+          let block = output_result(output).ok().and_then(|b| parse_quantity(&b));
+          if let Some(Pending::Subscribe(_, _, start_block)) = self.pending.get_mut(&id) {
+            *start_block = block;
+          }
+          Ok(())
+        }
+        Some(Internal::Resubscribe(sub)) => self.resubscribed(sub, output, sender).await,
+        Some(Internal::Backfill(sub)) => {
+          self.backfilled(&sub, output);
+          Ok(())
+        }
+        None => Ok(()),
+      },
+      Id::Null => Ok(()),
+    }
+  }
+
+  fn respond_batch(&mut self, outputs: Vec<Output>) {
+    let key = outputs.iter().find_map(|output| match output.id() {
+      Id::Num(id) if self.pending.contains_key(&(*id as RequestId)) => Some(*id as RequestId),
+      _ => None,
+    });
+    if let Some(Pending::Batch(ids, reply)) = key.and_then(|key| self.pending.remove(&key)) {
+      let _ = reply.send(Ok(batch_results(&ids, outputs)));
+    }
+  }
+
+  async fn resubscribed(
+    &mut self,
+    sub: SubscriptionId,
+    output: Output,
+    sender: &mut Sender,
+  ) -> Result<(), connection::Error> {
+    let server_id = match output_result(output) {
+      Ok(Value::String(server_id)) => server_id,
+      result => {
+        cblog!(
+          "WebSocket {} failed to restore subscription {:?}: {:?}",
+          self.name,
+          sub,
+          result
+        );
+        // dropping it ends the stream, so the user can tell
+        self.subscriptions.remove(&sub);
+        return Ok(());
+      }
+    };
+    self.server_ids.insert(server_id, sub.clone());
+
+    let filter = match self.subscriptions.get_mut(&sub) {
+      Some(subscription) if subscription.is_logs() => match subscription
+        .last_log
+        .map(|(block, _)| block)
+        // logs of the head block itself were never going to be delivered
+        .or(subscription.start_block.map(|block| block + 1))
+      {
+        Some(block) => {
+          subscription.backfilling = true;
+          let mut filter = subscription
+            .params
+            .get(1)
+            .cloned()
+            .unwrap_or_else(|| Value::Object(Default::default()));
+          filter["fromBlock"] = Value::String(format!("0x{:x}", block));
+          filter["toBlock"] = Value::String("latest".into());
+          filter
+        }
+        None => return Ok(()),
+      },
+      _ => return Ok(()),
+    };
+
+    // fetch whatever was emitted while we were away
+    let id = self.internal_id();
+    self.internal.insert(id.clone(), Internal::Backfill(sub));
+    write(sender, internal_call(id, "eth_getLogs", vec![filter])).await
+  }
+
+  fn backfilled(&mut self, sub: &SubscriptionId, output: Output) {
+    let url = &self.name;
+    if let Some(subscription) = self.subscriptions.get_mut(sub) {
+      let mut values = match output_result(output) {
+        Ok(Value::Array(logs)) => logs,
+        result => {
+          cblog!("WebSocket {} failed to backfill logs: {:?}", url, result);
+          Vec::new()
+        }
+      };
+      values.extend(std::mem::take(&mut subscription.queued));
+      subscription.backfilling = false;
+      for value in values {
+        subscription.push(value);
+      }
+    }
+  }
+
+  fn internal_id(&mut self) -> String {
+    self.counter += 1;
+    format!("cb-ws-{}", self.counter)
+  }
+}
+
+async fn connect(url: &Url, auth: &Auth) -> web3::Result<(Sender, Receiver)> {
+  timeout(CONNECT_TIMEOUT, handshake(url, auth))
+    .await
+    .unwrap_or(Err(Error::Unreachable))
+}
+
+async fn handshake(url: &Url, auth: &Auth) -> web3::Result<(Sender, Receiver)> {
+  let host = url
+    .host_str()
+    .ok_or_else(|| message_error("Invalid WebSocket host".into()))?;
+  let port = url.port_or_known_default().unwrap_or(80);
+  let stream = TcpStream::connect((host, port)).await?;
+  stream.set_nodelay(true)?;
+  let socket: Box<dyn Socket> = match url.scheme() {
+    "wss" => {
+      let stream = async_native_tls::connect(host, stream)
+        .await
+        .or_else(|e| Err(message_error(e.to_string())))?;
+      Box::new(stream.compat())
+    }
+    "ws" => Box::new(stream.compat()),
+    scheme => {
+      return Err(message_error(format!(
+        "Invalid WebSocket scheme: {}",
+        scheme
+      )))
+    }
+  };
+
+  let resource = match url.query() {
+    Some(query) => format!("{}?{}", url.path(), query),
+    None => url.path().to_owned(),
+  };
+  let mut headers = auth.headers();
+  if let (Some(password), false) = (url.password(), auth.has_authorization()) {
+    let credentials = base64::encode(format!("{}:{}", url.username(), password));
+    headers.push(("Authorization".into(), format!("Basic {}", credentials)));
+  }
+  let headers: Vec<Header> = headers
+    .iter()
+    .map(|(name, value)| Header {
+      name: name.as_str(),
+      value: value.as_bytes(),
+    })
+    .collect();
+  let mut client = Client::new(socket, host, &resource);
+  client.set_headers(&headers);
+  let response = client
+    .handshake()
+    .await
+    .or_else(|e| Err(message_error(e.to_string())))?;
+  match response {
+    ServerResponse::Accepted { .. } => Ok(client.into_builder().finish()),
+    ServerResponse::Redirect { status_code, .. } | ServerResponse::Rejected { status_code } => {
+      Err(Error::Transport(TransportError::Code(status_code)))
+    }
+  }
+}
+
+async fn write(sender: &mut Sender, text: String) -> Result<(), connection::Error> {
+  sender.send_text(text).await?;
+  sender.flush().await
+}
+
+fn data_stream(receiver: Receiver) -> impl Stream<Item = Result<Vec<u8>, connection::Error>> {
+  futures::stream::unfold(receiver, |mut receiver| async move {
+    let mut data = Vec::new();
+    let result = receiver.receive_data(&mut data).await.map(|_| data);
+    Some((result, receiver))
+  })
+}
+
+fn internal_call(id: String, method: &str, params: Vec<Value>) -> String {
+  let call = Call::MethodCall(MethodCall {
+    jsonrpc: Some(Version::V2),
+    method: method.into(),
+    params: Params::Array(params),
+    id: Id::Str(id),
+  });
+  serde_json::to_string(&Request::Single(call)).unwrap()
+}
+
+fn params_of(params: &Params) -> Vec<Value> {
+  match params {
+    Params::Array(params) => params.clone(),
+    _ => Vec::new(),
+  }
+}
+
+fn log_key(log: &Value) -> Option<(u64, u64)> {
+  let block = parse_quantity(log.get("blockNumber")?)?;
+  let index = parse_quantity(log.get("logIndex")?)?;
+  Some((block, index))
+}
+
+fn message_error(message: String) -> Error {
+  Error::Transport(TransportError::Message(message))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blocks::mock::event_log;
+  use crate::blocks::mock::quantity;
+  use crate::blocks::mock::wait_until;
+  use crate::blocks::mock::MockNode;
+  use crate::blocks::mock::CONTRACT;
+  use crate::blocks::RUNTIME;
+  use jsonrpc_core::serde_json::json;
+
+  fn socket(node: &MockNode) -> WebSocket {
+    RUNTIME
+      .block_on(WebSocket::new(&node.ws_url(), Arc::new(Auth::default())))
+      .unwrap()
+  }
+
+  fn execute(ws: &WebSocket, method: &str, params: Vec<Value>) -> web3::Result<Value> {
+    RUNTIME.block_on(ws.execute(method, params))
+  }
+
+  fn subscribe(ws: &WebSocket, params: Vec<Value>) -> (String, mpsc::UnboundedReceiver<Value>) {
+    let id = execute(ws, "eth_subscribe", params).unwrap();
+    let id = id.as_str().unwrap().to_owned();
+    let stream = ws.subscribe(SubscriptionId::from(id.clone())).unwrap();
+    (id, stream)
+  }
+
+  fn next(stream: &mut mpsc::UnboundedReceiver<Value>) -> Option<Value> {
+    RUNTIME
+      .block_on(async { timeout(Duration::from_secs(5), stream.next()).await })
+      .expect("no notification")
+  }
+
+  #[test]
+  fn answers_requests_and_batches() {
+    let node = MockNode::start();
+    node.expect("eth_gasPrice", quantity(7));
+    node.expect("eth_blockNumber", quantity(0x20));
+    let ws = socket(&node);
+
+    assert_eq!(execute(&ws, "eth_gasPrice", vec![]).unwrap(), quantity(7));
+    let batch = vec![
+      ws.prepare("eth_blockNumber", vec![]),
+      ws.prepare("eth_gasPrice", vec![]),
+      ws.prepare("eth_nothing", vec![]),
+    ];
+    let results = RUNTIME.block_on(ws.send_batch(batch)).unwrap();
+    assert_eq!(results[0].as_ref().unwrap(), &quantity(0x20));
+    assert_eq!(results[1].as_ref().unwrap(), &quantity(7));
+    assert!(matches!(results[2], Err(Error::Rpc(_))));
+  }
+
+  #[test]
+  fn delivers_notifications() {
+    let node = MockNode::start();
+    let ws = socket(&node);
+    let (_, mut heads) = subscribe(&ws, vec![json!("newHeads")]);
+    node.notify("newHeads", json!({ "number": "0x1" }));
+    node.notify("newHeads", json!({ "number": "0x2" }));
+    assert_eq!(next(&mut heads), Some(json!({ "number": "0x1" })));
+    assert_eq!(next(&mut heads), Some(json!({ "number": "0x2" })));
+  }
+
+  #[test]
+  fn subscriptions_survive_reconnections() {
+    let node = MockNode::start();
+    let ws = socket(&node);
+    let (_, mut heads) = subscribe(&ws, vec![json!("newHeads")]);
+
+    node.drop_connections();
+    wait_until(|| node.subscriptions() == 1);
+    node.notify("newHeads", json!({ "number": "0x3" }));
+    assert_eq!(next(&mut heads), Some(json!({ "number": "0x3" })));
+    assert_eq!(node.count("eth_subscribe"), 2);
+  }
+
+  #[test]
+  fn logs_missed_while_disconnected_are_backfilled() {
+    let node = MockNode::start();
+    let ws = socket(&node);
+    let filter = json!({ "address": format!("0x{}", CONTRACT) });
+    let (_, mut logs) = subscribe(&ws, vec![json!("logs"), filter]);
+    node.notify("logs", event_log(5, 0));
+    assert_eq!(next(&mut logs), Some(event_log(5, 0)));
+
+    node.expect("eth_getLogs", json!([event_log(5, 0), event_log(6, 0)]));
+    node.drop_connections();
+    wait_until(|| node.subscriptions() == 1);
+    assert_eq!(next(&mut logs), Some(event_log(6, 0)));
+
+    let backfill = &node.requests("eth_getLogs")[0];
+    assert_eq!(backfill.params[0]["fromBlock"], "0x5");
+    assert_eq!(backfill.params[0]["address"], format!("0x{}", CONTRACT));
+
+    // the live subscription might repeat what the backfill delivered
+    node.notify("logs", event_log(6, 0));
+    node.notify("logs", event_log(7, 0));
+    assert_eq!(next(&mut logs), Some(event_log(7, 0)));
+  }
+
+  #[test]
+  fn logs_before_the_first_notification_are_backfilled() {
+    let node = MockNode::start();
+    node.expect("eth_blockNumber", quantity(0x12));
+    let ws = socket(&node);
+    let filter = json!({ "address": format!("0x{}", CONTRACT) });
+    let (_, mut logs) = subscribe(&ws, vec![json!("logs"), filter]);
+
+    node.expect("eth_getLogs", json!([event_log(0x13, 0)]));
+    node.drop_connections();
+    wait_until(|| node.subscriptions() == 1);
+    assert_eq!(next(&mut logs), Some(event_log(0x13, 0)));
+    let backfill = &node.requests("eth_getLogs")[0];
+    assert_eq!(backfill.params[0]["fromBlock"], "0x13");
+  }
+
+  #[test]
+  fn unsubscribing_ends_the_stream() {
+    let node = MockNode::start();
+    let ws = socket(&node);
+    let (id, mut heads) = subscribe(&ws, vec![json!("newHeads")]);
+    assert_eq!(
+      execute(&ws, "eth_unsubscribe", vec![json!(id)]).unwrap(),
+      json!(true)
+    );
+    assert_eq!(node.subscriptions(), 0);
+    assert_eq!(next(&mut heads), None);
+  }
+}
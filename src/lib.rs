@@ -1,4 +1,5 @@
-#[macro_use]
+// the blocks only register themselves outside of tests
+#[cfg_attr(not(test), macro_use)]
 extern crate ctor;
 extern crate log;
 #[macro_use]
@@ -95,23 +96,48 @@ macro_rules! bytes_to_var_no_opt {
   }};
 }
 
-#[cfg(not(test))]
 mod blocks {
+  mod address;
+  mod auth;
   mod block;
+  mod cache;
+  mod chainid;
+  mod code;
   mod contract;
   mod currentblock;
+  mod ens;
+  mod erc20;
   mod estimategas;
   mod eth;
   mod gasprice;
+  mod http;
+  mod limiter;
+  mod metrics;
+  #[cfg(test)]
+  mod mock;
+  mod multi;
+  mod proof;
   mod read;
   mod read_batch;
+  mod record;
+  mod retry;
+  mod rlp;
+  mod rpc;
+  mod selector;
   mod sendraw;
+  mod slot;
+  mod stats;
   mod storage;
+  mod storage_var;
   mod tokens;
   mod transaction;
+  mod trie;
+  mod units;
   mod unlock;
+  mod verify_block;
   mod waitevent;
   mod write;
+  mod ws;
 
   extern crate chainblocks;
   extern crate futures;
@@ -121,9 +147,18 @@ mod blocks {
   extern crate web3;
   extern crate zeroize;
 
+  use address::parse_address;
+  use address::Create;
+  use address::Create2;
+  use address::FromKey;
+  use address::ToChecksum;
   use block::EthBlock;
+  use cache::Cache;
   use chainblocks::cbstr;
+  #[cfg(not(test))]
   use chainblocks::core::init;
+  #[cfg(not(test))]
+  use chainblocks::core::log;
   use chainblocks::core::registerBlock;
   use chainblocks::types::common_type;
   use chainblocks::types::ExposedTypes;
@@ -131,45 +166,157 @@ mod blocks {
   use chainblocks::types::RawString;
   use chainblocks::types::Type;
   use chainblocks::types::Var;
+  use chainid::ChainId;
+  use code::Code;
   use contract::SharedContract;
   use currentblock::CurrentBlock;
+  use ens::EnsCache;
+  use ens::EnsRecord;
+  use ens::LookupAddress;
+  use erc20::Allowance;
+  use erc20::Approve;
+  use erc20::BalanceOf;
+  use erc20::Decimals;
+  use erc20::DecimalsCache;
+  use erc20::Permit;
+  use erc20::Symbol;
+  use erc20::TotalSupply;
+  use erc20::Transfer;
+  use erc20::TransferFrom;
   use estimategas::EstimateGas;
   use eth::Eth;
   use gasprice::GasPrice;
   use json::JsonValue;
+  use metrics::Metrics;
+  use proof::Proof;
   use read::Read;
   use read_batch::ReadBatch;
+  use retry::RetryPolicy;
+  use rpc::Rpc;
   use sendraw::SendRaw;
+  use slot::Slot;
+  use stats::Stats;
   use std::convert::TryInto;
   use std::env;
   use std::ffi::CString;
-  use std::rc::Rc;
+  use std::sync::Arc;
+  use std::sync::RwLock;
   use std::time::Duration;
   use storage::Storage;
+  use storage_var::StorageVar;
+  use tokio::runtime::Builder;
   use tokio::runtime::Runtime;
   use transaction::Transaction;
+  use units::FormatUnits;
+  use units::ParseUnits;
   use unlock::Unlock;
+  use verify_block::VerifyBlock;
   use waitevent::WaitEvent;
   use web3::contract::Contract;
   use web3::types::Address;
+  use write::Nonces;
   use write::Write;
 
-  type Transport =
-    web3::transports::either::Either<web3::transports::Http, web3::transports::WebSocket>;
+  type Transport = multi::MultiTransport;
+
+  // there is no chainblocks host to log to in tests
+  #[cfg(test)]
+  fn log(s: &str) {
+    eprintln!("{}", s.trim_end_matches('\0'));
+  }
 
   struct NodeData {
     web3: web3::Web3<Transport>,
-    scheduler: Runtime,
+    retry: RetryPolicy,
+    chain_id: u64,
+    // used by blocks without their own Timeout
+    timeout: Duration,
+    cache: Option<Arc<Cache>>,
+    metrics: Arc<Metrics>,
+    ens: EnsCache,
+    // None on chains without ENS
+    ens_registry: Option<Address>,
+    decimals: DecimalsCache,
+    nonces: Nonces,
+  }
+
+  lazy_static! {
+    // shared by every node, so requests from different blocks overlap.
+    // blocks still block_on it from do_blocking or activate_blocking,
+    // so each waiting block holds a chainblocks worker thread until it's answered
+    static ref RUNTIME: Runtime = Builder::new_multi_thread()
+      .enable_all()
+      .thread_name("chainblocks-web3")
+      .build()
+      .expect("Failed to create tokio runtime!");
+  }
+
+  // nodes and contracts are used by chains running on any thread
+  const _: fn() = || {
+    fn shared<T: Send + Sync>() {}
+    shared::<NodeData>();
+    shared::<ContractData>();
+  };
+
+  /// The object an Eth or Eth.Contract exposes to other blocks.
+  /// Their variable points right at it, so chainblocks never owns a count,
+  /// every user takes its own Arc.
+  struct Shared<T>(RwLock<Option<Arc<T>>>);
+
+  impl<T> Default for Shared<T> {
+    fn default() -> Self {
+      Shared(RwLock::new(None))
+    }
+  }
+
+  impl<T> Shared<T> {
+    /// Replaces the object, users keep the one they took until they take it again.
+    fn set(&self, value: Option<T>) {
+      *self.0.write().unwrap_or_else(|e| e.into_inner()) = value.map(Arc::new);
+    }
+
+    /// The variable to expose, valid as long as the block owning this lives.
+    fn var(&self, info: &Type) -> Var {
+      Var::new_object_from_ptr(self as *const Shared<T>, info)
+    }
+  }
+
+  /// Takes our own handle to the object exposed by Eth or Eth.Contract.
+  fn shared_from_var<'a, T>(var: Var, info: &'a Type) -> Result<Arc<T>, &'a str> {
+    let shared = Var::from_object_ptr_mut_ref::<Shared<T>>(var, info)?;
+    let object = shared.0.read().unwrap_or_else(|e| e.into_inner()).clone();
+    object.ok_or("Object was not initialized")
+  }
+
+  fn get_shared<T>(shared: &Option<Arc<T>>) -> Result<&T, &'static str> {
+    shared
+      .as_deref()
+      .ok_or("Failed to unwrap shared object, was empty")
   }
 
   static NODE_TYPE: Type = Type::object(1936289387, 1702127694);
   static NODE_TYPE_VEC: &'static [Type] = &[NODE_TYPE];
   static NODE_VAR: Type = Type::context_variable(NODE_TYPE_VEC);
 
+  static TIMEOUT_TYPES: &'static [Type] = &[common_type::int, common_type::float];
+  static TIMEOUT_VAR: Type = Type::context_variable(TIMEOUT_TYPES);
+
+  // an EIP-1898 block hash, see selector::BlockSelector
+  static BLOCK_HASH_TYPES: &'static [Type] = &[common_type::bytes, common_type::bool];
+  const BLOCK_HASH_KEYS: &[RawString] = &[cbstr!("blockHash"), cbstr!("requireCanonical")];
+  static BLOCK_HASH_TYPE: Type = Type::table(BLOCK_HASH_KEYS, BLOCK_HASH_TYPES);
+  static BLOCK_TYPES: &'static [Type] = &[
+    common_type::int,
+    common_type::string,
+    common_type::bytes,
+    BLOCK_HASH_TYPE,
+  ];
+  static BLOCK_VAR: Type = Type::context_variable(BLOCK_TYPES);
+
   struct ContractData {
     contract: Contract<Transport>,
     json_abi: JsonValue,
-    node: Var,
+    node: Arc<NodeData>,
   }
 
   static CONTRACT_TYPE: Type = Type::object(1936289387, 1702127683);
@@ -193,7 +340,7 @@ mod blocks {
   static TX_TABLE_TYPE: Type = Type::table(TABLE_KEYS, TABLE_TYPES);
 
   struct EthData {
-    contract: Option<Rc<Option<ContractData>>>,
+    contract: Option<Arc<ContractData>>,
     method: CString,
     from: Option<CString>,
     input_types: Vec<String>,
@@ -203,7 +350,7 @@ mod blocks {
     instance: ParamVar,
     from: ParamVar,
     data: EthData,
-    node: Option<Rc<Option<NodeData>>>,
+    node: Option<Arc<NodeData>>,
     requiring: ExposedTypes,
   }
 
@@ -212,15 +359,7 @@ mod blocks {
       let saddress: Result<&str, &str> = v.as_ref().try_into();
       let baddress: Result<&[u8], &str> = v.as_ref().try_into();
       if let Ok(s) = saddress {
-        if s.starts_with("0x") {
-          let subs: &str = &s[2..];
-          subs
-            .parse()
-            .or_else(|_| Err("Failed to parse Contract address"))?
-        } else {
-          s.parse()
-            .or_else(|_| Err("Failed to parse Contract address"))?
-        }
+        parse_address(s, "Failed to parse Contract address")?
       } else if let Ok(b) = baddress {
         let a20: [u8; 20] = b
           .try_into()
@@ -247,10 +386,42 @@ mod blocks {
     }
   }
 
+  /// Reads a Timeout parameter value in seconds, none if unset.
+  pub fn timeout_from_var<'a>(value: &Var) -> Result<Option<Duration>, &'a str> {
+    if value.is_none() {
+      return Ok(None);
+    }
+    let secs: f64 = if let Ok(secs) = TryInto::<i64>::try_into(value) {
+      secs as f64
+    } else {
+      value.try_into()?
+    };
+    // from_secs_f64 panics on infinity and on anything past u64::MAX seconds
+    if !secs.is_finite() || secs >= u64::MAX as f64 {
+      Err("Timeout must be a finite number of seconds")
+    } else if secs > 0.0 {
+      Ok(Some(Duration::from_secs_f64(secs)))
+    } else {
+      Err("Timeout must be greater than 0")
+    }
+  }
+
+  // the block Timeout if set, otherwise the Eth one
+  pub fn get_block_timeout<'a>(param: &ParamVar, node: &NodeData) -> Result<Duration, &'a str> {
+    Ok(timeout_from_var(&param.get())?.unwrap_or(node.timeout))
+  }
+
+  #[cfg(not(test))]
   #[ctor]
-  fn register_blocks() {
+  fn init_blocks() {
     env_logger::init();
     init();
+    register_blocks();
+  }
+
+  // blocks only run inside a chainblocks host, tests drive what sits underneath
+  #[cfg_attr(test, allow(dead_code))]
+  fn register_blocks() {
     registerBlock::<Eth>();
     registerBlock::<CurrentBlock>();
     registerBlock::<EstimateGas>();
@@ -265,5 +436,30 @@ mod blocks {
     registerBlock::<ReadBatch>();
     registerBlock::<SendRaw>();
     registerBlock::<EthBlock>();
+    registerBlock::<ChainId>();
+    registerBlock::<Stats>();
+    registerBlock::<Rpc>();
+    registerBlock::<Slot>();
+    registerBlock::<StorageVar>();
+    registerBlock::<Proof>();
+    registerBlock::<VerifyBlock>();
+    registerBlock::<Code>();
+    registerBlock::<LookupAddress>();
+    registerBlock::<EnsRecord>();
+    registerBlock::<ToChecksum>();
+    registerBlock::<FromKey>();
+    registerBlock::<Create>();
+    registerBlock::<Create2>();
+    registerBlock::<ParseUnits>();
+    registerBlock::<FormatUnits>();
+    registerBlock::<BalanceOf>();
+    registerBlock::<Allowance>();
+    registerBlock::<Decimals>();
+    registerBlock::<Symbol>();
+    registerBlock::<TotalSupply>();
+    registerBlock::<Transfer>();
+    registerBlock::<Approve>();
+    registerBlock::<TransferFrom>();
+    registerBlock::<Permit>();
   }
 }
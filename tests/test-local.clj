@@ -13,7 +13,7 @@
 
    ; Estimate gas
    "0x0" >> .args ; from addr
-   1000 (BigInt) (BigInt.Shift 18) >> .args ; amount
+   1000 (Eth.ParseUnits) >> .args ; amount
    .args
    (Eth.EstimateGas :Method "approve"
                     :From "0x0")
@@ -21,10 +21,9 @@
 
    (Clear .args)
    
-   "0x0" >> .args ; addr
-   .args
-   (Eth.Read :Method "balanceOf")
-   (Take 0) (BigInt.ToString) (Log "balance")
+   "0x0" ; addr
+   (Eth.ERC20.BalanceOf :Token "0x6b175474e89094c44da98b954eedeac495271d0f")
+   (Take "amount") (Log "balance")
    (Pause 1.0)))
 
 (schedule Root test)
@@ -26,7 +26,7 @@
    ; Make a constant call
    "0xEeeeeEeeeEeEeeEeEeEeeEEEeeeeEeeeeeeeEEeE" >> .args ; from token (ETH)
    "0x6b175474e89094c44da98b954eedeac495271d0f" >> .args ; to token (DAI)
-   1 (BigInt) (BigInt.Shift 18) >> .args ; amount
+   1 (Eth.ParseUnits) >> .args ; amount
    100  >> .args ; parts
    0 >> .args ; disable flags
    .args
@@ -35,18 +35,18 @@
    (Log) >= .res
    ; Print results
    .res (Take 0) (ExpectBytes) >= .expected
-   (BigInt.ToFloat -18) (Log "price")
+   (Eth.FormatUnits) (Log "price")
    .res (Take 1) (ExpectSeq) >= .distribution
    (ForEach #((ExpectBytes) (BigInt.ToFloat) (Log "dexes")))
 
    (Clear .args)
 
-   1 (BigInt) (BigInt.Shift 18) (Set "options" "value")
+   1 (Eth.ParseUnits) (Set "options" "value")
    (Eth.GasPrice) (Set "options" "gas-price")
    "500000" (BigInt) (Set "options" "gas")
    "0xEeeeeEeeeEeEeeEeEeEeeEEEeeeeEeeeeeeeEEeE" >> .args ; from token (ETH)
    "0x6b175474e89094c44da98b954eedeac495271d0f" >> .args ; to token (DAI)
-   1 (BigInt) (BigInt.Shift 18) >> .args ; amount
+   1 (Eth.ParseUnits) >> .args ; amount
    .expected >> .args ; min return
    .distribution >> .args ; distribution
    0 >> .args ; flags